@@ -0,0 +1,252 @@
+use super::*;
+use crate::{
+    blockchain::Blockchain,
+    hash::TxId,
+    transaction::{block_subsidy, Transaction, HALVING_INTERVAL},
+    utxoset::UTXOSet,
+    wallets::*,
+};
+use std::collections::HashMap;
+
+/// AddressClusters groups public key hashes that are inferred to belong to
+/// the same owner using the common-input-ownership heuristic: every input of
+/// a transaction is assumed to be signed by the same entity.
+pub struct AddressClusters {
+    parent: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Cluster is the set of addresses inferred to share an owner
+pub struct Cluster {
+    pub addresses: Vec<String>,
+    pub balance: i32,
+}
+
+/// EraEmission compares the coins the subsidy schedule allows in one halving
+/// era with the coins the chain actually created
+pub struct EraEmission {
+    pub era: i32,
+    pub first_height: i32,
+    pub last_height: i32,
+    pub blocks: i32,
+    pub subsidy: i32,
+    pub theoretical: i64,
+    pub issued: i64,
+    pub fees: i64,
+    pub burned: i64,
+}
+
+impl EraEmission {
+    /// unclaimed is the part of the allowed subsidy and fees that miners did not collect
+    pub fn unclaimed(&self) -> i64 {
+        self.theoretical - self.issued
+    }
+}
+
+/// emission_report walks the chain from genesis and groups coin issuance by halving era.
+/// Fees collected by a coinbase are moved coins, so they are not counted as issued.
+pub fn emission_report(blockchain: &Blockchain) -> Vec<EraEmission> {
+    let mut output_values: HashMap<TxId, Vec<i32>> = HashMap::new();
+    let mut eras: Vec<EraEmission> = Vec::new();
+    for block in blockchain.walk().oldest_first().blocks() {
+        let height = block.get_height();
+        let era = height / HALVING_INTERVAL;
+        if eras.last().map(|e| e.era) != Some(era) {
+            eras.push(EraEmission {
+                era,
+                first_height: height,
+                last_height: height,
+                blocks: 0,
+                subsidy: block_subsidy(height),
+                theoretical: 0,
+                issued: 0,
+                fees: 0,
+                burned: 0,
+            });
+        }
+
+        let mut coinbase_value: i64 = 0;
+        let mut fees: i64 = 0;
+        let mut burned: i64 = 0;
+        for tx in block.get_transactions() {
+            let out_value: i64 = tx.vout.iter().map(|out| out.value as i64).sum();
+            burned += tx
+                .vout
+                .iter()
+                .filter(|out| out.is_unspendable())
+                .map(|out| out.value as i64)
+                .sum::<i64>();
+            if tx.is_coinbase() {
+                coinbase_value += out_value;
+            } else {
+                let in_value: i64 = tx
+                    .vin
+                    .iter()
+                    .filter_map(|vin| {
+                        output_values
+                            .get(&vin.txid)
+                            .and_then(|values| values.get(vin.vout as usize))
+                    })
+                    .map(|value| *value as i64)
+                    .sum();
+                fees += in_value - out_value;
+            }
+            output_values.insert(tx.id, tx.vout.iter().map(|out| out.value).collect());
+        }
+
+        let entry = eras.last_mut().unwrap();
+        entry.last_height = height;
+        entry.blocks += 1;
+        entry.theoretical += block_subsidy(height) as i64;
+        entry.fees += fees;
+        entry.burned += burned;
+        entry.issued += coinbase_value - fees;
+    }
+    eras
+}
+
+impl AddressClusters {
+    pub fn build(blockchain: &Blockchain) -> Self {
+        let mut transactions = Vec::new();
+        for block in blockchain.walk().blocks() {
+            transactions.extend(block.get_transactions().iter().cloned());
+        }
+        Self::from_transactions(&transactions)
+    }
+
+    pub fn from_transactions(transactions: &[Transaction]) -> Self {
+        let mut clusters = AddressClusters {
+            parent: HashMap::new(),
+        };
+
+        for tx in transactions {
+            for out in &tx.vout {
+                clusters.insert(&out.pub_key_hash);
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+
+            let mut first: Option<Vec<u8>> = None;
+            for vin in &tx.vin {
+                let mut pub_key_hash = vin.pub_key.clone();
+                hash_pub_key(&mut pub_key_hash);
+                clusters.insert(&pub_key_hash);
+                match &first {
+                    Some(f) => clusters.union(f, &pub_key_hash),
+                    None => first = Some(pub_key_hash),
+                }
+            }
+        }
+        clusters
+    }
+
+    /// members returns every public key hash in the same cluster as `pub_key_hash`
+    pub fn members(&mut self, pub_key_hash: &[u8]) -> Vec<Vec<u8>> {
+        if !self.parent.contains_key(pub_key_hash) {
+            return vec![pub_key_hash.to_vec()];
+        }
+        let root = self.find(pub_key_hash);
+        let keys: Vec<Vec<u8>> = self.parent.keys().cloned().collect();
+        let mut members = Vec::new();
+        for key in keys {
+            if self.find(&key) == root {
+                members.push(key);
+            }
+        }
+        members.sort();
+        members
+    }
+
+    /// cluster resolves the members of the cluster to addresses and sums their unspent outputs
+    pub fn cluster(&mut self, pub_key_hash: &[u8], utxo_set: &UTXOSet) -> Result<Cluster> {
+        let mut addresses = Vec::new();
+        let mut balance = 0;
+        for member in self.members(pub_key_hash) {
+            for utxo in utxo_set.find_UTXO(&member)? {
+                balance += utxo.output.value;
+            }
+            addresses.push(hash_to_address(member));
+        }
+        Ok(Cluster { addresses, balance })
+    }
+
+    fn insert(&mut self, pub_key_hash: &[u8]) {
+        if !self.parent.contains_key(pub_key_hash) {
+            self.parent
+                .insert(pub_key_hash.to_vec(), pub_key_hash.to_vec());
+        }
+    }
+
+    fn find(&mut self, pub_key_hash: &[u8]) -> Vec<u8> {
+        let mut root = pub_key_hash.to_vec();
+        while self.parent[&root] != root {
+            root = self.parent[&root].clone();
+        }
+
+        let mut node = pub_key_hash.to_vec();
+        while node != root {
+            let next = self.parent[&node].clone();
+            self.parent.insert(node, root.clone());
+            node = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: &[u8], b: &[u8]) {
+        let root_a = self.find(a);
+        let root_b = self.find(b);
+        if root_a != root_b {
+            self.parent.insert(root_b, root_a);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::LockScript;
+    use crate::transaction::{TXInput, TXOutput};
+
+    fn input(key: u8) -> TXInput {
+        TXInput {
+            txid: TxId::digest(b"prev"),
+            vout: 0,
+            signature: Vec::new(),
+            pub_key: vec![key; 32],
+        }
+    }
+
+    fn hashed(key: u8) -> Vec<u8> {
+        let mut pub_key_hash = vec![key; 32];
+        hash_pub_key(&mut pub_key_hash);
+        pub_key_hash
+    }
+
+    #[test]
+    fn test_common_input_ownership() {
+        let tx1 = Transaction {
+            id: TxId::digest(b"tx1"),
+            vin: vec![input(1), input(2)],
+            vout: vec![TXOutput {
+                value: 5,
+                pub_key_hash: hashed(9),
+                lock: LockScript::PayToPubkeyHash,
+            }],
+            lock_time: 0,
+            expiry_height: 0,
+        };
+        let tx2 = Transaction {
+            id: TxId::digest(b"tx2"),
+            vin: vec![input(2), input(3)],
+            vout: Vec::new(),
+            lock_time: 0,
+            expiry_height: 0,
+        };
+
+        let mut clusters = AddressClusters::from_transactions(&[tx1, tx2]);
+        let alice = clusters.members(&hashed(1));
+        assert_eq!(alice.len(), 3);
+        assert!(alice.contains(&hashed(3)));
+        assert_eq!(clusters.members(&hashed(9)), vec![hashed(9)]);
+    }
+}
@@ -0,0 +1,471 @@
+use super::*;
+use crate::{
+    block::Block,
+    blockchain::BlockPruned,
+    events::TimedEvent,
+    hash::{BlockHash, TxId},
+    mempool::Mempool,
+    metrics::Metrics,
+    transaction::Transaction,
+    utxoset::UTXOSet,
+    websocket,
+};
+use bincode::serialize;
+use bitcoincash_addr::Address;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::mpsc::Receiver,
+};
+
+/// BLOCKS_PAGE is the number of blocks `/blocks` lists, the most recent first
+const BLOCKS_PAGE: usize = 20;
+
+/// ApiResponse is the HTTP status and body answering an API request
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl ApiResponse {
+    fn ok<T: Serialize>(body: &T) -> Result<Self> {
+        Ok(ApiResponse {
+            status: 200,
+            content_type: "application/json",
+            body: serde_json::to_string(body)?,
+        })
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        ApiResponse {
+            status,
+            content_type: "application/json",
+            body: json!({ "error": message }).to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BlockSummary {
+    pub hash: BlockHash,
+    pub prev_hash: BlockHash,
+    pub height: i32,
+    pub timestamp: u128,
+    pub transactions: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BlockView {
+    pub hash: BlockHash,
+    pub prev_hash: BlockHash,
+    pub height: i32,
+    pub timestamp: u128,
+    pub merkle_root: String,
+    pub transactions: Vec<TxView>,
+}
+
+/// TxView is a transaction as explorers show it, with outputs paid to addresses
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TxView {
+    pub id: TxId,
+    pub confirmed: bool,
+    pub coinbase: bool,
+    pub inputs: Vec<InputView>,
+    pub outputs: Vec<OutputView>,
+}
+
+/// InputView shows the value and address of the output an input spends, when it is
+/// still found
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct InputView {
+    pub txid: TxId,
+    pub vout: i32,
+    pub value: Option<i32>,
+    pub address: Option<String>,
+}
+
+/// OutputView has no address for provably unspendable outputs
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct OutputView {
+    pub value: i32,
+    pub address: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MempoolView {
+    pub id: TxId,
+    /// fee is unknown while the transaction spends outputs of other pending transactions
+    pub fee: Option<i32>,
+    pub size: usize,
+}
+
+impl TxView {
+    fn new(tx: &Transaction, confirmed: bool, utxo: &UTXOSet) -> Result<Self> {
+        Ok(TxView {
+            id: tx.id,
+            confirmed,
+            coinbase: tx.is_coinbase(),
+            inputs: tx
+                .vin
+                .iter()
+                .zip(utxo.resolve_inputs(tx)?)
+                .map(|(vin, prev)| InputView {
+                    txid: vin.txid,
+                    vout: vin.vout,
+                    value: prev.as_ref().map(|out| out.value),
+                    address: prev.and_then(|out| out.address()),
+                })
+                .collect(),
+            outputs: tx
+                .vout
+                .iter()
+                .map(|out| OutputView {
+                    value: out.value,
+                    address: out.address(),
+                })
+                .collect(),
+        })
+    }
+}
+
+impl BlockView {
+    fn new(block: &Block, utxo: &UTXOSet) -> Result<Self> {
+        Ok(BlockView {
+            hash: block.get_hash(),
+            prev_hash: block.get_prev_hash(),
+            height: block.get_height(),
+            timestamp: block.get_timestamp(),
+            merkle_root: hex::encode(block.get_merkle_root()),
+            transactions: block
+                .get_transactions()
+                .iter()
+                .map(|tx| TxView::new(tx, true, utxo))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// route answers a GET of `path`, without its query string, from the node state
+pub fn route(path: &str, utxo: &UTXOSet, mempool: &Mempool, metrics: &Metrics) -> ApiResponse {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = match segments.as_slice() {
+        ["blocks"] => list_blocks(utxo, None),
+        ["blocks", height] => match height.parse() {
+            Ok(height) => list_blocks(utxo, Some(height)),
+            Err(_) => Ok(ApiResponse::error(
+                400,
+                &format!("Invalid height: {}", height),
+            )),
+        },
+        ["block", hash] => match hash.parse() {
+            Ok(hash) => get_block(utxo, &hash),
+            Err(e) => Ok(ApiResponse::error(400, &e.to_string())),
+        },
+        ["tx", id] => match id.parse() {
+            Ok(id) => get_transaction(utxo, mempool, &id),
+            Err(e) => Ok(ApiResponse::error(400, &e.to_string())),
+        },
+        ["tx", id, "spent", vout] => match (id.parse(), vout.parse()) {
+            (Ok(id), Ok(vout)) => get_spender(utxo, &id, vout),
+            (Err(e), _) => Ok(ApiResponse::error(400, &e.to_string())),
+            (_, Err(_)) => Ok(ApiResponse::error(
+                400,
+                &format!("Invalid output index: {}", vout),
+            )),
+        },
+        ["block", hash, "filter"] => match hash.parse() {
+            Ok(hash) => get_block_filter(utxo, &hash),
+            Err(e) => Ok(ApiResponse::error(400, &e.to_string())),
+        },
+        ["address", address, "balance"] => get_balance(utxo, address),
+        ["address", address, "txs"] => list_address_transactions(utxo, address),
+        ["indexes"] => utxo
+            .blockchain
+            .index_info()
+            .and_then(|info| ApiResponse::ok(&info)),
+        ["mempool"] => list_mempool(utxo, mempool),
+        ["metrics"] => Ok(ApiResponse {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: metrics.render(),
+        }),
+        _ => Ok(ApiResponse::error(
+            404,
+            &format!("No such endpoint: {}", path),
+        )),
+    };
+    response.unwrap_or_else(|e| ApiResponse::error(500, &e.to_string()))
+}
+
+/// list_blocks pages through the best chain from height `from`, or from the tip, down
+/// to the first pruned block
+fn list_blocks(utxo: &UTXOSet, from: Option<i32>) -> Result<ApiResponse> {
+    let from = match from {
+        Some(from) => from.min(utxo.blockchain.get_best_height()?),
+        None => utxo.blockchain.get_best_height()?,
+    };
+    let start = match utxo.blockchain.get_block_hash(from)? {
+        Some(hash) => hash,
+        None => return ApiResponse::ok(&Vec::<BlockSummary>::new()),
+    };
+    let blocks: Vec<BlockSummary> = utxo
+        .blockchain
+        .walk_from(start)
+        .with_bodies()
+        .take(BLOCKS_PAGE)
+        .map_while(|entry| {
+            Some(BlockSummary {
+                hash: entry.header.hash,
+                prev_hash: entry.header.prev_block_hash,
+                height: entry.height,
+                timestamp: entry.header.timestamp,
+                transactions: entry.body?.get_transactions().len(),
+            })
+        })
+        .collect();
+    ApiResponse::ok(&blocks)
+}
+
+fn get_block(utxo: &UTXOSet, hash: &BlockHash) -> Result<ApiResponse> {
+    if !utxo.blockchain.has_block(hash)? {
+        return Ok(ApiResponse::error(
+            404,
+            &format!("Block {} is not found", hash),
+        ));
+    }
+    match utxo.blockchain.get_block(hash) {
+        Ok(block) => ApiResponse::ok(&BlockView::new(&block, utxo)?),
+        Err(e) if e.downcast_ref::<BlockPruned>().is_some() => {
+            Ok(ApiResponse::error(410, &e.to_string()))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn get_transaction(utxo: &UTXOSet, mempool: &Mempool, id: &TxId) -> Result<ApiResponse> {
+    if let Some(tx) = mempool.get(id) {
+        return ApiResponse::ok(&TxView::new(tx, false, utxo)?);
+    }
+    match utxo.blockchain.find_transaction(id) {
+        Ok(tx) => ApiResponse::ok(&TxView::new(&tx, true, utxo)?),
+        Err(_) => Ok(ApiResponse::error(
+            404,
+            &format!("Transaction {} is not found", id),
+        )),
+    }
+}
+
+fn get_balance(utxo: &UTXOSet, address: &str) -> Result<ApiResponse> {
+    let pub_key_hash = match Address::decode(address) {
+        Ok(address) => address.body,
+        Err(_) => {
+            return Ok(ApiResponse::error(
+                400,
+                &format!("Invalid address: {}", address),
+            ))
+        }
+    };
+    let balance: i32 = utxo
+        .find_UTXO(&pub_key_hash)?
+        .iter()
+        .map(|utxo| utxo.output.value)
+        .sum();
+    ApiResponse::ok(&json!({
+        "address": address,
+        "balance": balance,
+        "height": utxo.blockchain.get_best_height()?,
+    }))
+}
+
+/// unavailable answers 501 for an index the node does not keep, so explorers can tell
+/// it apart from a missing entry
+fn unavailable(utxo: &UTXOSet, index: &str) -> Option<ApiResponse> {
+    if utxo.blockchain.is_archive() {
+        return None;
+    }
+    Some(ApiResponse::error(
+        501,
+        &format!("The {} is only kept by archive nodes", index),
+    ))
+}
+
+fn get_spender(utxo: &UTXOSet, id: &TxId, vout: i32) -> Result<ApiResponse> {
+    if let Some(response) = unavailable(utxo, "spent index") {
+        return Ok(response);
+    }
+    match utxo.blockchain.find_spender(id, vout)? {
+        Some((spender, height)) => ApiResponse::ok(&json!({
+            "txid": id,
+            "vout": vout,
+            "spent": true,
+            "spender": spender,
+            "height": height,
+        })),
+        None => ApiResponse::ok(&json!({ "txid": id, "vout": vout, "spent": false })),
+    }
+}
+
+fn get_block_filter(utxo: &UTXOSet, hash: &BlockHash) -> Result<ApiResponse> {
+    if let Some(response) = unavailable(utxo, "compact filter index") {
+        return Ok(response);
+    }
+    match utxo.blockchain.get_block_filter(hash)? {
+        Some(filter) => ApiResponse::ok(&json!({
+            "hash": hash,
+            "items": filter.n,
+            "filter": hex::encode(&filter.data),
+        })),
+        None => Ok(ApiResponse::error(
+            404,
+            &format!("No filter of block {} is indexed", hash),
+        )),
+    }
+}
+
+fn list_address_transactions(utxo: &UTXOSet, address: &str) -> Result<ApiResponse> {
+    if let Some(response) = unavailable(utxo, "address index") {
+        return Ok(response);
+    }
+    let pub_key_hash = match Address::decode(address) {
+        Ok(address) => address.body,
+        Err(_) => {
+            return Ok(ApiResponse::error(
+                400,
+                &format!("Invalid address: {}", address),
+            ))
+        }
+    };
+    let txs: Vec<_> = utxo
+        .blockchain
+        .find_address_transactions(&pub_key_hash)?
+        .into_iter()
+        .map(|(height, txid)| json!({ "txid": txid, "height": height }))
+        .collect();
+    ApiResponse::ok(&json!({ "address": address, "transactions": txs }))
+}
+
+fn list_mempool(utxo: &UTXOSet, mempool: &Mempool) -> Result<ApiResponse> {
+    let mut txs = Vec::new();
+    for tx in mempool.transactions() {
+        txs.push(MempoolView {
+            id: tx.id,
+            fee: utxo.blockchain.get_fee(tx).ok(),
+            size: serialize(tx)?.len(),
+        });
+    }
+    ApiResponse::ok(&txs)
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        410 => "Gone",
+        501 => "Not Implemented",
+        _ => "Internal Server Error",
+    }
+}
+
+/// serve reads one HTTP request from `stream` and writes the answer of `handler`
+/// for its path; only GET is supported. A WebSocket upgrade of `/events` instead
+/// streams every event of the receiver `events` returns as a JSON text frame.
+pub fn serve(
+    stream: TcpStream,
+    handler: impl Fn(&str) -> ApiResponse,
+    events: impl FnOnce() -> Receiver<TimedEvent>,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header == "\r\n" || header == "\n" {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => {
+            let path = target.split('?').next().unwrap_or_default();
+            let upgrade = headers.get("upgrade").map(|v| v.to_lowercase());
+            match headers.get("sec-websocket-key") {
+                Some(key) if path == "/events" && upgrade.as_deref() == Some("websocket") => {
+                    return stream_events(reader.get_mut(), key, events());
+                }
+                _ => handler(path),
+            }
+        }
+        (Some(_), Some(_)) => ApiResponse::error(405, "Only GET is supported"),
+        _ => ApiResponse::error(400, "Malformed request"),
+    };
+
+    write!(
+        reader.get_mut(),
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        status_text(response.status),
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    Ok(())
+}
+
+/// stream_events forwards events over a WebSocket until the client goes away
+fn stream_events(stream: &mut TcpStream, key: &str, events: Receiver<TimedEvent>) -> Result<()> {
+    websocket::handshake(stream, key)?;
+    for event in events {
+        if websocket::send_text(stream, &serde_json::to_string(&event)?).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{io::Read, net::TcpListener, thread};
+
+    fn request(raw: &str) -> String {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(
+                stream,
+                |path| ApiResponse::ok(&json!({ "path": path })).unwrap(),
+                || std::sync::mpsc::channel().1,
+            )
+            .unwrap();
+        });
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(raw.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve() {
+        let response = request("GET /block/00ab?verbose=1 HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"{"path":"/block/00ab"}"#));
+
+        let response = request("POST /tx HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
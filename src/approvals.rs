@@ -0,0 +1,99 @@
+use crate::{script::LockScript, utxoset::CoinSelection};
+use crypto::{digest::Digest, sha2::Sha256};
+use serde::{Deserialize, Serialize};
+
+/// DAY is the window, in seconds, the daily spend limit counts sends over
+pub const DAY: u64 = 24 * 60 * 60;
+
+/// SpendLimits cap what an address sends; larger sends wait for the approval code
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpendLimits {
+    /// per_tx caps the amount of a single send
+    pub per_tx: Option<i32>,
+    /// per_day caps the amount sent over the last 24 hours
+    pub per_day: Option<i32>,
+    /// approval_hash is the hash of the code releasing sends above the limits
+    pub approval_hash: String,
+}
+
+impl SpendLimits {
+    pub fn new(per_tx: Option<i32>, per_day: Option<i32>, approval_code: &str) -> Self {
+        SpendLimits {
+            per_tx,
+            per_day,
+            approval_hash: hash_code(approval_code),
+        }
+    }
+
+    /// exceeded tells which limit sending `amount` breaks after `spent_today` was sent
+    /// in the last 24 hours
+    pub fn exceeded(&self, amount: i32, spent_today: i32) -> Option<String> {
+        if let Some(per_tx) = self.per_tx
+            && amount > per_tx
+        {
+            return Some(format!(
+                "{} is above the limit of {} per send",
+                amount, per_tx
+            ));
+        }
+        if let Some(per_day) = self.per_day
+            && spent_today + amount > per_day
+        {
+            return Some(format!(
+                "{} after {} sent today is above the limit of {} per day",
+                amount, spent_today, per_day
+            ));
+        }
+        None
+    }
+
+    pub fn accepts_code(&self, code: &str) -> bool {
+        hash_code(code) == self.approval_hash
+    }
+}
+
+/// PendingSend is a send held back by the spend limits until it is approved
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingSend {
+    pub id: u64,
+    pub from: String,
+    pub recipients: Vec<(String, i32)>,
+    pub lock: LockScript,
+    pub lock_time: u64,
+    pub expiry_height: i32,
+    pub selection: CoinSelection,
+    pub mine_now: bool,
+    /// created is the unix time, in seconds, the send was requested
+    pub created: u64,
+    /// reason is the limit the send is above
+    pub reason: String,
+}
+
+impl PendingSend {
+    pub fn amount(&self) -> i32 {
+        self.recipients.iter().map(|(_, amount)| amount).sum()
+    }
+}
+
+fn hash_code(code: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(code);
+    hasher.result_str()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spend_limits() {
+        let limits = SpendLimits::new(Some(10), Some(25), "second key");
+        assert_eq!(limits.exceeded(10, 0), None);
+        assert!(limits.exceeded(11, 0).unwrap().contains("per send"));
+        assert_eq!(limits.exceeded(5, 20), None);
+        assert!(limits.exceeded(6, 20).unwrap().contains("per day"));
+        assert!(limits.accepts_code("second key"));
+        assert!(!limits.accepts_code("guess"));
+        assert_ne!(limits.approval_hash, "second key");
+    }
+}
@@ -0,0 +1,96 @@
+use super::*;
+use crate::{config::data_path, scheduler::now_secs};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::Path,
+};
+
+const AUDIT_LOG: &str = "audit.log";
+
+/// AuditEntry is a security relevant action taken by the node or its operator
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// timestamp is the unix time, in seconds
+    pub timestamp: u64,
+    /// source is "cli", "node" or the address of the RPC client
+    pub source: String,
+    pub action: String,
+    pub details: String,
+}
+
+/// record appends an action to the audit log. The log is a file of JSON lines that is
+/// only ever appended to, so the CLI and a running node can write it at the same time.
+pub fn record(source: &str, action: &str, details: &str) -> Result<()> {
+    let entry = AuditEntry {
+        timestamp: now_secs()?,
+        source: source.to_string(),
+        action: action.to_string(),
+        details: details.to_string(),
+    };
+    append(Path::new(&data_path(AUDIT_LOG)), &entry)
+}
+
+/// record_or_log is record for the node, which logs a failed write instead of dropping
+/// the action that caused it
+pub fn record_or_log(source: &str, action: &str, details: &str) {
+    if let Err(e) = record(source, action, details) {
+        error!("failed to write the audit log: {}", e);
+    }
+}
+
+/// read_log returns the audit log, oldest entry first
+pub fn read_log() -> Result<Vec<AuditEntry>> {
+    read(Path::new(&data_path(AUDIT_LOG)))
+}
+
+fn append(path: &Path, entry: &AuditEntry) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<Vec<AuditEntry>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for line in data.lines().filter(|line| !line.is_empty()) {
+        entries.push(serde_json::from_str(line)?);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_audit_log() {
+        let path = std::env::temp_dir().join(format!("audit-{}.log", std::process::id()));
+        assert!(read(&path).unwrap().is_empty());
+        let entry = |action: &str| AuditEntry {
+            timestamp: 1,
+            source: String::from("cli"),
+            action: action.to_string(),
+            details: String::from("address 1abc"),
+        };
+        append(&path, &entry("wallet-created")).unwrap();
+        append(&path, &entry("send")).unwrap();
+        assert_eq!(
+            read(&path).unwrap(),
+            vec![entry("wallet-created"), entry("send")]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+use super::*;
+use crate::config::data_path;
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use std::{fmt, net::IpAddr, str::FromStr};
+
+const BANLIST_DB: &str = "banlist";
+
+/// IpRange is an address block in CIDR notation; a plain address is a block of one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(addr: IpAddr) -> Self {
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        IpRange { addr, prefix }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        if prefix > bits {
+            return Err(format_err!("Prefix /{} is too long for {}", prefix, addr));
+        }
+        // keep the network address, so 10.1.2.3/8 and 10.0.0.0/8 are the same range
+        let addr = match addr {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                IpAddr::V4((u32::from(ip) & mask).into())
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                IpAddr::V6((u128::from(ip) & mask).into())
+            }
+        };
+        Ok(IpRange { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// BanEntry is a banned range with when the ban was made and when it ends, in seconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BanEntry {
+    pub range: String,
+    pub created: u64,
+    /// banned_until is unset for bans that last until they are removed
+    pub banned_until: Option<u64>,
+    pub reason: String,
+}
+
+impl BanEntry {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.banned_until.is_none_or(|until| now < until)
+    }
+}
+
+/// BanList keeps the banned address ranges of a node across restarts
+pub struct BanList {
+    db: Db,
+}
+
+impl BanList {
+    pub fn open() -> Result<Self> {
+        Ok(BanList {
+            db: sled::open(data_path(BANLIST_DB))?,
+        })
+    }
+
+    /// add bans `range` for `duration` seconds, or until it is removed
+    pub fn add(
+        &self,
+        range: &IpRange,
+        duration: Option<u64>,
+        reason: &str,
+        now: u64,
+    ) -> Result<()> {
+        let entry = BanEntry {
+            range: range.to_string(),
+            created: now,
+            banned_until: duration.map(|secs| now + secs),
+            reason: reason.to_string(),
+        };
+        self.db.insert(entry.range.as_bytes(), serialize(&entry)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// remove lifts the ban of `range`; it returns false when the range was not banned
+    pub fn remove(&self, range: &IpRange) -> Result<bool> {
+        let removed = self.db.remove(range.to_string().as_bytes())?.is_some();
+        self.db.flush()?;
+        Ok(removed)
+    }
+
+    /// list returns the bans still in force, dropping those that expired
+    pub fn list(&self, now: u64) -> Result<Vec<BanEntry>> {
+        let mut entries = Vec::new();
+        for kv in self.db.iter() {
+            let (key, value) = kv?;
+            let entry: BanEntry = deserialize(&value)?;
+            if entry.is_active(now) {
+                entries.push(entry);
+            } else {
+                self.db.remove(key)?;
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, now: u64) -> Result<bool> {
+        for entry in self.list(now)? {
+            if entry.range.parse::<IpRange>()?.contains(ip) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ip_range() {
+        let range: IpRange = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(range.to_string(), "10.0.0.0/8");
+        assert!(range.contains("10.200.0.1".parse().unwrap()));
+        assert!(!range.contains("11.0.0.1".parse().unwrap()));
+        assert!(!range.contains("::1".parse().unwrap()));
+
+        let single: IpRange = "192.168.0.7".parse().unwrap();
+        assert_eq!(single.to_string(), "192.168.0.7/32");
+        assert!(!single.contains("192.168.0.8".parse().unwrap()));
+
+        let v6: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:1::1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<IpRange>()
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+
+        let entry = BanEntry {
+            range: range.to_string(),
+            created: 100,
+            banned_until: Some(200),
+            reason: String::new(),
+        };
+        assert!(entry.is_active(199));
+        assert!(!entry.is_active(200));
+    }
+}
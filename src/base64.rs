@@ -0,0 +1,71 @@
+use super::*;
+use failure::format_err;
+
+const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// encode writes `data` in standard, padded base64
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// decode reads standard, padded base64
+pub fn decode(text: &str) -> Result<Vec<u8>> {
+    let text = text.trim().as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return Err(format_err!("Base64 length must be a multiple of 4"));
+    }
+    let mut out = Vec::new();
+    for chunk in text.chunks(4) {
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 {
+            return Err(format_err!("Invalid base64 padding"));
+        }
+        let mut n = 0u32;
+        for c in &chunk[..4 - padding] {
+            let value = match ALPHABET.iter().position(|a| a == c) {
+                Some(value) => value as u32,
+                None => return Err(format_err!("Invalid base64 character {}", *c as char)),
+            };
+            n = n << 6 | value;
+        }
+        n <<= 6 * padding as u32;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        for (data, text) in [
+            (&b"ab"[..], "YWI="),
+            (b"abc", "YWJj"),
+            (b"a", "YQ=="),
+            (b"", ""),
+        ] {
+            assert_eq!(encode(data), text);
+            assert_eq!(decode(text).unwrap(), data);
+        }
+        assert!(decode("YQ=").is_err());
+        assert!(decode("Y!==").is_err());
+    }
+}
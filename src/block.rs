@@ -1,28 +1,272 @@
-use crate::{error::Result, transaction::Transaction};
+use crate::{
+    error::Result,
+    hash::{BlockHash, TxId},
+    script::MAX_VAULT_DELAY,
+    transaction::{block_subsidy, Transaction},
+    u256::U256,
+};
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use failure::format_err;
 use log::info;
-use merkle_cbt::merkle_tree::{Merge, CBMT};
+use merkle_cbt::merkle_tree::{Merge, MerkleProof, CBMT};
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 
+/// TARGET_HEXS is the default difficulty of new chains, in leading zero hex digits
 pub const TARGET_HEXS: usize = 4;
+/// MAX_BLOCK_SIZE is the default most bytes a serialized block may take
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+/// MAX_FUTURE_BLOCK_TIME is how far ahead of the local clock a block timestamp may be, in ms
+const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+/// CANCEL_CHECK_INTERVAL is how many nonces are tried between checks whether mining was
+/// cancelled
+const CANCEL_CHECK_INTERVAL: u64 = 1 << 12;
+
+/// PowSearch is the outcome of a proof of work search
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowSearch {
+    /// found tells whether the proof of work was found rather than the search cancelled
+    pub found: bool,
+    /// hashes counts the nonces tried by every thread together
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl PowSearch {
+    /// hashrate is the number of hashes tried a second
+    pub fn hashrate(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.hashes as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+/// BlockRejection records which consensus rule a block broke, and how
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockRejection {
+    pub block_hash: BlockHash,
+    pub height: i32,
+    pub rule: String,
+    pub txid: Option<TxId>,
+    pub expected: String,
+    pub actual: String,
+    /// rejected_at is the unix time, in seconds, the block was rejected
+    pub rejected_at: u64,
+}
+
+impl fmt::Display for BlockRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Block {} breaks rule {}", self.block_hash, self.rule)?;
+        if let Some(txid) = &self.txid {
+            write!(f, " in transaction {}", txid)?;
+        }
+        write!(f, ": expected {}, got {}", self.expected, self.actual)
+    }
+}
+
+impl failure::Fail for BlockRejection {}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     timestamp: u128,
     transactions: Vec<Transaction>,
-    prev_block_hash: String,
-    hash: String,
-    nonce: i32,
+    prev_block_hash: BlockHash,
+    merkle_root: Vec<u8>,
+    hash: BlockHash,
+    nonce: u64,
     height: i32,
+    /// target is the most the hash of the block may be
+    target: U256,
+}
+
+/// BlockHeader is a block without its transactions, which it commits to through the merkle root
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BlockHeader {
+    pub timestamp: u128,
+    pub prev_block_hash: BlockHash,
+    pub merkle_root: Vec<u8>,
+    pub hash: BlockHash,
+    pub nonce: u64,
+    pub height: i32,
+    pub target: U256,
+}
+
+/// ParentState is everything validating a block needs from the chain it extends, so
+/// the consensus rules run as a pure function of the block and this state
+#[derive(Debug, Clone)]
+pub struct ParentState {
+    pub prev: Option<BlockHeader>,
+    pub target: U256,
+    /// max_block_size is the most bytes the serialized block may take
+    pub max_block_size: usize,
+    /// checkpoint is the hash the chain parameters require at the block's height
+    pub checkpoint: Option<BlockHash>,
+    /// check_signatures is false for the blocks below the last checkpoint. The vault
+    /// rules are skipped with the signatures there, since only the signature tells
+    /// whether the hot or the recovery key spends; the checkpoint vouches for both.
+    pub check_signatures: bool,
+    /// prev_txs holds the transactions of the chain the block extends that its inputs
+    /// spend, by id
+    pub prev_txs: HashMap<TxId, Transaction>,
+    /// spent holds the outputs the block's inputs spend that the chain it extends already
+    /// spent
+    pub spent: HashSet<(TxId, i32)>,
+    /// now is the local clock in milliseconds, which block timestamps may not run ahead of
+    pub now: u128,
+}
+
+impl Default for ParentState {
+    fn default() -> Self {
+        ParentState {
+            prev: None,
+            target: U256::MAX,
+            max_block_size: MAX_BLOCK_SIZE,
+            checkpoint: None,
+            check_signatures: false,
+            prev_txs: HashMap::new(),
+            spent: HashSet::new(),
+            now: 0,
+        }
+    }
+}
+
+/// MerkleBranch proves that a transaction is committed to by a block's merkle root
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MerkleBranch {
+    pub indices: Vec<u32>,
+    pub lemmas: Vec<Vec<u8>>,
+}
+
+impl BlockHeader {
+    /// validate checks the link to `prev`, the timestamp, that the block claims the
+    /// `target` the retarget rules expect and its proof of work, which is everything
+    /// that can be checked before the transactions are downloaded
+    pub fn validate(&self, prev: Option<&BlockHeader>, target: U256) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis();
+        self.validate_at(prev, target, now)
+    }
+
+    /// validate_at is validate with the local clock at `now` milliseconds
+    pub fn validate_at(&self, prev: Option<&BlockHeader>, target: U256, now: u128) -> Result<()> {
+        match prev {
+            Some(prev) => {
+                if self.prev_block_hash != prev.hash {
+                    return Err(self.reject("prev-hash", None, prev.hash, self.prev_block_hash));
+                }
+                if self.height != prev.height + 1 {
+                    return Err(self.reject("height", None, prev.height + 1, self.height));
+                }
+                if self.timestamp < prev.timestamp {
+                    return Err(self.reject(
+                        "timestamp",
+                        None,
+                        format!(">= {}", prev.timestamp),
+                        self.timestamp,
+                    ));
+                }
+            }
+            None => {
+                if !self.prev_block_hash.is_zero() || self.height != 0 {
+                    return Err(self.reject("genesis", None, 0, self.height));
+                }
+            }
+        }
+
+        if self.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(self.reject(
+                "future-timestamp",
+                None,
+                format!("<= {}", now + MAX_FUTURE_BLOCK_TIME),
+                self.timestamp,
+            ));
+        }
+
+        if self.target != target {
+            return Err(self.reject("bits", None, target, self.target));
+        }
+        self.check_hash()?;
+        if U256::from(self.hash) > target {
+            return Err(self.reject("target", None, format!("<= {}", target), self.hash));
+        }
+        Ok(())
+    }
+
+    /// check_hash checks that the hash is the one of the header fields
+    pub fn check_hash(&self) -> Result<()> {
+        let data = hash_data(
+            &self.prev_block_hash,
+            &self.merkle_root,
+            self.timestamp,
+            &self.target,
+            self.nonce,
+        )?;
+        let hash = BlockHash::digest(&data);
+        if hash != self.hash {
+            return Err(self.reject("hash", None, hash, self.hash));
+        }
+        Ok(())
+    }
+
+    /// reject builds the error for a broken consensus `rule`
+    pub fn reject(
+        &self,
+        rule: &str,
+        txid: Option<&TxId>,
+        expected: impl fmt::Display,
+        actual: impl fmt::Display,
+    ) -> failure::Error {
+        BlockRejection {
+            block_hash: self.hash,
+            height: self.height,
+            rule: rule.to_string(),
+            txid: txid.copied(),
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+            rejected_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }
+        .into()
+    }
 }
 
 impl Block {
+    /// new mines a block whose hash is not above `target`
+    #[cfg(test)]
     pub fn new(
         transactions: Vec<Transaction>,
-        prev_block_hash: String,
+        prev_block_hash: BlockHash,
         height: i32,
+        target: U256,
+    ) -> Result<Self> {
+        let mut block = Block::new_unmined(transactions, prev_block_hash, height, target)?;
+        block.run_proof_of_work()?;
+        Ok(block)
+    }
+
+    /// new_unmined assembles a block whose proof of work at `target` is still to be
+    /// found by mine
+    pub fn new_unmined(
+        transactions: Vec<Transaction>,
+        prev_block_hash: BlockHash,
+        height: i32,
+        target: U256,
     ) -> Result<Self> {
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
@@ -31,52 +275,469 @@ impl Block {
             timestamp,
             transactions,
             prev_block_hash,
-            hash: String::new(),
+            merkle_root: Vec::new(),
+            hash: BlockHash::default(),
             nonce: 0,
             height,
+            target,
         };
-        block.run_proof_of_work()?;
+        block.merkle_root = block.hash_transactions()?;
         Ok(block)
     }
 
-    pub fn new_genesis_block(coinbase: Transaction) -> Self {
-        Block::new(vec![coinbase], String::new(), 0).expect("Failed to create genesis block")
+    /// new_genesis_block builds the genesis block paying `coinbase` at `timestamp`. With
+    /// no `nonce` the proof of work is searched from nonce zero on a single thread, so
+    /// every node finds the same block.
+    pub fn new_genesis_block(
+        coinbase: Transaction,
+        timestamp: u128,
+        target: U256,
+        nonce: Option<u64>,
+    ) -> Result<Self> {
+        let mut block = Block::new_unmined(vec![coinbase], BlockHash::default(), 0, target)?;
+        block.timestamp = timestamp;
+        match nonce {
+            Some(nonce) => {
+                block.nonce = nonce;
+                block.hash = BlockHash::digest(&block.prepare_hash_data()?);
+            }
+            None => block.run_proof_of_work()?,
+        }
+        Ok(block)
     }
 
-    pub fn get_hash(&self) -> String {
-        self.hash.clone()
+    pub fn get_hash(&self) -> BlockHash {
+        self.hash
     }
 
-    pub fn get_prev_hash(&self) -> String {
-        self.prev_block_hash.clone()
+    pub fn get_prev_hash(&self) -> BlockHash {
+        self.prev_block_hash
     }
 
     pub fn get_transactions(&self) -> &Vec<Transaction> {
         &self.transactions
     }
 
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
     pub fn get_height(&self) -> i32 {
         self.height
     }
 
-    fn run_proof_of_work(&mut self) -> Result<()> {
-        info!("Mining the block");
-        while !self.validate()? {
-            self.nonce += 1;
+    pub fn get_target(&self) -> U256 {
+        self.target
+    }
+
+    pub fn get_merkle_root(&self) -> &[u8] {
+        &self.merkle_root
+    }
+
+    /// size returns the bytes the block takes serialized
+    pub fn size(&self) -> Result<usize> {
+        Ok(bincode::serialized_size(self)? as usize)
+    }
+
+    /// get_merkle_proof returns the merkle branch of transaction `txid`, if the block contains it
+    pub fn get_merkle_proof(&self, txid: &TxId) -> Result<Option<MerkleBranch>> {
+        let index = match self.transactions.iter().position(|tx| tx.id == *txid) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let leaves = self.merkle_leaves()?;
+        Ok(
+            CBMT::<Vec<u8>, MergeVu8>::build_merkle_proof(&leaves, &[index as u32]).map(|proof| {
+                MerkleBranch {
+                    indices: proof.indices().to_vec(),
+                    lemmas: proof.lemmas().to_vec(),
+                }
+            }),
+        )
+    }
+
+    /// header returns the part of the block peers sync before downloading its transactions
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp,
+            prev_block_hash: self.prev_block_hash,
+            merkle_root: self.merkle_root.clone(),
+            hash: self.hash,
+            nonce: self.nonce,
+            height: self.height,
+            target: self.target,
+        }
+    }
+
+    /// validate_full checks everything a peer could get wrong in a block extending
+    /// `state`: its header, the transactions it commits to, the coinbase rules and, when
+    /// the state asks for it, every transaction signature. It reads nothing but its
+    /// arguments. Failures are `BlockRejection`s.
+    pub fn validate_full(&self, state: &ParentState) -> Result<()> {
+        let header = self.header();
+        if let Some(hash) = &state.checkpoint
+            && *hash != header.hash
+        {
+            return Err(header.reject("checkpoint", None, hash, header.hash));
+        }
+        header.validate_at(state.prev.as_ref(), state.target, state.now)?;
+        let size = self.size()?;
+        if size > state.max_block_size {
+            return Err(header.reject(
+                "block-size",
+                None,
+                format!("<= {}", state.max_block_size),
+                size,
+            ));
+        }
+        self.check_merkle_root()?;
+        self.validate_transactions(state)
+    }
+
+    /// check_commitments checks what holds whatever chain the block extends: its hash,
+    /// its merkle root and the ids of its transactions, which open with a single coinbase
+    pub fn check_commitments(&self) -> Result<()> {
+        self.header().check_hash()?;
+        self.check_merkle_root()?;
+        for (idx, tx) in self.transactions.iter().enumerate() {
+            if tx.is_coinbase() != (idx == 0) {
+                return Err(self.reject("coinbase-first", Some(&tx.id), 0, idx));
+            }
+            let id = tx.clone().hash()?;
+            if tx.id != id {
+                return Err(self.reject("txid", Some(&tx.id), id, tx.id));
+            }
+        }
+        if self.transactions.is_empty() {
+            return Err(self.reject("coinbase-first", None, "coinbase", "no transactions"));
         }
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
         Ok(())
     }
 
-    fn hash_transactions(&mut self) -> Result<Vec<u8>> {
+    fn check_merkle_root(&self) -> Result<()> {
+        let merkle_root = self.clone().hash_transactions()?;
+        if merkle_root != self.merkle_root {
+            return Err(self.reject(
+                "merkle-root",
+                None,
+                hex::encode(&merkle_root),
+                hex::encode(&self.merkle_root),
+            ));
+        }
+        Ok(())
+    }
+
+    fn reject(
+        &self,
+        rule: &str,
+        txid: Option<&TxId>,
+        expected: impl fmt::Display,
+        actual: impl fmt::Display,
+    ) -> failure::Error {
+        self.header().reject(rule, txid, expected, actual)
+    }
+
+    fn validate_transactions(&self, state: &ParentState) -> Result<()> {
+        match self.transactions.first() {
+            Some(tx) if tx.is_coinbase() => {}
+            Some(tx) => {
+                return Err(self.reject("coinbase-first", Some(&tx.id), "coinbase", "spend"))
+            }
+            None => return Err(self.reject("coinbase-first", None, "coinbase", "no transactions")),
+        }
+
+        let mut block_txs: HashMap<TxId, &Transaction> = HashMap::new();
+        let mut spent: HashSet<(TxId, i32)> = HashSet::new();
+        let mut fees = 0;
+        for (idx, tx) in self.transactions.iter().enumerate() {
+            let id = tx.clone().hash()?;
+            if tx.id != id {
+                return Err(self.reject("txid", Some(&tx.id), id, tx.id));
+            }
+            if tx.vout.is_empty() {
+                return Err(self.reject("no-outputs", Some(&tx.id), ">= 1", 0));
+            }
+            if let Some(out) = tx.vout.iter().find(|out| out.value <= 0) {
+                return Err(self.reject("output-value", Some(&tx.id), "> 0", out.value));
+            }
+            if let Some(out) = tx.vout.iter().find(|out| !out.lock.is_valid()) {
+                return Err(self.reject(
+                    "output-lock",
+                    Some(&tx.id),
+                    format!("vault delay of 1 to {} blocks", MAX_VAULT_DELAY),
+                    format!("{:?}", out.lock),
+                ));
+            }
+            if idx == 0 {
+                block_txs.insert(tx.id, tx);
+                continue;
+            }
+            if tx.is_coinbase() {
+                return Err(self.reject("single-coinbase", Some(&tx.id), 1, 2));
+            }
+            if !tx.is_final_at(self.height, self.timestamp) {
+                return Err(self.reject(
+                    "locktime",
+                    Some(&tx.id),
+                    format!("lock time {} reached", tx.lock_time),
+                    format!("height {} at {}", self.height, self.timestamp),
+                ));
+            }
+            if tx.is_expired_at(self.height) {
+                return Err(self.reject(
+                    "expiry",
+                    Some(&tx.id),
+                    format!("height below {}", tx.expiry_height),
+                    self.height,
+                ));
+            }
+
+            let mut in_value = 0;
+            let mut vault_inputs = Vec::new();
+            for (in_id, vin) in tx.vin.iter().enumerate() {
+                let outpoint = format!("{}:{}", vin.txid, vin.vout);
+                if !spent.insert((vin.txid, vin.vout)) {
+                    return Err(self.reject(
+                        "double-spend",
+                        Some(&tx.id),
+                        "unspent output",
+                        format!("{} spent twice", outpoint),
+                    ));
+                }
+                if state.spent.contains(&(vin.txid, vin.vout)) {
+                    return Err(self.reject(
+                        "missing-input",
+                        Some(&tx.id),
+                        "unspent output",
+                        format!("{} already spent", outpoint),
+                    ));
+                }
+                let prev_tx = match block_txs.get(&vin.txid) {
+                    Some(prev_tx) => Some(*prev_tx),
+                    None => state.prev_txs.get(&vin.txid),
+                };
+                match prev_tx.and_then(|prev_tx| prev_tx.vout.get(vin.vout as usize)) {
+                    Some(out) if out.is_unspendable() => {
+                        return Err(self.reject(
+                            "missing-input",
+                            Some(&tx.id),
+                            "unspent output",
+                            format!("{} is unspendable", outpoint),
+                        ))
+                    }
+                    Some(out) if !out.lock.is_spendable_at(self.height) => {
+                        return Err(self.reject(
+                            "timelock",
+                            Some(&tx.id),
+                            format!("{} unlocked", outpoint),
+                            format!("{:?} at height {}", out.lock, self.height),
+                        ))
+                    }
+                    Some(out) => {
+                        if out.lock.is_vault() {
+                            vault_inputs.push((in_id, outpoint, out));
+                        }
+                        in_value = self.add_value(&tx.id, in_value, out.value)?
+                    }
+                    None => {
+                        return Err(self.reject(
+                            "missing-input",
+                            Some(&tx.id),
+                            "existing output",
+                            format!("{} not found", outpoint),
+                        ))
+                    }
+                }
+            }
+
+            // the signature tells which vault key spends, so the vault rules are skipped
+            // along with the signatures
+            for (in_id, outpoint, out) in vault_inputs {
+                if state.check_signatures
+                    && let Some(violation) = tx.vault_violation(in_id, out, in_value, self.height)
+                {
+                    return Err(self.reject(
+                        "vault",
+                        Some(&tx.id),
+                        format!("{} spent by the vault rules", outpoint),
+                        violation,
+                    ));
+                }
+            }
+
+            let out_value = self.sum_values(tx)?;
+            if out_value > in_value {
+                return Err(self.reject(
+                    "overspend",
+                    Some(&tx.id),
+                    format!("<= {}", in_value),
+                    out_value,
+                ));
+            }
+            fees = self.add_value(&tx.id, fees, in_value - out_value)?;
+            block_txs.insert(tx.id, tx);
+        }
+
+        if state.check_signatures {
+            let mut prev_txs = state.prev_txs.clone();
+            for (id, tx) in block_txs {
+                prev_txs.insert(id, tx.clone());
+            }
+            let invalid = Transaction::verify_batch(&self.transactions, &prev_txs)?;
+            if let Some(txid) = invalid.first() {
+                return Err(self.reject("signature", Some(txid), "valid", "invalid"));
+            }
+        }
+
+        let coinbase = &self.transactions[0];
+        let coinbase_value = self.sum_values(coinbase)?;
+        let allowed = self.add_value(&coinbase.id, block_subsidy(self.height), fees)?;
+        if coinbase_value > allowed {
+            return Err(self.reject(
+                "coinbase-value",
+                Some(&coinbase.id),
+                format!("<= {}", allowed),
+                coinbase_value,
+            ));
+        }
+        Ok(())
+    }
+
+    /// add_value adds two amounts of transaction `txid`, rejecting a sum past i32::MAX
+    fn add_value(&self, txid: &TxId, a: i32, b: i32) -> Result<i32> {
+        a.checked_add(b).ok_or_else(|| {
+            self.reject(
+                "overflow",
+                Some(txid),
+                format!("<= {}", i32::MAX),
+                format!("{} + {}", a, b),
+            )
+        })
+    }
+
+    /// sum_values adds up the output values of `tx`
+    fn sum_values(&self, tx: &Transaction) -> Result<i32> {
+        tx.vout
+            .iter()
+            .try_fold(0, |sum, out| self.add_value(&tx.id, sum, out.value))
+    }
+
+    fn run_proof_of_work(&mut self) -> Result<()> {
+        self.mine(1, &AtomicBool::new(false))?;
+        Ok(())
+    }
+
+    /// mine searches the proof of work at the block's target on `threads` threads until
+    /// one finds it or `cancel` is raised
+    pub fn mine(&mut self, threads: usize, cancel: &AtomicBool) -> Result<PowSearch> {
+        info!(
+            "Mining the block at target {} on {} threads",
+            self.target, threads
+        );
+        let started = Instant::now();
+        let mut hashes = 0;
+        let found = loop {
+            let (nonce, tried) = self.search_nonce(u64::MAX, threads, cancel)?;
+            hashes += tried;
+            if let Some(nonce) = nonce {
+                self.nonce = nonce;
+                self.hash = BlockHash::digest(&self.prepare_hash_data()?);
+                break true;
+            }
+            if cancel.load(Ordering::Relaxed) {
+                break false;
+            }
+            let extra_nonce = self.roll_extra_nonce()?;
+            info!(
+                "Nonce space exhausted, rolled the extra nonce to {}",
+                extra_nonce
+            );
+        };
+        Ok(PowSearch {
+            found,
+            hashes,
+            elapsed: started.elapsed(),
+        })
+    }
+
+    /// search_nonce splits the header nonces up to `max_nonce` between `threads`
+    /// threads, each trying every `threads`-th nonce, and stops them all once one meets
+    /// the target. It returns that nonce, none when no nonce does or `cancel` is
+    /// raised, with the number of nonces tried.
+    fn search_nonce(
+        &self,
+        max_nonce: u64,
+        threads: usize,
+        cancel: &AtomicBool,
+    ) -> Result<(Option<u64>, u64)> {
+        let stride = threads.max(1) as u64;
+        let solution: Mutex<Option<u64>> = Mutex::new(None);
+        let done = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..stride)
+                .map(|first| {
+                    let (solution, done, hashes) = (&solution, &done, &hashes);
+                    scope.spawn(move || -> Result<()> {
+                        let mut nonce = first;
+                        let mut tried: u64 = 0;
+                        while nonce <= max_nonce {
+                            if tried.is_multiple_of(CANCEL_CHECK_INTERVAL)
+                                && (done.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed))
+                            {
+                                break;
+                            }
+                            tried += 1;
+                            if self.meets_target(nonce)? {
+                                solution.lock().unwrap().get_or_insert(nonce);
+                                done.store(true, Ordering::Relaxed);
+                                break;
+                            }
+                            nonce = match nonce.checked_add(stride) {
+                                Some(next) => next,
+                                None => break,
+                            };
+                        }
+                        hashes.fetch_add(tried, Ordering::Relaxed);
+                        Ok(())
+                    })
+                })
+                .collect();
+            for worker in workers {
+                worker.join().expect("proof of work thread panicked")?;
+            }
+            Ok::<(), failure::Error>(())
+        })?;
+        let nonce = *solution.lock().unwrap();
+        Ok((nonce, hashes.into_inner()))
+    }
+
+    /// roll_extra_nonce changes the coinbase, and so the merkle root, to open a fresh
+    /// header nonce space
+    fn roll_extra_nonce(&mut self) -> Result<u64> {
+        let extra_nonce = match self.transactions.first_mut() {
+            Some(tx) if tx.is_coinbase() => tx.roll_extra_nonce()?,
+            _ => {
+                return Err(format_err!(
+                    "Block has no coinbase to roll the extra nonce of"
+                ))
+            }
+        };
+        self.merkle_root = self.hash_transactions()?;
+        Ok(extra_nonce)
+    }
+
+    fn merkle_leaves(&self) -> Result<Vec<Vec<u8>>> {
         let mut transactions = Vec::new();
 
-        for tx in &mut self.transactions {
-            transactions.push(tx.hash()?.as_bytes().to_owned());
+        for tx in &self.transactions {
+            transactions.push(tx.clone().hash()?.0.to_vec());
         }
+        Ok(transactions)
+    }
+
+    fn hash_transactions(&mut self) -> Result<Vec<u8>> {
+        let transactions = self.merkle_leaves()?;
 
         let tree = CBMT::<Vec<u8>, MergeVu8>::build_merkle_tree(&transactions);
 
@@ -84,28 +745,65 @@ impl Block {
     }
 
     fn prepare_hash_data(&mut self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
+        hash_data(
+            &self.prev_block_hash,
+            &self.merkle_root,
             self.timestamp,
-            TARGET_HEXS,
+            &self.target,
             self.nonce,
-        );
-        let bytes: Vec<u8> = bincode::serialize(&content)?;
-        Ok(bytes)
+        )
     }
 
-    fn validate(&mut self) -> Result<bool> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        let mut vec1: Vec<u8> = Vec::new();
-        vec1.resize(TARGET_HEXS, 0 as u8);
-        println!("vec1: {:?}", vec1);
-        Ok(&hasher.result_str()[0..TARGET_HEXS] == String::from_utf8(vec1)?)
+    /// meets_target tells whether the header hashed with `nonce` meets the target
+    fn meets_target(&self, nonce: u64) -> Result<bool> {
+        let data = hash_data(
+            &self.prev_block_hash,
+            &self.merkle_root,
+            self.timestamp,
+            &self.target,
+            nonce,
+        )?;
+        Ok(U256::from(BlockHash::digest(&data)) <= self.target)
     }
 }
 
+/// hash_data is what the proof of work hashes, shared by blocks and their headers
+fn hash_data(
+    prev_block_hash: &BlockHash,
+    merkle_root: &[u8],
+    timestamp: u128,
+    target: &U256,
+    nonce: u64,
+) -> Result<Vec<u8>> {
+    let content = (prev_block_hash, merkle_root, timestamp, target, nonce);
+    let bytes: Vec<u8> = bincode::serialize(&content)?;
+    Ok(bytes)
+}
+
+/// coinbase_branch is the merkle branch of the coinbase of a block holding `transactions`
+/// after it. The branch does not depend on the coinbase itself, so a miner completes the
+/// merkle root with a coinbase of its own.
+pub fn coinbase_branch(transactions: &[Transaction]) -> Result<MerkleBranch> {
+    let mut leaves = vec![vec![0; 32]];
+    for tx in transactions {
+        leaves.push(tx.clone().hash()?.0.to_vec());
+    }
+    match CBMT::<Vec<u8>, MergeVu8>::build_merkle_proof(&leaves, &[0]) {
+        Some(proof) => Ok(MerkleBranch {
+            indices: proof.indices().to_vec(),
+            lemmas: proof.lemmas().to_vec(),
+        }),
+        None => Err(format_err!("No merkle branch for the coinbase")),
+    }
+}
+
+/// verify_merkle_branch checks that `branch` links the transaction hash `leaf` to `merkle_root`
+pub fn verify_merkle_branch(leaf: &[u8], branch: &MerkleBranch, merkle_root: &[u8]) -> bool {
+    let proof =
+        MerkleProof::<Vec<u8>, MergeVu8>::new(branch.indices.clone(), branch.lemmas.clone());
+    proof.verify(&merkle_root.to_vec(), &[leaf.to_vec()])
+}
+
 struct MergeVu8 {}
 
 impl Merge for MergeVu8 {
@@ -121,3 +819,303 @@ impl Merge for MergeVu8 {
         result.to_vec()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::LockScript;
+    use crate::transaction::{TXInput, TXOutput, LOCKTIME_THRESHOLD};
+
+    fn dummy_tx(data: u8) -> Transaction {
+        let mut tx = Transaction {
+            id: TxId::default(),
+            vin: vec![TXInput {
+                txid: TxId::default(),
+                vout: -1,
+                signature: Vec::new(),
+                pub_key: vec![data; 32],
+            }],
+            vout: vec![TXOutput {
+                value: 10,
+                pub_key_hash: vec![data; 20],
+                lock: LockScript::PayToPubkeyHash,
+            }],
+            lock_time: 0,
+            expiry_height: 0,
+        };
+        tx.id = tx.hash().unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let txs: Vec<Transaction> = (1..=3).map(dummy_tx).collect();
+        let block = Block::new(txs.clone(), BlockHash::default(), 0, U256::from_hexs(1)).unwrap();
+
+        for tx in &txs {
+            let branch = block.get_merkle_proof(&tx.id).unwrap().unwrap();
+            assert!(tx
+                .verify_inclusion(&branch, block.get_merkle_root())
+                .unwrap());
+        }
+
+        let branch = block.get_merkle_proof(&txs[0].id).unwrap().unwrap();
+        assert!(!txs[1]
+            .verify_inclusion(&branch, block.get_merkle_root())
+            .unwrap());
+        assert!(block.get_merkle_proof(&TxId::default()).unwrap().is_none());
+
+        // any coinbase completes the merkle root of a template with the same branch
+        let branch = coinbase_branch(&txs[1..]).unwrap();
+        for coinbase in [dummy_tx(4), dummy_tx(5)] {
+            let mut block_txs = vec![coinbase.clone()];
+            block_txs.extend(txs[1..].iter().cloned());
+            let block =
+                Block::new_unmined(block_txs, BlockHash::default(), 0, U256::from_hexs(1)).unwrap();
+            assert!(coinbase
+                .verify_inclusion(&branch, block.get_merkle_root())
+                .unwrap());
+        }
+    }
+
+    #[test]
+    fn test_header_validation() {
+        let rule = |result: Result<()>| {
+            result
+                .unwrap_err()
+                .downcast::<BlockRejection>()
+                .unwrap()
+                .rule
+        };
+        let genesis = Block::new(
+            vec![dummy_tx(1)],
+            BlockHash::default(),
+            0,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        let header = genesis.header();
+        header.validate(None, U256::from_hexs(1)).unwrap();
+
+        let mut forged = header.clone();
+        forged.nonce += 1;
+        assert_eq!(rule(forged.validate(None, U256::from_hexs(1))), "hash");
+        // a block must claim the target the retarget rules expect, not an easier one
+        assert_eq!(rule(header.validate(None, U256::from_hexs(2))), "bits");
+        let mut forged = header.clone();
+        forged.target = U256::from_hexs(0);
+        assert_eq!(rule(forged.validate(None, U256::from_hexs(1))), "bits");
+        assert_eq!(rule(forged.validate(None, U256::from_hexs(0))), "hash");
+
+        let child =
+            Block::new(vec![dummy_tx(2)], genesis.get_hash(), 2, U256::from_hexs(1)).unwrap();
+        assert_eq!(
+            rule(child.header().validate(Some(&header), U256::from_hexs(1))),
+            "height"
+        );
+        let child =
+            Block::new(vec![dummy_tx(2)], genesis.get_hash(), 1, U256::from_hexs(1)).unwrap();
+        child
+            .header()
+            .validate(Some(&header), U256::from_hexs(1))
+            .unwrap();
+        assert_eq!(
+            rule(child.header().validate(None, U256::from_hexs(1))),
+            "genesis"
+        );
+    }
+
+    #[test]
+    fn test_validate_full() {
+        let rule = |result: Result<()>| {
+            result
+                .unwrap_err()
+                .downcast::<BlockRejection>()
+                .unwrap()
+                .rule
+        };
+        let prev_tx = dummy_tx(5);
+        let mut spend = Transaction {
+            id: TxId::default(),
+            vin: vec![TXInput {
+                txid: prev_tx.id,
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: vec![5; 32],
+            }],
+            vout: vec![TXOutput {
+                value: 4,
+                pub_key_hash: vec![6; 20],
+                lock: LockScript::PayToPubkeyHash,
+            }],
+            lock_time: 0,
+            expiry_height: 0,
+        };
+        spend.id = spend.hash().unwrap();
+        let block = Block::new(
+            vec![dummy_tx(1), spend.clone()],
+            BlockHash::default(),
+            0,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        let state = ParentState {
+            target: U256::from_hexs(1),
+            prev_txs: HashMap::from([(prev_tx.id, prev_tx)]),
+            now: block.get_timestamp(),
+            ..ParentState::default()
+        };
+        block.validate_full(&state).unwrap();
+
+        let oversized = ParentState {
+            max_block_size: block.size().unwrap() - 1,
+            ..state.clone()
+        };
+        assert_eq!(rule(block.validate_full(&oversized)), "block-size");
+
+        let missing = ParentState {
+            prev_txs: HashMap::new(),
+            ..state.clone()
+        };
+        assert_eq!(rule(block.validate_full(&missing)), "missing-input");
+        let respent = ParentState {
+            spent: HashSet::from([(spend.vin[0].txid, 0)]),
+            ..state.clone()
+        };
+        assert_eq!(rule(block.validate_full(&respent)), "missing-input");
+
+        let with_outputs = |values: &[i32]| {
+            let mut tx = spend.clone();
+            tx.vout = values
+                .iter()
+                .map(|value| TXOutput {
+                    value: *value,
+                    ..spend.vout[0].clone()
+                })
+                .collect();
+            tx.id = tx.hash().unwrap();
+            Block::new(
+                vec![dummy_tx(1), tx],
+                BlockHash::default(),
+                0,
+                U256::from_hexs(1),
+            )
+            .unwrap()
+        };
+        assert_eq!(rule(with_outputs(&[]).validate_full(&state)), "no-outputs");
+        assert_eq!(
+            rule(with_outputs(&[10, -10]).validate_full(&state)),
+            "output-value"
+        );
+        assert_eq!(
+            rule(with_outputs(&[i32::MAX, 1]).validate_full(&state)),
+            "overflow"
+        );
+        let mut vaulting = spend.clone();
+        vaulting.vout[0].lock = LockScript::Vault {
+            delay: MAX_VAULT_DELAY + 1,
+        };
+        vaulting.id = vaulting.hash().unwrap();
+        let vault_block = Block::new(
+            vec![dummy_tx(1), vaulting],
+            BlockHash::default(),
+            0,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        assert_eq!(rule(vault_block.validate_full(&state)), "output-lock");
+        let early = ParentState {
+            now: block.get_timestamp() - MAX_FUTURE_BLOCK_TIME - 1,
+            ..state.clone()
+        };
+        assert_eq!(rule(block.validate_full(&early)), "future-timestamp");
+        let checkpointed = ParentState {
+            checkpoint: Some(BlockHash::digest(b"checkpoint")),
+            ..state.clone()
+        };
+        assert_eq!(rule(block.validate_full(&checkpointed)), "checkpoint");
+        let signed = ParentState {
+            check_signatures: true,
+            ..state.clone()
+        };
+        assert_eq!(rule(block.validate_full(&signed)), "signature");
+
+        spend.lock_time = 1;
+        spend.id = spend.hash().unwrap();
+        let locked = Block::new(
+            vec![dummy_tx(1), spend.clone()],
+            BlockHash::default(),
+            0,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        assert_eq!(rule(locked.validate_full(&state)), "locktime");
+        assert!(spend.is_final_at(1, 0));
+        spend.lock_time = LOCKTIME_THRESHOLD;
+        assert!(!spend.is_final_at(i32::MAX, 499_999_999_999));
+        assert!(spend.is_final_at(0, 500_000_000_000));
+
+        spend.lock_time = 0;
+        spend.expiry_height = 1;
+        spend.id = spend.hash().unwrap();
+        assert!(!spend.is_expired_at(0));
+        let expired = Block::new(
+            vec![dummy_tx(2), spend.clone()],
+            block.get_hash(),
+            1,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        let child = ParentState {
+            prev: Some(block.header()),
+            ..state.clone()
+        };
+        assert_eq!(rule(expired.validate_full(&child)), "expiry");
+    }
+
+    #[test]
+    fn test_extra_nonce() {
+        let mut block = Block::new(
+            vec![dummy_tx(1)],
+            BlockHash::default(),
+            0,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        let coinbase_id = block.get_transactions()[0].id;
+        let merkle_root = block.get_merkle_root().to_vec();
+        // no single nonce meets a target this hard
+        block.target = U256::from_hexs(8);
+        assert_eq!(
+            block.search_nonce(0, 1, &AtomicBool::new(false)).unwrap(),
+            (None, 1)
+        );
+        // nor is one searched for once mining is cancelled
+        assert!(!block.mine(4, &AtomicBool::new(true)).unwrap().found);
+
+        assert_eq!(block.roll_extra_nonce().unwrap(), 0x0101010101010102);
+        assert_ne!(block.get_transactions()[0].id, coinbase_id);
+        assert_ne!(block.get_merkle_root(), &merkle_root[..]);
+        block.target = U256::from_hexs(2);
+        let search = block.mine(4, &AtomicBool::new(false)).unwrap();
+        assert!(search.found);
+        assert!(search.hashes > 0);
+        block.header().validate(None, U256::from_hexs(2)).unwrap();
+        let state = ParentState {
+            target: U256::from_hexs(2),
+            now: block.get_timestamp(),
+            ..ParentState::default()
+        };
+        block.validate_full(&state).unwrap();
+
+        let mut spend = Block::new(
+            vec![dummy_tx(3)],
+            BlockHash::default(),
+            0,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        spend.transactions[0].vin[0].vout = 0;
+        assert!(spend.roll_extra_nonce().is_err());
+    }
+}
@@ -1,134 +1,1602 @@
 use super::*;
-use crate::{block::*, transaction::*};
+use crate::{
+    block::*,
+    config::{data_path, ChainParams, Config},
+    error::{CodedError, ErrorCode},
+    filter::BlockFilter,
+    hash::{BlockHash, TxId, HASH_LEN},
+    script::spender_hash,
+    signer::Signer,
+    transaction::*,
+    u256::U256,
+    utxoset::outpoint_key,
+    wallets::hash_to_address,
+};
 use bincode::{deserialize, serialize};
+use crypto::{digest::Digest, sha2::Sha256};
 use failure::format_err;
-use log::info;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 use sled::{open, Db};
-use std::{collections::HashMap, fs::remove_dir_all};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    fs::remove_dir_all,
+    sync::atomic::AtomicBool,
+    time::SystemTime,
+};
+
+/// BLOCKS_DB is the block database in the data directory
+const BLOCKS_DB: &str = "blocks";
+/// HEADER_TIP is the key of the highest header in the headers tree
+const HEADER_TIP: &str = "TIP";
+/// NETWORK_KEY is the key of the network the block database was created for
+const NETWORK_KEY: &str = "NETWORK";
+/// GENESIS_COINBASE opens the coinbase data of a genesis block, followed by the magic
+/// bytes of its network
+const GENESIS_COINBASE: &str = "GENESIS_COINBASE";
+/// GENESIS_PAYEE is the public key hash the genesis coinbase pays, which no key hashes to
+const GENESIS_PAYEE: [u8; 20] = [0; 20];
+/// SPENDERS_INDEXED marks the spenders tree as holding every stored block
+const SPENDERS_INDEXED: &str = "INDEXED";
+/// CHECKSUM_LEN is the size of the checksum stored in front of every block body
+const CHECKSUM_LEN: usize = 4;
+/// MAX_REJECTIONS is how many rejected blocks are kept for diagnosis
+const MAX_REJECTIONS: usize = 100;
+/// ARCHIVE_CHECK_DEPTH is how many of the most recent blocks an archive node checks
+/// its indexes against on startup
+const ARCHIVE_CHECK_DEPTH: usize = 100;
 
 #[derive(Debug, Clone)]
 pub struct Blockchain {
-    tip: String,
+    tip: BlockHash,
     db: Db,
+    params: ChainParams,
+    /// txindex keeps the block of every best chain transaction in the "txindex" tree
+    txindex: bool,
+    /// archive also keeps the "addrindex", "spentindex" and "filters" trees and refuses
+    /// pruning, so every historical query can be answered
+    archive: bool,
+    /// mining_threads is how many threads mine_block searches the proof of work on
+    mining_threads: usize,
+}
+
+/// BlockPruned is returned for a block whose body was deleted by pruning
+#[derive(Debug, Clone)]
+pub struct BlockPruned {
+    pub hash: BlockHash,
+    pub height: i32,
+}
+
+impl fmt::Display for BlockPruned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Block {} at height {} is pruned", self.hash, self.height)
+    }
+}
+
+impl failure::Fail for BlockPruned {}
+
+/// BlockDamaged is returned for a best chain block whose stored body is missing or no
+/// longer matches its checksum, until a peer sends the block again
+#[derive(Debug, Clone)]
+pub struct BlockDamaged {
+    pub hash: BlockHash,
+}
+
+impl fmt::Display for BlockDamaged {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Block {} is missing or corrupted on disk and waits to be downloaded again",
+            self.hash
+        )
+    }
+}
+
+impl failure::Fail for BlockDamaged {}
+
+/// DbCorruption is returned when the block database lost the records that locate the
+/// best chain and no intact block is left to recover it from
+#[derive(Debug, Clone)]
+pub struct DbCorruption {
+    pub reason: String,
+}
+
+impl fmt::Display for DbCorruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The block database is corrupted: {}", self.reason)
+    }
+}
+
+impl failure::Fail for DbCorruption {}
+
+/// HistoryEntry is a transaction that pays to or spends from an address
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub txid: TxId,
+    pub height: i32,
+    pub timestamp: u128,
+    pub received: i32,
+    pub sent: i32,
+}
+
+/// IndexInfo tells RPC clients and explorers which historical queries the node answers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexInfo {
+    pub archive: bool,
+    pub txindex: bool,
+    pub address_index: bool,
+    pub spent_index: bool,
+    pub compact_filters: bool,
+    /// pruned tells that some old block bodies are gone
+    pub pruned: bool,
+    pub height: i32,
+}
+
+/// WalletChanges is what a light wallet watching some addresses needs to catch up
+/// with the best chain from a block it synced to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalletChanges {
+    pub tip: BlockHash,
+    pub height: i32,
+    /// rescan tells that the block the wallet synced to left the best chain, so the
+    /// changes start from the genesis block and replace what the wallet knew
+    pub rescan: bool,
+    /// transactions pay to or spend from the addresses, oldest first
+    pub transactions: Vec<(i32, Transaction)>,
+    /// created are the outputs paid to the addresses that are still unspent
+    pub created: Vec<(TxId, i32, TXOutput)>,
+    /// spent are the outputs of the addresses from before the changes that were spent
+    pub spent: Vec<(TxId, i32)>,
+}
+
+/// ChainEntry is a block met by a ChainWalk: its height and header, and its body when
+/// the walk reads bodies and the block still has one
+#[derive(Debug, Clone)]
+pub struct ChainEntry {
+    pub height: i32,
+    pub header: BlockHeader,
+    pub body: Option<Block>,
 }
 
-pub struct BlockchainIterator<'a> {
-    tip: String,
+/// ChainWalk iterates a chain from a block back to genesis, reading one header at a
+/// time. Bodies are only read and decoded when asked for with `with_bodies`.
+pub struct ChainWalk<'a> {
     bc: &'a Blockchain,
+    next: BlockHash,
+    /// oldest_first holds the headers left to yield when walking from genesis up
+    oldest_first: Option<std::vec::IntoIter<BlockHeader>>,
+    bodies: bool,
 }
 
 impl Blockchain {
     pub fn new() -> Result<Self> {
+        Blockchain::open(Config::load()?)
+    }
+
+    /// open opens the block database with the node settings of `config`, building the
+    /// indexes they enable
+    pub fn open(config: Config) -> Result<Self> {
         info!("Opening blockchain");
 
-        let db: Db = open("data/blocks")?;
-        let hash = db
-            .get("LAST")?
-            .expect("Must create a new block database first");
+        let db: Db = open(data_path(BLOCKS_DB))?;
+        if db.is_empty() {
+            return Err(format_err!("Must create a new block database first"));
+        }
         info!("Found block database");
 
-        let last_hash = String::from_utf8(hash.to_vec())?;
-        Ok(Blockchain { tip: last_hash, db })
+        let mut bc = Blockchain {
+            tip: BlockHash::default(),
+            db,
+            params: config.chain,
+            txindex: config.node.txindex || config.node.archive,
+            archive: config.node.archive,
+            mining_threads: config.node.mining_threads,
+        };
+        bc.tip = bc.recover_tip()?;
+        if bc.db.open_tree("heights")?.is_empty() {
+            info!("building height index");
+            bc.index_heights(&bc.get_block(&bc.tip)?.header())?;
+        }
+        bc.check_genesis()?;
+        if bc.txindex && bc.db.open_tree("txindex")?.is_empty() {
+            info!("building transaction index");
+            for block in bc.walk().blocks() {
+                bc.index_transactions(&block)?;
+            }
+        }
+        if !bc
+            .db
+            .open_tree("spenders")?
+            .contains_key(SPENDERS_INDEXED)?
+        {
+            info!("building spenders index");
+            for block in bc.walk().blocks() {
+                bc.index_spenders(&block)?;
+            }
+            bc.db.open_tree("spenders")?.insert(SPENDERS_INDEXED, &[])?;
+        }
+        if bc.archive {
+            bc.check_archive(config.node.prune)?;
+        }
+        Ok(bc)
+    }
+
+    /// new_temporary opens an empty in-memory chain following `params`, for replaying
+    /// blocks without touching the node's database
+    pub fn new_temporary(params: ChainParams) -> Result<Self> {
+        Ok(Blockchain {
+            tip: BlockHash::default(),
+            db: sled::Config::new().temporary(true).open()?,
+            params,
+            txindex: true,
+            archive: false,
+            mining_threads: 1,
+        })
     }
 
-    pub fn create_blockchain(address: String) -> Result<Self> {
+    /// create_blockchain creates the block database holding the genesis block of the
+    /// configured network, checked against its known hash
+    pub fn create_blockchain() -> Result<Self> {
         info!("Creating blockchain");
-        if remove_dir_all("data/blocks").is_err() {
+        let config = Config::load()?;
+        let params = config.chain;
+        let genesis = genesis_block(&params)?;
+        if let Some(expected) = &params.genesis_hash
+            && *expected != genesis.get_hash()
+        {
+            return Err(CodedError::new(
+                ErrorCode::WrongNetwork,
+                format!(
+                    "The genesis block built from the chain parameters is {}, network {} starts with {}",
+                    genesis.get_hash(),
+                    params.network,
+                    expected
+                ),
+            )
+            .with("expected", expected)
+            .with("actual", genesis.get_hash())
+            .into());
+        }
+        genesis.validate_full(&ParentState {
+            target: params.initial_target(),
+            max_block_size: params.max_block_size,
+            check_signatures: true,
+            now: genesis.get_timestamp(),
+            ..ParentState::default()
+        })?;
+
+        if remove_dir_all(data_path(BLOCKS_DB)).is_err() {
             info!("not exists any blocks to delete")
         }
-        let db: Db = open("data/blocks")?;
+        let db: Db = open(data_path(BLOCKS_DB))?;
         info!("Creating new block database");
-        let cbtx = Transaction::new_coinbase(address, String::from("GENESIS_COINBASE"))?;
-        let genesis: Block = Block::new_genesis_block(cbtx);
-        db.insert(genesis.get_hash(), serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
+        db.insert(genesis.get_hash(), encode_block(&genesis)?)?;
+        db.insert("LAST", genesis.get_hash().as_ref())?;
+        db.insert(NETWORK_KEY, params.network.as_bytes())?;
+        db.open_tree("spenders")?.insert(SPENDERS_INDEXED, &[])?;
         let bc = Blockchain {
             tip: genesis.get_hash(),
             db: db.clone(),
+            params,
+            txindex: config.node.txindex || config.node.archive,
+            archive: config.node.archive,
+            mining_threads: config.node.mining_threads,
         };
+        bc.store_header(&genesis.header())?;
+        bc.index_heights(&genesis.header())?;
         bc.db.flush()?;
 
         Ok(bc)
     }
 
+    /// recover_tip returns the block the LAST record points at when its body is intact.
+    /// Otherwise it walks the headers back from there, or from the highest indexed
+    /// block when LAST itself is lost, to the first block whose body is intact, and
+    /// makes that block the tip.
+    fn recover_tip(&self) -> Result<BlockHash> {
+        let last = match self.db.get("LAST")? {
+            Some(hash) => BlockHash::from_slice(&hash).ok(),
+            None => None,
+        };
+        let mut hash = match last {
+            Some(hash) => hash,
+            None => match self.db.open_tree("heights")?.last()? {
+                Some((_, hash)) => BlockHash::from_slice(&hash)?,
+                None => {
+                    return Err(DbCorruption {
+                        reason: String::from("the LAST record and the height index are lost"),
+                    }
+                    .into())
+                }
+            },
+        };
+        let tip = loop {
+            match self.read_block(&hash) {
+                Ok(Some(block)) => break block,
+                Ok(None) => {}
+                Err(e) if e.downcast_ref::<BlockDamaged>().is_some() => {}
+                Err(e) => return Err(e),
+            }
+            let prev = match self.db.open_tree("headers")?.get(hash)? {
+                Some(header) => deserialize::<BlockHeader>(&header)?.prev_block_hash,
+                None => {
+                    return Err(DbCorruption {
+                        reason: format!("block {} has neither an intact body nor a header", hash),
+                    }
+                    .into())
+                }
+            };
+            if prev.is_zero() {
+                return Err(DbCorruption {
+                    reason: String::from("no block down to the genesis has an intact body"),
+                }
+                .into());
+            }
+            warn!(
+                "block {} is missing or corrupted, trying its parent {}",
+                hash, prev
+            );
+            hash = prev;
+        };
+        if last != Some(tip.get_hash()) {
+            warn!(
+                "recovered the chain at block {}, height {}",
+                tip.get_hash(),
+                tip.get_height()
+            );
+            self.db.insert("LAST", tip.get_hash().as_ref())?;
+            self.index_heights(&tip.header())?;
+            self.db.flush()?;
+        }
+        Ok(tip.get_hash())
+    }
+
+    /// mine_block mines a block of `transactions` on the tip and adds it, validated like
+    /// a block received from a peer
     pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
         info!("Mining new block");
 
-        for tx in &transactions {
-            if !self.verify_transaction(tx)? {
-                return Err(format_err!("ERROR: Invalid transaction"));
+        let mut new_block = self.new_block(transactions)?;
+        let search = new_block.mine(self.mining_threads, &AtomicBool::new(false))?;
+        info!(
+            "Mined block {} at {:.0} hashes/s",
+            new_block.get_hash(),
+            search.hashrate()
+        );
+        self.add_block(new_block.clone())?;
+        Ok(new_block)
+    }
+
+    /// new_block assembles a block of `transactions` on top of the tip, at the difficulty
+    /// the retarget rules set for it
+    pub fn new_block(&self, transactions: Vec<Transaction>) -> Result<Block> {
+        if let Some(txid) = self.verify_batch(&transactions)?.first() {
+            return Err(format_err!("ERROR: Invalid transaction {}", txid));
+        }
+
+        let last_hash = match self.db.get("LAST")? {
+            Some(hash) => BlockHash::from_slice(&hash)?,
+            None => {
+                return Err(DbCorruption {
+                    reason: String::from("the LAST record is lost"),
+                }
+                .into())
             }
+        };
+        let target = self.target_after(&self.get_block(&last_hash)?.header())?;
+        let block =
+            Block::new_unmined(transactions, last_hash, self.get_best_height()? + 1, target)?;
+        let size = block.size()?;
+        if size > self.params.max_block_size {
+            return Err(format_err!(
+                "Block of {} bytes exceeds the {} bytes limit",
+                size,
+                self.params.max_block_size
+            ));
         }
+        Ok(block)
+    }
 
-        let last_hash = self.db.get("LAST")?.unwrap();
+    pub fn get_block(&self, hash: &BlockHash) -> Result<Block> {
+        if let Some(block) = self.read_block(hash)? {
+            return Ok(block);
+        }
+        if self.db.open_tree("damaged")?.contains_key(hash)? {
+            return Err(BlockDamaged { hash: *hash }.into());
+        }
+        if let Some(height) = self.db.open_tree("pruned")?.get(hash)? {
+            return Err(BlockPruned {
+                hash: *hash,
+                height: deserialize(&height)?,
+            }
+            .into());
+        }
+        match self.get_header(hash)? {
+            Some(header) if self.get_block_hash(header.height)? == Some(*hash) => {
+                warn!(
+                    "block {} at height {} lost its body, downloading it again",
+                    hash, header.height
+                );
+                self.db.open_tree("damaged")?.insert(hash, vec![])?;
+                self.db.flush()?;
+                Err(BlockDamaged { hash: *hash }.into())
+            }
+            _ => Err(format_err!("Block {} is not found", hash)),
+        }
+    }
 
-        let new_block = Block::new(
-            transactions,
-            String::from_utf8(last_hash.to_vec())?,
-            self.get_best_height()? + 1,
-        )?;
+    /// read_block reads the stored body of block `hash` and checks it against its
+    /// checksum. A corrupted body is moved to the "damaged" tree, so the block is
+    /// asked from peers again, and reported as BlockDamaged.
+    fn read_block(&self, hash: &BlockHash) -> Result<Option<Block>> {
+        let data = match self.db.get(hash)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        if let Some(block) = decode_block(&data) {
+            return Ok(Some(block));
+        }
+        warn!(
+            "block {} failed its checksum, dropping the stored copy",
+            hash
+        );
+        self.db.open_tree("damaged")?.insert(hash, data)?;
+        self.db.remove(hash)?;
+        self.db.flush()?;
+        Err(BlockDamaged { hash: *hash }.into())
+    }
+
+    /// get_damaged_blocks returns the best chain blocks whose stored bodies went missing
+    /// or failed their checksum
+    pub fn get_damaged_blocks(&self) -> Result<Vec<BlockHash>> {
+        let mut hashes = Vec::new();
+        for entry in self.db.open_tree("damaged")?.iter() {
+            hashes.push(BlockHash::from_slice(&entry?.0)?);
+        }
+        Ok(hashes)
+    }
+
+    /// restore_block stores again the body of a damaged block, once `block`, sent by a
+    /// peer, proves to be the same block
+    fn restore_block(&self, block: &Block) -> Result<()> {
+        block.validate_full(&self.parent_state(block)?)?;
+        self.db.insert(block.get_hash(), encode_block(block)?)?;
+        self.db.open_tree("damaged")?.remove(block.get_hash())?;
+        self.db.flush()?;
+        warn!(
+            "repaired block {} with a copy from a peer",
+            block.get_hash()
+        );
+        Ok(())
+    }
+
+    pub fn has_block(&self, hash: &BlockHash) -> Result<bool> {
+        Ok(self.db.contains_key(hash)?)
+    }
+
+    /// prune_block deletes the body of `block` and its index entries but keeps its
+    /// header and, so their outputs can still be spent, the given transactions of it
+    pub fn prune_block(&self, block: &Block, spendable: &[Transaction]) -> Result<()> {
+        self.store_header(&block.header())?;
+        let kept = self.db.open_tree("spendable")?;
+        for tx in spendable {
+            kept.insert(tx.id, serialize(tx)?)?;
+        }
+        self.unindex_block(block, spendable)?;
         self.db
-            .insert(new_block.get_hash(), serialize(&new_block)?)?;
-        self.db.insert("LAST", new_block.get_hash().as_bytes())?;
+            .open_tree("pruned")?
+            .insert(block.get_hash(), serialize(&block.get_height())?)?;
+        self.db.remove(block.get_hash())?;
         self.db.flush()?;
+        Ok(())
+    }
 
-        self.tip = new_block.get_hash();
-        Ok(new_block)
+    /// truncate cuts the best chain before block `hash`: the bodies of the block and of
+    /// the best chain blocks after it are deleted and marked invalid, and its parent
+    /// becomes the tip and the header tip. It returns how many blocks were deleted.
+    pub fn truncate(&mut self, hash: &BlockHash) -> Result<usize> {
+        let header = match self.get_header(hash)? {
+            Some(header) if self.get_block_hash(header.height)? == Some(*hash) => header,
+            _ => return Err(format_err!("Block {} is not on the best chain", hash)),
+        };
+        let parent = match self.get_header(&header.prev_block_hash)? {
+            Some(parent) => parent,
+            None => return Err(format_err!("Cannot truncate the chain before its genesis")),
+        };
+        let removed: Vec<BlockHash> = self
+            .walk()
+            .take_while(|entry| entry.height >= header.height)
+            .map(|entry| entry.header.hash)
+            .collect();
+        let damaged = self.db.open_tree("damaged")?;
+        let invalid = self.db.open_tree("invalid")?;
+        for hash in &removed {
+            self.db.remove(hash)?;
+            damaged.remove(hash)?;
+            invalid.insert(hash, &[])?;
+        }
+        let rejection = header.reject("truncated", None, "a block passing verifychain", hash);
+        if let Some(rejection) = rejection.downcast_ref::<BlockRejection>() {
+            self.record_rejection(rejection)?;
+        }
+        self.db
+            .open_tree("headers")?
+            .insert(HEADER_TIP, parent.hash.as_ref())?;
+        self.db.insert("LAST", parent.hash.as_ref())?;
+        self.tip = parent.hash;
+        self.index_heights(&parent)?;
+        self.db.flush()?;
+        warn!(
+            "truncated the chain before block {}, the tip is now {}",
+            hash, parent.hash
+        );
+        Ok(removed.len())
     }
 
-    pub fn get_block(&self, hash: &str) -> Result<Block> {
-        let data = self.db.get(hash.as_bytes())?.unwrap();
-        let block = deserialize(&data)?;
-        Ok(block)
+    /// is_pruned tells whether any block body was deleted
+    pub fn is_pruned(&self) -> Result<bool> {
+        Ok(!self.db.open_tree("pruned")?.is_empty())
     }
 
     pub fn add_block(&mut self, block: Block) -> Result<()> {
-        let data = serialize(&block)?;
+        if self
+            .db
+            .open_tree("damaged")?
+            .contains_key(block.get_hash())?
+        {
+            return self.restore_block(&block);
+        }
         if (self.db.get(block.get_hash())?).is_some() {
             return Ok(());
         }
-        self.db.insert(block.get_hash(), data)?;
 
-        let last_height = self.get_best_height()?;
-        if block.get_height() > last_height {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
+        let state = self.parent_state(&block)?;
+        let valid = self
+            .check_invalid(&block.header())
+            .and_then(|_| self.check_genesis_hash(&block.header()))
+            .and_then(|_| block.validate_full(&state));
+        if let Err(e) = valid {
+            if let Some(rejection) = e.downcast_ref::<BlockRejection>() {
+                self.record_rejection(rejection)?;
+            }
+            return Err(e);
+        }
+        self.store_block(&block)
+    }
+
+    /// check_genesis refuses a block database that was created for another network or
+    /// whose genesis block does not follow the chain parameters. Databases older than
+    /// the network record are taken to belong to the configured network.
+    fn check_genesis(&self) -> Result<()> {
+        let network = &self.params.network;
+        match self.db.get(NETWORK_KEY)? {
+            Some(stored) if stored != network.as_bytes() => {
+                let stored = String::from_utf8_lossy(&stored).to_string();
+                return Err(CodedError::new(
+                    ErrorCode::WrongNetwork,
+                    format!(
+                        "{} belongs to network {}, not the configured {}",
+                        data_path(BLOCKS_DB),
+                        stored,
+                        network
+                    ),
+                )
+                .with("expected", network)
+                .with("actual", stored)
+                .into());
+            }
+            Some(_) => {}
+            None => {
+                self.db.insert(NETWORK_KEY, network.as_bytes())?;
+            }
+        }
+
+        let hash = match self.get_block_hash(0)? {
+            Some(hash) => hash,
+            None => {
+                return Err(format_err!(
+                    "{} holds no genesis block",
+                    data_path(BLOCKS_DB)
+                ))
+            }
+        };
+        if let Some(expected) = &self.params.genesis_hash
+            && *expected != hash
+        {
+            return Err(CodedError::new(
+                ErrorCode::WrongNetwork,
+                format!(
+                    "The genesis block of {} is {}, network {} starts with {}",
+                    data_path(BLOCKS_DB),
+                    hash,
+                    network,
+                    expected
+                ),
+            )
+            .with("expected", expected)
+            .with("actual", hash)
+            .into());
+        }
+        let checked = match self.get_block(&hash) {
+            Ok(genesis) => genesis.validate_full(&self.parent_state(&genesis)?),
+            Err(e) if e.downcast_ref::<BlockPruned>().is_some() => match self.get_header(&hash)? {
+                Some(header) => header.validate(None, self.params.initial_target()),
+                None => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = checked {
+            return Err(CodedError::new(
+                ErrorCode::WrongNetwork,
+                format!(
+                    "The genesis block of {} does not follow the chain parameters of network {}: {}",
+                    data_path(BLOCKS_DB),
+                    network,
+                    e
+                ),
+            )
+            .with("block_hash", hash)
+            .into());
+        }
+
+        // genesis blocks created before networks had magic bytes carry the bare tag
+        if let Ok(genesis) = self.get_block(&hash)
+            && let Some(coinbase) = genesis.get_transactions().first()
+            && let Some(input) = coinbase.vin.first()
+        {
+            let tag = genesis_tag(&self.params);
+            if input
+                .pub_key
+                .starts_with(format!("{} ", GENESIS_COINBASE).as_bytes())
+                && !input.pub_key.starts_with(tag.as_bytes())
+            {
+                return Err(CodedError::new(
+                    ErrorCode::WrongNetwork,
+                    format!(
+                        "The genesis block of {} was not created for network {}",
+                        data_path(BLOCKS_DB),
+                        network
+                    ),
+                )
+                .with("block_hash", hash)
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// parent_state gathers from the chain what validating `block` needs, including the
+    /// transactions its inputs spend that it does not create itself
+    pub fn parent_state(&self, block: &Block) -> Result<ParentState> {
+        let prev: Option<Block> = if block.get_prev_hash().is_zero() {
+            None
+        } else {
+            match self.read_block(&block.get_prev_hash())? {
+                Some(prev) => Some(prev),
+                None => {
+                    return Err(format_err!(
+                        "Previous block {} of {} is unknown",
+                        block.get_prev_hash(),
+                        block.get_hash()
+                    ))
+                }
+            }
+        };
+        let prev = prev.map(|prev| prev.header());
+        let target = match &prev {
+            Some(prev) => self.target_after(prev)?,
+            None => self.params.initial_target(),
+        };
+
+        let in_block: HashSet<TxId> = block.get_transactions().iter().map(|tx| tx.id).collect();
+        let mut prev_txs = HashMap::new();
+        let mut spent = HashSet::new();
+        for tx in block
+            .get_transactions()
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+        {
+            for vin in &tx.vin {
+                let parent = match &prev {
+                    Some(parent) if !in_block.contains(&vin.txid) => parent,
+                    _ => continue,
+                };
+                if !prev_txs.contains_key(&vin.txid)
+                    && let Some(prev_tx) = self.find_transaction_at(parent, &vin.txid)?
+                {
+                    prev_txs.insert(vin.txid, prev_tx);
+                }
+                if self.is_spent_at(parent, &vin.txid, vin.vout)? {
+                    spent.insert((vin.txid, vin.vout));
+                }
+            }
+        }
+
+        Ok(ParentState {
+            prev,
+            target,
+            max_block_size: self.params.max_block_size,
+            checkpoint: self.params.checkpoints.get(&block.get_height()).cloned(),
+            check_signatures: !self.below_checkpoint(&block.get_hash(), block.get_height())?,
+            prev_txs,
+            spent,
+            now: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_millis(),
+        })
+    }
+
+    /// store_block saves `block` without validating it, making it the tip when its
+    /// chain has more work than the best one
+    pub fn store_block(&mut self, block: &Block) -> Result<()> {
+        let tip_work = match self.db.get("LAST")? {
+            Some(_) => Some(self.get_chainwork(&self.get_block(&self.tip)?.header())?),
+            None => None,
+        };
+        self.db.insert(block.get_hash(), encode_block(block)?)?;
+        self.index_spenders(block)?;
+        let work = self.get_chainwork(&block.header())?;
+        if tip_work.is_none_or(|tip_work| work > tip_work) {
+            self.db.insert("LAST", block.get_hash().as_ref())?;
             self.tip = block.get_hash();
-            self.db.flush()?;
+        }
+        self.store_header(&block.header())?;
+        if self.tip == block.get_hash() {
+            self.index_heights(&block.header())?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// index_spenders records `block` as a spender of every output its inputs spend, in
+    /// the "spenders" tree keyed by outpoint and block hash, whichever branch it is on
+    fn index_spenders(&self, block: &Block) -> Result<()> {
+        let spenders = self.db.open_tree("spenders")?;
+        for tx in block
+            .get_transactions()
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+        {
+            for vin in &tx.vin {
+                let mut key = outpoint_key(&vin.txid, vin.vout);
+                key.extend_from_slice(block.get_hash().as_ref());
+                spenders.insert(key, &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// is_spent_at tells whether a block of the chain ending at `tip` spends output
+    /// `vout` of transaction `txid`
+    fn is_spent_at(&self, tip: &BlockHeader, txid: &TxId, vout: i32) -> Result<bool> {
+        let spenders = self.db.open_tree("spenders")?;
+        for entry in spenders.scan_prefix(outpoint_key(txid, vout)) {
+            let key = entry?.0;
+            let spender = BlockHash::from_slice(&key[key.len() - HASH_LEN..])?;
+            if self.is_ancestor(&spender, tip)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// is_ancestor tells whether block `hash` is `tip` or one of its ancestors. Only the
+    /// part of `tip`'s chain off the best chain is walked, the rest is in the height index.
+    fn is_ancestor(&self, hash: &BlockHash, tip: &BlockHeader) -> Result<bool> {
+        let height = match self.get_header(hash)? {
+            Some(header) => header.height,
+            None => return Ok(false),
+        };
+        Ok(self
+            .ancestor_at(tip, height)?
+            .is_some_and(|header| header.hash == *hash))
+    }
+
+    /// ancestor_at returns the header at `height` of the chain ending at `tip`. Only the
+    /// part of the chain off the best chain is walked; below it the heights index is used.
+    fn ancestor_at(&self, tip: &BlockHeader, height: i32) -> Result<Option<BlockHeader>> {
+        let mut header = tip.clone();
+        while header.height > height && self.get_block_hash(header.height)? != Some(header.hash) {
+            header = match self.get_header(&header.prev_block_hash)? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+        }
+        if header.height <= height {
+            return Ok(Some(header).filter(|header| header.height == height));
+        }
+        match self.get_block_hash(height)? {
+            Some(hash) => self.get_header(&hash),
+            None => Ok(None),
+        }
+    }
+
+    /// find_transaction_at looks for transaction `id` in block `tip` and its ancestors.
+    /// The blocks of a side branch are read down to where it leaves the best chain, below
+    /// which the transaction index is used when there is one.
+    fn find_transaction_at(&self, tip: &BlockHeader, id: &TxId) -> Result<Option<Transaction>> {
+        let mut header = tip.clone();
+        while self.get_block_hash(header.height)? != Some(header.hash) {
+            if let Some(block) = self.read_block(&header.hash)?
+                && let Some(tx) = block.get_transactions().iter().find(|tx| tx.id == *id)
+            {
+                return Ok(Some(tx.clone()));
+            }
+            header = match self.get_header(&header.prev_block_hash)? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+        }
+        if !self.txindex {
+            return Ok(self.find_transaction_from(&header.hash, id).ok());
+        }
+        if let Some(height) = self.find_transaction_height(id)? {
+            if height > header.height {
+                return Ok(None);
+            }
+            if let Some(tx) = self.find_indexed_transaction(id)? {
+                return Ok(Some(tx));
+            }
+        }
+        // the transactions of pruned blocks that still have unspent outputs
+        match self.db.open_tree("spendable")?.get(id)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// index_heights points the height index at `tip`, the new best block, and its
+    /// ancestors, stopping at the first height that already has the right hash
+    fn index_heights(&self, tip: &BlockHeader) -> Result<()> {
+        let heights = self.db.open_tree("heights")?;
+        // a heavier chain may be shorter than the one it replaces
+        for entry in heights.range((tip.height + 1).to_be_bytes()..) {
+            heights.remove(entry?.0)?;
+        }
+        let mut header = tip.clone();
+        loop {
+            let key = header.height.to_be_bytes();
+            if heights.get(key)?.as_deref() == Some(header.hash.as_ref()) {
+                break;
+            }
+            heights.insert(key, header.hash.as_ref())?;
+            if self.txindex
+                && let Some(block) = self.read_block(&header.hash)?
+            {
+                self.index_transactions(&block)?;
+                if self.archive {
+                    self.index_archive(&block)?;
+                }
+            }
+            if header.prev_block_hash.is_zero() {
+                break;
+            }
+            header = match self.get_header(&header.prev_block_hash)? {
+                Some(header) => header,
+                None => {
+                    return Err(format_err!(
+                        "Header {} is not found",
+                        header.prev_block_hash
+                    ))
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// index_transactions points the transaction index at `block` for its transactions
+    fn index_transactions(&self, block: &Block) -> Result<()> {
+        let txindex = self.db.open_tree("txindex")?;
+        for tx in block.get_transactions() {
+            txindex.insert(tx.id, block.get_hash().as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// index_archive records the addresses `block` pays to and spends from, the outputs
+    /// it spends and its compact filter
+    fn index_archive(&self, block: &Block) -> Result<()> {
+        let addrindex = self.db.open_tree("addrindex")?;
+        let spentindex = self.db.open_tree("spentindex")?;
+        let hash = block.get_hash();
+        for tx in block.get_transactions() {
+            let mut hashes: Vec<Vec<u8>> = tx
+                .vout
+                .iter()
+                .filter(|out| !out.is_unspendable())
+                .map(|out| out.pub_key_hash.clone())
+                .collect();
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    spentindex.insert(
+                        outpoint_key(&vin.txid, vin.vout),
+                        serialize(&(&tx.id, &hash))?,
+                    )?;
+                    hashes.push(spender_hash(&vin.pub_key));
+                }
+            }
+            for pub_key_hash in hashes {
+                let key = [pub_key_hash.as_slice(), tx.id.as_ref()].concat();
+                addrindex.insert(key, hash.as_ref())?;
+            }
+        }
+        self.db
+            .open_tree("filters")?
+            .insert(hash, serialize(&BlockFilter::of_block(block))?)?;
+        Ok(())
+    }
+
+    /// unindex_block removes the entries of the indexes pointing at `block`, except the
+    /// transaction index entries of the `kept` transactions, whose heights stay known
+    fn unindex_block(&self, block: &Block, kept: &[Transaction]) -> Result<()> {
+        let txindex = self.db.open_tree("txindex")?;
+        let addrindex = self.db.open_tree("addrindex")?;
+        let spentindex = self.db.open_tree("spentindex")?;
+        let hash = block.get_hash();
+        for tx in block.get_transactions() {
+            if !kept.iter().any(|kept| kept.id == tx.id)
+                && txindex.get(tx.id)?.as_deref() == Some(hash.as_ref())
+            {
+                txindex.remove(tx.id)?;
+            }
+            let mut hashes: Vec<Vec<u8>> =
+                tx.vout.iter().map(|out| out.pub_key_hash.clone()).collect();
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    spentindex.remove(outpoint_key(&vin.txid, vin.vout))?;
+                    hashes.push(spender_hash(&vin.pub_key));
+                }
+            }
+            for pub_key_hash in hashes {
+                addrindex.remove([pub_key_hash.as_slice(), tx.id.as_ref()].concat())?;
+            }
+        }
+        self.db.open_tree("filters")?.remove(hash)?;
+        Ok(())
+    }
+
+    /// check_archive makes sure an archive node can answer for every best chain block:
+    /// nothing may be pruned, and indexes missing any of the most recent blocks, left
+    /// by a node that ran without the archive profile, are rebuilt
+    fn check_archive(&self, prune: Option<i32>) -> Result<()> {
+        if prune.is_some() || self.is_pruned()? {
+            return Err(CodedError::new(
+                ErrorCode::InvalidArgument,
+                "An archive node keeps every block body, it cannot be pruned",
+            )
+            .into());
+        }
+        if self.archive_indexed()? {
+            return Ok(());
+        }
+        warn!("the archive indexes do not cover the best chain, rebuilding them");
+        for tree in ["txindex", "addrindex", "spentindex", "filters"] {
+            self.db.open_tree(tree)?.clear()?;
+        }
+        for block in self.walk().blocks() {
+            self.index_transactions(&block)?;
+            self.index_archive(&block)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// archive_indexed tells whether every index covers the most recent blocks
+    fn archive_indexed(&self) -> Result<bool> {
+        let txindex = self.db.open_tree("txindex")?;
+        let addrindex = self.db.open_tree("addrindex")?;
+        let spentindex = self.db.open_tree("spentindex")?;
+        let filters = self.db.open_tree("filters")?;
+        for block in self.walk().blocks().take(ARCHIVE_CHECK_DEPTH) {
+            let hash = block.get_hash();
+            if !filters.contains_key(hash)? {
+                return Ok(false);
+            }
+            for tx in block.get_transactions() {
+                if txindex.get(tx.id)?.as_deref() != Some(hash.as_ref()) {
+                    return Ok(false);
+                }
+                for out in tx.vout.iter().filter(|out| !out.is_unspendable()) {
+                    let key = [out.pub_key_hash.as_slice(), tx.id.as_ref()].concat();
+                    if !addrindex.contains_key(key)? {
+                        return Ok(false);
+                    }
+                }
+                if tx.is_coinbase() {
+                    continue;
+                }
+                for vin in &tx.vin {
+                    let key = outpoint_key(&vin.txid, vin.vout);
+                    if spentindex.get(key)?.is_none() {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// require_archive fails when `index` is not kept because the node is no archive
+    fn require_archive(&self, index: &str) -> Result<()> {
+        if self.archive {
+            return Ok(());
+        }
+        Err(CodedError::new(
+            ErrorCode::NotFound,
+            format!("The {} is only kept by archive nodes", index),
+        )
+        .into())
+    }
+
+    /// best_chain_height is the height of block `hash` when it is on the best chain
+    fn best_chain_height(&self, hash: &BlockHash) -> Result<Option<i32>> {
+        match self.get_header(hash)? {
+            Some(header) if self.get_block_hash(header.height)? == Some(*hash) => {
+                Ok(Some(header.height))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// find_address_transactions returns the height and id of every best chain
+    /// transaction paying to or spending from `pub_key_hash`, oldest first
+    pub fn find_address_transactions(&self, pub_key_hash: &[u8]) -> Result<Vec<(i32, TxId)>> {
+        self.require_archive("address index")?;
+        let mut txs = Vec::new();
+        for entry in self.db.open_tree("addrindex")?.scan_prefix(pub_key_hash) {
+            let (key, hash) = entry?;
+            let txid = TxId::from_slice(&key[pub_key_hash.len()..])?;
+            if let Some(height) = self.best_chain_height(&BlockHash::from_slice(&hash)?)? {
+                txs.push((height, txid));
+            }
+        }
+        txs.sort();
+        Ok(txs)
+    }
+
+    /// wallet_changes collects from the archive indexes what changed for the outputs of
+    /// `pub_key_hashes` in the best chain blocks after block `since`, or from the genesis
+    /// block. Outputs both created and spent since are left out.
+    pub fn wallet_changes(
+        &self,
+        pub_key_hashes: &[Vec<u8>],
+        since: Option<&BlockHash>,
+    ) -> Result<WalletChanges> {
+        let (from, rescan) = match since {
+            Some(hash) => match self.best_chain_height(hash)? {
+                Some(height) => (height + 1, false),
+                None => (0, true),
+            },
+            None => (0, false),
+        };
+        let mut txids = BTreeSet::new();
+        for pub_key_hash in pub_key_hashes {
+            for (height, txid) in self.find_address_transactions(pub_key_hash)? {
+                if height >= from {
+                    txids.insert((height, txid));
+                }
+            }
+        }
+        let mut transactions = Vec::new();
+        for (height, txid) in txids {
+            transactions.push((height, self.find_transaction(&txid)?));
+        }
+
+        let mut created = BTreeMap::new();
+        for (_, tx) in &transactions {
+            for (vout, out) in tx.vout.iter().enumerate() {
+                if !out.is_unspendable() && pub_key_hashes.contains(&out.pub_key_hash) {
+                    created.insert((tx.id, vout as i32), out.clone());
+                }
+            }
+        }
+        let mut spent = Vec::new();
+        for (_, tx) in transactions.iter().filter(|(_, tx)| !tx.is_coinbase()) {
+            for vin in &tx.vin {
+                if pub_key_hashes.contains(&spender_hash(&vin.pub_key))
+                    && created.remove(&(vin.txid, vin.vout)).is_none()
+                {
+                    spent.push((vin.txid, vin.vout));
+                }
+            }
+        }
+        Ok(WalletChanges {
+            tip: self.tip,
+            height: self.get_best_height()?,
+            rescan,
+            transactions,
+            created: created
+                .into_iter()
+                .map(|((txid, vout), out)| (txid, vout, out))
+                .collect(),
+            spent,
+        })
+    }
+
+    /// find_spender returns the id and height of the best chain transaction spending
+    /// output `vout` of `txid`
+    pub fn find_spender(&self, txid: &TxId, vout: i32) -> Result<Option<(TxId, i32)>> {
+        self.require_archive("spent index")?;
+        let data = match self
+            .db
+            .open_tree("spentindex")?
+            .get(outpoint_key(txid, vout))?
+        {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let (spender, hash): (TxId, BlockHash) = deserialize(&data)?;
+        Ok(self
+            .best_chain_height(&hash)?
+            .map(|height| (spender, height)))
+    }
+
+    /// get_block_filter returns the compact filter of block `hash`
+    pub fn get_block_filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>> {
+        self.require_archive("compact filter index")?;
+        match self.db.open_tree("filters")?.get(hash)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// index_info describes the indexes the node keeps
+    pub fn index_info(&self) -> Result<IndexInfo> {
+        Ok(IndexInfo {
+            archive: self.archive,
+            txindex: self.txindex,
+            address_index: self.archive,
+            spent_index: self.archive,
+            compact_filters: self.archive,
+            pruned: self.is_pruned()?,
+            height: self.get_best_height()?,
+        })
+    }
+
+    /// has_txindex tells whether transactions are looked up through the transaction index
+    pub fn has_txindex(&self) -> bool {
+        self.txindex
+    }
+
+    /// is_archive tells whether the node runs the archive profile
+    pub fn is_archive(&self) -> bool {
+        self.archive
+    }
+
+    /// find_indexed_transaction looks transaction `id` up in the transaction index,
+    /// ignoring entries left behind by blocks a reorg disconnected
+    fn find_indexed_transaction(&self, id: &TxId) -> Result<Option<Transaction>> {
+        if !self.txindex {
+            return Ok(None);
+        }
+        let hash = match self.db.open_tree("txindex")?.get(id)? {
+            Some(hash) => BlockHash::from_slice(&hash)?,
+            None => return Ok(None),
+        };
+        let block = match self.read_block(&hash)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        if self.get_block_hash(block.get_height())? != Some(hash) {
+            return Ok(None);
+        }
+        Ok(block
+            .get_transactions()
+            .iter()
+            .find(|tx| tx.id == *id)
+            .cloned())
+    }
+
+    /// find_transaction_height returns the height of the best chain block holding
+    /// transaction `id`, which the transaction index tells without reading block bodies
+    pub fn find_transaction_height(&self, id: &TxId) -> Result<Option<i32>> {
+        if !self.txindex {
+            return Ok(self
+                .walk()
+                .blocks()
+                .find(|block| block.get_transactions().iter().any(|tx| tx.id == *id))
+                .map(|block| block.get_height()));
+        }
+        let hash = match self.db.open_tree("txindex")?.get(id)? {
+            Some(hash) => BlockHash::from_slice(&hash)?,
+            None => return Ok(None),
+        };
+        match self.get_header(&hash)? {
+            Some(header) if self.get_block_hash(header.height)? == Some(hash) => {
+                Ok(Some(header.height))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// get_block_hash returns the hash of the best chain block at `height`
+    pub fn get_block_hash(&self, height: i32) -> Result<Option<BlockHash>> {
+        if height > self.get_best_height()? {
+            return Ok(None);
+        }
+        match self.db.open_tree("heights")?.get(height.to_be_bytes())? {
+            Some(hash) => Ok(Some(BlockHash::from_slice(&hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// get_block_by_height returns the best chain block at `height`
+    pub fn get_block_by_height(&self, height: i32) -> Result<Option<Block>> {
+        match self.get_block_hash(height)? {
+            Some(hash) => Ok(Some(self.get_block(&hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// add_header validates a header received before its block and adds it to the header
+    /// chain; it returns false when the header is already known
+    pub fn add_header(&self, header: BlockHeader) -> Result<bool> {
+        if let Err(e) = self.check_invalid(&header) {
+            if let Some(rejection) = e.downcast_ref::<BlockRejection>() {
+                self.record_rejection(rejection)?;
+            }
+            return Err(e);
+        }
+        if self.get_header(&header.hash)?.is_some() {
+            return Ok(false);
+        }
+        let prev = if header.prev_block_hash.is_zero() {
+            None
+        } else {
+            match self.get_header(&header.prev_block_hash)? {
+                Some(prev) => Some(prev),
+                None => {
+                    return Err(format_err!(
+                        "Previous header {} of {} is unknown",
+                        header.prev_block_hash,
+                        header.hash
+                    ))
+                }
+            }
+        };
+        let target = match &prev {
+            Some(prev) => self.target_after(prev)?,
+            None => self.params.initial_target(),
+        };
+        let valid = self
+            .check_checkpoint(&header)
+            .and_then(|_| self.check_genesis_hash(&header))
+            .and_then(|_| header.validate(prev.as_ref(), target));
+        if let Err(e) = valid {
+            if let Some(rejection) = e.downcast_ref::<BlockRejection>() {
+                self.record_rejection(rejection)?;
+            }
+            return Err(e);
+        }
+        self.store_header(&header)?;
+        Ok(true)
+    }
+
+    /// check_checkpoint rejects a block or header at a checkpoint height with another hash,
+    /// so chains diverging before a checkpoint are dropped
+    fn check_checkpoint(&self, header: &BlockHeader) -> Result<()> {
+        match self.params.checkpoints.get(&header.height) {
+            Some(hash) if *hash != header.hash => {
+                Err(header.reject("checkpoint", None, hash, header.hash))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// check_genesis_hash rejects a block or header without a parent unless it is the
+    /// genesis block of the network, or the one the chain was created with, so no peer
+    /// can start a chain of its own
+    fn check_genesis_hash(&self, header: &BlockHeader) -> Result<()> {
+        if !header.prev_block_hash.is_zero() {
+            return Ok(());
+        }
+        let expected = match self.params.genesis_hash {
+            Some(hash) => Some(hash),
+            None => self.get_block_hash(0)?,
+        };
+        match expected {
+            Some(hash) if hash != header.hash => {
+                Err(header.reject("genesis", None, hash, header.hash))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// check_invalid refuses a block or header that truncate cut from the chain, or one
+    /// extending it, which is marked invalid in turn
+    fn check_invalid(&self, header: &BlockHeader) -> Result<()> {
+        let invalid = self.db.open_tree("invalid")?;
+        if invalid.contains_key(header.hash)? {
+            return Err(header.reject("invalid", None, "a block not marked invalid", header.hash));
+        }
+        if invalid.contains_key(header.prev_block_hash)? {
+            invalid.insert(header.hash, &[])?;
+            return Err(header.reject(
+                "invalid",
+                None,
+                "a parent not marked invalid",
+                header.prev_block_hash,
+            ));
         }
         Ok(())
     }
 
+    /// below_checkpoint tells whether block `hash` at `height` is an ancestor of the last
+    /// checkpoint, whose signatures need not be checked
+    fn below_checkpoint(&self, hash: &BlockHash, height: i32) -> Result<bool> {
+        let (checkpoint_height, checkpoint_hash) = match self.params.checkpoints.iter().next_back()
+        {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(false),
+        };
+        if height > *checkpoint_height {
+            return Ok(false);
+        }
+        let mut header = match self.get_header(checkpoint_hash)? {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+        while header.height > height {
+            header = match self.get_header(&header.prev_block_hash)? {
+                Some(header) => header,
+                None => return Ok(false),
+            };
+        }
+        Ok(header.hash == *hash)
+    }
+
+    /// store_header saves `header` and moves the header tip when its chain has the most work
+    fn store_header(&self, header: &BlockHeader) -> Result<()> {
+        let headers = self.db.open_tree("headers")?;
+        headers.insert(header.hash, serialize(header)?)?;
+        if self.get_chainwork(header)? > self.get_chainwork(&self.get_header_tip()?)? {
+            headers.insert(HEADER_TIP, header.hash.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// get_chainwork returns the work of the chain ending at `header`, the sum of the
+    /// work of the targets of its blocks. It is stored by block hash, and computed for
+    /// the headers stored before it was.
+    pub fn get_chainwork(&self, header: &BlockHeader) -> Result<U256> {
+        let chainwork = self.db.open_tree("chainwork")?;
+        let mut pending = Vec::new();
+        let mut header = header.clone();
+        let mut work = loop {
+            if let Some(work) = chainwork.get(header.hash)? {
+                break U256(work.as_ref().try_into()?);
+            }
+            let prev = header.prev_block_hash;
+            pending.push(header);
+            if prev.is_zero() {
+                break U256::default();
+            }
+            header = match self.get_header(&prev)? {
+                Some(header) => header,
+                None => return Err(format_err!("Header {} is not found", prev)),
+            };
+        };
+        for header in pending.iter().rev() {
+            work = work.saturating_add(&header.target.work());
+            chainwork.insert(header.hash, &work.0)?;
+        }
+        Ok(work)
+    }
+
+    /// get_header returns the header of a block, whether or not the block itself is stored
+    pub fn get_header(&self, hash: &BlockHash) -> Result<Option<BlockHeader>> {
+        if let Some(data) = self.db.open_tree("headers")?.get(hash)? {
+            return Ok(Some(deserialize(&data)?));
+        }
+        Ok(self.read_block(hash)?.map(|block| block.header()))
+    }
+
+    /// get_header_tip returns the header with the most chainwork, which may be ahead of
+    /// the best block
+    pub fn get_header_tip(&self) -> Result<BlockHeader> {
+        let tip = self.get_block(&self.tip)?.header();
+        let hash = match self.db.open_tree("headers")?.get(HEADER_TIP)? {
+            Some(hash) => BlockHash::from_slice(&hash)?,
+            None => return Ok(tip),
+        };
+        match self.get_header(&hash)? {
+            Some(header) if self.get_chainwork(&header)? > self.get_chainwork(&tip)? => Ok(header),
+            _ => Ok(tip),
+        }
+    }
+
+    /// get_locator lists header hashes from the header tip back to the genesis, dense
+    /// near the tip and exponentially sparser further back, so a peer can find where
+    /// our header chain leaves its own
+    pub fn get_locator(&self) -> Result<Vec<BlockHash>> {
+        let mut locator = Vec::new();
+        let mut header = self.get_header_tip()?;
+        let mut step = 1;
+        loop {
+            locator.push(header.hash);
+            if header.prev_block_hash.is_zero() {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            for _ in 0..step {
+                match self.get_header(&header.prev_block_hash)? {
+                    Some(prev) => header = prev,
+                    None => return Ok(locator),
+                }
+                if header.prev_block_hash.is_zero() {
+                    break;
+                }
+            }
+        }
+        Ok(locator)
+    }
+
+    /// headers_after returns up to `max` headers of the best chain following the first
+    /// `locator` hash on it, oldest first. The heights index finds that hash, so only the
+    /// headers returned are read.
+    pub fn headers_after(&self, locator: &[BlockHash], max: usize) -> Result<Vec<BlockHeader>> {
+        let mut start = 0;
+        for hash in locator {
+            if let Some(header) = self.get_header(hash)?
+                && self.get_block_hash(header.height)? == Some(*hash)
+            {
+                start = header.height + 1;
+                break;
+            }
+        }
+        let mut headers = Vec::new();
+        let mut height = start;
+        while headers.len() < max {
+            let header = match self.get_block_hash(height)? {
+                Some(hash) => self.get_header(&hash)?,
+                None => None,
+            };
+            match header {
+                Some(header) => headers.push(header),
+                None => break,
+            }
+            height += 1;
+        }
+        Ok(headers)
+    }
+
+    /// missing_blocks returns the headers of the header chain whose blocks are not
+    /// downloaded yet, oldest first
+    pub fn missing_blocks(&self) -> Result<Vec<BlockHeader>> {
+        let mut missing = Vec::new();
+        let mut header = self.get_header_tip()?;
+        while !self.has_block(&header.hash)? {
+            let prev = header.prev_block_hash;
+            missing.push(header);
+            match self.get_header(&prev)? {
+                Some(prev) => header = prev,
+                None => break,
+            }
+        }
+        missing.reverse();
+        Ok(missing)
+    }
+
+    /// target_after returns the target of the block following `prev`: the target of
+    /// `prev`, retargeted when `prev` closes a retarget window
+    pub fn target_after(&self, prev: &BlockHeader) -> Result<U256> {
+        let start = match self.params.retarget_start(prev.height) {
+            Some(start) => start,
+            None => return Ok(prev.target),
+        };
+        let first = match self.ancestor_at(prev, start)? {
+            Some(first) => first,
+            None => {
+                return Err(format_err!(
+                    "Header at height {} below {} is not found",
+                    start,
+                    prev.hash
+                ))
+            }
+        };
+        Ok(self
+            .params
+            .retarget(prev.target, first.timestamp, prev.timestamp))
+    }
+
+    /// network_hashrate estimates the hashes a second the network spends on the chain
+    /// from the difficulty and the intervals of the last `blocks` blocks of the best chain
+    pub fn network_hashrate(&self, blocks: usize) -> Result<f64> {
+        let headers: Vec<BlockHeader> = self
+            .walk()
+            .take(blocks.saturating_add(1))
+            .map(|entry| entry.header)
+            .collect();
+        let (last, first) = match (headers.first(), headers.last()) {
+            (Some(last), Some(first)) => (last, first),
+            _ => return Ok(0.0),
+        };
+        let hashes: f64 = headers[..headers.len() - 1]
+            .iter()
+            .map(|header| header.target.expected_hashes())
+            .sum();
+        let span = last.timestamp.saturating_sub(first.timestamp);
+        if span == 0 {
+            return Ok(0.0);
+        }
+        Ok(hashes * 1000.0 / span as f64)
+    }
+
+    /// record_rejection keeps `rejection` among the last MAX_REJECTIONS rejected blocks
+    fn record_rejection(&self, rejection: &BlockRejection) -> Result<()> {
+        let rejections = self.db.open_tree("rejections")?;
+        let id = self.db.generate_id()?;
+        rejections.insert(id.to_be_bytes(), serialize(rejection)?)?;
+        while rejections.len() > MAX_REJECTIONS {
+            rejections.pop_min()?;
+        }
+        rejections.flush()?;
+        Ok(())
+    }
+
+    /// get_rejections returns the recently rejected blocks, newest first
+    pub fn get_rejections(&self) -> Result<Vec<BlockRejection>> {
+        let rejections = self.db.open_tree("rejections")?;
+        let mut list = Vec::new();
+        for kv in rejections.iter().rev() {
+            let (_, value) = kv?;
+            list.push(deserialize(&value)?);
+        }
+        Ok(list)
+    }
+
+    pub fn get_params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn get_tip_hash(&self) -> BlockHash {
+        self.tip
+    }
+
     pub fn get_best_height(&self) -> Result<i32> {
         let last_hash = if let Some(h) = self.db.get("LAST")? {
             h
         } else {
             return Ok(-1);
         };
-        let last_data = self.db.get(last_hash)?.unwrap();
-        let last_block: Block = deserialize(&last_data)?;
-        Ok(last_block.get_height())
+        match self.get_header(&BlockHash::from_slice(&last_hash)?)? {
+            Some(header) => Ok(header.height),
+            None => Err(format_err!("The tip block is not found")),
+        }
     }
 
-    pub fn get_block_hashes(&self) -> Vec<String> {
-        let mut list = Vec::new();
-        for b in self.iter() {
-            list.push(b.get_hash());
-        }
-        list
+    /// get_block_hashes lists the best chain blocks down to the first pruned one
+    pub fn get_block_hashes(&self) -> Vec<BlockHash> {
+        self.walk()
+            .map(|entry| entry.header.hash)
+            .take_while(|hash| self.has_block(hash).unwrap_or(false))
+            .collect()
+    }
+
+    /// walk iterates the best chain from the tip down
+    pub fn walk(&self) -> ChainWalk<'_> {
+        self.walk_from(self.tip)
     }
 
-    pub fn iter(&self) -> BlockchainIterator {
-        BlockchainIterator {
-            tip: self.tip.clone(),
+    /// walk_from iterates block `hash` and its ancestors, which need not be on the
+    /// best chain
+    pub fn walk_from(&self, hash: BlockHash) -> ChainWalk<'_> {
+        ChainWalk {
             bc: self,
+            next: hash,
+            oldest_first: None,
+            bodies: false,
         }
     }
 
     fn find_unspent_transactions(&self, address: &[u8]) -> Vec<Transaction> {
-        let mut spent_TXOs: HashMap<String, Vec<i32>> = HashMap::new();
+        let mut spent_TXOs: HashMap<TxId, Vec<i32>> = HashMap::new();
         let mut unspend_TXOs: Vec<Transaction> = Vec::new();
 
-        for block in self.iter() {
+        for block in self.walk().blocks() {
             for tx in block.get_transactions() {
                 for index in 0..tx.vout.len() {
                     if let Some(ids) = spent_TXOs.get(&tx.id)
@@ -150,7 +1618,7 @@ impl Blockchain {
                                     v.push(i.vout);
                                 }
                                 None => {
-                                    spent_TXOs.insert(i.txid.clone(), vec![i.vout]);
+                                    spent_TXOs.insert(i.txid, vec![i.vout]);
                                 }
                             }
                         }
@@ -162,10 +1630,11 @@ impl Blockchain {
         unspend_TXOs
     }
 
-    pub fn find_UTXO(&self) -> HashMap<String, TXOutputs> {
-        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
-        let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
-        for block in self.iter() {
+    /// find_UTXO returns the unspent, spendable outputs of every transaction with their vout index
+    pub fn find_UTXO(&self) -> HashMap<TxId, Vec<(i32, TXOutput)>> {
+        let mut utxos: HashMap<TxId, Vec<(i32, TXOutput)>> = HashMap::new();
+        let mut spend_txos: HashMap<TxId, Vec<i32>> = HashMap::new();
+        for block in self.walk().blocks() {
             for tx in block.get_transactions() {
                 for index in 0..tx.vout.len() {
                     if let Some(ids) = spend_txos.get(&tx.id)
@@ -173,18 +1642,16 @@ impl Blockchain {
                     {
                         continue;
                     }
+                    if tx.vout[index].is_unspendable() {
+                        continue;
+                    }
 
                     match utxos.get_mut(&tx.id) {
                         Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
+                            v.push((index as i32, tx.vout[index].clone()));
                         }
                         None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()],
-                                },
-                            );
+                            utxos.insert(tx.id, vec![(index as i32, tx.vout[index].clone())]);
                         }
                     }
                 }
@@ -196,7 +1663,7 @@ impl Blockchain {
                                 v.push(i.vout);
                             }
                             None => {
-                                spend_txos.insert(i.txid.clone(), vec![i.vout]);
+                                spend_txos.insert(i.txid, vec![i.vout]);
                             }
                         }
                     }
@@ -206,56 +1673,715 @@ impl Blockchain {
         utxos
     }
 
-    pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
-        for block in self.iter() {
+    /// find_transactions_for lists, oldest first, the transactions that pay to or spend
+    /// from `pub_key_hash` with the amounts received and sent by that key
+    pub fn find_transactions_for(&self, pub_key_hash: &[u8]) -> Vec<HistoryEntry> {
+        let mut owned: HashMap<(TxId, i32), i32> = HashMap::new();
+        let mut history = Vec::new();
+        for block in self.walk().oldest_first().blocks() {
             for tx in block.get_transactions() {
-                if tx.id == id {
+                let mut sent = 0;
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        if let Some(value) = owned.remove(&(vin.txid, vin.vout)) {
+                            sent += value;
+                        }
+                    }
+                }
+
+                let mut received = 0;
+                for (vout, out) in tx.vout.iter().enumerate() {
+                    if out.is_locked_with_key(pub_key_hash) {
+                        owned.insert((tx.id, vout as i32), out.value);
+                        received += out.value;
+                    }
+                }
+
+                if received > 0 || sent > 0 {
+                    history.push(HistoryEntry {
+                        txid: tx.id,
+                        height: block.get_height(),
+                        timestamp: block.get_timestamp(),
+                        received,
+                        sent,
+                    });
+                }
+            }
+        }
+        history
+    }
+
+    pub fn find_transaction(&self, id: &TxId) -> Result<Transaction> {
+        if let Some(tx) = self.find_indexed_transaction(id)? {
+            return Ok(tx);
+        }
+        self.find_transaction_from(&self.tip, id)
+    }
+
+    /// find_transaction_from looks for transaction `id` in block `hash` and its
+    /// ancestors, which need not be on the best chain
+    pub fn find_transaction_from(&self, hash: &BlockHash, id: &TxId) -> Result<Transaction> {
+        for block in self.walk_from(*hash).blocks() {
+            for tx in block.get_transactions() {
+                if tx.id == *id {
                     return Ok(tx.clone());
                 }
             }
         }
 
-        Err(format_err!("Transaction is not found"))
+        match self.db.open_tree("spendable")?.get(id)? {
+            Some(data) => Ok(deserialize(&data)?),
+            None => Err(format_err!("Transaction is not found")),
+        }
+    }
+
+    /// get_fee returns what the inputs of `tx` spend beyond its outputs
+    pub fn get_fee(&self, tx: &Transaction) -> Result<i32> {
+        if tx.is_coinbase() {
+            return Ok(0);
+        }
+        let prev_TXs = self.get_prev_tx_map(tx)?;
+        let mut in_value = 0;
+        for vin in &tx.vin {
+            match prev_TXs[&vin.txid].vout.get(vin.vout as usize) {
+                Some(out) => in_value += out.value,
+                None => {
+                    return Err(format_err!(
+                        "Transaction {} spends missing output {}:{}",
+                        tx.id,
+                        vin.txid,
+                        vin.vout
+                    ))
+                }
+            }
+        }
+        let out_value: i32 = tx.vout.iter().map(|out| out.value).sum();
+        Ok(in_value - out_value)
     }
 
-    pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
+    pub fn sign_transaction(&self, tx: &mut Transaction, signer: &dyn Signer) -> Result<()> {
         let prev_TXs = self.get_prev_tx_map(tx)?;
-        tx.sign(private_key, prev_TXs)?;
+        tx.sign_with(signer, prev_TXs)?;
         Ok(())
     }
 
     pub fn verify_transaction(&self, tx: &Transaction) -> Result<bool> {
+        // a coinbase spends nothing, so there are no previous transactions to look up
+        if tx.is_coinbase() {
+            return Ok(true);
+        }
         let prev_TXs = self.get_prev_tx_map(tx)?;
         tx.verify(prev_TXs)
     }
 
-    fn get_prev_tx_map(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+    /// verify_batch checks the signatures of `txs`, which may spend each other like the
+    /// transactions of a block, and returns the ids of the invalid ones
+    pub fn verify_batch(&self, txs: &[Transaction]) -> Result<Vec<TxId>> {
+        let mut prev_TXs: HashMap<TxId, Transaction> =
+            txs.iter().map(|tx| (tx.id, tx.clone())).collect();
+        for tx in txs.iter().filter(|tx| !tx.is_coinbase()) {
+            for vin in &tx.vin {
+                if !prev_TXs.contains_key(&vin.txid) {
+                    let prev_TX = self.find_transaction(&vin.txid)?;
+                    prev_TXs.insert(prev_TX.id, prev_TX);
+                }
+            }
+        }
+        Transaction::verify_batch(txs, &prev_TXs)
+    }
+
+    fn get_prev_tx_map(&self, tx: &Transaction) -> Result<HashMap<TxId, Transaction>> {
         let mut prev_TXs = HashMap::new();
         for vin in &tx.vin {
             let prev_TX = self.find_transaction(&vin.txid)?;
-            prev_TXs.insert(prev_TX.id.clone(), prev_TX);
+            prev_TXs.insert(prev_TX.id, prev_TX);
         }
         Ok(prev_TXs)
     }
 }
 
-impl<'a> Iterator for BlockchainIterator<'a> {
-    type Item = Block;
+impl<'a> ChainWalk<'a> {
+    /// with_bodies makes the walk read and decode the body of every block
+    pub fn with_bodies(mut self) -> Self {
+        self.bodies = true;
+        self
+    }
+
+    /// oldest_first turns the walk around to go from genesis up, holding only the
+    /// headers in memory
+    pub fn oldest_first(mut self) -> Self {
+        let mut headers = Vec::new();
+        while let Some(header) = self.next_header() {
+            headers.push(header);
+        }
+        headers.reverse();
+        self.oldest_first = Some(headers.into_iter());
+        self
+    }
+
+    /// blocks yields the bodies alone, skipping the blocks without one, such as pruned
+    /// blocks
+    pub fn blocks(self) -> impl Iterator<Item = Block> + 'a {
+        self.with_bodies().filter_map(|entry| entry.body)
+    }
+
+    fn next_header(&mut self) -> Option<BlockHeader> {
+        if let Some(headers) = &mut self.oldest_first {
+            return headers.next();
+        }
+        if self.next.is_zero() {
+            return None;
+        }
+        let header = self.bc.get_header(&self.next).ok()??;
+        self.next = header.prev_block_hash;
+        Some(header)
+    }
+}
+
+impl<'a> Iterator for ChainWalk<'a> {
+    type Item = ChainEntry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(encode_block) = self.bc.db.get(&self.tip) {
-            return match encode_block {
-                Some(encode_block) => {
-                    if let Ok(block) = deserialize::<Block>(&encode_block) {
-                        self.tip = block.get_prev_hash();
-                        Some(block)
-                    } else {
-                        None
-                    }
-                }
-                None => None,
-            };
+        let header = self.next_header()?;
+        let body = if self.bodies {
+            self.bc.read_block(&header.hash).ok().flatten()
+        } else {
+            None
+        };
+        Some(ChainEntry {
+            height: header.height,
+            header,
+            body,
+        })
+    }
+}
+
+/// encode_block serializes `block` behind the checksum of its bytes
+fn encode_block(block: &Block) -> Result<Vec<u8>> {
+    let data = serialize(block)?;
+    let mut encoded = checksum(&data).to_vec();
+    encoded.extend(data);
+    Ok(encoded)
+}
+
+/// decode_block deserializes a stored block body, or returns None when it does not
+/// match its checksum
+fn decode_block(encoded: &[u8]) -> Option<Block> {
+    if encoded.len() < CHECKSUM_LEN {
+        return None;
+    }
+    let (sum, data) = encoded.split_at(CHECKSUM_LEN);
+    if sum != checksum(data) {
+        return None;
+    }
+    deserialize(data).ok()
+}
+
+/// checksum is the first CHECKSUM_LEN bytes of the sha256 hash of `data`
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    let mut sum = [0; CHECKSUM_LEN];
+    sum.copy_from_slice(&hash[..CHECKSUM_LEN]);
+    sum
+}
+
+/// genesis_tag is the coinbase data of the genesis block of the network of `params`
+fn genesis_tag(params: &ChainParams) -> String {
+    format!("{} {}", GENESIS_COINBASE, hex::encode(params.magic()))
+}
+
+/// genesis_block builds the genesis block of the network of `params`, the same on every
+/// node: its coinbase carries the genesis tag and pays GENESIS_PAYEE
+pub fn genesis_block(params: &ChainParams) -> Result<Block> {
+    let coinbase = Transaction::new_coinbase(
+        hash_to_address(GENESIS_PAYEE.to_vec()),
+        genesis_tag(params),
+        0,
+    )?;
+    Block::new_genesis_block(
+        coinbase,
+        params.genesis_timestamp as u128,
+        params.initial_target(),
+        params.genesis_nonce,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{MAIN_NETWORK, REGTEST_NETWORK, TEST_NETWORK};
+
+    #[test]
+    fn test_target_after() {
+        let params = ChainParams {
+            retarget_interval: 2,
+            initial_target_hexs: 1,
+            genesis_hash: None,
+            ..ChainParams::default()
+        };
+        let mut chain = Blockchain::new_temporary(params).unwrap();
+        let address = crate::wallets::hash_to_address(vec![3; 20]);
+        let coinbase = |height: i32| {
+            Transaction::new_coinbase(address.clone(), format!("block {}", height), height).unwrap()
+        };
+        chain
+            .add_block(
+                Block::new(
+                    vec![coinbase(0)],
+                    BlockHash::default(),
+                    0,
+                    U256::from_hexs(1),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        for height in 1..6 {
+            chain.mine_block(vec![coinbase(height)]).unwrap();
+        }
+
+        // every second block closes a window and retargets from the first one's time
+        let headers: Vec<BlockHeader> = chain
+            .walk()
+            .oldest_first()
+            .map(|entry| entry.header)
+            .collect();
+        let mut target = chain.params.initial_target();
+        for header in &headers {
+            assert_eq!(header.target, target);
+            if header.height % 2 == 1 {
+                let first = &headers[header.height as usize - 1];
+                target = chain
+                    .params
+                    .retarget(target, first.timestamp, header.timestamp);
+            }
+            assert_eq!(chain.target_after(header).unwrap(), target);
+        }
+        assert!(target < chain.params.initial_target());
+        assert!(chain.network_hashrate(3).unwrap() > 0.0);
+        assert_eq!(chain.network_hashrate(0).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_height_index() {
+        let params = ChainParams {
+            initial_target_hexs: 1,
+            genesis_hash: None,
+            ..ChainParams::default()
+        };
+        let mut chain = Blockchain::new_temporary(params).unwrap();
+        let address = crate::wallets::hash_to_address(vec![3; 20]);
+        let coinbase = |height: i32, tag: &str| {
+            Transaction::new_coinbase(address.clone(), format!("{} {}", tag, height), height)
+                .unwrap()
+        };
+        chain
+            .add_block(
+                Block::new(
+                    vec![coinbase(0, "main")],
+                    BlockHash::default(),
+                    0,
+                    U256::from_hexs(1),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let one = chain.mine_block(vec![coinbase(1, "main")]).unwrap();
+        let two = chain.mine_block(vec![coinbase(2, "main")]).unwrap();
+        assert_eq!(chain.get_block_hash(2).unwrap(), Some(two.get_hash()));
+        assert_eq!(chain.get_block_hash(3).unwrap(), None);
+
+        // a longer branch from block 1 takes over heights 2 and 3
+        let fork_two = Block::new(
+            vec![coinbase(2, "fork")],
+            one.get_hash(),
+            2,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        let fork_three = Block::new(
+            vec![coinbase(3, "fork")],
+            fork_two.get_hash(),
+            3,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        chain.add_block(fork_two.clone()).unwrap();
+        assert_eq!(chain.get_block_hash(2).unwrap(), Some(two.get_hash()));
+        chain.add_block(fork_three.clone()).unwrap();
+        assert_eq!(chain.get_block_hash(1).unwrap(), Some(one.get_hash()));
+        assert_eq!(chain.get_block_hash(2).unwrap(), Some(fork_two.get_hash()));
+        assert_eq!(
+            chain.get_block_by_height(3).unwrap().unwrap().get_hash(),
+            fork_three.get_hash()
+        );
+
+        // the transaction index follows the reorg too
+        let txid = &fork_three.get_transactions()[0].id;
+        assert_eq!(
+            chain.find_indexed_transaction(txid).unwrap().unwrap().id,
+            *txid
+        );
+        assert_eq!(chain.find_transaction_height(txid).unwrap(), Some(3));
+        let stale = &two.get_transactions()[0].id;
+        assert!(chain.find_indexed_transaction(stale).unwrap().is_none());
+        assert_eq!(chain.find_transaction_height(stale).unwrap(), None);
+        assert!(chain.find_transaction(stale).is_err());
+
+        // walks follow the best chain and only read bodies when asked to
+        let heights: Vec<i32> = chain.walk().map(|entry| entry.height).collect();
+        assert_eq!(heights, vec![3, 2, 1, 0]);
+        assert!(chain.walk().all(|entry| entry.body.is_none()));
+        let oldest: Vec<BlockHash> = chain
+            .walk()
+            .oldest_first()
+            .blocks()
+            .map(|block| block.get_hash())
+            .collect();
+        assert_eq!(oldest[2], fork_two.get_hash());
+        assert_eq!(oldest.len(), 4);
+        let side: Vec<i32> = chain
+            .walk_from(two.get_hash())
+            .with_bodies()
+            .map(|entry| entry.body.unwrap().get_height())
+            .collect();
+        assert_eq!(side, vec![2, 1, 0]);
+
+        // getheaders skips a stale locator hash and serves the best chain after the next
+        let after = |locator: &[BlockHash], max| {
+            chain
+                .headers_after(locator, max)
+                .unwrap()
+                .into_iter()
+                .map(|header| header.hash)
+                .collect::<Vec<BlockHash>>()
+        };
+        assert_eq!(
+            after(&[two.get_hash(), one.get_hash()], 10),
+            vec![fork_two.get_hash(), fork_three.get_hash()]
+        );
+        assert_eq!(after(&[], 2).len(), 2);
+        assert_eq!(after(&[], 2)[1], one.get_hash());
+        assert!(after(&[fork_three.get_hash()], 10).is_empty());
+
+        // a shorter branch of 16x harder blocks has more work than the longer one
+        let work =
+            |chain: &Blockchain, block: &Block| chain.get_chainwork(&block.header()).unwrap();
+        assert_eq!(work(&chain, &fork_three), U256::from_u64(64));
+        let heavy_two = Block::new(
+            vec![coinbase(2, "heavy")],
+            one.get_hash(),
+            2,
+            U256::from_hexs(2),
+        )
+        .unwrap();
+        chain.store_block(&heavy_two).unwrap();
+        assert_eq!(work(&chain, &heavy_two), U256::from_u64(288));
+        assert_eq!(chain.get_tip_hash(), heavy_two.get_hash());
+        assert_eq!(chain.get_header_tip().unwrap().hash, heavy_two.get_hash());
+        assert_eq!(chain.get_block_hash(2).unwrap(), Some(heavy_two.get_hash()));
+        assert_eq!(chain.get_block_hash(3).unwrap(), None);
+    }
+
+    #[test]
+    fn test_genesis_blocks() {
+        for network in [MAIN_NETWORK, TEST_NETWORK, REGTEST_NETWORK] {
+            let params = ChainParams::for_network(network);
+            let genesis = genesis_block(&params).unwrap();
+            assert_eq!(Some(genesis.get_hash()), params.genesis_hash);
+            genesis
+                .header()
+                .validate(None, params.initial_target())
+                .unwrap();
+        }
+
+        // other networks find the same genesis block on every node
+        let params = ChainParams::for_network("private");
+        assert_eq!(params.genesis_hash, None);
+        let genesis = genesis_block(&params).unwrap();
+        assert_eq!(
+            genesis.get_hash(),
+            genesis_block(&params).unwrap().get_hash()
+        );
+        assert_ne!(
+            genesis.get_hash(),
+            genesis_block(&ChainParams {
+                genesis_timestamp: params.genesis_timestamp + 1,
+                ..params.clone()
+            })
+            .unwrap()
+            .get_hash()
+        );
+    }
+
+    #[test]
+    fn test_check_genesis() {
+        let params = ChainParams {
+            initial_target_hexs: 1,
+            genesis_hash: None,
+            ..ChainParams::default()
+        };
+        let mut chain = Blockchain::new_temporary(params).unwrap();
+        let address = crate::wallets::hash_to_address(vec![4; 20]);
+        let genesis = Block::new(
+            vec![Transaction::new_coinbase(address, String::from("genesis"), 0).unwrap()],
+            BlockHash::default(),
+            0,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        chain.add_block(genesis.clone()).unwrap();
+        chain.check_genesis().unwrap();
+
+        // no peer may start another chain, whatever its work
+        let address = crate::wallets::hash_to_address(vec![5; 20]);
+        let rival = Block::new(
+            vec![Transaction::new_coinbase(address, String::from("rival"), 0).unwrap()],
+            BlockHash::default(),
+            0,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        let rule = |e: failure::Error| e.downcast::<BlockRejection>().unwrap().rule;
+        assert_eq!(
+            rule(chain.add_header(rival.header()).unwrap_err()),
+            "genesis"
+        );
+        assert_eq!(rule(chain.add_block(rival).unwrap_err()), "genesis");
+        let code = |chain: &Blockchain| CodedError::from(&chain.check_genesis().unwrap_err()).code;
+
+        // the first check recorded the network
+        chain.params.network = String::from("test");
+        assert_eq!(code(&chain), ErrorCode::WrongNetwork);
+        chain.params.network = String::from("main");
+        chain.params.genesis_hash = Some(BlockHash::default());
+        assert_eq!(code(&chain), ErrorCode::WrongNetwork);
+        chain.params.genesis_hash = Some(genesis.get_hash());
+        chain.check_genesis().unwrap();
+        chain.params.initial_target_hexs = 8;
+        assert_eq!(code(&chain), ErrorCode::WrongNetwork);
+
+        // a genesis block tagged with the magic of another network
+        let mut chain = Blockchain::new_temporary(ChainParams {
+            initial_target_hexs: 1,
+            genesis_hash: None,
+            ..ChainParams::default()
+        })
+        .unwrap();
+        let address = crate::wallets::hash_to_address(vec![4; 20]);
+        let tag = genesis_tag(&ChainParams::for_network("test"));
+        let genesis = Block::new(
+            vec![Transaction::new_coinbase(address, tag, 0).unwrap()],
+            BlockHash::default(),
+            0,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        chain.add_block(genesis).unwrap();
+        assert_eq!(code(&chain), ErrorCode::WrongNetwork);
+    }
+
+    #[test]
+    fn test_block_checksums() {
+        let mut chain = Blockchain::new_temporary(ChainParams {
+            initial_target_hexs: 1,
+            genesis_hash: None,
+            ..ChainParams::default()
+        })
+        .unwrap();
+        let address = crate::wallets::hash_to_address(vec![3; 20]);
+        let coinbase = Transaction::new_coinbase(address, String::from("checksum"), 0).unwrap();
+        let genesis =
+            Block::new(vec![coinbase], BlockHash::default(), 0, U256::from_hexs(1)).unwrap();
+        let hash = genesis.get_hash();
+        chain.add_block(genesis.clone()).unwrap();
+
+        let mut data = chain.db.get(hash).unwrap().unwrap().to_vec();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        chain.db.insert(hash, data).unwrap();
+        let e = chain.get_block(&hash).unwrap_err();
+        assert!(e.downcast_ref::<BlockDamaged>().is_some());
+        assert!(!chain.has_block(&hash).unwrap());
+        assert_eq!(chain.get_damaged_blocks().unwrap(), vec![hash]);
+        assert_eq!(chain.get_best_height().unwrap(), 0);
+
+        chain.add_block(genesis.clone()).unwrap();
+        assert_eq!(chain.get_block(&hash).unwrap().get_hash(), hash);
+        assert!(chain.get_damaged_blocks().unwrap().is_empty());
+
+        chain.db.remove(hash).unwrap();
+        let e = chain.get_block(&hash).unwrap_err();
+        assert!(e.downcast_ref::<BlockDamaged>().is_some());
+        assert_eq!(chain.get_damaged_blocks().unwrap(), vec![hash]);
+        chain.add_block(genesis).unwrap();
+        assert!(chain.get_damaged_blocks().unwrap().is_empty());
+        assert!(chain.get_block(&BlockHash::digest(b"unknown")).is_err());
+        assert!(chain.get_damaged_blocks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recover_tip() {
+        let mut chain = Blockchain::new_temporary(ChainParams {
+            initial_target_hexs: 1,
+            genesis_hash: None,
+            ..ChainParams::default()
+        })
+        .unwrap();
+        let address = crate::wallets::hash_to_address(vec![4; 20]);
+        let coinbase = |height| {
+            Transaction::new_coinbase(address.clone(), format!("recover {}", height), height)
+                .unwrap()
+        };
+        let genesis = Block::new(
+            vec![coinbase(0)],
+            BlockHash::default(),
+            0,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        chain.add_block(genesis.clone()).unwrap();
+        let first = chain.mine_block(vec![coinbase(1)]).unwrap();
+        let second = chain.mine_block(vec![coinbase(2)]).unwrap();
+        assert_eq!(chain.recover_tip().unwrap(), second.get_hash());
+
+        // a garbled tip falls back to its parent, and a lost LAST to the height index
+        let mut data = chain.db.get(second.get_hash()).unwrap().unwrap().to_vec();
+        data[0] ^= 1;
+        chain.db.insert(second.get_hash(), data).unwrap();
+        chain.db.remove("LAST").unwrap();
+        assert_eq!(chain.recover_tip().unwrap(), first.get_hash());
+        assert_eq!(chain.get_best_height().unwrap(), 1);
+        assert_eq!(chain.get_block_hash(2).unwrap(), None);
+
+        chain.db.insert("LAST", &[1, 2, 3]).unwrap();
+        chain.db.remove(first.get_hash()).unwrap();
+        chain.db.remove(genesis.get_hash()).unwrap();
+        let e = chain.recover_tip().unwrap_err();
+        assert!(e.downcast_ref::<DbCorruption>().is_some());
+    }
+
+    #[test]
+    fn test_archive_indexes() {
+        let params = ChainParams {
+            initial_target_hexs: 1,
+            ..ChainParams::default()
+        };
+        let mut chain = Blockchain::new_temporary(params).unwrap();
+        assert!(chain.find_spender(&TxId::default(), 0).is_err());
+        chain.archive = true;
+
+        let pub_key = vec![5; 32];
+        let key_hash = spender_hash(&pub_key);
+        let coinbase = Transaction::new_coinbase(
+            crate::wallets::hash_to_address(key_hash.clone()),
+            String::from("archive"),
+            0,
+        )
+        .unwrap();
+        let genesis = Block::new(
+            vec![coinbase.clone()],
+            BlockHash::default(),
+            0,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        chain.store_block(&genesis).unwrap();
+        let mut spend = Transaction {
+            id: TxId::default(),
+            vin: vec![TXInput {
+                txid: coinbase.id,
+                vout: 0,
+                signature: Vec::new(),
+                pub_key,
+            }],
+            vout: vec![TXOutput {
+                value: 1,
+                pub_key_hash: vec![6; 20],
+                lock: crate::script::LockScript::PayToPubkeyHash,
+            }],
+            lock_time: 0,
+            expiry_height: 0,
+        };
+        spend.id = spend.hash().unwrap();
+        let one = Block::new(
+            vec![spend.clone()],
+            genesis.get_hash(),
+            1,
+            U256::from_hexs(1),
+        )
+        .unwrap();
+        chain.store_block(&one).unwrap();
+
+        assert_eq!(
+            chain.find_address_transactions(&key_hash).unwrap(),
+            vec![(0, coinbase.id), (1, spend.id)]
+        );
+        assert_eq!(
+            chain.find_spender(&coinbase.id, 0).unwrap(),
+            Some((spend.id, 1))
+        );
+        assert_eq!(chain.find_spender(&spend.id, 0).unwrap(), None);
+
+        let changes = chain.wallet_changes(&[key_hash.clone()], None).unwrap();
+        assert_eq!(changes.transactions.len(), 2);
+        assert!(changes.created.is_empty() && changes.spent.is_empty());
+        let changes = chain
+            .wallet_changes(&[key_hash.clone()], Some(&genesis.get_hash()))
+            .unwrap();
+        assert_eq!(changes.transactions.len(), 1);
+        assert_eq!(changes.transactions[0].1.id, spend.id);
+        assert_eq!(changes.spent, vec![(coinbase.id, 0)]);
+        let changes = chain
+            .wallet_changes(&[vec![6; 20]], Some(&BlockHash::default()))
+            .unwrap();
+        assert!(changes.rescan);
+        assert_eq!(changes.created.len(), 1);
+        assert_eq!(
+            (changes.created[0].0, changes.created[0].2.value),
+            (spend.id, 1)
+        );
+        let filter = chain.get_block_filter(&one.get_hash()).unwrap().unwrap();
+        assert!(filter.matches_any(&one.get_hash(), &[key_hash.clone()]));
+        assert!(filter.matches_any(&one.get_hash(), &[vec![6; 20]]));
+        assert!(!filter.matches_any(&one.get_hash(), &[vec![7; 20]]));
+
+        // indexes written without the archive profile are rebuilt
+        assert!(chain.archive_indexed().unwrap());
+        chain.db.open_tree("filters").unwrap().clear().unwrap();
+        assert!(!chain.archive_indexed().unwrap());
+        chain.check_archive(None).unwrap();
+        assert!(chain.archive_indexed().unwrap());
+        assert!(chain.check_archive(Some(10)).is_err());
+        assert!(chain.index_info().unwrap().compact_filters);
+
+        // the coinbase output is spent on the best chain, but not on a branch off genesis
+        let mut respend = spend.clone();
+        respend.vout[0].value = 2;
+        respend.id = respend.hash().unwrap();
+        let on_top =
+            Block::new(vec![respend.clone()], one.get_hash(), 2, U256::from_hexs(1)).unwrap();
+        let state = chain.parent_state(&on_top).unwrap();
+        assert!(state.spent.contains(&(coinbase.id, 0)));
+        let branch = Block::new(vec![respend], genesis.get_hash(), 1, U256::from_hexs(1)).unwrap();
+        let state = chain.parent_state(&branch).unwrap();
+        assert!(state.spent.is_empty());
+        assert!(state.prev_txs.contains_key(&coinbase.id));
+
+        // pruning trims the index entries of a block left by an earlier archive profile,
+        // but a transaction kept spendable stays indexed
+        chain.archive = false;
+        chain.prune_block(&genesis, &[coinbase.clone()]).unwrap();
+        chain.prune_block(&one, &[]).unwrap();
+        assert_eq!(
+            chain.find_transaction_height(&coinbase.id).unwrap(),
+            Some(0)
+        );
+        assert_eq!(chain.find_transaction_height(&spend.id).unwrap(), None);
+        for tree in ["addrindex", "spentindex", "filters"] {
+            assert!(chain.db.open_tree(tree).unwrap().is_empty(), "{}", tree);
         }
-        None
     }
 }
@@ -0,0 +1,330 @@
+use super::*;
+use crate::{
+    block::{BlockHeader, MerkleBranch},
+    blockchain::Blockchain,
+    config::{data_path, ChainParams},
+    hash::{BlockHash, TxId},
+    transaction::Transaction,
+    u256::U256,
+};
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use sled::{Db, Tree};
+
+const BRIDGE_DB: &str = "bridge";
+/// HEADER_TIP is the key of the highest header in a foreign header tree
+const HEADER_TIP: &str = "TIP";
+
+/// SpvProof is what another deployment hands over to prove one of its transactions:
+/// the transaction and the merkle branch tying it to a block header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpvProof {
+    pub block_hash: BlockHash,
+    pub branch: MerkleBranch,
+    pub transaction: Transaction,
+}
+
+impl SpvProof {
+    pub fn new(blockchain: &Blockchain, block_hash: &BlockHash, txid: &TxId) -> Result<Self> {
+        let block = blockchain.get_block(block_hash)?;
+        let branch = match block.get_merkle_proof(txid)? {
+            Some(branch) => branch,
+            None => {
+                return Err(format_err!(
+                    "Transaction {} is not in block {}",
+                    txid,
+                    block_hash
+                ))
+            }
+        };
+        let transaction = match block.get_transactions().iter().find(|tx| tx.id == *txid) {
+            Some(tx) => tx.clone(),
+            None => return Err(format_err!("Transaction {} is not found", txid)),
+        };
+        Ok(SpvProof {
+            block_hash: *block_hash,
+            branch,
+            transaction,
+        })
+    }
+}
+
+/// ForeignTransaction is a transaction of another chain proven to be in its best chain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForeignTransaction {
+    pub chain: String,
+    pub block_hash: BlockHash,
+    pub height: i32,
+    pub transaction: Transaction,
+}
+
+/// chain_headers returns the headers of the best chain, genesis first, for a bridge
+/// on another deployment to follow
+pub fn chain_headers(blockchain: &Blockchain) -> Result<Vec<BlockHeader>> {
+    let headers: Vec<BlockHeader> = blockchain
+        .walk()
+        .oldest_first()
+        .map(|entry| entry.header)
+        .collect();
+    if headers.len() as i32 != blockchain.get_best_height()? + 1 {
+        return Err(format_err!("The header chain is incomplete"));
+    }
+    Ok(headers)
+}
+
+/// Bridge follows the header chains of other deployments of this chain, each with its
+/// own ChainParams, like a light client, and indexes the foreign transactions proven
+/// against them
+pub struct Bridge {
+    db: Db,
+}
+
+impl Bridge {
+    pub fn open() -> Result<Self> {
+        Ok(Bridge {
+            db: sled::open(data_path(BRIDGE_DB))?,
+        })
+    }
+
+    fn headers(&self, chain: &str) -> Result<Tree> {
+        Ok(self.db.open_tree(format!("headers/{}", chain))?)
+    }
+
+    fn get_header(headers: &Tree, hash: &BlockHash) -> Result<Option<BlockHeader>> {
+        match headers.get(hash)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// target_after returns the target of the header following `prev`: the target of
+    /// `prev`, retargeted when `prev` closes a retarget window
+    fn target_after(headers: &Tree, params: &ChainParams, prev: &BlockHeader) -> Result<U256> {
+        let start = match params.retarget_start(prev.height) {
+            Some(start) => start,
+            None => return Ok(prev.target),
+        };
+        let mut first = prev.clone();
+        while first.height > start {
+            first = match Bridge::get_header(headers, &first.prev_block_hash)? {
+                Some(header) => header,
+                None => return Err(format_err!("Header {} is not found", first.prev_block_hash)),
+            };
+        }
+        Ok(params.retarget(prev.target, first.timestamp, prev.timestamp))
+    }
+
+    /// get_tip returns the highest known header of `chain`
+    pub fn get_tip(&self, chain: &str) -> Result<Option<BlockHeader>> {
+        let headers = self.headers(chain)?;
+        match headers.get(HEADER_TIP)? {
+            Some(hash) => Bridge::get_header(&headers, &BlockHash::from_slice(&hash)?),
+            None => Ok(None),
+        }
+    }
+
+    /// add_headers checks the proof of work, links and checkpoints of `headers` from
+    /// `chain` under its `params`, parents first, and returns how many were new
+    pub fn add_headers(
+        &self,
+        chain: &str,
+        params: &ChainParams,
+        headers: &[BlockHeader],
+    ) -> Result<usize> {
+        let tree = self.headers(chain)?;
+        let mut added = 0;
+        for header in headers {
+            if tree.contains_key(header.hash)? {
+                continue;
+            }
+            let prev = if header.prev_block_hash.is_zero() {
+                None
+            } else {
+                let prev = match Bridge::get_header(&tree, &header.prev_block_hash)? {
+                    Some(prev) => prev,
+                    None => {
+                        return Err(format_err!(
+                            "Header {} of {} follows unknown header {}",
+                            header.hash,
+                            chain,
+                            header.prev_block_hash
+                        ))
+                    }
+                };
+                Some(prev)
+            };
+            if let Some(hash) = params.checkpoints.get(&header.height)
+                && *hash != header.hash
+            {
+                return Err(header.reject("checkpoint", None, hash, header.hash));
+            }
+            let target = match &prev {
+                Some(prev) => Bridge::target_after(&tree, params, prev)?,
+                None => params.initial_target(),
+            };
+            header.validate(prev.as_ref(), target)?;
+
+            tree.insert(header.hash, serialize(header)?)?;
+            let higher = match self.get_tip(chain)? {
+                Some(tip) => header.height > tip.height,
+                None => true,
+            };
+            if higher {
+                tree.insert(HEADER_TIP, header.hash.as_ref())?;
+            }
+            added += 1;
+        }
+        tree.flush()?;
+        Ok(added)
+    }
+
+    /// verify checks that the transaction of `proof` is in a block of the best header
+    /// chain of `chain` buried under at least `min_confirmations` blocks, and records it
+    pub fn verify(
+        &self,
+        chain: &str,
+        proof: &SpvProof,
+        min_confirmations: i32,
+    ) -> Result<ForeignTransaction> {
+        let tree = self.headers(chain)?;
+        let header = match Bridge::get_header(&tree, &proof.block_hash)? {
+            Some(header) => header,
+            None => {
+                return Err(format_err!(
+                    "Block {} of {} is not known",
+                    proof.block_hash,
+                    chain
+                ))
+            }
+        };
+        let tip = self.get_tip(chain)?.unwrap_or_else(|| header.clone());
+        let mut ancestor = tip.clone();
+        while ancestor.height > header.height {
+            ancestor = match Bridge::get_header(&tree, &ancestor.prev_block_hash)? {
+                Some(ancestor) => ancestor,
+                None => {
+                    return Err(format_err!(
+                        "Header {} is not found",
+                        ancestor.prev_block_hash
+                    ))
+                }
+            };
+        }
+        if ancestor.hash != header.hash {
+            return Err(format_err!(
+                "Block {} is not on the best chain of {}",
+                header.hash,
+                chain
+            ));
+        }
+        let confirmations = tip.height - header.height + 1;
+        if confirmations < min_confirmations {
+            return Err(format_err!(
+                "Block {} has {} confirmations, {} are needed",
+                header.hash,
+                confirmations,
+                min_confirmations
+            ));
+        }
+
+        let tx = &proof.transaction;
+        if tx.clone().hash()? != tx.id {
+            return Err(format_err!("Transaction {} does not match its id", tx.id));
+        }
+        if !tx.verify_inclusion(&proof.branch, &header.merkle_root)? {
+            return Err(format_err!(
+                "Transaction {} is not in block {}",
+                tx.id,
+                header.hash
+            ));
+        }
+
+        let foreign = ForeignTransaction {
+            chain: chain.to_string(),
+            block_hash: header.hash,
+            height: header.height,
+            transaction: tx.clone(),
+        };
+        let txs = self.db.open_tree("transactions")?;
+        txs.insert(
+            format!("{}/{}", chain, tx.id).as_bytes(),
+            serialize(&foreign)?,
+        )?;
+        txs.flush()?;
+        Ok(foreign)
+    }
+
+    /// get_transaction returns a verified transaction of `chain`
+    pub fn get_transaction(&self, chain: &str, txid: &TxId) -> Result<Option<ForeignTransaction>> {
+        match self
+            .db
+            .open_tree("transactions")?
+            .get(format!("{}/{}", chain, txid).as_bytes())?
+        {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{block::Block, u256::U256};
+
+    #[test]
+    fn test_bridge() {
+        let params = ChainParams {
+            initial_target_hexs: 1,
+            genesis_hash: None,
+            ..ChainParams::default()
+        };
+        let mut foreign = Blockchain::new_temporary(params.clone()).unwrap();
+        let address = crate::wallets::hash_to_address(vec![9; 20]);
+        let coinbase = |height| {
+            Transaction::new_coinbase(address.clone(), format!("bridge {}", height), height)
+                .unwrap()
+        };
+        foreign
+            .add_block(
+                Block::new(
+                    vec![coinbase(0)],
+                    BlockHash::default(),
+                    0,
+                    U256::from_hexs(1),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let block = foreign.mine_block(vec![coinbase(1)]).unwrap();
+        foreign.mine_block(vec![coinbase(2)]).unwrap();
+        let txid = &block.get_transactions()[0].id;
+        let proof = SpvProof::new(&foreign, &block.get_hash(), txid).unwrap();
+
+        let bridge = Bridge {
+            db: sled::Config::new().temporary(true).open().unwrap(),
+        };
+        let headers = chain_headers(&foreign).unwrap();
+        // a bridge expecting more work rejects the foreign headers
+        let strict = ChainParams {
+            initial_target_hexs: 8,
+            ..params.clone()
+        };
+        assert!(bridge.add_headers("dev", &strict, &headers).is_err());
+        assert_eq!(bridge.add_headers("dev", &params, &headers).unwrap(), 3);
+        assert_eq!(bridge.add_headers("dev", &params, &headers).unwrap(), 0);
+
+        assert!(bridge.verify("dev", &proof, 3).is_err());
+        let verified = bridge.verify("dev", &proof, 2).unwrap();
+        assert_eq!(verified.height, 1);
+        let stored = bridge.get_transaction("dev", txid).unwrap().unwrap();
+        assert_eq!(stored.block_hash, verified.block_hash);
+        assert_eq!(stored.transaction.id, *txid);
+        assert!(bridge
+            .get_transaction("dev", &TxId::default())
+            .unwrap()
+            .is_none());
+        assert!(bridge.verify("other", &proof, 1).is_err());
+    }
+}
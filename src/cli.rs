@@ -1,10 +1,44 @@
 use crate::{
-    blockchain::Blockchain, error::Result, server::Server, transaction::Transaction,
-    utxoset::UTXOSet, wallets::Wallets,
+    analysis::{emission_report, AddressClusters},
+    approvals::{PendingSend, SpendLimits},
+    audit,
+    block::{Block, BlockHeader},
+    blockchain::Blockchain,
+    bridge::{chain_headers, Bridge, SpvProof},
+    config::{set_network, Config, NodeSettings, MAIN_NETWORK},
+    descriptor::Descriptor,
+    error::{CodedError, ErrorCode, Result},
+    hash::{BlockHash, TxId},
+    mempool::Mempool,
+    payout::{check_sizes, parse_payouts, split_batches, DEFAULT_MAX_TX_SIZE},
+    psbt::PartiallySignedTransaction,
+    replay::{export_chain, load_export, replay, verify_chain},
+    rpc::{RpcRequest, RpcResponse},
+    scheduler::{now_secs, parse_interval, Scheduler},
+    script::{LockScript, VaultKey, PUB_KEY_LEN, SIGNATURE_LEN},
+    server::{Server, DEFAULT_READ_TIMEOUT, KNOWN_NODE1, MAX_LONGPOLL_TIMEOUT},
+    services::Services,
+    stress::StressTest,
+    transaction::{total_value, Transaction},
+    utxoset::{CoinSelection, UTXOSet, UTXOSnapshot},
+    validation::{first_failure, trace_transaction},
+    vault::{Vault, DEFAULT_VAULT_DELAY},
+    wallets::{
+        fingerprint, hash_pub_key, hash_to_address, Conflict, KeyPolicy, Wallet, Wallets,
+        DEFAULT_GAP_LIMIT,
+    },
 };
+use bincode::{deserialize, serialize};
 use bitcoincash_addr::Address;
-use clap::{arg, Command};
-use std::process::exit;
+use clap::{arg, ArgAction, ArgMatches, Command};
+use failure::format_err;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    process::exit,
+    time::Duration,
+};
 
 pub struct Cli {}
 
@@ -17,40 +51,545 @@ impl Cli {
             .version("0.1")
             .author("Lazizjon-web-dev")
             .about("A simple CLI for interacting with a blockchain")
+            .arg(
+                arg!(--network <NETWORK> "'main, regtest or another network, each with its own data directory'")
+                    .global(true)
+                    .default_value(MAIN_NETWORK),
+            )
             .subcommand(Command::new("print").about("Print the blockchain"))
-            .subcommand(Command::new("create_wallet").about("Create a new wallet"))
-            .subcommand(Command::new("list_addresses").about("List all addresses"))
+            .subcommand(
+                Command::new("getblock")
+                    .about("Print a block by hash")
+                    .arg(arg!(<HASH>"'The block hash'"))
+                    .arg(arg!(--verbose "'print its transactions too'")),
+            )
+            .subcommand(
+                Command::new("getblockheight")
+                    .about("Print the best chain block at a height")
+                    .arg(arg!(<HEIGHT>"'The block height'"))
+                    .arg(arg!(--verbose "'print its transactions too'")),
+            )
+            .subcommand(
+                Command::new("create_wallet")
+                    .about("Create a new wallet")
+                    .arg(arg!(--mnemonic " 'derive the wallet from a mnemonic backup phrase'")),
+            )
+            .subcommand(
+                Command::new("restore_wallet")
+                    .about("Restore the wallets derived from a mnemonic phrase")
+                    .arg(arg!(<MNEMONIC> ... " 'The mnemonic words'"))
+                    .arg(arg!(--count <COUNT> " 'number of addresses to derive'")),
+            )
+            .subcommand(
+                Command::new("list_addresses")
+                    .about("List all addresses")
+                    .arg(arg!(--"with-labels" "'show labels and the labelled addresses of others'")),
+            )
+            .subcommand(
+                Command::new("label")
+                    .about("Name an address, which send then accepts in its place")
+                    .arg(arg!(<ADDRESS>"'The address to name'"))
+                    .arg(arg!(<NAME>"'The label'")),
+            )
+            .subcommand(
+                Command::new("checkwallets")
+                    .about("Verify that every stored secret key produces its address"),
+            )
+            .subcommand(
+                Command::new("getnewaddress")
+                    .about("Get an unused receive address that the key policy has not retired"),
+            )
+            .subcommand(
+                Command::new("setkeypolicy")
+                    .about("Retire receive addresses after some payments or an age")
+                    .arg(arg!(--"max-uses" <COUNT> "'payments an address may receive'"))
+                    .arg(arg!(--"max-age" <INTERVAL> "'age such as 30d after which an address retires'")),
+            )
+            .subcommand(
+                Command::new("setspendlimits")
+                    .about("Hold back sends of an address above a limit until they are approved; without limits the caps are lifted")
+                    .arg(arg!(<ADDRESS>"'the wallet address to limit'"))
+                    .arg(arg!(--"per-tx" <AMOUNT> "'most a single send may pay'"))
+                    .arg(arg!(--"per-day" <AMOUNT> "'most the address may send in 24 hours'"))
+                    .arg(
+                        arg!(--"approval-code" <CODE> "'second credential releasing held sends'")
+                            .required(true),
+                    )
+                    .arg(arg!(--"current-code" <CODE> "'approval code of the limits in force'")),
+            )
+            .subcommand(
+                Command::new("listpendingapprovals")
+                    .about("List the sends held back by spend limits"),
+            )
+            .subcommand(
+                Command::new("approve")
+                    .about("Release a send held back by spend limits")
+                    .arg(arg!(<ID>"'the pending send'"))
+                    .arg(arg!(--code <CODE> "'the approval code of the address'").required(true)),
+            )
+            .subcommand(
+                Command::new("rotatekeys")
+                    .about("Sweep the balances of retired addresses to fresh addresses")
+                    .arg(arg!(-m --mine " 'mine the sweep transactions immidiately'")),
+            )
+            .subcommand(
+                Command::new("listdescriptors")
+                    .about("List the descriptors that derive the wallet's addresses"),
+            )
+            .subcommand(
+                Command::new("importdescriptor")
+                    .about("Import a pk(), hd(), multi() or vault() descriptor into the wallet")
+                    .arg(arg!(<DESCRIPTOR>" 'The descriptor to import'"))
+                    .arg(arg!(--range <COUNT> " 'number of hd addresses to derive'"))
+                    .arg(arg!(--rescan " 'scan the chain for the imported addresses'")),
+            )
+            .subcommand(
+                Command::new("create_multisig")
+                    .about("Combine public keys into a multisig address watched by the wallet")
+                    .arg(arg!(<REQUIRED>" 'The number of signatures a spend needs'"))
+                    .arg(
+                        arg!(<PUBLIC_KEY>" 'The hex encoded ed25519 public keys'")
+                            .num_args(1..),
+                    ),
+            )
+            .subcommand(
+                Command::new("spend_multisig")
+                    .about("Build a spend from a multisig address and sign it with the local keys")
+                    .arg(arg!(<ADDRESS>" 'The multisig address'"))
+                    .arg(
+                        arg!(--to <RECIPIENT> " 'ADDRESS:AMOUNT output, may be repeated'")
+                            .action(ArgAction::Append)
+                            .required(true),
+                    )
+                    .arg(arg!(
+                        --"coin-selection" <STRATEGY> " 'largest-first, smallest-first or bnb'"
+                    ))
+                    .arg(arg!(--psbt " 'print a base64 partially signed transaction'")),
+            )
+            .subcommand(
+                Command::new("signpsbt")
+                    .about("Add the signatures of the local keys to a partially signed transaction")
+                    .arg(arg!(<PSBT>" 'The base64 partially signed transaction'")),
+            )
+            .subcommand(
+                Command::new("decodepsbt")
+                    .about("Show the inputs, outputs and signatures of a partially signed transaction")
+                    .arg(arg!(<PSBT>" 'The base64 partially signed transaction'")),
+            )
+            .subcommand(
+                Command::new("combinepsbt")
+                    .about("Merge the signatures of copies of a partially signed transaction")
+                    .arg(arg!(<PSBT>" 'The base64 partially signed transactions'").num_args(1..)),
+            )
+            .subcommand(
+                Command::new("finalizepsbt")
+                    .about("Extract the signed transaction from a complete partially signed one")
+                    .arg(arg!(<PSBT>" 'The base64 partially signed transaction'"))
+                    .arg(arg!(--send "'broadcast the transaction'")),
+            )
+            .subcommand(
+                Command::new("createrawtransaction")
+                    .about("Build an unsigned spend of a key's coins to sign offline")
+                    .arg(arg!(<PUBLIC_KEY>" 'The hex encoded ed25519 public key paying'"))
+                    .arg(
+                        arg!(--to <RECIPIENT> " 'ADDRESS:AMOUNT output, may be repeated'")
+                            .action(ArgAction::Append)
+                            .required(true),
+                    )
+                    .arg(arg!(
+                        --"coin-selection" <STRATEGY> " 'largest-first, smallest-first or bnb'"
+                    )),
+            )
+            .subcommand(
+                Command::new("signrawtransaction")
+                    .about("Sign a transaction from createrawtransaction, without a chain")
+                    .arg(arg!(<PSBT>" 'The base64 unsigned transaction'"))
+                    .arg(arg!(--"wallet-file" <PATH> " 'the wallet database to sign with'")),
+            )
+            .subcommand(
+                Command::new("sendrawtransaction")
+                    .about("Broadcast a signed transaction")
+                    .arg(arg!(<HEX>"'the serialized transaction'")),
+            )
+            .subcommand(
+                Command::new("sign_raw")
+                    .about("Add the signatures of the local keys to a hex encoded multisig spend")
+                    .arg(arg!(<HEX>"'the serialized transaction'"))
+                    .arg(arg!(--send "'broadcast it once every signature is in'")),
+            )
+            .subcommand(
+                Command::new("importsignerkey")
+                    .about("Add a key whose secret is kept by the configured remote signer")
+                    .arg(arg!(<PUBLIC_KEY>" 'The hex encoded ed25519 public key'")),
+            )
+            .subcommand(
+                Command::new("rescan")
+                    .about("Scan the chain for transactions of every address the wallet watches"),
+            )
             .subcommand(Command::new("reindex").about("Reindex the UTXO set"))
+            .subcommand(
+                Command::new("exportutxos")
+                    .about("Export the UTXO set to a snapshot file")
+                    .arg(arg!(<FILE>"'The snapshot file to write'")),
+            )
+            .subcommand(
+                Command::new("exportchain")
+                    .about("Export the blocks of the best chain to a file")
+                    .arg(arg!(<FILE>"'The export file to write'")),
+            )
+            .subcommand(
+                Command::new("replay")
+                    .about("Validate an exported chain again and report the blocks that now fail")
+                    .arg(arg!(<EXPORT_FILE>"'A file written by exportchain'"))
+                    .arg(arg!(--against <RULESET> "'config file with the chain parameters to apply'")),
+            )
+            .subcommand(
+                Command::new("verifychain")
+                    .about("Validate the stored chain from genesis and report the first bad block")
+                    .arg(arg!(--truncate "'cut the chain before the bad block, making its parent the tip'")),
+            )
+            .subcommand(
+                Command::new("utxodiff")
+                    .about("Compare two UTXO snapshots")
+                    .arg(arg!(<SNAPSHOT_A>"'The older snapshot'"))
+                    .arg(arg!(<SNAPSHOT_B>"'The newer snapshot'")),
+            )
             .subcommand(
                 Command::new("getbalance")
                     .about("Get the balance of an address")
-                    .arg(arg!(<address> "'The address to get the balance of'")),
+                    .arg(arg!(<ADDRESS> "'The address to get the balance of'"))
+                    .arg(
+                        arg!(--"min-conf" <N> "'confirmations an output needs to count'")
+                            .default_value("1"),
+                    ),
+            )
+            .subcommand(
+                Command::new("gettransaction")
+                    .about("Print a chain transaction with its block and confirmations")
+                    .arg(arg!(<TXID>"'The transaction id'")),
+            )
+            .subcommand(
+                Command::new("getbalances").about("Get the balance of every wallet address"),
+            )
+            .subcommand(
+                Command::new("history")
+                    .about("List the transactions paying to or spending from an address")
+                    .arg(arg!(<ADDRESS>"'The address to list'")),
+            )
+            .subcommand(
+                Command::new("getcluster")
+                    .about("List the addresses inferred to share an owner with an address")
+                    .arg(arg!(<ADDRESS>"'The address to cluster'")),
+            )
+            .subcommand(
+                Command::new("getemission")
+                    .about("Compare the subsidy schedule with the coins issued per halving era"),
+            )
+            .subcommand(
+                Command::new("getmempoolfeehistogram")
+                    .about("Show pending transaction sizes by fee rate of a running node")
+                    .arg(arg!(--node <NODE> "'node to query'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("getblocktemplate")
+                    .about("Show the transactions a running node would mine next")
+                    .arg(arg!(--node <NODE> "'node to query'").default_value(KNOWN_NODE1))
+                    .arg(arg!(--longpoll <ID> "'wait until the template with this long-poll id is stale'"))
+                    .arg(
+                        arg!(--timeout <SECONDS> "'how long to wait with --longpoll'")
+                            .default_value("60"),
+                    ),
+            )
+            .subcommand(
+                Command::new("getmininginfo")
+                    .about("Show the difficulty, the network hashrate and the local miner's work")
+                    .arg(arg!(--node <NODE> "'node to query'").default_value(KNOWN_NODE1))
+                    .arg(
+                        arg!(--blocks <COUNT> "'recent blocks to estimate the network hashrate from'")
+                            .default_value("20"),
+                    ),
+            )
+            .subcommand(
+                Command::new("submitblock")
+                    .about("Hand a running node a block mined from its block template")
+                    .arg(arg!(<HEX>"'The hex encoded block'"))
+                    .arg(arg!(--node <NODE> "'node to submit to'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("getrejectedblocks")
+                    .about("Show why a running node rejected recent blocks")
+                    .arg(arg!(--node <NODE> "'node to query'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("getindexinfo")
+                    .about("Show which historical queries a running node can answer")
+                    .arg(arg!(--node <NODE> "'node to query'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("getpeerinfo")
+                    .about("Show the services of a running node and of its peers")
+                    .arg(arg!(--node <NODE> "'node to query'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("findblocks")
+                    .about("Find the blocks that may concern an address through the compact filters of a peer serving them")
+                    .arg(arg!(<ADDRESS>"'the address to look for'"))
+                    .arg(arg!(--from <HEIGHT> "'first block height to scan'").default_value("0"))
+                    .arg(arg!(--node <NODE> "'node to start from'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("syncwallet")
+                    .about("Fetch what changed for addresses since a block from an archive node, as a light wallet catching up")
+                    .arg(arg!(<ADDRESS>"'the addresses of the wallet'").num_args(1..))
+                    .arg(arg!(--since <HASH> "'the last block the wallet synced to'"))
+                    .arg(arg!(--node <NODE> "'archive node to ask'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("getblockfilter")
+                    .about("Print the compact filter of a block kept by an archive chain")
+                    .arg(arg!(<HASH>"'the block hash'"))
+                    .arg(
+                        arg!(--address <ADDRESS> "'tell whether the block may concern this address'")
+                            .action(ArgAction::Append),
+                    ),
+            )
+            .subcommand(
+                Command::new("getauditlog")
+                    .about("Print the audit log of wallet, send, ban and admin actions")
+                    .arg(arg!(--action <ACTION> "'only print entries of this action'")),
+            )
+            .subcommand(
+                Command::new("reloadconfig")
+                    .about("Make a running node reload its settings from the config file")
+                    .arg(arg!(--node <NODE> "'node to reload'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("setban")
+                    .about("Ban or unban an address or CIDR range on a running node")
+                    .arg(arg!(<RANGE>"'address or range such as 10.0.0.0/8'"))
+                    .arg(
+                        arg!(<COMMAND>"'add or remove'")
+                            .value_parser(["add", "remove"]),
+                    )
+                    .arg(arg!([SECONDS]"'ban duration, forever when left out'"))
+                    .arg(arg!(--node <NODE> "'node to ban on'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("listbanned")
+                    .about("List the address ranges a running node has banned")
+                    .arg(arg!(--node <NODE> "'node to query'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("getpeerevents")
+                    .about("Show the peers a running node connected, lost and banned")
+                    .arg(arg!(--node <NODE> "'node to query'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("getdoublespends")
+                    .about("Show the pending transactions a running node saw double spent")
+                    .arg(arg!(--node <NODE> "'node to query'").default_value(KNOWN_NODE1)),
+            )
+            .subcommand(
+                Command::new("listconflicts")
+                    .about("List the wallet transactions double spent by others"),
+            )
+            .subcommand(
+                Command::new("getmerkleproof")
+                    .about("Print the merkle branch proving a transaction is in a block")
+                    .arg(arg!(<BLOCK>"'The hash of the block'"))
+                    .arg(arg!(<TXID>"'The transaction id'")),
+            )
+            .subcommand(
+                Command::new("exportheaders")
+                    .about("Export the block headers of the best chain for a bridge to follow")
+                    .arg(arg!(<FILE>"'The header file to write'")),
+            )
+            .subcommand(
+                Command::new("exportspvproof")
+                    .about("Export a transaction with the merkle branch proving it is in a block")
+                    .arg(arg!(<BLOCK>"'The hash of the block'"))
+                    .arg(arg!(<TXID>"'The transaction id'"))
+                    .arg(arg!(<FILE>"'The proof file to write'")),
+            )
+            .subcommand(
+                Command::new("bridgeheaders")
+                    .about("Check and store the headers exported by another deployment")
+                    .arg(arg!(<CHAIN>"'A local name for the other deployment'"))
+                    .arg(arg!(<FILE>"'A file written by exportheaders'"))
+                    .arg(
+                        arg!(--params <CONFIG> "'config file with the chain parameters of the other deployment'")
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                Command::new("bridgeverify")
+                    .about("Verify and record a transaction of another deployment")
+                    .arg(arg!(<CHAIN>"'The name the headers were stored under'"))
+                    .arg(arg!(<PROOF_FILE>"'A file written by exportspvproof'"))
+                    .arg(arg!(--"min-conf" <N> "'confirmations the block needs'").default_value("6")),
+            )
+            .subcommand(
+                Command::new("getforeigntx")
+                    .about("Print a verified transaction of another deployment")
+                    .arg(arg!(<CHAIN>"'The name of the other deployment'"))
+                    .arg(arg!(<TXID>"'The transaction id'")),
+            )
+            .subcommand(
+                Command::new("stress")
+                    .about("Pay between the local wallets at a fixed rate and mine on the local chain")
+                    .arg(arg!(--tps <N> "'transactions per second'").default_value("10"))
+                    .arg(arg!(--duration <SECONDS> "'length of the run'").default_value("60"))
+                    .arg(arg!(--"block-interval" <SECONDS> "'time between blocks, the chain target by default'")),
+            )
+            .subcommand(
+                Command::new("validatetx")
+                    .about("Check a hex encoded transaction against the local chain")
+                    .arg(arg!(<HEX>"'the serialized transaction'"))
+                    .arg(arg!(--trace "'report every rule, not just the first failure'")),
             )
             .subcommand(
                 Command::new("startnode")
                     .about("Start the node server")
-                    .arg(arg!(<PORT>"'the port server bind to locally'")),
+                    .arg(arg!(<PORT>"'the port server bind to locally'"))
+                    .arg(arg!(--seed <NODE> "'bootstrap node address'").action(ArgAction::Append))
+                    .arg(arg!(--"dns-seed" <HOST> "'host name resolving to bootstrap nodes'").action(ArgAction::Append))
+                    .arg(arg!(--bind <ADDR> "'host:port to listen on'"))
+                    .arg(arg!(--"external-address" <ADDR> "'host:port other machines reach the node at'"))
+                    .arg(arg!(--"read-timeout" <SECONDS> "'deadline for receiving a message'"))
+                    .arg(arg!(--"write-timeout" <SECONDS> "'deadline for sending a message'"))
+                    .arg(arg!(--rbf "'let higher-fee transactions replace conflicting ones'"))
+                    .arg(arg!(--prune <BLOCKS> "'keep only the most recent block bodies'"))
+                    .arg(arg!(--archive "'keep every block and the address, spent and filter indexes'"))
+                    .arg(arg!(--"blocks-only" "'ask peers not to relay unconfirmed transactions'"))
+                    .arg(arg!(--explorer "'disable the wallet and serve the chain read-only'"))
+                    .arg(arg!(--paranoid "'check every invariant of every peer message'"))
+                    .arg(arg!(--api <ADDR> "'serve the REST API on this address'")),
+            )
+            .subcommand(
+                Command::new("generate")
+                    .about("Mine blocks holding only their coinbase, instantly on regtest")
+                    .arg(arg!(<COUNT>"'how many blocks to mine'"))
+                    .arg(arg!(<ADDRESS>"'the address the block rewards go to'")),
             )
             .subcommand(
                 Command::new("create")
-                    .about("Create a new blockchain")
-                    .arg(arg!(<ADDRESS>"'The address to send genesis block reward to' ")),
+                    .about("Create a new blockchain from the genesis block of the network"),
             )
             .subcommand(
                 Command::new("send")
                     .about("send coins in the blockchain")
+                    .arg(arg!(<FROM>" 'Source wallet address or label'"))
+                    .arg(arg!([TO]" 'Destination wallet address or label'"))
+                    .arg(arg!([AMOUNT]" 'Amount to send'"))
+                    .arg(
+                        arg!(--to <RECIPIENT> " 'ADDRESS:AMOUNT output, may be repeated; the address may be a label'")
+                            .action(ArgAction::Append),
+                    )
+                    .arg(arg!(
+                        --"coin-selection" <STRATEGY> " 'largest-first, smallest-first or bnb'"
+                    ))
+                    .arg(arg!(
+                        --"lock-height" <HEIGHT> " 'recipients can spend the coins from this block height on'"
+                    ))
+                    .arg(arg!(
+                        --locktime <LOCKTIME> " 'keep the transaction out of blocks below this height, or before this unix time from 500000000 on'"
+                    ))
+                    .arg(arg!(
+                        --"expiry-height" <HEIGHT> " 'keep the transaction out of blocks from this height on'"
+                    ))
+                    .arg(arg!(-m --mine " 'the from address mine immidiately'")),
+            )
+            .subcommand(
+                Command::new("burn")
+                    .about("Destroy coins by sending them to a provably unspendable output")
                     .arg(arg!(<FROM>" 'Source wallet address'"))
-                    .arg(arg!(<TO>" 'Destination wallet address'"))
-                    .arg(arg!(<AMOUNT>" 'Amount to send'"))
+                    .arg(arg!(<AMOUNT>" 'Amount to burn'"))
                     .arg(arg!(-m --mine " 'the from address mine immidiately'")),
             )
+            .subcommand(
+                Command::new("payout")
+                    .about("Pay every address,amount row of a CSV file in batched transactions")
+                    .arg(arg!(<FROM>" 'Source wallet address or label'"))
+                    .arg(arg!(<FILE>" 'CSV file of address,amount rows'"))
+                    .arg(arg!(
+                        --"max-tx-size" <BYTES> " 'largest payout transaction, 100000 bytes by default'"
+                    ))
+                    .arg(arg!(
+                        --"coin-selection" <STRATEGY> " 'largest-first, smallest-first or bnb'"
+                    ))
+                    .arg(arg!(-y --yes " 'broadcast without asking for confirmation'")),
+            )
+            .subcommand(
+                Command::new("createvault")
+                    .about("Create a vault spent by a hot key through unvaults a recovery key can claw back")
+                    .arg(arg!(<HOT_ADDRESS>" 'Wallet address whose key starts unvaults'"))
+                    .arg(arg!(<RECOVERY_KEY>" 'Hex encoded public key of the cold recovery wallet'"))
+                    .arg(arg!(--delay <BLOCKS> " 'blocks an unvault waits, 144 by default'")),
+            )
+            .subcommand(
+                Command::new("getvault")
+                    .about("Print the vaulted funds of a vault and its pending unvaults")
+                    .arg(arg!(<ADDRESS>" 'The vault address'")),
+            )
+            .subcommand(
+                Command::new("unvault")
+                    .about("Start moving funds out of a vault with its hot key")
+                    .arg(arg!(<ADDRESS>" 'The vault address'"))
+                    .arg(arg!(<AMOUNT>" 'Amount to unvault'")),
+            )
+            .subcommand(
+                Command::new("withdrawvault")
+                    .about("Pay the unvaulted funds of a vault whose delay is over")
+                    .arg(arg!(<ADDRESS>" 'The vault address'"))
+                    .arg(arg!(<TO>" 'Destination address'")),
+            )
+            .subcommand(
+                Command::new("clawback")
+                    .about("Move every output of a vault, unvaulting ones included, with its recovery key")
+                    .arg(arg!(<ADDRESS>" 'The vault address'"))
+                    .arg(arg!(<TO>" 'Destination address'")),
+            )
             .subcommand(
                 Command::new("startminer")
                     .about("Start the miner server")
                     .arg(arg!(<PORT>"'the port server bind to locally'"))
-                    .arg(arg!(<ADDRESS>"'wallet address'")),
+                    .arg(arg!(<ADDRESS>"'wallet address'"))
+                    .arg(arg!(--"max-block-txs" <COUNT> "'most transactions per block'"))
+                    .arg(arg!(--"min-fee-rate" <RATE> "'lowest fee per 1000 bytes worth mining'"))
+                    .arg(arg!(--"mining-threads" <COUNT> "'threads searching the proof of work'"))
+                    .arg(arg!(--seed <NODE> "'bootstrap node address'").action(ArgAction::Append))
+                    .arg(arg!(--"dns-seed" <HOST> "'host name resolving to bootstrap nodes'").action(ArgAction::Append))
+                    .arg(arg!(--bind <ADDR> "'host:port to listen on'"))
+                    .arg(arg!(--"external-address" <ADDR> "'host:port other machines reach the node at'"))
+                    .arg(arg!(--"read-timeout" <SECONDS> "'deadline for receiving a message'"))
+                    .arg(arg!(--"write-timeout" <SECONDS> "'deadline for sending a message'"))
+                    .arg(arg!(--rbf "'let higher-fee transactions replace conflicting ones'"))
+                    .arg(arg!(--prune <BLOCKS> "'keep only the most recent block bodies'"))
+                    .arg(arg!(--archive "'keep every block and the address, spent and filter indexes'"))
+                    .arg(arg!(--"blocks-only" "'ask peers not to relay unconfirmed transactions'"))
+                    .arg(arg!(--explorer "'disable the wallet and serve the chain read-only'"))
+                    .arg(arg!(--paranoid "'check every invariant of every peer message'"))
+                    .arg(arg!(--api <ADDR> "'serve the REST API on this address'")),
+            )
+            .subcommand(
+                Command::new("schedulepayment")
+                    .about("Schedule a recurring payment executed by the running node")
+                    .arg(arg!(<FROM>" 'Source wallet address'"))
+                    .arg(arg!(<TO>" 'Destination wallet address'"))
+                    .arg(arg!(<AMOUNT>" 'Amount to send'"))
+                    .arg(
+                        arg!(--every <INTERVAL> " 'interval such as 30s, 15m, 2h or 1d'")
+                            .required(true),
+                    ),
+            )
+            .subcommand(Command::new("listschedules").about("List scheduled payments"))
+            .subcommand(
+                Command::new("cancelschedule")
+                    .about("Cancel a scheduled payment")
+                    .arg(arg!(<ID>" 'Scheduled payment id'")),
             )
             .get_matches();
+        set_network(matches.get_one::<String>("network").unwrap())?;
 
         if let Some(ref matches) = matches.subcommand_matches("startminer") {
             let port = if let Some(port) = matches.get_one::<String>("PORT") {
@@ -67,149 +606,2281 @@ impl Cli {
                 exit(1)
             };
 
-            let blockchain = Blockchain::new()?;
+            let mut settings = node_settings(matches)?;
+            if settings.explorer {
+                return Err(CodedError::new(
+                    ErrorCode::InvalidArgument,
+                    "An explorer node does not mine",
+                )
+                .into());
+            }
+            let blockchain = open_node_chain(&settings)?;
             let utxo_set = UTXOSet { blockchain };
-            let server = Server::new(port, address, utxo_set)?;
+            let mut server = Server::new(port, address, utxo_set)?;
+            if let Some(addr) = settings.external_address.clone() {
+                server.set_external_address(addr);
+            }
+            if let Some(count) = matches.get_one::<String>("max-block-txs") {
+                settings.max_block_txs = count.parse()?;
+            }
+            if let Some(rate) = matches.get_one::<String>("min-fee-rate") {
+                settings.min_fee_rate = rate.parse()?;
+            }
+            if let Some(threads) = matches.get_one::<String>("mining-threads") {
+                settings.mining_threads = threads.parse()?;
+            }
+            server.apply_settings(settings)?;
+            if let Some(addr) = matches.get_one::<String>("api") {
+                server.start_api(addr)?;
+            }
             server.start()?;
         }
 
-        if matches.subcommand_matches("create_wallet").is_some() {
-            println!("address: {}", cmd_create_wallet()?);
+        if let Some(ref matches) = matches.subcommand_matches("create_wallet") {
+            println!(
+                "address: {}",
+                cmd_create_wallet(matches.get_flag("mnemonic"))?
+            );
         }
 
-        if matches.subcommand_matches("list_addresses").is_some() {
-            cmd_list_addresses()?;
+        if let Some(ref matches) = matches.subcommand_matches("restore_wallet") {
+            let words: Vec<&str> = matches
+                .get_many::<String>("MNEMONIC")
+                .unwrap()
+                .map(|w| w.as_str())
+                .collect();
+            let count = match matches.get_one::<String>("count") {
+                Some(count) => count.parse()?,
+                None => DEFAULT_GAP_LIMIT,
+            };
+            cmd_restore_wallet(&words.join(" "), count)?;
         }
 
-        if matches.subcommand_matches("reindex").is_some() {
-            let count = cmd_reindex()?;
-            println!("Done! There are {count} transactions in the UTXO set");
+        if let Some(ref matches) = matches.subcommand_matches("list_addresses") {
+            cmd_list_addresses(matches.get_flag("with-labels"))?;
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("create") {
-            if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                cmd_create_blockchain(address)?;
-            }
+        if let Some(ref matches) = matches.subcommand_matches("label") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let name = matches.get_one::<String>("NAME").unwrap();
+            decode_address(address)?;
+            let mut wallets = Wallets::new()?;
+            wallets.set_label(address, name)?;
+            wallets.save_all()?;
+            println!("{address} is labelled {name}");
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
-            if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let balance = cmd_get_balance(address)?;
-                println!("Balance: {balance}\n");
-            }
+        if matches.subcommand_matches("checkwallets").is_some() && !cmd_check_wallets()? {
+            exit(1)
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("startnode") {
-            if let Some(port) = matches.get_one::<String>("PORT") {
-                let blockchain = Blockchain::new()?;
-                let utxo_set = UTXOSet { blockchain };
-                let server = Server::new(port, "", utxo_set)?;
-                server.start()?;
-            }
+        if matches.subcommand_matches("getnewaddress").is_some() {
+            println!("address: {}", cmd_get_new_address()?);
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("send") {
-            let from = if let Some(address) = matches.get_one::<String>("FROM") {
-                address
-            } else {
-                println!("from not supply!: usage");
-                exit(1)
+        if let Some(ref matches) = matches.subcommand_matches("setkeypolicy") {
+            let policy = KeyPolicy {
+                max_uses: match matches.get_one::<String>("max-uses") {
+                    Some(uses) => Some(uses.parse()?),
+                    None => None,
+                },
+                max_age: match matches.get_one::<String>("max-age") {
+                    Some(age) => Some(parse_interval(age)?),
+                    None => None,
+                },
             };
+            let mut wallets = Wallets::new()?;
+            wallets.set_policy(policy);
+            wallets.save_all()?;
+            audit::record("cli", "key-policy", &format!("{:?}", policy))?;
+            println!("key policy: {:?}", policy);
+        }
 
-            let to = if let Some(address) = matches.get_one::<String>("TO") {
-                address
-            } else {
-                println!("to not supply!: usage");
-                exit(1)
+        if let Some(ref matches) = matches.subcommand_matches("setspendlimits") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let per_tx = match matches.get_one::<String>("per-tx") {
+                Some(amount) => Some(amount.parse()?),
+                None => None,
             };
-
-            let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
-                amount.parse()?
+            let per_day = match matches.get_one::<String>("per-day") {
+                Some(amount) => Some(amount.parse()?),
+                None => None,
+            };
+            let limits = if per_tx.is_some() || per_day.is_some() {
+                let code = matches.get_one::<String>("approval-code").unwrap();
+                Some(SpendLimits::new(per_tx, per_day, code))
             } else {
-                println!("amount not supply!: usage");
-                exit(1)
+                None
             };
+            let current_code = match matches.get_one::<String>("current-code") {
+                Some(code) => code,
+                None => matches.get_one::<String>("approval-code").unwrap(),
+            };
+            let mut wallets = Wallets::new()?;
+            let address = wallets.resolve(address);
+            wallets.set_spend_limits(&address, limits, current_code)?;
+            wallets.save_all()?;
+            let details = format!("{}: per send {:?}, per day {:?}", address, per_tx, per_day);
+            audit::record("cli", "spend-limits", &details)?;
+            println!("spend limits of {}", details);
+        }
 
-            cmd_send(from, to, amount, matches.contains_id("mine"))?;
+        if matches.subcommand_matches("listpendingapprovals").is_some() {
+            for pending in Wallets::new()?.get_pending_sends() {
+                println!(
+                    "{} at {} from {} amount {}: {}",
+                    pending.id,
+                    pending.created,
+                    pending.from,
+                    pending.amount(),
+                    pending.reason
+                );
+                for (to, amount) in &pending.recipients {
+                    println!("  {} {}", to, amount);
+                }
+            }
         }
 
-        if matches.subcommand_matches("print").is_some() {
-            cmd_print_chain()?;
+        if let Some(ref matches) = matches.subcommand_matches("approve") {
+            cmd_approve(
+                matches.get_one::<String>("ID").unwrap().parse()?,
+                matches.get_one::<String>("code").unwrap(),
+            )?;
         }
 
-        Ok(())
-    }
-}
+        if let Some(ref matches) = matches.subcommand_matches("rotatekeys") {
+            cmd_rotate_keys(matches.get_flag("mine"))?;
+        }
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
-    let blockchain = Blockchain::new()?;
-    let mut utxo_set = UTXOSet { blockchain };
-    let wallets = Wallets::new()?;
-    let wallet = wallets.get_wallet(from).unwrap();
-    let transaction = Transaction::new_UTXO(wallet, to, amount, &utxo_set)?;
-    if mine_now {
-        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("Reward"))?;
-        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, transaction])?;
-        utxo_set.update(&new_block)?;
-    } else {
-        Server::send_transaction(&transaction, utxo_set)?;
-    }
+        if matches.subcommand_matches("listdescriptors").is_some() {
+            cmd_list_descriptors()?;
+        }
 
-    println!("Success! Transaction sent");
-    Ok(())
-}
+        if let Some(ref matches) = matches.subcommand_matches("importsignerkey") {
+            let public_key = hex::decode(matches.get_one::<String>("PUBLIC_KEY").unwrap())?;
+            let mut wallets = Wallets::new()?;
+            let address = wallets.import_remote_key(public_key)?;
+            wallets.save_all()?;
+            audit::record("cli", "key-imported", &format!("signer key {address}"))?;
+            println!("address: {address}");
+        }
 
-fn cmd_create_wallet() -> Result<String> {
-    let mut wallets = Wallets::new()?;
-    let address = wallets.create_wallet();
-    wallets.save_all()?;
-    Ok(address)
-}
+        if let Some(ref matches) = matches.subcommand_matches("importdescriptor") {
+            let descriptor = matches.get_one::<String>("DESCRIPTOR").unwrap();
+            let range = match matches.get_one::<String>("range") {
+                Some(range) => range.parse()?,
+                None => DEFAULT_GAP_LIMIT,
+            };
+            let addresses = cmd_import_descriptor(descriptor, range)?;
+            if matches.get_flag("rescan") {
+                cmd_rescan(&addresses)?;
+            }
+        }
 
-fn cmd_reindex() -> Result<i32> {
-    let blockchain = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain };
-    utxo_set.reindex()?;
-    utxo_set.count_transactions()
-}
+        if let Some(ref matches) = matches.subcommand_matches("create_multisig") {
+            let required = matches.get_one::<String>("REQUIRED").unwrap();
+            let keys: Vec<&String> = matches.get_many::<String>("PUBLIC_KEY").unwrap().collect();
+            let descriptor = format!(
+                "multi({},{})",
+                required,
+                keys.iter()
+                    .map(|key| key.as_str())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+            cmd_import_descriptor(&descriptor, 1)?;
+            println!("descriptor: {descriptor}");
+        }
 
-fn cmd_list_addresses() -> Result<()> {
-    let wallets = Wallets::new()?;
-    let addresses = wallets.get_all_addresses();
-    println!("addresses: ");
-    for address in addresses {
-        println!("{address}");
-    }
-    Ok(())
-}
+        if let Some(ref matches) = matches.subcommand_matches("spend_multisig") {
+            let mut recipients = Vec::new();
+            for output in matches.get_many::<String>("to").unwrap() {
+                recipients.push(parse_recipient(output)?);
+            }
+            let selection = match matches.get_one::<String>("coin-selection") {
+                Some(selection) => selection.parse()?,
+                None => CoinSelection::default(),
+            };
+            cmd_spend_multisig(
+                matches.get_one::<String>("ADDRESS").unwrap(),
+                &recipients,
+                selection,
+                matches.get_flag("psbt"),
+            )?;
+        }
 
-fn cmd_create_blockchain(address: &str) -> Result<()> {
-    let address = String::from(address);
-    let blockchain = Blockchain::create_blockchain(address)?;
+        if let Some(ref matches) = matches.subcommand_matches("createrawtransaction") {
+            let mut recipients = Vec::new();
+            for output in matches.get_many::<String>("to").unwrap() {
+                recipients.push(parse_recipient(output)?);
+            }
+            let selection = match matches.get_one::<String>("coin-selection") {
+                Some(selection) => selection.parse()?,
+                None => CoinSelection::default(),
+            };
+            let pub_key = hex::decode(matches.get_one::<String>("PUBLIC_KEY").unwrap())?;
+            let utxo_set = UTXOSet {
+                blockchain: Blockchain::new()?,
+            };
+            let tx = Transaction::new_unsigned(&pub_key, &recipients, selection, &utxo_set)?;
+            println!("{}", new_psbt(tx, &utxo_set)?.to_base64()?);
+        }
 
-    let utxo_set = UTXOSet { blockchain };
-    utxo_set.reindex()?;
-    println!("create blockchain");
-    Ok(())
-}
+        if let Some(ref matches) = matches.subcommand_matches("signrawtransaction") {
+            let mut psbt = PartiallySignedTransaction::from_base64(
+                matches.get_one::<String>("PSBT").unwrap(),
+            )?;
+            let wallets = match matches.get_one::<String>("wallet-file") {
+                Some(path) => Wallets::open(path)?,
+                None => Wallets::new()?,
+            };
+            sign_psbt(&mut psbt, &wallets)?;
+            match psbt.missing_signatures() {
+                0 => println!("{}", hex::encode(serialize(&psbt.finalize()?)?)),
+                missing => {
+                    println!("{}", psbt.to_base64()?);
+                    println!("missing signatures: {missing}");
+                }
+            }
+        }
 
-fn cmd_get_balance(address: &str) -> Result<i32> {
-    let pub_key_hash = Address::decode(address).unwrap().body;
-    let blockchain = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain };
-    let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
+        if let Some(ref matches) = matches.subcommand_matches("sendrawtransaction") {
+            let hex_tx = matches.get_one::<String>("HEX").unwrap();
+            let tx: Transaction = deserialize(&hex::decode(hex_tx.trim())?)?;
+            Server::send_transaction(&tx)?;
+            println!("Success! Transaction {} sent", tx.id);
+        }
 
-    let mut balance = 0;
-    for output in utxos.outputs {
-        balance += output.value;
-    }
-    Ok(balance)
-}
+        if let Some(ref matches) = matches.subcommand_matches("signpsbt") {
+            let mut psbt = PartiallySignedTransaction::from_base64(
+                matches.get_one::<String>("PSBT").unwrap(),
+            )?;
+            sign_psbt(&mut psbt, &Wallets::new()?)?;
+            println!("{}", psbt.to_base64()?);
+            println!("missing signatures: {}", psbt.missing_signatures());
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("decodepsbt") {
+            cmd_decode_psbt(matches.get_one::<String>("PSBT").unwrap())?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("combinepsbt") {
+            let mut psbts = matches.get_many::<String>("PSBT").unwrap();
+            let mut psbt = PartiallySignedTransaction::from_base64(psbts.next().unwrap())?;
+            for other in psbts {
+                psbt.combine(&PartiallySignedTransaction::from_base64(other)?)?;
+            }
+            println!("{}", psbt.to_base64()?);
+            println!("missing signatures: {}", psbt.missing_signatures());
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("finalizepsbt") {
+            let psbt = PartiallySignedTransaction::from_base64(
+                matches.get_one::<String>("PSBT").unwrap(),
+            )?;
+            let tx = psbt.finalize()?;
+            println!("{}", hex::encode(serialize(&tx)?));
+            if matches.get_flag("send") {
+                Server::send_transaction(&tx)?;
+                println!("Success! Transaction sent");
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("sign_raw") {
+            cmd_sign_raw(
+                matches.get_one::<String>("HEX").unwrap(),
+                matches.get_flag("send"),
+            )?;
+        }
+
+        if matches.subcommand_matches("rescan").is_some() {
+            cmd_rescan(&Wallets::new()?.watched_addresses()?)?;
+        }
+
+        if matches.subcommand_matches("reindex").is_some() {
+            let count = cmd_reindex()?;
+            println!("Done! There are {count} transactions in the UTXO set");
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("exportutxos") {
+            cmd_export_utxos(matches.get_one::<String>("FILE").unwrap())?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("exportchain") {
+            let path = matches.get_one::<String>("FILE").unwrap();
+            let count = export_chain(&Blockchain::new()?, path)?;
+            println!("Exported {count} blocks to {path}");
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("replay") {
+            let path = matches.get_one::<String>("EXPORT_FILE").unwrap();
+            let ruleset = matches.get_one::<String>("against").map(|s| s.as_str());
+            if !cmd_replay(path, ruleset)? {
+                exit(1)
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("verifychain") {
+            if !cmd_verify_chain(matches.get_flag("truncate"))? {
+                exit(1)
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("utxodiff") {
+            cmd_utxo_diff(
+                matches.get_one::<String>("SNAPSHOT_A").unwrap(),
+                matches.get_one::<String>("SNAPSHOT_B").unwrap(),
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("generate") {
+            cmd_generate(
+                matches.get_one::<String>("COUNT").unwrap().parse()?,
+                matches.get_one::<String>("ADDRESS").unwrap(),
+            )?;
+        }
+
+        if matches.subcommand_matches("create").is_some() {
+            cmd_create_blockchain()?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let min_conf = matches.get_one::<String>("min-conf").unwrap().parse()?;
+                let balance = cmd_get_balance(address, min_conf)?;
+                println!("Balance: {balance}\n");
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("gettransaction") {
+            cmd_get_transaction(&matches.get_one::<String>("TXID").unwrap().parse()?)?;
+        }
+
+        if matches.subcommand_matches("getbalances").is_some() {
+            cmd_get_balances()?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("history") {
+            cmd_history(matches.get_one::<String>("ADDRESS").unwrap())?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getcluster") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                cmd_get_cluster(address)?;
+            }
+        }
+
+        if matches.subcommand_matches("getemission").is_some() {
+            cmd_get_emission()?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getmempoolfeehistogram") {
+            cmd_get_mempool_fee_histogram(matches.get_one::<String>("node").unwrap())?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getblocktemplate") {
+            cmd_get_block_template(
+                matches.get_one::<String>("node").unwrap(),
+                matches.get_one::<String>("longpoll"),
+                matches.get_one::<String>("timeout").unwrap().parse()?,
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getmininginfo") {
+            cmd_get_mining_info(
+                matches.get_one::<String>("node").unwrap(),
+                matches.get_one::<String>("blocks").unwrap().parse()?,
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("submitblock") {
+            cmd_submit_block(
+                matches.get_one::<String>("node").unwrap(),
+                matches.get_one::<String>("HEX").unwrap(),
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getrejectedblocks") {
+            cmd_get_rejected_blocks(matches.get_one::<String>("node").unwrap())?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getindexinfo") {
+            cmd_get_index_info(matches.get_one::<String>("node").unwrap())?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getpeerinfo") {
+            cmd_get_peer_info(matches.get_one::<String>("node").unwrap())?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("findblocks") {
+            cmd_find_blocks(
+                matches.get_one::<String>("node").unwrap(),
+                matches.get_one::<String>("ADDRESS").unwrap(),
+                matches.get_one::<String>("from").unwrap().parse()?,
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("syncwallet") {
+            let addresses: Vec<&String> = matches.get_many::<String>("ADDRESS").unwrap().collect();
+            let since = match matches.get_one::<String>("since") {
+                Some(since) => Some(since.parse()?),
+                None => None,
+            };
+            cmd_sync_wallet(
+                matches.get_one::<String>("node").unwrap(),
+                &addresses,
+                since,
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getblockfilter") {
+            let addresses: Vec<String> = matches
+                .get_many::<String>("address")
+                .map(|addresses| addresses.cloned().collect())
+                .unwrap_or_default();
+            let hash = matches.get_one::<String>("HASH").unwrap().parse()?;
+            cmd_get_block_filter(&hash, &addresses)?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getauditlog") {
+            let action = matches.get_one::<String>("action");
+            for entry in audit::read_log()? {
+                if action.is_none_or(|action| *action == entry.action) {
+                    println!(
+                        "{} {} {}: {}",
+                        entry.timestamp, entry.source, entry.action, entry.details
+                    );
+                }
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("reloadconfig") {
+            cmd_reload_config(matches.get_one::<String>("node").unwrap())?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("setban") {
+            let duration = match matches.get_one::<String>("SECONDS") {
+                Some(secs) => Some(secs.parse()?),
+                None => None,
+            };
+            let request = RpcRequest::SetBan {
+                range: matches.get_one::<String>("RANGE").unwrap().clone(),
+                remove: matches.get_one::<String>("COMMAND").unwrap() == "remove",
+                duration,
+            };
+            cmd_list_banned(matches.get_one::<String>("node").unwrap(), request)?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("listbanned") {
+            cmd_list_banned(
+                matches.get_one::<String>("node").unwrap(),
+                RpcRequest::ListBanned,
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getpeerevents") {
+            cmd_get_peer_events(
+                matches.get_one::<String>("node").unwrap(),
+                RpcRequest::PeerEvents,
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getdoublespends") {
+            cmd_get_peer_events(
+                matches.get_one::<String>("node").unwrap(),
+                RpcRequest::DoubleSpends,
+            )?;
+        }
+
+        if matches.subcommand_matches("listconflicts").is_some() {
+            for conflict in Wallets::new()?.get_conflicts(None) {
+                print_conflict(conflict);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getmerkleproof") {
+            let block_hash = matches.get_one::<String>("BLOCK").unwrap().parse()?;
+            let txid = matches.get_one::<String>("TXID").unwrap().parse()?;
+            cmd_get_merkle_proof(&block_hash, &txid)?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("exportheaders") {
+            let path = matches.get_one::<String>("FILE").unwrap();
+            let headers = chain_headers(&Blockchain::new()?)?;
+            fs::write(path, serialize(&headers)?)?;
+            println!("Exported {} headers to {path}", headers.len());
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("exportspvproof") {
+            let proof = SpvProof::new(
+                &Blockchain::new()?,
+                &matches.get_one::<String>("BLOCK").unwrap().parse()?,
+                &matches.get_one::<String>("TXID").unwrap().parse()?,
+            )?;
+            let path = matches.get_one::<String>("FILE").unwrap();
+            fs::write(path, serialize(&proof)?)?;
+            println!("Exported the proof of {} to {path}", proof.transaction.id);
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("bridgeheaders") {
+            let chain = matches.get_one::<String>("CHAIN").unwrap();
+            let params = Config::load_from(matches.get_one::<String>("params").unwrap())?.chain;
+            let headers: Vec<BlockHeader> =
+                deserialize(&fs::read(matches.get_one::<String>("FILE").unwrap())?)?;
+            let bridge = Bridge::open()?;
+            let added = bridge.add_headers(chain, &params, &headers)?;
+            if let Some(tip) = bridge.get_tip(chain)? {
+                println!(
+                    "Added {added} headers of {chain}, tip {} at height {}",
+                    tip.hash, tip.height
+                );
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("bridgeverify") {
+            let chain = matches.get_one::<String>("CHAIN").unwrap();
+            let proof: SpvProof =
+                deserialize(&fs::read(matches.get_one::<String>("PROOF_FILE").unwrap())?)?;
+            let min_conf = matches.get_one::<String>("min-conf").unwrap().parse()?;
+            let foreign = Bridge::open()?.verify(chain, &proof, min_conf)?;
+            println!(
+                "Verified {} of {chain} in block {} at height {}",
+                foreign.transaction.id, foreign.block_hash, foreign.height
+            );
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getforeigntx") {
+            let chain = matches.get_one::<String>("CHAIN").unwrap();
+            let txid = matches.get_one::<String>("TXID").unwrap().parse()?;
+            match Bridge::open()?.get_transaction(chain, &txid)? {
+                Some(foreign) => {
+                    println!("block: {} at height {}", foreign.block_hash, foreign.height);
+                    println!("{:#?}", foreign.transaction);
+                }
+                None => println!("Transaction {txid} of {chain} is not verified"),
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("stress") {
+            let tps = matches.get_one::<String>("tps").unwrap().parse()?;
+            let duration = matches.get_one::<String>("duration").unwrap().parse()?;
+            let block_interval = match matches.get_one::<String>("block-interval") {
+                Some(secs) => Some(secs.parse()?),
+                None => None,
+            };
+            cmd_stress(tps, duration, block_interval)?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("validatetx") {
+            let hex_tx = matches.get_one::<String>("HEX").unwrap();
+            if !cmd_validate_tx(hex_tx, matches.get_flag("trace"))? {
+                exit(1)
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("startnode") {
+            if let Some(port) = matches.get_one::<String>("PORT") {
+                let settings = node_settings(matches)?;
+                let blockchain = open_node_chain(&settings)?;
+                let utxo_set = UTXOSet { blockchain };
+                let mut server = Server::new(port, "", utxo_set)?;
+                if let Some(addr) = settings.external_address.clone() {
+                    server.set_external_address(addr);
+                }
+                server.apply_settings(settings)?;
+                if let Some(addr) = matches.get_one::<String>("api") {
+                    server.start_api(addr)?;
+                }
+                server.start()?;
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("send") {
+            let from = if let Some(address) = matches.get_one::<String>("FROM") {
+                address
+            } else {
+                println!("from not supply!: usage");
+                exit(1)
+            };
+
+            let mut recipients = Vec::new();
+            if let Some(to) = matches.get_one::<String>("TO") {
+                let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+                    amount.parse()?
+                } else {
+                    println!("amount not supply!: usage");
+                    exit(1)
+                };
+                recipients.push((to.clone(), amount));
+            }
+            if let Some(outputs) = matches.get_many::<String>("to") {
+                for output in outputs {
+                    recipients.push(parse_recipient(output)?);
+                }
+            }
+            if recipients.is_empty() {
+                println!("to not supply!: usage");
+                exit(1)
+            }
+
+            let selection = match matches.get_one::<String>("coin-selection") {
+                Some(selection) => selection.parse()?,
+                None => CoinSelection::default(),
+            };
+            let lock = match matches.get_one::<String>("lock-height") {
+                Some(height) => LockScript::TimeLock {
+                    height: height.parse()?,
+                },
+                None => LockScript::PayToPubkeyHash,
+            };
+            let lock_time = match matches.get_one::<String>("locktime") {
+                Some(lock_time) => lock_time.parse()?,
+                None => 0,
+            };
+            let expiry_height = match matches.get_one::<String>("expiry-height") {
+                Some(height) => height.parse()?,
+                None => 0,
+            };
+            cmd_send(
+                from,
+                &recipients,
+                lock,
+                lock_time,
+                expiry_height,
+                selection,
+                matches.get_flag("mine"),
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("burn") {
+            let from = matches.get_one::<String>("FROM").unwrap();
+            let amount: i32 = matches.get_one::<String>("AMOUNT").unwrap().parse()?;
+            cmd_burn(from, amount, matches.get_flag("mine"))?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("payout") {
+            let max_size = match matches.get_one::<String>("max-tx-size") {
+                Some(size) => size.parse()?,
+                None => DEFAULT_MAX_TX_SIZE,
+            };
+            let selection = match matches.get_one::<String>("coin-selection") {
+                Some(selection) => selection.parse()?,
+                None => CoinSelection::default(),
+            };
+            cmd_payout(
+                matches.get_one::<String>("FROM").unwrap(),
+                matches.get_one::<String>("FILE").unwrap(),
+                max_size,
+                selection,
+                matches.get_flag("yes"),
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("createvault") {
+            let delay = match matches.get_one::<String>("delay") {
+                Some(delay) => delay.parse()?,
+                None => DEFAULT_VAULT_DELAY,
+            };
+            cmd_create_vault(
+                matches.get_one::<String>("HOT_ADDRESS").unwrap(),
+                matches.get_one::<String>("RECOVERY_KEY").unwrap(),
+                delay,
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getvault") {
+            cmd_get_vault(matches.get_one::<String>("ADDRESS").unwrap())?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("unvault") {
+            let amount: i32 = matches.get_one::<String>("AMOUNT").unwrap().parse()?;
+            cmd_unvault(matches.get_one::<String>("ADDRESS").unwrap(), amount)?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("withdrawvault") {
+            cmd_withdraw_vault(
+                matches.get_one::<String>("ADDRESS").unwrap(),
+                matches.get_one::<String>("TO").unwrap(),
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("clawback") {
+            cmd_clawback(
+                matches.get_one::<String>("ADDRESS").unwrap(),
+                matches.get_one::<String>("TO").unwrap(),
+            )?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("schedulepayment") {
+            let from = matches.get_one::<String>("FROM").unwrap();
+            let to = matches.get_one::<String>("TO").unwrap();
+            let amount: i32 = matches.get_one::<String>("AMOUNT").unwrap().parse()?;
+            let interval = parse_interval(matches.get_one::<String>("every").unwrap())?;
+            let payment = Scheduler::new()?.add(from, to, amount, interval)?;
+            println!(
+                "Scheduled payment {} every {}s",
+                payment.id, payment.interval
+            );
+        }
+
+        if matches.subcommand_matches("listschedules").is_some() {
+            cmd_list_schedules()?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("cancelschedule") {
+            let id: u64 = matches.get_one::<String>("ID").unwrap().parse()?;
+            if Scheduler::new()?.cancel(id)? {
+                println!("Cancelled scheduled payment {id}");
+            } else {
+                println!("No scheduled payment with id {id}");
+            }
+        }
+
+        if matches.subcommand_matches("print").is_some() {
+            cmd_print_chain()?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getblock") {
+            let utxo_set = UTXOSet {
+                blockchain: Blockchain::new()?,
+            };
+            let block = utxo_set
+                .blockchain
+                .get_block(&matches.get_one::<String>("HASH").unwrap().parse()?)?;
+            print_block(&utxo_set, &block, matches.get_flag("verbose"))?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getblockheight") {
+            let height: i32 = matches.get_one::<String>("HEIGHT").unwrap().parse()?;
+            let utxo_set = UTXOSet {
+                blockchain: Blockchain::new()?,
+            };
+            match utxo_set.blockchain.get_block_by_height(height)? {
+                Some(block) => print_block(&utxo_set, &block, matches.get_flag("verbose"))?,
+                None => return Err(format_err!("No block at height {}", height)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn seed_nodes(matches: &ArgMatches, id: &str) -> Vec<String> {
+    matches
+        .get_many::<String>(id)
+        .map(|seeds| seeds.cloned().collect())
+        .unwrap_or_default()
+}
+
+/// node_settings reads the node settings of the config file, overridden by command line flags
+fn node_settings(matches: &ArgMatches) -> Result<NodeSettings> {
+    let mut settings = Config::load()?.node;
+    settings.seeds.extend(seed_nodes(matches, "seed"));
+    settings.dns_seeds.extend(seed_nodes(matches, "dns-seed"));
+    if let Some(addr) = matches.get_one::<String>("bind") {
+        settings.bind = Some(addr.clone());
+    }
+    if let Some(addr) = matches.get_one::<String>("external-address") {
+        settings.external_address = Some(addr.clone());
+    }
+    if let Some(secs) = matches.get_one::<String>("read-timeout") {
+        settings.read_timeout = secs.parse()?;
+    }
+    if let Some(secs) = matches.get_one::<String>("write-timeout") {
+        settings.write_timeout = secs.parse()?;
+    }
+    if matches.get_flag("rbf") {
+        settings.replace_by_fee = true;
+    }
+    if let Some(blocks) = matches.get_one::<String>("prune") {
+        settings.prune = Some(blocks.parse()?);
+    }
+    if matches.get_flag("archive") {
+        settings.archive = true;
+    }
+    if matches.get_flag("blocks-only") {
+        settings.blocks_only = true;
+    }
+    if matches.get_flag("explorer") {
+        settings.explorer = true;
+    }
+    if matches.get_flag("paranoid") {
+        settings.paranoid = true;
+    }
+    Ok(settings)
+}
+
+/// open_node_chain opens the chain with the indexes the node `settings` enable
+fn open_node_chain(settings: &NodeSettings) -> Result<Blockchain> {
+    let mut config = Config::load()?;
+    config.node = settings.clone();
+    Blockchain::open(config)
+}
+
+/// parse_recipient splits an `ADDRESS:AMOUNT` output given with `--to`
+fn parse_recipient(output: &str) -> Result<(String, i32)> {
+    match output.rsplit_once(':') {
+        Some((address, amount)) => Ok((address.to_string(), amount.parse()?)),
+        None => Err(format_err!("Expected ADDRESS:AMOUNT, got {}", output)),
+    }
+}
+
+fn cmd_send(
+    from: &str,
+    recipients: &[(String, i32)],
+    lock: LockScript,
+    lock_time: u64,
+    expiry_height: i32,
+    selection: CoinSelection,
+    mine_now: bool,
+) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    let from = wallets.resolve(from);
+    wallet_of(&wallets, &from)?;
+    let pending = PendingSend {
+        id: 0,
+        recipients: recipients
+            .iter()
+            .map(|(to, amount)| (wallets.resolve_recipient(to), *amount))
+            .collect(),
+        from,
+        lock,
+        lock_time,
+        expiry_height,
+        selection,
+        mine_now,
+        created: now_secs()?,
+        reason: String::new(),
+    };
+    if let Some(reason) = wallets.check_spend(&pending.from, pending.amount(), pending.created) {
+        let from = pending.from.clone();
+        let id = wallets.queue_send(PendingSend {
+            reason: reason.clone(),
+            ..pending
+        });
+        wallets.save_all()?;
+        audit::record(
+            "cli",
+            "send-held",
+            &format!("pending send {} from {}: {}", id, from, reason),
+        )?;
+        println!("send {} is pending approval: {}", id, reason);
+        return Ok(());
+    }
+    send_pending(&mut wallets, &pending)
+}
+
+/// cmd_approve releases a send held back by the spend limits of its address
+fn cmd_approve(id: u64, code: &str) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    let pending = wallets.approve(id, code)?;
+    audit::record(
+        "cli",
+        "approve",
+        &format!("pending send {} from {}", id, pending.from),
+    )?;
+    send_pending(&mut wallets, &pending)
+}
+
+/// send_pending builds and submits a send, counting it towards the daily limit of its
+/// address. The wallets are saved once it is submitted, so a released send that fails
+/// stays pending.
+fn send_pending(wallets: &mut Wallets, pending: &PendingSend) -> Result<()> {
+    let blockchain = Blockchain::new()?;
+    let utxo_set = UTXOSet { blockchain };
+    let transaction = Transaction::new_locked(
+        wallet_of(wallets, &pending.from)?,
+        &pending.recipients,
+        pending.lock,
+        pending.lock_time,
+        pending.expiry_height,
+        pending.selection,
+        &utxo_set,
+    )?;
+    submit_transaction(utxo_set, &pending.from, transaction, pending.mine_now)?;
+    wallets.record_spend(&pending.from, pending.amount(), now_secs()?);
+    wallets.save_all()
+}
+
+fn cmd_burn(from: &str, amount: i32, mine_now: bool) -> Result<()> {
+    let blockchain = Blockchain::new()?;
+    let utxo_set = UTXOSet { blockchain };
+    let wallets = Wallets::new()?;
+    let wallet = wallet_of(&wallets, from)?;
+    let transaction = Transaction::new_burn(wallet, amount, &utxo_set)?;
+    submit_transaction(utxo_set, from, transaction, mine_now)
+}
+
+/// cmd_payout pays the rows of the payout file at `path` from `from` in transactions
+/// under `max_size` bytes each, once the summary is confirmed
+fn cmd_payout(
+    from: &str,
+    path: &str,
+    max_size: usize,
+    selection: CoinSelection,
+    confirmed: bool,
+) -> Result<()> {
+    let payments = parse_payouts(&fs::read_to_string(path)?)?;
+    let batches = split_batches(&payments, max_size)?;
+    let paid = batches
+        .iter()
+        .map(|batch| Ok((batch.len(), total_value(batch)?)))
+        .collect::<Result<Vec<(usize, i32)>>>()?;
+    let total = paid
+        .iter()
+        .try_fold(0i32, |sum, (_, amount)| sum.checked_add(*amount))
+        .ok_or_else(|| format_err!("The payout adds up to more than {}", i32::MAX))?;
+
+    let mut wallets = Wallets::new()?;
+    let from = wallets.resolve(from);
+    let now = now_secs()?;
+    if let Some(reason) = wallets.check_spend(&from, total, now) {
+        return Err(format_err!(
+            "The payout of {} from {} is above its spend limits: {}",
+            total,
+            from,
+            reason
+        ));
+    }
+    let utxo_set = UTXOSet {
+        blockchain: Blockchain::new()?,
+    };
+    let txs = Transaction::new_payout(wallet_of(&wallets, &from)?, batches, selection, &utxo_set)?;
+    check_sizes(&txs, max_size)?;
+
+    println!(
+        "Paying {} to {} addresses from {} in {} transactions:",
+        total,
+        payments.len(),
+        from,
+        txs.len()
+    );
+    for (tx, (count, amount)) in txs.iter().zip(&paid) {
+        println!(
+            "  {}  pays {} to {} addresses  {} bytes",
+            tx.id,
+            amount,
+            count,
+            serialize(tx)?.len()
+        );
+    }
+    if !confirmed && !confirm("Broadcast the payout?")? {
+        println!("Payout cancelled");
+        return Ok(());
+    }
+
+    audit::record(
+        "cli",
+        "payout",
+        &format!(
+            "{} to {} addresses from {} in {} transactions",
+            total,
+            payments.len(),
+            from,
+            txs.len()
+        ),
+    )?;
+    Server::send_transactions(&txs)?;
+    wallets.record_spend(&from, total, now);
+    wallets.save_all()?;
+    println!("Success! Payout sent");
+    Ok(())
+}
+
+/// confirm asks `question` on the terminal, telling whether the answer was yes
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// submit_transaction mines the transaction right away with `from` as the miner, or hands it to the network
+fn submit_transaction(
+    mut utxo_set: UTXOSet,
+    from: &str,
+    transaction: Transaction,
+    mine_now: bool,
+) -> Result<()> {
+    if mine_now {
+        audit::record("cli", "send", &format!("mined txid {}", transaction.id))?;
+        let height = utxo_set.blockchain.get_best_height()? + 1;
+        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("Reward"), height)?;
+        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, transaction])?;
+        utxo_set.update(&new_block)?;
+    } else {
+        Server::send_transaction(&transaction)?;
+    }
+
+    println!("Success! Transaction sent");
+    Ok(())
+}
+
+fn cmd_stress(tps: u32, duration: u64, block_interval: Option<u64>) -> Result<()> {
+    let blockchain = Blockchain::new()?;
+    let block_interval = block_interval.unwrap_or(blockchain.get_params().target_block_interval);
+    let wallets = Wallets::new()?;
+    let mut stress = StressTest::new(UTXOSet { blockchain }, &wallets)?;
+    let report = stress.run(
+        tps,
+        Duration::from_secs(duration),
+        Duration::from_secs(block_interval),
+    )?;
+
+    for (second, size) in report.mempool_sizes.iter().enumerate() {
+        println!("{:>4}s mempool: {}", second + 1, size);
+    }
+    println!(
+        "submitted: {} rejected: {} starved: {}",
+        report.submitted, report.rejected, report.starved
+    );
+    println!(
+        "blocks: {} transactions mined: {}",
+        report.blocks, report.mined
+    );
+    println!(
+        "inclusion latency: mean {:.2}s max {:.2}s",
+        report.mean_latency.as_secs_f64(),
+        report.max_latency.as_secs_f64()
+    );
+    println!("validation throughput: {:.0} tx/s", report.validation_tps);
+    Ok(())
+}
+
+fn cmd_list_schedules() -> Result<()> {
+    let payments = Scheduler::new()?.list()?;
+    println!("scheduled payments: ");
+    for p in payments {
+        println!(
+            "{}: {} -> {} amount: {} every: {}s next run: {}",
+            p.id, p.from, p.to, p.amount, p.interval, p.next_run
+        );
+    }
+    Ok(())
+}
+
+fn cmd_create_wallet(mnemonic: bool) -> Result<String> {
+    let mut wallets = Wallets::new()?;
+    let address = if mnemonic {
+        if !wallets.has_hd_seed() {
+            let phrase = Wallets::generate_mnemonic()?;
+            wallets.set_mnemonic(&phrase)?;
+            audit::record("cli", "mnemonic-exported", "new hd seed")?;
+            println!("mnemonic: {phrase}");
+            println!("Write these words down, they are the only backup of your derived wallets");
+        }
+        wallets.create_hd_wallet()?
+    } else {
+        wallets.create_wallet()?
+    };
+    wallets.save_all()?;
+    audit::record("cli", "wallet-created", &address)?;
+    Ok(address)
+}
+
+fn cmd_restore_wallet(phrase: &str, count: u32) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    let addresses = wallets.restore_from_mnemonic(phrase, count)?;
+    wallets.save_all()?;
+    audit::record(
+        "cli",
+        "wallet-restored",
+        &format!("{} new addresses", addresses.len()),
+    )?;
+    if addresses.is_empty() {
+        println!("no new addresses, the wallet already holds these keys");
+        return Ok(());
+    }
+    println!("restored addresses: ");
+    for address in addresses {
+        println!("{address}");
+    }
+    Ok(())
+}
+
+/// cmd_check_wallets prints the fingerprint of every key and whether it is intact
+fn cmd_check_wallets() -> Result<bool> {
+    let wallets = Wallets::new()?;
+    let mut addresses = wallets.get_all_addresses();
+    addresses.sort();
+    let broken: HashMap<String, String> = wallets.check_keys().into_iter().collect();
+    for address in &addresses {
+        let wallet = wallets.get_wallet(address).unwrap();
+        match broken.get(address) {
+            Some(problem) => println!("{} {} BROKEN: {}", wallet.fingerprint(), address, problem),
+            None => println!("{} {} ok", wallet.fingerprint(), address),
+        }
+    }
+    println!("{} keys, {} broken", addresses.len(), broken.len());
+    Ok(broken.is_empty())
+}
+
+/// receive_count is the number of payments an address has received
+fn receive_count(blockchain: &Blockchain, address: &str) -> u32 {
+    let pub_key_hash = Address::decode(address).unwrap().body;
+    blockchain
+        .find_transactions_for(&pub_key_hash)
+        .iter()
+        .filter(|entry| entry.received > 0)
+        .count() as u32
+}
+
+/// fresh_address creates a new key, derived from the mnemonic when the wallet has one
+fn fresh_address(wallets: &mut Wallets) -> Result<String> {
+    let address = if wallets.has_hd_seed() {
+        wallets.create_hd_wallet()?
+    } else {
+        wallets.create_wallet()?
+    };
+    audit::record("cli", "wallet-created", &address)?;
+    Ok(address)
+}
+
+fn cmd_get_new_address() -> Result<String> {
+    let mut wallets = Wallets::new()?;
+    let blockchain = Blockchain::new()?;
+    let policy = wallets.get_policy();
+    let now = now_secs()?;
+
+    let mut addresses = wallets.get_all_addresses();
+    addresses.sort();
+    for address in addresses {
+        let created = wallets.get_created(&address).unwrap_or(now);
+        let uses = receive_count(&blockchain, &address);
+        if uses == 0 && !policy.retired(created, uses, now) {
+            return Ok(address);
+        }
+    }
+
+    let address = fresh_address(&mut wallets)?;
+    wallets.save_all()?;
+    Ok(address)
+}
+
+fn cmd_rotate_keys(mine_now: bool) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    let policy = wallets.get_policy();
+    let now = now_secs()?;
+    let mut addresses = wallets.get_all_addresses();
+    addresses.sort();
+
+    for address in addresses {
+        let utxo_set = UTXOSet {
+            blockchain: Blockchain::new()?,
+        };
+        let created = wallets.get_created(&address).unwrap_or(now);
+        if !policy.retired(created, receive_count(&utxo_set.blockchain, &address), now) {
+            continue;
+        }
+        let pub_key_hash = Address::decode(&address).unwrap().body;
+        let balance: i32 = utxo_set
+            .find_UTXO(&pub_key_hash)?
+            .iter()
+            .map(|utxo| utxo.output.value)
+            .sum();
+        if balance <= 0 {
+            continue;
+        }
+
+        let to = fresh_address(&mut wallets)?;
+        wallets.save_all()?;
+        println!("sweeping {balance} from retired {address} to {to}");
+        let wallet = wallets.get_wallet(&address).unwrap();
+        let transaction = Transaction::new_UTXO(
+            wallet,
+            &[(to.clone(), balance)],
+            CoinSelection::default(),
+            &utxo_set,
+        )?;
+        submit_transaction(utxo_set, &address, transaction, mine_now)?;
+    }
+    Ok(())
+}
+
+fn cmd_list_descriptors() -> Result<()> {
+    let wallets = Wallets::new()?;
+    println!("descriptors: ");
+    for (descriptor, range) in wallets.list_descriptors()? {
+        println!("{descriptor} range: {range}");
+    }
+    Ok(())
+}
+
+fn cmd_import_descriptor(descriptor: &str, range: u32) -> Result<Vec<String>> {
+    let mut wallets = Wallets::new()?;
+    let addresses = wallets.import_descriptor(descriptor, range)?;
+    wallets.save_all()?;
+    audit::record("cli", "key-imported", &format!("descriptor {descriptor}"))?;
+    println!("imported addresses: ");
+    for address in &addresses {
+        println!("{address}");
+    }
+    Ok(addresses)
+}
+
+fn cmd_create_vault(hot: &str, recovery_key: &str, delay: i32) -> Result<()> {
+    let wallets = Wallets::new()?;
+    let hot_key = hex::encode(&wallet_of(&wallets, &wallets.resolve(hot))?.public_key);
+    let descriptor = format!("vault({delay},{hot_key},{recovery_key})");
+    cmd_import_descriptor(&descriptor, 1)?;
+    println!("descriptor: {descriptor}");
+    Ok(())
+}
+
+/// vault_of returns the vault imported at `address`
+fn vault_of(wallets: &Wallets, address: &str) -> Result<Vault> {
+    match wallets.get_vault(&wallets.resolve(address)) {
+        Some(vault) => Ok(vault),
+        None => Err(CodedError::new(
+            ErrorCode::UnknownAddress,
+            format!("{} is not a vault of the wallet", address),
+        )
+        .with("address", address)
+        .into()),
+    }
+}
+
+/// vault_wallet returns the wallet holding the secret of the vault's `key`
+fn vault_wallet<'a>(wallets: &'a Wallets, vault: &Vault, key: VaultKey) -> Result<&'a Wallet> {
+    let mut pub_key_hash = vault.key(key).to_vec();
+    hash_pub_key(&mut pub_key_hash);
+    match wallets.get_wallet(&hash_to_address(pub_key_hash)) {
+        Some(wallet) => Ok(wallet),
+        None => Err(format_err!(
+            "The {} key of the vault is not in the wallet",
+            match key {
+                VaultKey::Hot => "hot",
+                VaultKey::Recovery => "recovery",
+            }
+        )),
+    }
+}
+
+fn cmd_get_vault(address: &str) -> Result<()> {
+    let vault = vault_of(&Wallets::new()?, address)?;
+    let utxo_set = UTXOSet {
+        blockchain: Blockchain::new()?,
+    };
+    let height = utxo_set.blockchain.get_best_height()? + 1;
+    let mut vaulted = 0;
+    let mut unvaulting = Vec::new();
+    for (txid, vout, out) in vault.outputs(&utxo_set)? {
+        match out.lock {
+            LockScript::Unvault { height: unlock } => {
+                unvaulting.push((txid, vout, out.value, unlock))
+            }
+            _ => vaulted += out.value,
+        }
+    }
+    println!("delay: {} blocks", vault.delay);
+    println!("vaulted: {vaulted}");
+    for (txid, vout, value, unlock) in unvaulting {
+        if unlock <= height {
+            println!("unvaulted {txid}:{vout} {value}, spendable");
+        } else {
+            println!("unvaulting {txid}:{vout} {value}, spendable at height {unlock}");
+        }
+    }
+    Ok(())
+}
+
+fn cmd_unvault(address: &str, amount: i32) -> Result<()> {
+    let wallets = Wallets::new()?;
+    let vault = vault_of(&wallets, address)?;
+    let wallet = vault_wallet(&wallets, &vault, VaultKey::Hot)?;
+    let utxo_set = UTXOSet {
+        blockchain: Blockchain::new()?,
+    };
+    let height = utxo_set.blockchain.get_best_height()? + 1;
+    let tx = vault.new_unvault(amount, height, &utxo_set, wallet.signer()?.as_ref())?;
+    audit::record(
+        "cli",
+        "unvault",
+        &format!("{} from vault {} in {}", amount, address, tx.id),
+    )?;
+    Server::send_transaction(&tx)?;
+    println!(
+        "unvault {} sent, spendable from height {}",
+        tx.id,
+        height + vault.delay
+    );
+    Ok(())
+}
+
+fn cmd_withdraw_vault(address: &str, to: &str) -> Result<()> {
+    let wallets = Wallets::new()?;
+    let vault = vault_of(&wallets, address)?;
+    let wallet = vault_wallet(&wallets, &vault, VaultKey::Hot)?;
+    let utxo_set = UTXOSet {
+        blockchain: Blockchain::new()?,
+    };
+    let height = utxo_set.blockchain.get_best_height()? + 1;
+    let to = wallets.resolve(to);
+    let tx = vault.new_withdrawal(&to, height, &utxo_set, wallet.signer()?.as_ref())?;
+    audit::record(
+        "cli",
+        "withdraw-vault",
+        &format!("vault {} to {} in {}", address, to, tx.id),
+    )?;
+    Server::send_transaction(&tx)?;
+    println!("withdrawal {} sent", tx.id);
+    Ok(())
+}
 
-fn cmd_print_chain() -> Result<()> {
+fn cmd_clawback(address: &str, to: &str) -> Result<()> {
+    let wallets = Wallets::new()?;
+    let vault = vault_of(&wallets, address)?;
+    let wallet = vault_wallet(&wallets, &vault, VaultKey::Recovery)?;
+    let utxo_set = UTXOSet {
+        blockchain: Blockchain::new()?,
+    };
+    let to = wallets.resolve(to);
+    let tx = vault.new_clawback(&to, &utxo_set, wallet.signer()?.as_ref())?;
+    audit::record(
+        "cli",
+        "clawback",
+        &format!("vault {} to {} in {}", address, to, tx.id),
+    )?;
+    Server::send_transaction(&tx)?;
+    println!("clawback {} sent", tx.id);
+    Ok(())
+}
+
+fn cmd_spend_multisig(
+    address: &str,
+    recipients: &[(String, i32)],
+    selection: CoinSelection,
+    as_psbt: bool,
+) -> Result<()> {
+    let wallets = Wallets::new()?;
+    let (required, keys) = match wallets.get_multisig(address) {
+        Some(Descriptor::Multi { required, keys }) => (required, keys),
+        _ => {
+            return Err(format_err!(
+                "{} is not a multisig address of the wallet",
+                address
+            ))
+        }
+    };
+    let utxo_set = UTXOSet {
+        blockchain: Blockchain::new()?,
+    };
+    let mut tx = Transaction::new_multisig(&keys, required, recipients, selection, &utxo_set)?;
+    if as_psbt {
+        let mut psbt = new_psbt(tx, &utxo_set)?;
+        sign_psbt(&mut psbt, &wallets)?;
+        println!("{}", psbt.to_base64()?);
+        println!("missing signatures: {}", psbt.missing_signatures());
+        return Ok(());
+    }
+    let missing = sign_multisig(&mut tx, &wallets, &utxo_set)?;
+    println!("{}", hex::encode(serialize(&tx)?));
+    println!("missing signatures: {missing}");
+    Ok(())
+}
+
+fn cmd_sign_raw(hex_tx: &str, send: bool) -> Result<()> {
+    let mut tx: Transaction = deserialize(&hex::decode(hex_tx.trim())?)?;
+    let utxo_set = UTXOSet {
+        blockchain: Blockchain::new()?,
+    };
+    let missing = sign_multisig(&mut tx, &Wallets::new()?, &utxo_set)?;
+    println!("{}", hex::encode(serialize(&tx)?));
+    println!("missing signatures: {missing}");
+    if send && missing == 0 {
+        Server::send_transaction(&tx)?;
+        println!("Success! Transaction sent");
+    }
+    Ok(())
+}
+
+/// new_psbt wraps the unsigned `tx` with the outputs it spends, so it can be signed
+/// away from the chain
+fn new_psbt(tx: Transaction, utxo_set: &UTXOSet) -> Result<PartiallySignedTransaction> {
+    let mut psbt = PartiallySignedTransaction::new(tx.vout)?;
+    for vin in tx.vin {
+        let prev_out = match utxo_set.get_output(&vin.txid, vin.vout)? {
+            Some(out) => out,
+            None => {
+                return Err(format_err!(
+                    "Input {}:{} is not unspent",
+                    vin.txid,
+                    vin.vout
+                ))
+            }
+        };
+        psbt.add_input(&vin.txid, vin.vout, vin.pub_key, prev_out)?;
+    }
+    Ok(psbt)
+}
+
+/// sign_psbt adds the signatures of the wallet keys to every input of `psbt`
+fn sign_psbt(psbt: &mut PartiallySignedTransaction, wallets: &Wallets) -> Result<()> {
+    for in_id in 0..psbt.tx.vin.len() {
+        let pub_key = psbt.tx.vin[in_id].pub_key.clone();
+        for key in pub_key.chunks(PUB_KEY_LEN) {
+            let mut pub_key_hash = key.to_vec();
+            hash_pub_key(&mut pub_key_hash);
+            if let Some(wallet) = wallets.get_wallet(&hash_to_address(pub_key_hash)) {
+                psbt.sign(in_id, key, wallet.signer()?.as_ref())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cmd_decode_psbt(text: &str) -> Result<()> {
+    let psbt = PartiallySignedTransaction::from_base64(text)?;
+    println!("transaction: {}", psbt.tx.id);
+    for (vin, input) in psbt.tx.vin.iter().zip(&psbt.inputs) {
+        println!(
+            "input {}:{} value {} lock {:?}",
+            vin.txid, vin.vout, input.prev_output.value, input.prev_output.lock
+        );
+        for key in vin.pub_key.chunks(PUB_KEY_LEN) {
+            let signed = input.signatures.contains_key(&hex::encode(key));
+            println!(
+                "  key {} {}",
+                fingerprint(key),
+                if signed { "signed" } else { "unsigned" }
+            );
+        }
+    }
+    for out in &psbt.tx.vout {
+        println!(
+            "output {} to {}",
+            out.value,
+            hash_to_address(out.pub_key_hash.clone())
+        );
+    }
+    println!("fee: {}", psbt.fee()?);
+    println!("missing signatures: {}", psbt.missing_signatures());
+    Ok(())
+}
+
+/// sign_multisig adds the signatures of the wallet keys to the multisig inputs of `tx`
+/// and returns how many signatures its inputs still need
+fn sign_multisig(tx: &mut Transaction, wallets: &Wallets, utxo_set: &UTXOSet) -> Result<usize> {
+    let mut missing = 0;
+    for in_id in 0..tx.vin.len() {
+        let vin = tx.vin[in_id].clone();
+        let prev_out = match utxo_set.get_output(&vin.txid, vin.vout)? {
+            Some(out) => out,
+            None => {
+                return Err(format_err!(
+                    "Input {}:{} is not unspent",
+                    vin.txid,
+                    vin.vout
+                ))
+            }
+        };
+        if !matches!(prev_out.lock, LockScript::MultiSig { .. }) {
+            continue;
+        }
+        for key in vin.pub_key.chunks(PUB_KEY_LEN) {
+            let mut pub_key_hash = key.to_vec();
+            hash_pub_key(&mut pub_key_hash);
+            if let Some(wallet) = wallets.get_wallet(&hash_to_address(pub_key_hash)) {
+                tx.add_multisig_signature(in_id, &prev_out, wallet.signer()?.as_ref(), key)?;
+            }
+        }
+        let signed = tx.vin[in_id].signature.len() / SIGNATURE_LEN;
+        missing += prev_out.lock.signatures().saturating_sub(signed);
+    }
+    Ok(missing)
+}
+
+/// cmd_rescan walks the chain and reports the transactions and balance of each address
+fn cmd_rescan(addresses: &[String]) -> Result<()> {
+    let blockchain = Blockchain::new()?;
+    let mut pub_key_hashes = Vec::new();
+    for address in addresses {
+        pub_key_hashes.push(Address::decode(address).unwrap().body);
+    }
+
+    let mut transactions = vec![0; addresses.len()];
+    for block in blockchain.walk().blocks() {
+        for tx in block.get_transactions() {
+            for (i, pub_key_hash) in pub_key_hashes.iter().enumerate() {
+                let received = tx
+                    .vout
+                    .iter()
+                    .any(|out| out.is_locked_with_key(pub_key_hash));
+                let spent = !tx.is_coinbase()
+                    && tx
+                        .vin
+                        .iter()
+                        .any(|vin| vin.can_unlock_output_with(pub_key_hash));
+                if received || spent {
+                    transactions[i] += 1;
+                }
+            }
+        }
+    }
+
+    let utxo_set = UTXOSet { blockchain };
+    let mut total = 0;
+    for (i, address) in addresses.iter().enumerate() {
+        let balance: i32 = utxo_set
+            .find_UTXO(&pub_key_hashes[i])?
+            .iter()
+            .map(|utxo| utxo.output.value)
+            .sum();
+        total += balance;
+        println!(
+            "{address}: transactions: {} balance: {balance}",
+            transactions[i]
+        );
+    }
+    println!("Total balance: {total}");
+    Ok(())
+}
+
+fn cmd_reindex() -> Result<i32> {
+    let blockchain = Blockchain::new()?;
+    let utxo_set = UTXOSet { blockchain };
+    utxo_set.reindex()?;
+    utxo_set.count_transactions()
+}
+
+fn cmd_export_utxos(path: &str) -> Result<()> {
+    let blockchain = Blockchain::new()?;
+    let utxo_set = UTXOSet { blockchain };
+    let snapshot = utxo_set.snapshot()?;
+    snapshot.save(path)?;
+    println!(
+        "Exported {} outputs at height {} to {path}",
+        snapshot.outputs.len(),
+        snapshot.height
+    );
+    Ok(())
+}
+
+/// cmd_replay prints the blocks of an export the current rules, with the chain parameters
+/// of `ruleset` or of the node config, reject; it returns false when there are any
+fn cmd_replay(path: &str, ruleset: Option<&str>) -> Result<bool> {
+    let params = match ruleset {
+        Some(ruleset) => Config::load_from(ruleset)?.chain,
+        None => Config::load()?.chain,
+    };
+    let blocks = load_export(path)?;
+    let failures = replay(&blocks, params)?;
+    for failure in &failures {
+        println!(
+            "block {} at height {} fails {}: {}",
+            failure.hash, failure.height, failure.rule, failure.reason
+        );
+    }
+    println!(
+        "replayed {} blocks, {} failed",
+        blocks.len(),
+        failures.len()
+    );
+    Ok(failures.is_empty())
+}
+
+/// cmd_verify_chain prints the first block of the stored chain that fails verification,
+/// cutting the chain before it with `truncate`; it returns false when there is one
+fn cmd_verify_chain(truncate: bool) -> Result<bool> {
+    let mut blockchain = Blockchain::new()?;
+    let (verified, failure) = verify_chain(&blockchain)?;
+    let failure = match failure {
+        Some(failure) => failure,
+        None => {
+            println!("verified {verified} blocks, the chain is intact");
+            return Ok(true);
+        }
+    };
+    println!(
+        "block {} at height {} fails {}: {}",
+        failure.hash, failure.height, failure.rule, failure.reason
+    );
+    println!("verified {verified} blocks before it");
+    if truncate {
+        let removed = blockchain.truncate(&failure.hash)?;
+        audit::record(
+            "cli",
+            "chain-truncated",
+            &format!("{removed} blocks from {}", failure.hash),
+        )?;
+        let utxo_set = UTXOSet { blockchain };
+        utxo_set.reindex()?;
+        println!(
+            "removed {removed} blocks, the tip is now at height {}",
+            utxo_set.blockchain.get_best_height()?
+        );
+    }
+    Ok(false)
+}
+
+fn cmd_utxo_diff(path_a: &str, path_b: &str) -> Result<()> {
+    let a = UTXOSnapshot::load(path_a)?;
+    let b = UTXOSnapshot::load(path_b)?;
+    println!(
+        "from height {} ({}) to {} ({})",
+        a.height, a.tip, b.height, b.tip
+    );
+
+    let diff = a.diff(&b);
+    println!("created outputs: ");
+    for (outpoint, out) in &diff.created {
+        println!("+ {outpoint} value: {}", out.value);
+    }
+    println!("spent outputs: ");
+    for (outpoint, out) in &diff.spent {
+        println!("- {outpoint} value: {}", out.value);
+    }
+    println!("balance changes: ");
+    for (address, change) in &diff.balance_changes {
+        println!("{address}: {change:+}");
+    }
+    Ok(())
+}
+
+fn cmd_list_addresses(with_labels: bool) -> Result<()> {
+    let wallets = Wallets::new()?;
+    let addresses = wallets.get_all_addresses();
+    println!("addresses: ");
+    for address in &addresses {
+        match wallets.get_label(address) {
+            Some(label) if with_labels => println!("{address} {label}"),
+            _ => println!("{address}"),
+        }
+    }
+    if with_labels {
+        println!("address book: ");
+        for (address, label) in wallets.get_labels() {
+            if !addresses.contains(&address) {
+                println!("{address} {label}");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cmd_create_blockchain() -> Result<()> {
+    let blockchain = Blockchain::create_blockchain()?;
+
+    let utxo_set = UTXOSet { blockchain };
+    utxo_set.reindex()?;
+    println!("create blockchain");
+    Ok(())
+}
+
+/// cmd_generate mines `count` blocks on top of the local chain paying `address`
+fn cmd_generate(count: u32, address: &str) -> Result<()> {
+    decode_address(address)?;
+    let blockchain = Blockchain::new()?;
+    let mut utxo_set = UTXOSet { blockchain };
+    for _ in 0..count {
+        let height = utxo_set.blockchain.get_best_height()? + 1;
+        let cbtx =
+            Transaction::new_coinbase(address.to_string(), format!("generate {}", height), height)?;
+        let block = utxo_set.blockchain.mine_block(vec![cbtx])?;
+        utxo_set.update(&block)?;
+        println!("{} {}", height, block.get_hash());
+    }
+    Ok(())
+}
+
+/// decode_address returns the key hash of an address given on the command line
+fn decode_address(address: &str) -> Result<Vec<u8>> {
+    match Address::decode(address) {
+        Ok(address) => Ok(address.body),
+        Err(_) => Err(CodedError::new(
+            ErrorCode::InvalidArgument,
+            format!("{} is not a valid address", address),
+        )
+        .with("address", address)
+        .into()),
+    }
+}
+
+/// wallet_of returns the wallet holding the key of `address`
+fn wallet_of<'a>(wallets: &'a Wallets, address: &str) -> Result<&'a Wallet> {
+    match wallets.get_wallet(address) {
+        Some(wallet) => Ok(wallet),
+        None => Err(CodedError::new(
+            ErrorCode::UnknownAddress,
+            format!("{} is not an address of the wallet", address),
+        )
+        .with("address", address)
+        .into()),
+    }
+}
+
+/// cmd_get_balance sums the outputs of `address` with at least `min_conf` confirmations
+fn cmd_get_balance(address: &str, min_conf: i32) -> Result<i32> {
+    let pub_key_hash = decode_address(address)?;
+    let blockchain = Blockchain::new()?;
+    let utxo_set = UTXOSet { blockchain };
+    let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
+
+    let mut balance = 0;
+    for utxo in utxos {
+        if utxo.confirmations >= min_conf {
+            balance += utxo.output.value;
+        }
+    }
+    Ok(balance)
+}
+
+fn cmd_get_transaction(txid: &TxId) -> Result<()> {
+    // a double spent wallet transaction may never be mined; an explorer has no wallet
+    if let Ok(wallets) = Wallets::new() {
+        for conflict in wallets.get_conflicts(Some(txid)) {
+            print_conflict(conflict);
+        }
+    }
+    let utxo_set = UTXOSet {
+        blockchain: Blockchain::new()?,
+    };
+    let blockchain = &utxo_set.blockchain;
+    let tx = match blockchain.find_transaction(txid) {
+        Ok(tx) => tx,
+        Err(_) => {
+            return Err(CodedError::new(
+                ErrorCode::NotFound,
+                format!("Transaction {txid} is not found"),
+            )
+            .with("txid", txid.to_string())
+            .into())
+        }
+    };
+    match blockchain.find_transaction_height(txid)? {
+        Some(height) => {
+            let hash = blockchain.get_block_hash(height)?.unwrap_or_default();
+            println!("block: {hash} at height {height}");
+            println!(
+                "confirmations: {}",
+                blockchain.get_best_height()? - height + 1
+            );
+        }
+        None => println!("confirmations: unknown, the pruned block holding it is not indexed"),
+    }
+    println!("fee: {}", blockchain.get_fee(&tx)?);
+    print_transaction(&utxo_set, &tx)
+}
+
+fn print_conflict(conflict: &Conflict) {
+    let place = match &conflict.block {
+        Some(block) => format!("mined in {}", block),
+        None => String::from("offered to the mempool"),
+    };
+    println!(
+        "CONFLICT: {} double spent by {} {} at {}",
+        conflict.txid, conflict.conflicting, place, conflict.seen
+    );
+}
+
+fn cmd_get_balances() -> Result<()> {
+    let mut addresses = Wallets::new()?.get_all_addresses();
+    addresses.sort();
+    let pub_key_hashes: Vec<Vec<u8>> = addresses
+        .iter()
+        .map(|address| Address::decode(address).unwrap().body)
+        .collect();
+    let blockchain = Blockchain::new()?;
+    let utxo_set = UTXOSet { blockchain };
+    let balances = utxo_set.get_balances(&pub_key_hashes)?;
+
+    let mut total = 0;
+    println!("{:<36} {:>12}", "address", "balance");
+    for (address, pub_key_hash) in addresses.iter().zip(&pub_key_hashes) {
+        let balance = balances[pub_key_hash];
+        total += balance;
+        println!("{address:<36} {balance:>12}");
+    }
+    println!("{:<36} {:>12}", "total", total);
+    Ok(())
+}
+
+fn cmd_history(address: &str) -> Result<()> {
+    let pub_key_hash = decode_address(address)?;
+    let blockchain = Blockchain::new()?;
+    let history = blockchain.find_transactions_for(&pub_key_hash);
+
+    let wallets = Wallets::new()?;
+    let late: Vec<TxId> = match wallets.get_created(address) {
+        Some(created) => wallets
+            .get_policy()
+            .late_payments(created, &history)
+            .iter()
+            .map(|entry| entry.txid)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    println!("history of {address}: ");
+    for entry in &history {
+        println!(
+            "height: {} timestamp: {} txid: {} received: {} sent: {} net: {}",
+            entry.height,
+            entry.timestamp,
+            entry.txid,
+            entry.received,
+            entry.sent,
+            entry.received - entry.sent
+        );
+        if late.contains(&entry.txid) {
+            println!("  warning: paid to the address after the key policy retired it");
+        }
+    }
+    Ok(())
+}
+
+fn cmd_get_cluster(address: &str) -> Result<()> {
+    let pub_key_hash = decode_address(address)?;
+    let blockchain = Blockchain::new()?;
+    let mut clusters = AddressClusters::build(&blockchain);
+    let utxo_set = UTXOSet { blockchain };
+    let cluster = clusters.cluster(&pub_key_hash, &utxo_set)?;
+
+    println!("cluster of {address}: ");
+    for address in &cluster.addresses {
+        println!("{address}");
+    }
+    println!("Total balance: {}", cluster.balance);
+    Ok(())
+}
+
+fn cmd_get_emission() -> Result<()> {
+    let blockchain = Blockchain::new()?;
+    let mut theoretical = 0;
+    let mut issued = 0;
+    for era in emission_report(&blockchain) {
+        println!(
+            "era {} (heights {}-{}): blocks: {} subsidy: {} theoretical: {} issued: {} fees: {} unclaimed: {} burned: {}",
+            era.era,
+            era.first_height,
+            era.last_height,
+            era.blocks,
+            era.subsidy,
+            era.theoretical,
+            era.issued,
+            era.fees,
+            era.unclaimed(),
+            era.burned
+        );
+        theoretical += era.theoretical;
+        issued += era.issued;
+    }
+
+    let utxo_set = UTXOSet { blockchain };
+    let burned = utxo_set.total_burned()?;
+    println!(
+        "Total theoretical: {theoretical} issued: {issued} burned: {burned} circulating: {}",
+        issued - burned
+    );
+    Ok(())
+}
+
+fn cmd_get_mempool_fee_histogram(node: &str) -> Result<()> {
+    let buckets = match Server::rpc(node, RpcRequest::MempoolFeeHistogram)? {
+        RpcResponse::FeeHistogram(buckets) => buckets,
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    };
+    println!("fee rate (per 1000 bytes): transactions, bytes");
+    for bucket in buckets {
+        println!(
+            ">= {}: {}, {}",
+            bucket.min_fee_rate, bucket.count, bucket.size
+        );
+    }
+    Ok(())
+}
+
+/// cmd_get_block_template prints the next block template of `node`, with `longpoll` the
+/// first one superseding the template of that long-poll id within `timeout` seconds
+fn cmd_get_block_template(node: &str, longpoll: Option<&String>, timeout: u64) -> Result<()> {
+    let response = match longpoll {
+        Some(longpoll_id) => Server::rpc_with_timeout(
+            node,
+            RpcRequest::LongPollBlockTemplate {
+                longpoll_id: longpoll_id.clone(),
+                timeout,
+            },
+            Duration::from_secs(timeout).min(MAX_LONGPOLL_TIMEOUT) + DEFAULT_READ_TIMEOUT,
+        )?,
+        None => Server::rpc(node, RpcRequest::BlockTemplate)?,
+    };
+    let template = match response {
+        RpcResponse::BlockTemplate(template) => template,
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    };
+    println!("prev block: {}", template.prev_block_hash);
+    println!("height: {}", template.height);
+    println!("fees: {}", template.fees);
+    println!("coinbase value: {}", template.coinbase_value);
+    println!("target: {}", template.target);
+    println!("extra nonce bytes: {}", template.extra_nonce_len);
+    println!("long-poll id: {}", template.longpoll_id);
+    println!(
+        "coinbase tree index: {:?}",
+        template.coinbase_branch.indices
+    );
+    println!("coinbase branch: ");
+    for lemma in &template.coinbase_branch.lemmas {
+        println!("{}", hex::encode(lemma));
+    }
+    println!("transactions: ");
+    for tx in &template.transactions {
+        println!("{} {}", tx.id, hex::encode(serialize(tx)?));
+    }
+    Ok(())
+}
+
+fn cmd_get_mining_info(node: &str, blocks: usize) -> Result<()> {
+    let info = match Server::rpc(node, RpcRequest::MiningInfo { blocks })? {
+        RpcResponse::MiningInfo(info) => info,
+        RpcResponse::Error(e) => return Err(e.into()),
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    };
+    println!("height: {}", info.height);
+    println!("target: {}", info.target);
+    println!("network hashrate: {:.2} hashes/s", info.network_hashrate);
+    println!("mining: {}", info.mining);
+    println!("local hashrate: {:.0} hashes/s", info.miner.hashrate);
+    println!("hashes tried: {}", info.miner.hashes);
+    println!("blocks mined: {}", info.miner.blocks_mined);
+    println!("fees earned: {}", info.miner.fees_earned);
+    Ok(())
+}
+
+/// cmd_submit_block hands `node` a block found by a standalone miner
+fn cmd_submit_block(node: &str, hex_block: &str) -> Result<()> {
+    let block: Block = deserialize(&hex::decode(hex_block.trim())?)?;
+    match Server::rpc(node, RpcRequest::SubmitBlock { block })? {
+        RpcResponse::BlockAccepted(hash) => println!("accepted block {hash}"),
+        RpcResponse::Error(e) => return Err(e.into()),
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    }
+    Ok(())
+}
+
+fn cmd_get_rejected_blocks(node: &str) -> Result<()> {
+    let rejections = match Server::rpc(node, RpcRequest::RejectedBlocks)? {
+        RpcResponse::RejectedBlocks(rejections) => rejections,
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    };
+    for rejection in rejections {
+        println!("{} height {}", rejection.rejected_at, rejection.height);
+        println!("  {}", rejection);
+    }
+    Ok(())
+}
+
+fn cmd_get_index_info(node: &str) -> Result<()> {
+    let info = match Server::rpc(node, RpcRequest::IndexInfo)? {
+        RpcResponse::IndexInfo(info) => info,
+        RpcResponse::Error(e) => return Err(e.into()),
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    };
+    println!("archive: {}", info.archive);
+    println!("txindex: {}", info.txindex);
+    println!("address index: {}", info.address_index);
+    println!("spent index: {}", info.spent_index);
+    println!("compact filters: {}", info.compact_filters);
+    println!("pruned: {}", info.pruned);
+    println!("height: {}", info.height);
+    Ok(())
+}
+
+fn cmd_get_peer_info(node: &str) -> Result<()> {
+    let (services, peers) = match Server::rpc(node, RpcRequest::PeerInfo)? {
+        RpcResponse::PeerInfo { services, peers } => (services, peers),
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    };
+    println!("services: {}", services);
+    for peer in peers {
+        let height = match peer.height {
+            Some(height) => height.to_string(),
+            None => String::from("unknown"),
+        };
+        println!("{} height {}: {}", peer.address, height, peer.services);
+    }
+    Ok(())
+}
+
+/// cmd_find_blocks is a light client scan: it picks `node` or one of its peers that
+/// serves compact filters and prints the blocks whose filter matches `address`
+fn cmd_find_blocks(node: &str, address: &str, from: i32) -> Result<()> {
+    let pub_key_hash = decode_address(address)?;
+    let (services, peers) = match Server::rpc(node, RpcRequest::PeerInfo)? {
+        RpcResponse::PeerInfo { services, peers } => (services, peers),
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    };
+    let mut servers = Vec::new();
+    if services.contains(Services::FILTERS) {
+        servers.push(node.to_string());
+    }
+    servers.extend(
+        peers
+            .into_iter()
+            .filter(|peer| peer.services.contains(Services::FILTERS))
+            .map(|peer| peer.address),
+    );
+    let server = match servers.first() {
+        Some(server) => server,
+        None => {
+            return Err(CodedError::new(
+                ErrorCode::NotFound,
+                format!("Neither {} nor its peers serve compact filters", node),
+            )
+            .with("node", node)
+            .into())
+        }
+    };
+    println!("scanning the filters of {}", server);
+
+    let mut start_height = from;
+    loop {
+        let request = RpcRequest::BlockFilters {
+            start_height,
+            count: usize::MAX,
+        };
+        let filters = match Server::rpc(server, request)? {
+            RpcResponse::BlockFilters(filters) => filters,
+            response => return Err(format_err!("Unexpected response: {:?}", response)),
+        };
+        let last = match filters.last() {
+            Some(last) => last.height,
+            None => break,
+        };
+        for entry in filters {
+            if entry
+                .filter
+                .matches_any(&entry.hash, std::slice::from_ref(&pub_key_hash))
+            {
+                println!("{} {}", entry.height, entry.hash);
+            }
+        }
+        start_height = last + 1;
+    }
+    Ok(())
+}
+
+/// cmd_sync_wallet asks `node` for the changes to `addresses` after block `since` and
+/// prints them the way a light wallet would apply them
+fn cmd_sync_wallet(node: &str, addresses: &[&String], since: Option<BlockHash>) -> Result<()> {
+    let pub_key_hashes = addresses
+        .iter()
+        .map(|address| decode_address(address))
+        .collect::<Result<_>>()?;
+    let request = RpcRequest::WalletChanges {
+        pub_key_hashes,
+        since,
+    };
+    let changes = match Server::rpc(node, request)? {
+        RpcResponse::WalletChanges(changes) => changes,
+        RpcResponse::Error(e) => return Err(e.into()),
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    };
+    if changes.rescan {
+        println!("the block synced to left the best chain, rescanned from the genesis block");
+    }
+    println!("tip: {} at height {}", changes.tip, changes.height);
+    println!("transactions: ");
+    for (height, tx) in &changes.transactions {
+        println!("{} {}", height, tx.id);
+    }
+    println!("new outputs: ");
+    for (txid, vout, output) in &changes.created {
+        println!(
+            "{}:{} {} to {}",
+            txid,
+            vout,
+            output.value,
+            output.address().unwrap_or_default()
+        );
+    }
+    println!("spent outputs: ");
+    for (txid, vout) in &changes.spent {
+        println!("{}:{}", txid, vout);
+    }
+    Ok(())
+}
+
+fn cmd_get_block_filter(hash: &BlockHash, addresses: &[String]) -> Result<()> {
+    let blockchain = Blockchain::new()?;
+    let filter = match blockchain.get_block_filter(hash)? {
+        Some(filter) => filter,
+        None => {
+            return Err(CodedError::new(
+                ErrorCode::NotFound,
+                format!("No filter of block {} is indexed", hash),
+            )
+            .with("block_hash", hash)
+            .into())
+        }
+    };
+    println!("items: {}", filter.n);
+    println!("filter: {}", hex::encode(&filter.data));
+    for address in addresses {
+        let pub_key_hash = decode_address(address)?;
+        let matched = filter.matches_any(hash, &[pub_key_hash]);
+        println!(
+            "{}: {}",
+            address,
+            if matched { "may match" } else { "no match" }
+        );
+    }
+    Ok(())
+}
+
+fn cmd_reload_config(node: &str) -> Result<()> {
+    let changes = match Server::rpc(node, RpcRequest::ReloadConfig)? {
+        RpcResponse::ConfigReloaded(changes) => changes,
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    };
+    if changes.is_empty() {
+        println!("no settings changed");
+    }
+    for change in changes {
+        println!("{}", change);
+    }
+    Ok(())
+}
+
+/// cmd_list_banned sends a ban list request and prints the bans in force afterwards
+fn cmd_list_banned(node: &str, request: RpcRequest) -> Result<()> {
+    let bans = match Server::rpc(node, request)? {
+        RpcResponse::Banned(bans) => bans,
+        RpcResponse::Error(e) => return Err(e.into()),
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    };
+    for ban in bans {
+        let until = match ban.banned_until {
+            Some(until) => until.to_string(),
+            None => String::from("forever"),
+        };
+        println!("{} until {}: {}", ban.range, until, ban.reason);
+    }
+    Ok(())
+}
+
+fn cmd_get_peer_events(node: &str, request: RpcRequest) -> Result<()> {
+    let events = match Server::rpc(node, request)? {
+        RpcResponse::PeerEvents(events) => events,
+        response => return Err(format_err!("Unexpected response: {:?}", response)),
+    };
+    for timed in events {
+        println!("{} {}", timed.timestamp, timed.event);
+    }
+    Ok(())
+}
+
+fn cmd_get_merkle_proof(block_hash: &BlockHash, txid: &TxId) -> Result<()> {
     let blockchain = Blockchain::new()?;
-    for block in blockchain.iter() {
-        println!("{:#?}", block);
+    let block = blockchain.get_block(block_hash)?;
+    let branch = match block.get_merkle_proof(txid)? {
+        Some(branch) => branch,
+        None => {
+            println!("Transaction {txid} is not in block {block_hash}");
+            return Ok(());
+        }
+    };
+    let tx = blockchain.find_transaction(txid)?;
+
+    println!("merkle root: {}", hex::encode(block.get_merkle_root()));
+    println!("indices: {:?}", branch.indices);
+    println!("lemmas: ");
+    for lemma in &branch.lemmas {
+        println!("{}", hex::encode(lemma));
+    }
+    println!(
+        "verified: {}",
+        tx.verify_inclusion(&branch, block.get_merkle_root())?
+    );
+    Ok(())
+}
+
+fn cmd_validate_tx(hex_tx: &str, trace: bool) -> Result<bool> {
+    let tx: Transaction = deserialize(&hex::decode(hex_tx.trim())?)?;
+    let utxo_set = UTXOSet {
+        blockchain: Blockchain::new()?,
+    };
+    let checks = trace_transaction(&tx, &utxo_set, &Mempool::new())?;
+    if trace {
+        for check in &checks {
+            println!("{}", check);
+        }
+    }
+    match first_failure(checks) {
+        Some(failure) => {
+            println!("transaction {} is invalid: {}", tx.id, failure);
+            Ok(false)
+        }
+        None => {
+            println!("transaction {} is valid", tx.id);
+            Ok(true)
+        }
+    }
+}
+
+fn print_block(utxo_set: &UTXOSet, block: &Block, verbose: bool) -> Result<()> {
+    println!("hash: {}", block.get_hash());
+    println!("prev_hash: {}", block.get_prev_hash());
+    println!("height: {}", block.get_height());
+    println!("timestamp: {}", block.get_timestamp());
+    println!("target: {}", block.get_target());
+    println!(
+        "chainwork: {}",
+        utxo_set.blockchain.get_chainwork(&block.header())?
+    );
+    println!("merkle_root: {}", hex::encode(block.get_merkle_root()));
+    println!("transactions: {}", block.get_transactions().len());
+    if verbose {
+        for tx in block.get_transactions() {
+            print_transaction(utxo_set, tx)?;
+        }
+    }
+    Ok(())
+}
+
+/// print_transaction lists the outputs a transaction spends, with their value and
+/// address when they are still found, and the outputs it pays
+fn print_transaction(utxo_set: &UTXOSet, tx: &Transaction) -> Result<()> {
+    println!("transaction {}", tx.id);
+    if tx.is_coinbase() {
+        println!("  coinbase");
+    }
+    for (vin, prev) in tx.vin.iter().zip(utxo_set.resolve_inputs(tx)?) {
+        match prev {
+            Some(out) => println!(
+                "  in  {}:{} {} from {}",
+                vin.txid,
+                vin.vout,
+                out.value,
+                out.address()
+                    .unwrap_or_else(|| String::from("burned output"))
+            ),
+            None => println!("  in  {}:{} unknown output", vin.txid, vin.vout),
+        }
+    }
+    for (vout, out) in tx.vout.iter().enumerate() {
+        match out.address() {
+            Some(address) => println!("  out {} {} to {}", vout, out.value, address),
+            None => println!("  out {} {} burned", vout, out.value),
+        }
+    }
+    if tx.lock_time != 0 {
+        println!("  lock_time {}", tx.lock_time);
+    }
+    if tx.expiry_height != 0 {
+        println!("  expiry_height {}", tx.expiry_height);
+    }
+    Ok(())
+}
+
+fn cmd_print_chain() -> Result<()> {
+    let utxo_set = UTXOSet {
+        blockchain: Blockchain::new()?,
+    };
+    for block in utxo_set.blockchain.walk().blocks() {
+        print_block(&utxo_set, &block, true)?;
+        println!();
     }
     Ok(())
 }
@@ -0,0 +1,607 @@
+use super::*;
+use crate::error::{CodedError, ErrorCode};
+use crate::{
+    block::{MAX_BLOCK_SIZE, TARGET_HEXS},
+    hash::BlockHash,
+    miner::TemplatePolicy,
+    server::{DEFAULT_READ_TIMEOUT, DEFAULT_WRITE_TIMEOUT},
+    u256::U256,
+};
+use crypto::{digest::Digest, sha2::Sha256};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, io::ErrorKind, sync::OnceLock};
+
+/// CONFIG_FILE holds the node settings as JSON; settings it leaves out take their defaults
+pub const CONFIG_FILE: &str = "config.json";
+/// MAIN_NETWORK keeps its data directly in data/, as it did before networks were named
+pub const MAIN_NETWORK: &str = "main";
+/// TEST_NETWORK is the public test network
+pub const TEST_NETWORK: &str = "test";
+/// REGTEST_NETWORK is a local development network whose blocks need no proof of work
+pub const REGTEST_NETWORK: &str = "regtest";
+/// NETWORK_MAGICS are the magic bytes of the well-known networks
+const NETWORK_MAGICS: [(&str, [u8; 4]); 3] = [
+    (MAIN_NETWORK, [0xf0, 0x0d, 0xb1, 0x0c]),
+    (TEST_NETWORK, [0x0b, 0x11, 0x09, 0x07]),
+    (REGTEST_NETWORK, [0xfa, 0xbf, 0xb5, 0xda]),
+];
+
+/// GENESIS_TIMESTAMP is the time of the genesis blocks, in milliseconds
+const GENESIS_TIMESTAMP: u64 = 1_735_689_600_000;
+/// GENESIS_BLOCKS are the nonces and hashes of the genesis blocks of the well-known networks
+const GENESIS_BLOCKS: [(&str, u64, &str); 3] = [
+    (
+        MAIN_NETWORK,
+        161221,
+        "0000c36590f319971310a6f5f17c1400eb1ccc4f3ccbcc707325ffd5a9633749",
+    ),
+    (
+        TEST_NETWORK,
+        61919,
+        "0000ef62c3f1830c84e3b8a14e14a2e07485437b0b05bc5dbfa6daf9a2242797",
+    ),
+    (
+        REGTEST_NETWORK,
+        0,
+        "4838974d7e5a796b8ba55a326194ce087425bc21d01b3e0501597f608fab3f28",
+    ),
+];
+
+/// NETWORK is the network the process works on, chosen once at startup
+static NETWORK: OnceLock<String> = OnceLock::new();
+/// MAX_RETARGET is the most the target moves at a retarget, either way
+const MAX_RETARGET: u64 = 4;
+
+/// Config is the content of the node configuration file
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub chain: ChainParams,
+    pub node: NodeSettings,
+    pub wallet: WalletSettings,
+}
+
+/// ChainParams are the consensus settings every node of a network must share
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ChainParams {
+    /// target_block_interval is the intended time between blocks, in seconds
+    pub target_block_interval: u64,
+    /// retarget_interval is the number of blocks between difficulty adjustments
+    pub retarget_interval: i32,
+    /// initial_target_hexs is the number of leading zero hex digits the first blocks need
+    pub initial_target_hexs: usize,
+    /// max_block_size is the most bytes a serialized block may take
+    pub max_block_size: usize,
+    /// checkpoints are block hashes by height that the chain must contain
+    pub checkpoints: BTreeMap<i32, BlockHash>,
+    /// network names the chain; a block database created for another one is refused
+    pub network: String,
+    /// genesis_hash is the hash the genesis block must have, any when unset
+    pub genesis_hash: Option<BlockHash>,
+    /// genesis_timestamp is the time of the genesis block, in milliseconds
+    pub genesis_timestamp: u64,
+    /// genesis_nonce is the nonce of the genesis block; when unset it is the first one
+    /// meeting the initial target
+    pub genesis_nonce: Option<u64>,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams::for_network(network())
+    }
+}
+
+/// WalletSettings configure how wallet transactions are signed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct WalletSettings {
+    /// remote_signer is the host:port of the service signing for keys the wallet file
+    /// only holds the public key of
+    pub remote_signer: Option<String>,
+    /// signer_timeout bounds, in seconds, a request to the remote signer
+    pub signer_timeout: u64,
+    /// fee_bump_after is how many blocks a wallet transaction waits in the node's
+    /// mempool before the node replaces it with one paying more; unset never bumps
+    pub fee_bump_after: Option<i32>,
+    /// fee_bump_step is the fee every replacement adds
+    pub fee_bump_step: i32,
+    /// fee_bump_max caps the fee a replacement pays
+    pub fee_bump_max: i32,
+}
+
+impl Default for WalletSettings {
+    fn default() -> Self {
+        WalletSettings {
+            remote_signer: None,
+            signer_timeout: 30,
+            fee_bump_after: None,
+            fee_bump_step: 1,
+            fee_bump_max: 5,
+        }
+    }
+}
+
+/// NodeSettings are the settings a running node can reload without a restart
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NodeSettings {
+    /// log_level is off, error, warn, info, debug or trace; unset leaves RUST_LOG in charge
+    pub log_level: Option<String>,
+    pub replace_by_fee: bool,
+    pub max_block_txs: usize,
+    /// min_fee_rate is the lowest fee, in coins per 1000 bytes, the miner includes
+    pub min_fee_rate: u64,
+    /// mining_threads is how many threads search the proof of work of a block
+    pub mining_threads: usize,
+    /// read_timeout and write_timeout bound, in seconds, the transfer of one message
+    pub read_timeout: u64,
+    pub write_timeout: u64,
+    pub seeds: Vec<String>,
+    /// dns_seeds are host names, with an optional port, resolving to nodes to connect to
+    pub dns_seeds: Vec<String>,
+    /// min_peers is the peer count below which the seeds are queried again
+    pub min_peers: usize,
+    /// bind is the host:port the node listens on, localhost and its port when unset
+    pub bind: Option<String>,
+    /// external_address is the host:port other machines reach the node at; unset, the
+    /// node advertises the address most peers reached it at
+    pub external_address: Option<String>,
+    /// prune keeps only the bodies of this many most recent blocks; unset keeps them all
+    pub prune: Option<i32>,
+    /// txindex indexes the block of every transaction, so looking one up does not scan
+    /// the chain; it takes effect when the chain is opened
+    pub txindex: bool,
+    /// archive enables the transaction, address and spent indexes and the compact block
+    /// filters together and forbids pruning; it takes effect when the chain is opened
+    pub archive: bool,
+    /// blocks_only asks peers not to relay unconfirmed transactions to the node
+    pub blocks_only: bool,
+    /// explorer disables the wallet: the node only syncs and serves the chain, its
+    /// indexes and the read-only APIs, and no wallet database is opened
+    pub explorer: bool,
+    /// max_inbound and max_outbound cap the peers handshaken after they connected to
+    /// the node and after the node connected to them
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+    /// max_connections caps the connections handled at once, max_connections_per_ip
+    /// those from a single address
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    /// message_rate is how many messages a second an address may send on average, in
+    /// bursts of up to message_burst
+    pub message_rate: u32,
+    pub message_burst: u32,
+    /// paranoid checks every invariant of every peer message and refuses trailing bytes
+    pub paranoid: bool,
+    /// rpc_token admits the admin RPC requests of other hosts that present it; unset,
+    /// they are only served over the loopback interface
+    pub rpc_token: Option<String>,
+}
+
+impl Default for NodeSettings {
+    fn default() -> Self {
+        let policy = TemplatePolicy::default();
+        NodeSettings {
+            log_level: None,
+            replace_by_fee: false,
+            max_block_txs: policy.max_transactions,
+            min_fee_rate: policy.min_fee_rate,
+            mining_threads: 1,
+            read_timeout: DEFAULT_READ_TIMEOUT.as_secs(),
+            write_timeout: DEFAULT_WRITE_TIMEOUT.as_secs(),
+            seeds: Vec::new(),
+            dns_seeds: Vec::new(),
+            min_peers: 2,
+            bind: None,
+            external_address: None,
+            prune: None,
+            txindex: true,
+            archive: false,
+            blocks_only: false,
+            explorer: false,
+            max_inbound: 117,
+            max_outbound: 8,
+            max_connections: 256,
+            max_connections_per_ip: 64,
+            message_rate: 200,
+            message_burst: 1000,
+            paranoid: false,
+            rpc_token: None,
+        }
+    }
+}
+
+impl NodeSettings {
+    /// changes describes every setting that differs in `new`
+    pub fn changes(&self, new: &NodeSettings) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut compare = |name: &str, old: String, new: String| {
+            if old != new {
+                changes.push(format!("{}: {} -> {}", name, old, new));
+            }
+        };
+        compare(
+            "log_level",
+            format!("{:?}", self.log_level),
+            format!("{:?}", new.log_level),
+        );
+        compare(
+            "replace_by_fee",
+            self.replace_by_fee.to_string(),
+            new.replace_by_fee.to_string(),
+        );
+        compare(
+            "max_block_txs",
+            self.max_block_txs.to_string(),
+            new.max_block_txs.to_string(),
+        );
+        compare(
+            "min_fee_rate",
+            self.min_fee_rate.to_string(),
+            new.min_fee_rate.to_string(),
+        );
+        compare(
+            "mining_threads",
+            self.mining_threads.to_string(),
+            new.mining_threads.to_string(),
+        );
+        compare(
+            "read_timeout",
+            self.read_timeout.to_string(),
+            new.read_timeout.to_string(),
+        );
+        compare(
+            "write_timeout",
+            self.write_timeout.to_string(),
+            new.write_timeout.to_string(),
+        );
+        compare(
+            "seeds",
+            format!("{:?}", self.seeds),
+            format!("{:?}", new.seeds),
+        );
+        compare(
+            "dns_seeds",
+            format!("{:?}", self.dns_seeds),
+            format!("{:?}", new.dns_seeds),
+        );
+        compare(
+            "min_peers",
+            self.min_peers.to_string(),
+            new.min_peers.to_string(),
+        );
+        compare(
+            "bind",
+            format!("{:?}", self.bind),
+            format!("{:?}", new.bind),
+        );
+        compare(
+            "external_address",
+            format!("{:?}", self.external_address),
+            format!("{:?}", new.external_address),
+        );
+        compare(
+            "prune",
+            format!("{:?}", self.prune),
+            format!("{:?}", new.prune),
+        );
+        compare("txindex", self.txindex.to_string(), new.txindex.to_string());
+        compare("archive", self.archive.to_string(), new.archive.to_string());
+        compare(
+            "blocks_only",
+            self.blocks_only.to_string(),
+            new.blocks_only.to_string(),
+        );
+        compare(
+            "explorer",
+            self.explorer.to_string(),
+            new.explorer.to_string(),
+        );
+        compare(
+            "max_inbound",
+            self.max_inbound.to_string(),
+            new.max_inbound.to_string(),
+        );
+        compare(
+            "max_outbound",
+            self.max_outbound.to_string(),
+            new.max_outbound.to_string(),
+        );
+        compare(
+            "max_connections",
+            self.max_connections.to_string(),
+            new.max_connections.to_string(),
+        );
+        compare(
+            "max_connections_per_ip",
+            self.max_connections_per_ip.to_string(),
+            new.max_connections_per_ip.to_string(),
+        );
+        compare(
+            "message_rate",
+            self.message_rate.to_string(),
+            new.message_rate.to_string(),
+        );
+        compare(
+            "message_burst",
+            self.message_burst.to_string(),
+            new.message_burst.to_string(),
+        );
+        compare(
+            "paranoid",
+            self.paranoid.to_string(),
+            new.paranoid.to_string(),
+        );
+        // the token itself stays out of the logs
+        if self.rpc_token != new.rpc_token {
+            compare(
+                "rpc_token",
+                String::from(if self.rpc_token.is_some() {
+                    "set"
+                } else {
+                    "unset"
+                }),
+                String::from(if new.rpc_token.is_some() {
+                    "changed"
+                } else {
+                    "unset"
+                }),
+            );
+        }
+        changes
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        Config::load_from(&data_path(CONFIG_FILE))
+    }
+
+    pub fn load_from(path: &str) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(data) => {
+                let config: Config = serde_json::from_str(&data)?;
+                config.chain.check()?;
+                Ok(config)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl ChainParams {
+    /// for_network returns the default parameters of `network`. Regtest blocks meet
+    /// their target with any hash and the difficulty never moves, so they are mined
+    /// instantly. The genesis blocks of the well-known networks are fixed.
+    pub fn for_network(network: &str) -> Self {
+        let genesis = GENESIS_BLOCKS.iter().find(|(name, _, _)| *name == network);
+        let params = ChainParams {
+            target_block_interval: 10,
+            retarget_interval: 100,
+            initial_target_hexs: TARGET_HEXS,
+            max_block_size: MAX_BLOCK_SIZE,
+            checkpoints: BTreeMap::new(),
+            network: network.to_string(),
+            genesis_hash: genesis.map(|(_, _, hash)| hash.parse().expect("invalid genesis hash")),
+            genesis_timestamp: GENESIS_TIMESTAMP,
+            genesis_nonce: genesis.map(|(_, nonce, _)| *nonce),
+        };
+        if network == REGTEST_NETWORK {
+            return ChainParams {
+                retarget_interval: i32::MAX,
+                initial_target_hexs: 0,
+                ..params
+            };
+        }
+        params
+    }
+
+    /// magic returns the bytes opening every P2P message of the network, and recorded in
+    /// its genesis block. Networks other than the well-known ones take the first bytes of
+    /// the hash of their name.
+    pub fn magic(&self) -> [u8; 4] {
+        if let Some((_, magic)) = NETWORK_MAGICS
+            .iter()
+            .find(|(network, _)| *network == self.network)
+        {
+            return *magic;
+        }
+        let mut hasher = Sha256::new();
+        hasher.input_str(&self.network);
+        let mut digest = [0; 32];
+        hasher.result(&mut digest);
+        let mut magic = [0; 4];
+        magic.copy_from_slice(&digest[..4]);
+        magic
+    }
+
+    /// check refuses parameters the difficulty adjustment can not work with
+    pub fn check(&self) -> Result<()> {
+        if self.target_block_interval == 0 || self.retarget_interval <= 0 {
+            return Err(CodedError::new(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "Block and retarget intervals must be positive, got {} and {}",
+                    self.target_block_interval, self.retarget_interval
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// initial_target is the target of the first blocks: the largest hash starting
+    /// with initial_target_hexs zero hex digits
+    pub fn initial_target(&self) -> U256 {
+        U256::from_hexs(self.initial_target_hexs)
+    }
+
+    /// retarget_window is the number of blocks between retargets
+    fn retarget_window(&self) -> i32 {
+        self.retarget_interval.max(2)
+    }
+
+    /// retarget_start returns the height of the first block of the retarget window
+    /// closed by the block at `height`, if the block following it is retargeted
+    pub fn retarget_start(&self, height: i32) -> Option<i32> {
+        let window = i64::from(self.retarget_window());
+        let next = i64::from(height) + 1;
+        if next < window || next % window != 0 {
+            return None;
+        }
+        i32::try_from(next - window).ok()
+    }
+
+    /// retarget returns the target following a window of blocks mined at `target` whose
+    /// first and last blocks have the millisecond timestamps `first` and `last`. The
+    /// target is scaled by the time the window took over the intended time, by at most
+    /// MAX_RETARGET either way, and never eases past one leading zero hex digit unless
+    /// the initial target is easier.
+    pub fn retarget(&self, target: U256, first: u128, last: u128) -> U256 {
+        let limit = self.initial_target().max(U256::from_hexs(1));
+        let window = self.retarget_window() as u128;
+        let expected = (window - 1) * self.target_block_interval as u128 * 1000;
+        let expected = u64::try_from(expected).unwrap_or(u64::MAX).max(1);
+        let actual = u64::try_from(last.saturating_sub(first))
+            .unwrap_or(u64::MAX)
+            .clamp(
+                (expected / MAX_RETARGET).max(1),
+                expected.saturating_mul(MAX_RETARGET),
+            );
+        target.mul_div(actual, expected).min(limit)
+    }
+}
+
+/// set_network selects the network whose data directory and default chain parameters
+/// the process uses; it can only be chosen once
+pub fn set_network(network: &str) -> Result<()> {
+    if network.is_empty()
+        || !network
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+    {
+        return Err(CodedError::new(
+            ErrorCode::InvalidArgument,
+            format!("Invalid network name: {}", network),
+        )
+        .with("network", network)
+        .into());
+    }
+    if NETWORK.set(network.to_string()).is_err() && self::network() != network {
+        return Err(format_err!("The network is already {}", self::network()));
+    }
+    Ok(())
+}
+
+/// network is the network chosen with set_network, main by default
+pub fn network() -> &'static str {
+    NETWORK
+        .get()
+        .map_or(MAIN_NETWORK, |network| network.as_str())
+}
+
+/// data_path is the path of `name` in the data directory of the network: data/ for
+/// the main network, data/<network>/ for the others
+pub fn data_path(name: &str) -> String {
+    match network() {
+        MAIN_NETWORK => format!("data/{}", name),
+        network => format!("data/{}/{}", network, name),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_target() {
+        let params = ChainParams {
+            target_block_interval: 5,
+            retarget_interval: 3,
+            initial_target_hexs: 4,
+            ..ChainParams::default()
+        };
+        let initial = U256::from_hexs(4);
+
+        // windows of three blocks close at heights 2, 5, 8...
+        assert_eq!(params.retarget_start(1), None);
+        assert_eq!(params.retarget_start(2), Some(0));
+        assert_eq!(params.retarget_start(3), None);
+        assert_eq!(params.retarget_start(5), Some(3));
+        // the window took 2s instead of 10s, which is capped at a 4x harder target,
+        // then 10s, then 12s
+        let harder = initial.mul_div(1, 4);
+        assert_eq!(params.retarget(initial, 0, 2000), harder);
+        assert_eq!(params.retarget(harder, 3000, 13000), harder);
+        assert_eq!(
+            params.retarget(harder, 14000, 26000),
+            harder.mul_div(12, 10)
+        );
+        // the target never eases past one leading zero hex digit
+        let limit = U256::from_hexs(1);
+        assert_eq!(params.retarget(limit, 0, 1_000_000), limit);
+        // nor drops to zero, and intervals of zero are refused when loaded
+        let zero = ChainParams {
+            target_block_interval: 0,
+            ..params.clone()
+        };
+        assert_ne!(zero.retarget(initial, 5, 5), U256::from_u64(0));
+        assert!(params.check().is_ok());
+        assert!(zero.check().is_err());
+        let never = ChainParams {
+            retarget_interval: 0,
+            ..params.clone()
+        };
+        assert!(never.check().is_err());
+
+        let checkpoint = BlockHash::digest(b"checkpoint");
+        let config: Config = serde_json::from_str(&format!(
+            r#"{{"chain": {{"target_block_interval": 300, "checkpoints": {{"10": "{}"}}}}}}"#,
+            checkpoint
+        ))
+        .unwrap();
+        assert_eq!(config.chain.target_block_interval, 300);
+        assert_eq!(config.chain.checkpoints[&10], checkpoint);
+        assert_eq!(config.chain.retarget_interval, 100);
+        assert_eq!(config.node, NodeSettings::default());
+    }
+
+    #[test]
+    fn test_settings_changes() {
+        let old = NodeSettings::default();
+        let new = NodeSettings {
+            log_level: Some(String::from("info")),
+            min_fee_rate: 5,
+            ..NodeSettings::default()
+        };
+        assert_eq!(
+            old.changes(&new),
+            vec![
+                String::from(r#"log_level: None -> Some("info")"#),
+                String::from("min_fee_rate: 0 -> 5"),
+            ]
+        );
+        assert!(new.changes(&new).is_empty());
+    }
+
+    #[test]
+    fn test_regtest_params() {
+        let params = ChainParams::for_network(REGTEST_NETWORK);
+        assert_eq!(params.network, REGTEST_NETWORK);
+        assert_eq!(params.initial_target_hexs, 0);
+        assert_eq!(params.initial_target(), U256::MAX);
+        assert_eq!(params.retarget_start(999), None);
+        assert_eq!(ChainParams::for_network("main"), ChainParams::default());
+        assert!(set_network("../x").is_err());
+
+        let magics: HashSet<[u8; 4]> = [MAIN_NETWORK, TEST_NETWORK, REGTEST_NETWORK, "private"]
+            .iter()
+            .map(|network| ChainParams::for_network(network).magic())
+            .collect();
+        assert_eq!(magics.len(), 4);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::error::{CodedError, ErrorCode, Result};
+use bincode::Options;
+use serde::de::DeserializeOwned;
+
+/// MAX_MESSAGE_SIZE bounds the bytes a peer may send in one message
+pub const MAX_MESSAGE_SIZE: usize = 8 << 20;
+/// MAX_ADDRESS_LEN bounds the host:port a peer names a node with
+pub const MAX_ADDRESS_LEN: usize = 255;
+
+/// decode_untrusted deserializes bytes received from a peer. bincode may not read more
+/// than `bytes` holds, so a forged length prefix fails instead of allocating; `strict`
+/// also refuses bytes left over after the value.
+pub fn decode_untrusted<T: DeserializeOwned>(bytes: &[u8], strict: bool) -> Result<T> {
+    let options = bincode::options()
+        .with_fixint_encoding()
+        .with_limit(bytes.len() as u64);
+    let value = if strict {
+        options.reject_trailing_bytes().deserialize(bytes)
+    } else {
+        options.allow_trailing_bytes().deserialize(bytes)
+    };
+    value.map_err(|e| malformed(format!("undecodable payload: {}", e)))
+}
+
+/// malformed builds the error for a message breaking an invariant
+pub fn malformed(message: String) -> failure::Error {
+    CodedError::new(ErrorCode::InvalidArgument, message).into()
+}
+
+/// check_address refuses a node address that is not a short host:port
+pub fn check_address(address: &str) -> Result<()> {
+    let valid = address.len() <= MAX_ADDRESS_LEN
+        && match address.rsplit_once(':') {
+            Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+            None => false,
+        };
+    if !valid {
+        return Err(malformed(format!(
+            "invalid node address {:?}",
+            address.chars().take(MAX_ADDRESS_LEN).collect::<String>()
+        )));
+    }
+    Ok(())
+}
+
+/// check_count refuses a list of `what` with more than `max` entries
+pub fn check_count(what: &str, count: usize, max: usize) -> Result<()> {
+    if count > max {
+        return Err(malformed(format!(
+            "{} {}, at most {} allowed",
+            count, what, max
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bincode::serialize;
+
+    #[test]
+    fn test_decode_untrusted() {
+        let value = (String::from("node"), vec![1u32, 2, 3]);
+        let bytes = serialize(&value).unwrap();
+        let decoded: (String, Vec<u32>) = decode_untrusted(&bytes, true).unwrap();
+        assert_eq!(decoded, value);
+
+        // a length prefix claiming more than the message holds
+        let mut forged = serialize(&vec![0u8; 4]).unwrap();
+        forged[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(decode_untrusted::<Vec<u8>>(&forged, false).is_err());
+        assert!(decode_untrusted::<(String, Vec<u32>)>(&bytes[..bytes.len() - 1], false).is_err());
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(decode_untrusted::<(String, Vec<u32>)>(&trailing, false).is_ok());
+        assert!(decode_untrusted::<(String, Vec<u32>)>(&trailing, true).is_err());
+
+        assert!(check_address("localhost:3000").is_ok());
+        assert!(check_address("localhost").is_err());
+        assert!(check_address(":3000").is_err());
+        assert!(check_address(&format!("{}:1", "a".repeat(MAX_ADDRESS_LEN))).is_err());
+        assert!(check_count("headers", 3, 2).is_err());
+    }
+}
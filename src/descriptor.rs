@@ -0,0 +1,283 @@
+use super::*;
+use crate::{
+    script::{redeem_hash, LockScript, MAX_VAULT_DELAY, PUB_KEY_LEN},
+    transaction::TXOutput,
+    wallets::*,
+};
+use failure::format_err;
+use std::{fmt, str::FromStr};
+
+/// Descriptor describes how a set of wallet addresses is derived:
+///
+/// * `pk(<public key hex>)` a single key
+/// * `hd(<seed hex>/44'/0'/0'/0'/*')` the hardened children of an HD seed
+/// * `multi(<required>,<public key hex>,...)` an m-of-n multisig
+/// * `vault(<delay>,<hot key hex>,<recovery key hex>)` a vault whose funds leave through
+///   a `delay` block unvault the recovery key can claw back, its keys held concatenated
+#[derive(Debug, Clone, PartialEq)]
+pub enum Descriptor {
+    Key(Vec<u8>),
+    Hd { seed: Vec<u8>, path: Vec<u32> },
+    Multi { required: usize, keys: Vec<Vec<u8>> },
+    Vault { delay: i32, keys: Vec<u8> },
+}
+
+impl Descriptor {
+    /// wallets derives the first `count` spendable wallets of an HD descriptor
+    pub fn wallets(&self, count: u32) -> Result<Vec<Wallet>> {
+        match self {
+            Descriptor::Hd { seed, path } => Ok((0..count)
+                .map(|index| Wallet::derive(seed, path, index))
+                .collect()),
+            _ => Err(format_err!("Only hd descriptors hold private keys")),
+        }
+    }
+
+    /// addresses lists the first `count` addresses described by the descriptor
+    pub fn addresses(&self, count: u32) -> Result<Vec<String>> {
+        match self {
+            Descriptor::Key(public_key) => {
+                let mut pub_key_hash = public_key.clone();
+                hash_pub_key(&mut pub_key_hash);
+                Ok(vec![hash_to_address(pub_key_hash)])
+            }
+            Descriptor::Hd { .. } => Ok(self
+                .wallets(count)?
+                .iter()
+                .map(|w| w.get_address())
+                .collect()),
+            Descriptor::Multi { keys, .. } => {
+                Ok(vec![hash_to_address(redeem_hash(&keys.concat()))])
+            }
+            Descriptor::Vault { keys, .. } => Ok(vec![hash_to_address(redeem_hash(keys))]),
+        }
+    }
+
+    /// output pays `value` to a single key, multisig or vault descriptor
+    pub fn output(&self, value: i32) -> Result<TXOutput> {
+        match self {
+            Descriptor::Key(_) => TXOutput::new(value, self.addresses(1)?.remove(0)),
+            Descriptor::Hd { .. } => Err(format_err!(
+                "hd descriptors describe many addresses, pay one of them instead"
+            )),
+            Descriptor::Multi { required, keys } => Ok(TXOutput {
+                value,
+                pub_key_hash: redeem_hash(&keys.concat()),
+                lock: LockScript::MultiSig {
+                    m: *required as u8,
+                    n: keys.len() as u8,
+                },
+            }),
+            Descriptor::Vault { delay, keys } => Ok(TXOutput {
+                value,
+                pub_key_hash: redeem_hash(keys),
+                lock: LockScript::Vault { delay: *delay },
+            }),
+        }
+    }
+}
+
+impl FromStr for Descriptor {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (kind, body) = match s.split_once('(') {
+            Some((kind, rest)) if rest.ends_with(')') => (kind, &rest[..rest.len() - 1]),
+            _ => return Err(format_err!("Invalid descriptor: {}", s)),
+        };
+
+        match kind {
+            "pk" => Ok(Descriptor::Key(parse_key(body)?)),
+            "hd" => {
+                let mut parts = body.split('/');
+                let seed = hex::decode(parts.next().unwrap_or_default())?;
+                if seed.len() < 16 {
+                    return Err(format_err!("HD seed must be at least 16 bytes"));
+                }
+                let steps: Vec<&str> = parts.collect();
+                match steps.split_last() {
+                    Some((last, path)) if *last == "*'" || *last == "*h" => Ok(Descriptor::Hd {
+                        seed,
+                        path: path
+                            .iter()
+                            .map(|p| parse_hardened(p))
+                            .collect::<Result<_>>()?,
+                    }),
+                    _ => Err(format_err!(
+                        "hd descriptor path must end with a hardened wildcard `*'`"
+                    )),
+                }
+            }
+            "multi" => {
+                let mut parts = body.split(',');
+                let required: usize = parts.next().unwrap_or_default().parse()?;
+                let keys: Vec<Vec<u8>> = parts.map(parse_key).collect::<Result<_>>()?;
+                if keys.len() > u8::MAX as usize {
+                    return Err(format_err!(
+                        "multi descriptor takes at most {} keys",
+                        u8::MAX
+                    ));
+                }
+                if required == 0 || required > keys.len() {
+                    return Err(format_err!(
+                        "multi descriptor requires between 1 and {} signatures",
+                        keys.len()
+                    ));
+                }
+                Ok(Descriptor::Multi { required, keys })
+            }
+            "vault" => {
+                let parts: Vec<&str> = body.split(',').collect();
+                if parts.len() != 3 {
+                    return Err(format_err!(
+                        "vault descriptor takes a delay, a hot key and a recovery key"
+                    ));
+                }
+                let delay: i32 = parts[0].trim().parse()?;
+                if !(1..=MAX_VAULT_DELAY).contains(&delay) {
+                    return Err(format_err!(
+                        "vault delay must be between 1 and {} blocks",
+                        MAX_VAULT_DELAY
+                    ));
+                }
+                let (hot, recovery) = (parse_key(parts[1])?, parse_key(parts[2])?);
+                if hot == recovery {
+                    return Err(format_err!("vault hot and recovery keys must differ"));
+                }
+                Ok(Descriptor::Vault {
+                    delay,
+                    keys: [hot, recovery].concat(),
+                })
+            }
+            _ => Err(format_err!("Unknown descriptor type: {}", kind)),
+        }
+    }
+}
+
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Descriptor::Key(public_key) => write!(f, "pk({})", hex::encode(public_key)),
+            Descriptor::Hd { seed, path } => {
+                write!(f, "hd({}", hex::encode(seed))?;
+                for step in path {
+                    write!(f, "/{}'", step)?;
+                }
+                write!(f, "/*')")
+            }
+            Descriptor::Multi { required, keys } => {
+                write!(f, "multi({}", required)?;
+                for key in keys {
+                    write!(f, ",{}", hex::encode(key))?;
+                }
+                write!(f, ")")
+            }
+            Descriptor::Vault { delay, keys } => {
+                let (hot, recovery) = keys.split_at(PUB_KEY_LEN);
+                write!(
+                    f,
+                    "vault({},{},{})",
+                    delay,
+                    hex::encode(hot),
+                    hex::encode(recovery)
+                )
+            }
+        }
+    }
+}
+
+fn parse_key(key: &str) -> Result<Vec<u8>> {
+    let key = hex::decode(key.trim())?;
+    if key.len() != 32 {
+        return Err(format_err!(
+            "Public keys must be 32 bytes, got {}",
+            key.len()
+        ));
+    }
+    Ok(key)
+}
+
+/// parse_hardened reads a path step such as `44'` or `44h`; ed25519 keys only derive hardened children
+fn parse_hardened(step: &str) -> Result<u32> {
+    match step.strip_suffix('\'').or_else(|| step.strip_suffix('h')) {
+        Some(index) => Ok(index.parse()?),
+        None => Err(format_err!("Path step {} must be hardened", step)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_descriptors() {
+        let seed = vec![7; 64];
+        let hd: Descriptor = format!("hd({}/44'/0'/0h/0'/*')", hex::encode(&seed))
+            .parse()
+            .unwrap();
+        assert_eq!(
+            hd,
+            Descriptor::Hd {
+                seed: seed.clone(),
+                path: vec![44, 0, 0, 0]
+            }
+        );
+        assert_eq!(hd.to_string().parse::<Descriptor>().unwrap(), hd);
+
+        let wallets = hd.wallets(2).unwrap();
+        assert_eq!(hd.addresses(2).unwrap()[1], wallets[1].get_address());
+        let key = Descriptor::Key(wallets[0].public_key.clone());
+        assert_eq!(key.to_string().parse::<Descriptor>().unwrap(), key);
+        assert_eq!(key.addresses(5).unwrap(), vec![wallets[0].get_address()]);
+
+        let multi = format!(
+            "multi(2,{},{})",
+            hex::encode(&wallets[0].public_key),
+            hex::encode(&wallets[1].public_key)
+        );
+        let multi_descriptor = multi.parse::<Descriptor>().unwrap();
+        assert_eq!(multi_descriptor.to_string(), multi);
+        let out = multi_descriptor.output(3).unwrap();
+        assert_eq!(out.lock, LockScript::MultiSig { m: 2, n: 2 });
+        assert_eq!(
+            multi_descriptor.addresses(1).unwrap(),
+            vec![hash_to_address(out.pub_key_hash)]
+        );
+
+        assert!(format!("hd({}/44/*')", hex::encode(&seed))
+            .parse::<Descriptor>()
+            .is_err());
+        assert!(format!("hd({}/44'/0')", hex::encode(&seed))
+            .parse::<Descriptor>()
+            .is_err());
+        assert!("multi(3,00)".parse::<Descriptor>().is_err());
+
+        let vault = format!(
+            "vault(10,{},{})",
+            hex::encode(&wallets[0].public_key),
+            hex::encode(&wallets[1].public_key)
+        );
+        let vault_descriptor = vault.parse::<Descriptor>().unwrap();
+        assert_eq!(vault_descriptor.to_string(), vault);
+        let out = vault_descriptor.output(3).unwrap();
+        assert_eq!(out.lock, LockScript::Vault { delay: 10 });
+        assert_eq!(
+            vault_descriptor.addresses(1).unwrap(),
+            vec![hash_to_address(out.pub_key_hash)]
+        );
+        let same = hex::encode(&wallets[0].public_key);
+        assert!(format!("vault(10,{},{})", same, same)
+            .parse::<Descriptor>()
+            .is_err());
+        assert!(vault
+            .replace("vault(10", "vault(0")
+            .parse::<Descriptor>()
+            .is_err());
+        assert!(vault
+            .replace("vault(10", &format!("vault({}", MAX_VAULT_DELAY + 1))
+            .parse::<Descriptor>()
+            .is_err());
+        assert!("sh(00)".parse::<Descriptor>().is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use crate::error::Result;
+use log::{info, warn};
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, ToSocketAddrs},
+};
+
+/// resolve_seed looks up the nodes behind DNS seed `seed`, a host name with an optional
+/// port; without one the nodes are taken to listen on `default_port`
+pub fn resolve_seed(seed: &str, default_port: &str) -> Result<Vec<String>> {
+    let host = if seed.contains(':') {
+        seed.to_string()
+    } else {
+        format!("{}:{}", seed, default_port)
+    };
+    Ok(host
+        .to_socket_addrs()?
+        .map(|addr| addr.to_string())
+        .collect())
+}
+
+/// is_local tells whether `addr`, a host with a port, only reaches this machine
+pub fn is_local(addr: &str) -> bool {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback() || ip.is_unspecified(),
+        Err(_) => host == "localhost",
+    }
+}
+
+/// discover merges the static seed nodes with the nodes every DNS seed resolves to. A
+/// DNS seed that fails to resolve is logged and skipped.
+pub fn discover(static_seeds: &[String], dns_seeds: &[String], default_port: &str) -> Vec<String> {
+    let mut nodes: BTreeSet<String> = static_seeds.iter().cloned().collect();
+    for seed in dns_seeds {
+        match resolve_seed(seed, default_port) {
+            Ok(found) => {
+                info!("DNS seed {} returned {} nodes", seed, found.len());
+                nodes.extend(found);
+            }
+            Err(e) => warn!("failed to resolve DNS seed {}: {}", seed, e),
+        }
+    }
+    nodes.into_iter().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_discover() {
+        let mut found = resolve_seed("127.0.0.1", "3000").unwrap();
+        assert_eq!(found, vec!["127.0.0.1:3000"]);
+        found = resolve_seed("127.0.0.1:3005", "3000").unwrap();
+        assert_eq!(found, vec!["127.0.0.1:3005"]);
+        assert!(resolve_seed("no such seed.invalid", "3000").is_err());
+
+        let statics = vec![
+            String::from("localhost:3001"),
+            String::from("127.0.0.1:3002"),
+        ];
+        let dns = vec![
+            String::from("127.0.0.1:3002"),
+            String::from("bad seed.invalid"),
+        ];
+        assert_eq!(
+            discover(&statics, &dns, "3000"),
+            vec!["127.0.0.1:3002", "localhost:3001"]
+        );
+
+        assert!(is_local("localhost:3000"));
+        assert!(is_local("127.0.0.1:3000"));
+        assert!(is_local("[::1]:3000"));
+        assert!(!is_local("203.0.113.5:3000"));
+        assert!(!is_local("seed.example.org:3000"));
+    }
+}
@@ -1 +1,140 @@
+use crate::{
+    block::BlockRejection,
+    blockchain::{BlockDamaged, BlockPruned, DbCorruption},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, io, num::ParseIntError};
+
 pub type Result<T> = std::result::Result<T, failure::Error>;
+
+/// ErrorCode is the stable kind of a failure, which scripts and frontends branch on
+/// instead of the message, whose wording may change or be translated
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidArgument,
+    UnknownAddress,
+    InsufficientFunds,
+    NotFound,
+    TransactionRejected,
+    BlockRejected,
+    NodeSyncing,
+    NodeUnreachable,
+    WrongNetwork,
+    DbCorruption,
+    Unauthorized,
+    Internal,
+}
+
+/// CodedError is a failure with its code and the context fields, such as the address
+/// or amount involved, that the message is made of
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CodedError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub context: BTreeMap<String, String>,
+}
+
+impl CodedError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        CodedError {
+            code,
+            message: message.into(),
+            context: BTreeMap::new(),
+        }
+    }
+
+    /// with adds the context field `key`
+    pub fn with(mut self, key: &str, value: impl fmt::Display) -> Self {
+        self.context.insert(key.to_string(), value.to_string());
+        self
+    }
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl failure::Fail for CodedError {}
+
+impl From<&failure::Error> for CodedError {
+    /// from gives errors raised without a code the closest one, Internal by default
+    fn from(e: &failure::Error) -> Self {
+        if let Some(coded) = e.downcast_ref::<CodedError>() {
+            return coded.clone();
+        }
+        if let Some(rejection) = e.downcast_ref::<BlockRejection>() {
+            let mut coded = CodedError::new(ErrorCode::BlockRejected, e.to_string())
+                .with("block_hash", rejection.block_hash)
+                .with("height", rejection.height)
+                .with("rule", &rejection.rule)
+                .with("expected", &rejection.expected)
+                .with("actual", &rejection.actual);
+            if let Some(txid) = &rejection.txid {
+                coded = coded.with("txid", txid);
+            }
+            return coded;
+        }
+        if let Some(pruned) = e.downcast_ref::<BlockPruned>() {
+            return CodedError::new(ErrorCode::NotFound, e.to_string())
+                .with("block_hash", pruned.hash)
+                .with("height", pruned.height);
+        }
+        if let Some(damaged) = e.downcast_ref::<BlockDamaged>() {
+            return CodedError::new(ErrorCode::Internal, e.to_string())
+                .with("block_hash", damaged.hash);
+        }
+        if e.downcast_ref::<DbCorruption>().is_some() {
+            return CodedError::new(ErrorCode::DbCorruption, e.to_string());
+        }
+        if let Some(io_error) = e.downcast_ref::<io::Error>() {
+            let unreachable = matches!(
+                io_error.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::WouldBlock
+            );
+            if unreachable {
+                return CodedError::new(ErrorCode::NodeUnreachable, e.to_string());
+            }
+        }
+        let invalid = e.downcast_ref::<ParseIntError>().is_some()
+            || e.downcast_ref::<hex::FromHexError>().is_some();
+        let code = if invalid {
+            ErrorCode::InvalidArgument
+        } else {
+            ErrorCode::Internal
+        };
+        CodedError::new(code, e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use failure::format_err;
+
+    #[test]
+    fn test_coded_error() {
+        let error: failure::Error = CodedError::new(ErrorCode::InsufficientFunds, "Not enough")
+            .with("balance", 3)
+            .into();
+        let coded = CodedError::from(&error);
+        assert_eq!(coded.code, ErrorCode::InsufficientFunds);
+        assert_eq!(coded.context["balance"], "3");
+        assert_eq!(
+            serde_json::to_string(&coded).unwrap(),
+            r#"{"code":"insufficient_funds","message":"Not enough","context":{"balance":"3"}}"#
+        );
+
+        let parse: failure::Error = "x".parse::<i32>().unwrap_err().into();
+        assert_eq!(CodedError::from(&parse).code, ErrorCode::InvalidArgument);
+        assert_eq!(
+            CodedError::from(&format_err!("boom")).code,
+            ErrorCode::Internal
+        );
+    }
+}
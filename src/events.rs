@@ -0,0 +1,200 @@
+use crate::hash::{BlockHash, TxId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+/// MAX_EVENTS is the number of past events a node keeps for its peer history
+const MAX_EVENTS: usize = 1000;
+
+/// Direction tells which side opened a peer connection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// NodeEvent is something that happened to a running node
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum NodeEvent {
+    PeerConnected {
+        address: String,
+        direction: Direction,
+    },
+    PeerDisconnected {
+        address: String,
+        direction: Direction,
+        reason: String,
+    },
+    PeerBanned {
+        address: String,
+        reason: String,
+    },
+    NewBlock {
+        hash: BlockHash,
+        height: i32,
+    },
+    NewTransaction {
+        txid: TxId,
+    },
+    /// FeeBumped reports a stuck wallet transaction `replaced` by `txid`, paying `fee`
+    FeeBumped {
+        replaced: TxId,
+        txid: TxId,
+        fee: i32,
+    },
+    /// Reorg reports the chain switching branches to the block `tip`
+    Reorg {
+        tip: BlockHash,
+        disconnected: usize,
+        connected: usize,
+    },
+    /// DoubleSpend reports `conflicting` spending an output the pending `txid` spends,
+    /// offered to the mempool or mined in `block`; `wallet` is set when `txid` pays to or
+    /// spends from the wallet of the node
+    DoubleSpend {
+        txid: TxId,
+        conflicting: TxId,
+        block: Option<BlockHash>,
+        wallet: bool,
+    },
+}
+
+impl fmt::Display for NodeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeEvent::PeerConnected { address, direction } => {
+                write!(f, "peer connected: {} ({:?})", address, direction)
+            }
+            NodeEvent::PeerDisconnected {
+                address,
+                direction,
+                reason,
+            } => write!(
+                f,
+                "peer disconnected: {} ({:?}): {}",
+                address, direction, reason
+            ),
+            NodeEvent::PeerBanned { address, reason } => {
+                write!(f, "peer banned: {}: {}", address, reason)
+            }
+            NodeEvent::NewBlock { hash, height } => {
+                write!(f, "new block: {} at height {}", hash, height)
+            }
+            NodeEvent::NewTransaction { txid } => write!(f, "new transaction: {}", txid),
+            NodeEvent::FeeBumped {
+                replaced,
+                txid,
+                fee,
+            } => write!(
+                f,
+                "fee bumped: {} replaced by {} paying {}",
+                replaced, txid, fee
+            ),
+            NodeEvent::Reorg {
+                tip,
+                disconnected,
+                connected,
+            } => write!(
+                f,
+                "reorg to {}: {} blocks disconnected, {} connected",
+                tip, disconnected, connected
+            ),
+            NodeEvent::DoubleSpend {
+                txid,
+                conflicting,
+                block,
+                wallet,
+            } => {
+                write!(f, "DOUBLE SPEND of {} by {}", txid, conflicting)?;
+                match block {
+                    Some(block) => write!(f, " mined in {}", block)?,
+                    None => write!(f, " offered to the mempool")?,
+                }
+                if *wallet {
+                    write!(f, ", a wallet transaction")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// TimedEvent is a NodeEvent with the time, in milliseconds, it was published
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TimedEvent {
+    pub timestamp: u128,
+    pub event: NodeEvent,
+}
+
+/// EventBus keeps the recent events of a node and hands every new one to its subscribers
+#[derive(Default)]
+pub struct EventBus {
+    history: VecDeque<TimedEvent>,
+    subscribers: Vec<Sender<TimedEvent>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    /// publish records `event` and sends it to the subscribers still listening
+    pub fn publish(&mut self, timestamp: u128, event: NodeEvent) {
+        let event = TimedEvent { timestamp, event };
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        if self.history.len() == MAX_EVENTS {
+            self.history.pop_front();
+        }
+        self.history.push_back(event);
+    }
+
+    /// subscribe returns a receiver for every event published from now on
+    pub fn subscribe(&mut self) -> Receiver<TimedEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// history returns the recent events, oldest first
+    pub fn history(&self) -> Vec<TimedEvent> {
+        self.history.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event_bus() {
+        let mut bus = EventBus::new();
+        let connected = NodeEvent::PeerConnected {
+            address: String::from("localhost:3001"),
+            direction: Direction::Inbound,
+        };
+        bus.publish(1, connected.clone());
+
+        let receiver = bus.subscribe();
+        let banned = NodeEvent::PeerBanned {
+            address: String::from("127.0.0.1"),
+            reason: String::from("misbehavior score 100"),
+        };
+        bus.publish(2, banned.clone());
+        assert_eq!(receiver.try_recv().unwrap().event, banned);
+        assert!(receiver.try_recv().is_err());
+
+        drop(receiver);
+        for timestamp in 3..MAX_EVENTS as u128 + 3 {
+            bus.publish(timestamp, connected.clone());
+        }
+        assert!(bus.subscribers.is_empty());
+        let history = bus.history();
+        assert_eq!(history.len(), MAX_EVENTS);
+        assert_eq!(history[0].timestamp, 3);
+    }
+}
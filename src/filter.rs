@@ -0,0 +1,195 @@
+use crate::{block::Block, hash::BlockHash, script::spender_hash};
+use crypto::{digest::Digest, sha2::Sha256};
+use serde::{Deserialize, Serialize};
+
+/// FILTER_P is the Golomb-Rice parameter, the number of low bits of a delta written as is
+const FILTER_P: u8 = 19;
+/// FILTER_M is the inverse of the false positive rate of a single item, as in BIP158
+const FILTER_M: u64 = 784_931;
+
+/// BlockFilter is a compact filter of a block: a Golomb-coded set of the key hashes it
+/// pays to and spends from. A light client tests its addresses against the filter and
+/// downloads only the blocks that may concern it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockFilter {
+    /// n is the number of distinct items in the set
+    pub n: u32,
+    pub data: Vec<u8>,
+}
+
+impl BlockFilter {
+    /// new builds the filter of `items`, salted with `block_hash`
+    pub fn new(block_hash: &BlockHash, items: &[Vec<u8>]) -> Self {
+        let mut items: Vec<&Vec<u8>> = items.iter().collect();
+        items.sort_unstable();
+        items.dedup();
+        let n = items.len() as u64;
+        let mut values: Vec<u64> = items
+            .iter()
+            .map(|item| hash_to_range(block_hash, item, n * FILTER_M))
+            .collect();
+        values.sort_unstable();
+
+        let mut writer = BitWriter::default();
+        let mut last = 0;
+        for value in &values {
+            let delta = value - last;
+            for _ in 0..delta >> FILTER_P {
+                writer.write_bit(true);
+            }
+            writer.write_bit(false);
+            writer.write_bits(delta, FILTER_P);
+            last = *value;
+        }
+        BlockFilter {
+            n: n as u32,
+            data: writer.bytes,
+        }
+    }
+
+    /// of_block builds the filter of every key hash `block` pays to or spends from
+    pub fn of_block(block: &Block) -> Self {
+        let mut items = Vec::new();
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                items.extend(tx.vin.iter().map(|vin| spender_hash(&vin.pub_key)));
+            }
+            items.extend(
+                tx.vout
+                    .iter()
+                    .filter(|out| !out.is_unspendable())
+                    .map(|out| out.pub_key_hash.clone()),
+            );
+        }
+        BlockFilter::new(&block.get_hash(), &items)
+    }
+
+    /// matches_any tells whether any of `items` may be in the set. False positives
+    /// happen about once in FILTER_M items, false negatives never.
+    pub fn matches_any(&self, block_hash: &BlockHash, items: &[Vec<u8>]) -> bool {
+        if self.n == 0 || items.is_empty() {
+            return false;
+        }
+        let range = self.n as u64 * FILTER_M;
+        let mut queries: Vec<u64> = items
+            .iter()
+            .map(|item| hash_to_range(block_hash, item, range))
+            .collect();
+        queries.sort_unstable();
+
+        let mut reader = BitReader::new(&self.data);
+        let mut value = 0;
+        let mut query = 0;
+        for _ in 0..self.n {
+            let mut quotient = 0;
+            loop {
+                match reader.read_bit() {
+                    Some(true) => quotient += 1,
+                    Some(false) => break,
+                    None => return false,
+                }
+            }
+            let remainder = match reader.read_bits(FILTER_P) {
+                Some(remainder) => remainder,
+                None => return false,
+            };
+            value += (quotient << FILTER_P) + remainder;
+            while query < queries.len() && queries[query] < value {
+                query += 1;
+            }
+            if query == queries.len() {
+                return false;
+            }
+            if queries[query] == value {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// hash_to_range maps `item` uniformly onto [0, range), differently for every block
+fn hash_to_range(block_hash: &BlockHash, item: &[u8], range: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.input(block_hash.as_ref());
+    hasher.input(item);
+    let mut digest = [0; 32];
+    hasher.result(&mut digest);
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    ((u64::from_be_bytes(bytes) as u128 * range as u128) >> 64) as u64
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            self.write_bit(value >> i & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.position / 8)?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, count: u8) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_block_filter() {
+        let items: Vec<Vec<u8>> = (0..50).map(|i| vec![i; 20]).collect();
+        let hash = BlockHash::digest(b"block");
+        let filter = BlockFilter::new(&hash, &items);
+        assert_eq!(filter.n, 50);
+        for item in &items {
+            assert!(filter.matches_any(&hash, std::slice::from_ref(item)));
+        }
+        let others: Vec<Vec<u8>> = (100..200).map(|i| vec![i; 20]).collect();
+        assert!(!filter.matches_any(&hash, &others));
+        assert!(filter.matches_any(&hash, &[others[0].clone(), items[7].clone()]));
+        // ~2.6 bytes per item instead of 20
+        assert!(filter.data.len() < 50 * 3);
+
+        let empty = BlockFilter::new(&hash, &[]);
+        assert!(!empty.matches_any(&hash, &items));
+    }
+}
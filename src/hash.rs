@@ -0,0 +1,135 @@
+use crate::error::{CodedError, ErrorCode, Result};
+use crypto::{digest::Digest, sha2::Sha256};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+/// HASH_LEN is the size of a block hash or transaction id in bytes
+pub const HASH_LEN: usize = 32;
+
+/// hash_type defines a 32 byte sha256 digest type, shown and parsed as 64 hex digits
+/// and serialized as hex in JSON but as the raw bytes in bincode and storage keys
+macro_rules! hash_type {
+    ($(#[$meta:meta])* $name:ident, $what:expr) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+        pub struct $name(pub [u8; HASH_LEN]);
+
+        impl $name {
+            /// digest hashes `data` with sha256
+            pub fn digest(data: &[u8]) -> Self {
+                let mut hasher = Sha256::new();
+                hasher.input(data);
+                let mut bytes = [0; HASH_LEN];
+                hasher.result(&mut bytes);
+                $name(bytes)
+            }
+
+            /// from_slice reads the hash from its raw bytes, such as a storage key
+            pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+                let bytes: [u8; HASH_LEN] = bytes.try_into().map_err(|_| {
+                    CodedError::new(
+                        ErrorCode::InvalidArgument,
+                        format!("A {} is {} bytes, not {}", $what, HASH_LEN, bytes.len()),
+                    )
+                })?;
+                Ok($name(bytes))
+            }
+
+            /// is_zero tells whether every byte is zero, which stands for no hash
+            pub fn is_zero(&self) -> bool {
+                self.0 == [0; HASH_LEN]
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", hex::encode(self.0))
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = failure::Error;
+
+            fn from_str(s: &str) -> Result<Self> {
+                match hex::decode(s) {
+                    Ok(bytes) if bytes.len() == HASH_LEN => $name::from_slice(&bytes),
+                    _ => Err(CodedError::new(
+                        ErrorCode::InvalidArgument,
+                        format!("{} is not a {} of {} hex digits", s, $what, HASH_LEN * 2),
+                    )
+                    .with("value", s)
+                    .into()),
+                }
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+                if serializer.is_human_readable() {
+                    serializer.collect_str(self)
+                } else {
+                    self.0.serialize(serializer)
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+                if deserializer.is_human_readable() {
+                    let s = String::deserialize(deserializer)?;
+                    s.parse().map_err(de::Error::custom)
+                } else {
+                    <[u8; HASH_LEN]>::deserialize(deserializer).map($name)
+                }
+            }
+        }
+    };
+}
+
+hash_type!(
+    /// BlockHash is the sha256 hash of a block header, zero for the parent of the genesis block
+    BlockHash,
+    "block hash"
+);
+
+hash_type!(
+    /// TxId is the sha256 hash identifying a transaction, zero in coinbase inputs
+    TxId,
+    "transaction id"
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hash_format() {
+        let hash = BlockHash::digest(b"block");
+        let hex = hash.to_string();
+        assert_eq!(hex.len(), 64);
+        assert_eq!(hex.parse::<BlockHash>().unwrap(), hash);
+        assert!("00ff".parse::<BlockHash>().is_err());
+        assert!(BlockHash::default().is_zero());
+
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"{}\"", hex));
+        assert_eq!(serde_json::from_str::<BlockHash>(&json).unwrap(), hash);
+        let bytes = bincode::serialize(&hash).unwrap();
+        assert_eq!(bytes, hash.0);
+        assert_eq!(bincode::deserialize::<BlockHash>(&bytes).unwrap(), hash);
+
+        assert!(TxId::default().is_zero());
+    }
+}
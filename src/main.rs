@@ -1,18 +1,64 @@
 use cli::Cli;
-use error::Result;
+use error::{CodedError, Result};
+use log::LevelFilter;
+use std::process::exit;
 
+mod analysis;
+mod api;
+mod approvals;
+mod audit;
+mod banlist;
+mod base64;
 mod block;
 mod blockchain;
+mod bridge;
 mod cli;
+mod config;
+mod decode;
+mod descriptor;
+mod discovery;
 mod error;
+mod events;
+mod filter;
+mod hash;
+mod mempool;
+mod metrics;
+mod miner;
+mod payout;
+mod psbt;
+mod ratelimit;
+mod replay;
+mod rpc;
+mod scheduler;
+mod script;
 mod server;
+mod services;
+mod signer;
+mod stress;
 mod transaction;
+mod u256;
 mod utxoset;
+mod validation;
+mod vault;
 mod wallets;
+mod websocket;
 
-fn main() -> Result<()> {
-    let mut cli = Cli::new()?;
-    cli.run()?;
-
-    Ok(())
+fn main() {
+    // nodes lower the level from their config; without RUST_LOG nothing is logged
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .parse_default_env()
+        .init();
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(LevelFilter::Off);
+    }
+    // failures are printed as a JSON CodedError for scripts to branch on
+    if let Err(e) = Cli::new().and_then(|mut cli| cli.run()) {
+        let error = CodedError::from(&e);
+        match serde_json::to_string(&error) {
+            Ok(json) => eprintln!("{json}"),
+            Err(_) => eprintln!("{}", error),
+        }
+        exit(1);
+    }
 }
@@ -0,0 +1,147 @@
+use super::*;
+use crate::{hash::TxId, transaction::Transaction, utxoset::outpoint_key};
+use failure::format_err;
+use std::collections::HashMap;
+
+/// Mempool holds the transactions waiting to be mined, indexed by the outpoints they spend
+#[derive(Debug, Clone, Default)]
+pub struct Mempool {
+    transactions: HashMap<TxId, Transaction>,
+    spends: HashMap<Vec<u8>, TxId>,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Mempool::default()
+    }
+
+    /// insert adds `tx`, refusing it when it spends an outpoint another pending transaction spends
+    pub fn insert(&mut self, tx: Transaction) -> Result<()> {
+        if self.transactions.contains_key(&tx.id) {
+            return Ok(());
+        }
+        let conflicts = self.conflicts(&tx);
+        if !conflicts.is_empty() {
+            return Err(format_err!(
+                "Transaction {} double spends inputs of {:?}",
+                tx.id,
+                conflicts
+            ));
+        }
+        if !tx.is_coinbase() {
+            for vin in &tx.vin {
+                self.spends.insert(outpoint_key(&vin.txid, vin.vout), tx.id);
+            }
+        }
+        self.transactions.insert(tx.id, tx);
+        Ok(())
+    }
+
+    /// conflicts returns the ids of pending transactions spending any input of `tx`
+    pub fn conflicts(&self, tx: &Transaction) -> Vec<TxId> {
+        let mut conflicts: Vec<TxId> = Vec::new();
+        if tx.is_coinbase() {
+            return conflicts;
+        }
+        for vin in &tx.vin {
+            if let Some(txid) = self.spends.get(&outpoint_key(&vin.txid, vin.vout))
+                && *txid != tx.id
+                && !conflicts.contains(txid)
+            {
+                conflicts.push(*txid);
+            }
+        }
+        conflicts
+    }
+
+    /// remove drops a transaction, for example once it is mined
+    pub fn remove(&mut self, txid: &TxId) -> Option<Transaction> {
+        let tx = self.transactions.remove(txid)?;
+        for vin in &tx.vin {
+            self.spends.remove(&outpoint_key(&vin.txid, vin.vout));
+        }
+        Some(tx)
+    }
+
+    /// remove_with_descendants drops a transaction and every pending transaction
+    /// spending its outputs, which can never be mined without it
+    pub fn remove_with_descendants(&mut self, txid: &TxId) -> Vec<Transaction> {
+        let tx = match self.remove(txid) {
+            Some(tx) => tx,
+            None => return Vec::new(),
+        };
+        let mut removed = vec![tx];
+        let children: Vec<TxId> = self
+            .transactions
+            .values()
+            .filter(|child| child.vin.iter().any(|vin| vin.txid == *txid))
+            .map(|child| child.id)
+            .collect();
+        for child in children {
+            removed.extend(self.remove_with_descendants(&child));
+        }
+        removed
+    }
+
+    pub fn get(&self, txid: &TxId) -> Option<&Transaction> {
+        self.transactions.get(txid)
+    }
+
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.transactions.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::LockScript;
+    use crate::transaction::{TXInput, TXOutput};
+
+    fn tx(id: &str, spends: &[(&str, i32)]) -> Transaction {
+        Transaction {
+            id: TxId::digest(id.as_bytes()),
+            vin: spends
+                .iter()
+                .map(|(txid, vout)| TXInput {
+                    txid: TxId::digest(txid.as_bytes()),
+                    vout: *vout,
+                    signature: Vec::new(),
+                    pub_key: Vec::new(),
+                })
+                .collect(),
+            vout: vec![TXOutput {
+                value: 1,
+                pub_key_hash: vec![1; 20],
+                lock: LockScript::PayToPubkeyHash,
+            }],
+            lock_time: 0,
+            expiry_height: 0,
+        }
+    }
+
+    #[test]
+    fn test_double_spends() {
+        let mut mempool = Mempool::new();
+        mempool.insert(tx("a", &[("prev", 0)])).unwrap();
+        mempool.insert(tx("child", &[("a", 0)])).unwrap();
+
+        let double_spend = tx("b", &[("prev", 1), ("prev", 0)]);
+        assert_eq!(mempool.conflicts(&double_spend), vec![TxId::digest(b"a")]);
+        assert!(mempool.insert(double_spend.clone()).is_err());
+
+        let removed: Vec<TxId> = mempool
+            .remove_with_descendants(&TxId::digest(b"a"))
+            .into_iter()
+            .map(|tx| tx.id)
+            .collect();
+        assert_eq!(removed, vec![TxId::digest(b"a"), TxId::digest(b"child")]);
+        assert_eq!(mempool.len(), 0);
+        mempool.insert(double_spend).unwrap();
+        assert_eq!(mempool.len(), 1);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::{
+    block::{Block, PowSearch},
+    rpc::{fee_rate, FEE_RATE_BUCKETS},
+};
+use std::fmt::Write;
+
+/// Histogram counts observations into buckets with the given upper bounds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// render writes the histogram in the Prometheus text format, with cumulative buckets
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+/// Metrics are the chain usage statistics a node collects from the blocks it connects
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metrics {
+    block_size: Histogram,
+    block_transactions: Histogram,
+    tx_size: Histogram,
+    tx_fee_rate: Histogram,
+    validation_seconds: Histogram,
+    /// hashes counts the proof of work hashes the miner tried
+    hashes: u64,
+    /// hashrate is the hashes a second of the last proof of work search
+    hashrate: f64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let fee_rates: Vec<f64> = FEE_RATE_BUCKETS.iter().map(|r| *r as f64).collect();
+        Metrics {
+            block_size: Histogram::new(&[
+                1_000.0,
+                10_000.0,
+                100_000.0,
+                250_000.0,
+                500_000.0,
+                1_000_000.0,
+            ]),
+            block_transactions: Histogram::new(&[1.0, 2.0, 5.0, 10.0, 50.0, 100.0, 500.0]),
+            tx_size: Histogram::new(&[250.0, 500.0, 1_000.0, 2_000.0, 5_000.0, 10_000.0]),
+            tx_fee_rate: Histogram::new(&fee_rates),
+            validation_seconds: Histogram::new(&[0.001, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]),
+            hashes: 0,
+            hashrate: 0.0,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// observe_block records a connected block of `size` bytes whose non-coinbase
+    /// transactions have the given `(fee, size)`
+    pub fn observe_block(&mut self, block: &Block, size: usize, txs: &[(i32, usize)]) {
+        self.block_size.observe(size as f64);
+        self.block_transactions
+            .observe(block.get_transactions().len() as f64);
+        for (fee, size) in txs {
+            self.tx_size.observe(*size as f64);
+            self.tx_fee_rate.observe(fee_rate(*fee, *size) as f64);
+        }
+    }
+
+    /// observe_validation records how long a received block took to validate and connect
+    pub fn observe_validation(&mut self, seconds: f64) {
+        self.validation_seconds.observe(seconds);
+    }
+
+    /// observe_mining records a proof of work search of the miner, found or cancelled
+    pub fn observe_mining(&mut self, search: &PowSearch) {
+        self.hashes += search.hashes;
+        self.hashrate = search.hashrate();
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.block_size.render(
+            "blockchain_block_size_bytes",
+            "Size of connected blocks",
+            &mut out,
+        );
+        self.block_transactions.render(
+            "blockchain_block_transactions",
+            "Transactions per connected block, coinbase included",
+            &mut out,
+        );
+        self.tx_size.render(
+            "blockchain_tx_size_bytes",
+            "Size of confirmed transactions",
+            &mut out,
+        );
+        self.tx_fee_rate.render(
+            "blockchain_tx_fee_rate",
+            "Fee of confirmed transactions in coins per 1000 bytes",
+            &mut out,
+        );
+        self.validation_seconds.render(
+            "blockchain_block_validation_seconds",
+            "Time spent validating and connecting received blocks",
+            &mut out,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP blockchain_mining_hashes_total Proof of work hashes tried"
+        );
+        let _ = writeln!(out, "# TYPE blockchain_mining_hashes_total counter");
+        let _ = writeln!(out, "blockchain_mining_hashes_total {}", self.hashes);
+        let _ = writeln!(
+            out,
+            "# HELP blockchain_mining_hashrate Hashes a second of the last proof of work search"
+        );
+        let _ = writeln!(out, "# TYPE blockchain_mining_hashrate gauge");
+        let _ = writeln!(out, "blockchain_mining_hashrate {}", self.hashrate);
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(&[1.0, 10.0]);
+        for value in [0.5, 1.0, 7.0, 12.0] {
+            histogram.observe(value);
+        }
+        let mut out = String::new();
+        histogram.render("size", "Sizes", &mut out);
+        assert_eq!(
+            out,
+            "# HELP size Sizes\n# TYPE size histogram\n\
+             size_bucket{le=\"1\"} 2\nsize_bucket{le=\"10\"} 3\nsize_bucket{le=\"+Inf\"} 4\n\
+             size_sum 20.5\nsize_count 4\n"
+        );
+    }
+}
@@ -0,0 +1,386 @@
+use crate::{
+    block::{MerkleBranch, PowSearch, MAX_BLOCK_SIZE},
+    hash::{BlockHash, TxId},
+    rpc::fee_rate,
+    transaction::{block_subsidy, Transaction, EXTRA_NONCE_LEN},
+    u256::U256,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex,
+    },
+};
+
+/// BLOCK_OVERHEAD is the room a template leaves for the block header and the coinbase
+const BLOCK_OVERHEAD: usize = 1000;
+
+/// TemplatePolicy holds the node settings that decide which pending transactions are mined
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TemplatePolicy {
+    pub max_transactions: usize,
+    /// min_fee_rate is the lowest fee, in coins per 1000 bytes, worth mining
+    pub min_fee_rate: u64,
+    /// max_block_size is the consensus limit on the size of the mined block
+    pub max_block_size: usize,
+}
+
+impl Default for TemplatePolicy {
+    fn default() -> Self {
+        TemplatePolicy {
+            max_transactions: 1000,
+            min_fee_rate: 0,
+            max_block_size: MAX_BLOCK_SIZE,
+        }
+    }
+}
+
+/// MempoolEntry is a pending transaction with its fee and serialized size
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: i32,
+    pub size: usize,
+}
+
+/// BlockTemplate is the content of the next block apart from its coinbase
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockTemplate {
+    pub prev_block_hash: BlockHash,
+    pub height: i32,
+    pub transactions: Vec<Transaction>,
+    pub fees: i32,
+    /// coinbase_value is the most the coinbase may pay: the block subsidy and the fees
+    pub coinbase_value: i32,
+    /// target is the most the block hash may be
+    pub target: U256,
+    /// coinbase_branch links the coinbase, placed before the transactions, to the merkle root
+    pub coinbase_branch: MerkleBranch,
+    /// extra_nonce_len is how many bytes at the end of the coinbase input data a miner
+    /// may roll once the header nonce space is exhausted
+    pub extra_nonce_len: usize,
+    /// longpoll_id names the template for a long-poll request waiting for a better one
+    pub longpoll_id: String,
+}
+
+impl BlockTemplate {
+    /// supersedes tells whether a miner working on the template `longpoll_id` names
+    /// should switch to this one, which builds on another tip, collects more fees or,
+    /// for the same fees, confirms more transactions
+    pub fn supersedes(&self, longpoll_id: &str) -> bool {
+        let parts: Vec<&str> = longpoll_id.rsplitn(3, ':').collect();
+        if parts.len() != 3 || parts[2] != self.prev_block_hash.to_string() {
+            return true;
+        }
+        match (parts[1].parse::<i32>(), parts[0].parse::<usize>()) {
+            (Ok(fees), Ok(count)) => {
+                self.fees > fees || (self.fees == fees && self.transactions.len() > count)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// MinerStats counts the work of the local miner since the node started
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MinerStats {
+    /// hashes counts the proof of work hashes tried
+    pub hashes: u64,
+    /// hashrate is the hashes a second of the last proof of work search
+    pub hashrate: f64,
+    pub blocks_mined: u64,
+    /// fees_earned sums the fees of the mined blocks, the subsidies left out
+    pub fees_earned: i64,
+}
+
+/// TemplateManager hands work to the miner thread: pending transactions wake it up, and
+/// the block it is mining is cancelled once a competing block moves the tip or a better
+/// paying template can be built
+#[derive(Debug, Default)]
+pub struct TemplateManager {
+    work: Mutex<bool>,
+    wake: Condvar,
+    cancel: AtomicBool,
+    /// mining is the tip and fees of the template being mined, if any
+    mining: Mutex<Option<(BlockHash, i32)>>,
+    stats: Mutex<MinerStats>,
+}
+
+impl TemplateManager {
+    /// notify tells the miner there are transactions to mine
+    pub fn notify(&self) {
+        *self.work.lock().unwrap() = true;
+        self.wake.notify_one();
+    }
+
+    /// wait blocks until there are transactions to mine
+    pub fn wait(&self) {
+        let mut work = self.work.lock().unwrap();
+        while !*work {
+            work = self.wake.wait(work).unwrap();
+        }
+        *work = false;
+    }
+
+    /// cancel stops the search for the proof of work of the current block, which no
+    /// longer builds on the tip
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// start_block clears a previous cancellation before the next block is assembled,
+    /// returning the flag its proof of work search watches
+    pub fn start_block(&self) -> &AtomicBool {
+        self.cancel.store(false, Ordering::Relaxed);
+        &self.cancel
+    }
+
+    /// mine_template records the template the block being mined was assembled from
+    pub fn mine_template(&self, template: &BlockTemplate) {
+        *self.mining.lock().unwrap() = Some((template.prev_block_hash, template.fees));
+    }
+
+    /// idle records that no block is being mined
+    pub fn idle(&self) {
+        *self.mining.lock().unwrap() = None;
+    }
+
+    /// is_mining tells whether a block is being mined
+    pub fn is_mining(&self) -> bool {
+        self.mining.lock().unwrap().is_some()
+    }
+
+    /// record_search counts a proof of work search, found or cancelled
+    pub fn record_search(&self, search: &PowSearch) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.hashes += search.hashes;
+        stats.hashrate = search.hashrate();
+    }
+
+    /// record_block counts a block mined from `template`
+    pub fn record_block(&self, template: &BlockTemplate) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.blocks_mined += 1;
+        stats.fees_earned += template.fees as i64;
+    }
+
+    /// stats returns the counters of the miner
+    pub fn stats(&self) -> MinerStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// refresh cancels the block being mined when `template`, built from the current
+    /// mempool, builds on another tip or collects more fees, telling whether it did
+    pub fn refresh(&self, template: &BlockTemplate) -> bool {
+        let stale = match *self.mining.lock().unwrap() {
+            Some((tip, fees)) => template.prev_block_hash != tip || template.fees > fees,
+            None => false,
+        };
+        if stale {
+            self.cancel();
+        }
+        stale
+    }
+}
+
+/// build_template picks and orders the transactions of the block on top of `tip_hash`.
+/// It only depends on its arguments: entries are taken by fee rate, ties broken by
+/// txid, a transaction is never placed before a pending transaction it spends, and
+/// those that would push the block over its size limit are left out.
+pub fn build_template(
+    tip_hash: &BlockHash,
+    tip_height: i32,
+    entries: &[MempoolEntry],
+    policy: &TemplatePolicy,
+) -> BlockTemplate {
+    let mut candidates: Vec<&MempoolEntry> = entries
+        .iter()
+        .filter(|e| fee_rate(e.fee, e.size) >= policy.min_fee_rate)
+        .collect();
+    candidates.sort_by(|a, b| {
+        fee_rate(b.fee, b.size)
+            .cmp(&fee_rate(a.fee, a.size))
+            .then_with(|| a.tx.id.cmp(&b.tx.id))
+    });
+    let pending: HashSet<TxId> = entries.iter().map(|e| e.tx.id).collect();
+
+    let mut template = BlockTemplate {
+        prev_block_hash: *tip_hash,
+        height: tip_height + 1,
+        transactions: Vec::new(),
+        fees: 0,
+        coinbase_value: 0,
+        target: U256::MAX,
+        coinbase_branch: MerkleBranch::default(),
+        extra_nonce_len: EXTRA_NONCE_LEN,
+        longpoll_id: String::new(),
+    };
+    let mut selected: HashSet<TxId> = HashSet::new();
+    let mut room = policy.max_block_size.saturating_sub(BLOCK_OVERHEAD);
+    let mut progress = true;
+    while progress && template.transactions.len() < policy.max_transactions {
+        progress = false;
+        for entry in &candidates {
+            if template.transactions.len() >= policy.max_transactions {
+                break;
+            }
+            if selected.contains(&entry.tx.id) || entry.size > room {
+                continue;
+            }
+            let parents_selected = entry
+                .tx
+                .vin
+                .iter()
+                .all(|vin| !pending.contains(&vin.txid) || selected.contains(&vin.txid));
+            if !parents_selected {
+                continue;
+            }
+            selected.insert(entry.tx.id);
+            room -= entry.size;
+            template.transactions.push(entry.tx.clone());
+            template.fees += entry.fee;
+            progress = true;
+        }
+    }
+    template.coinbase_value = block_subsidy(template.height) + template.fees;
+    template.longpoll_id = format!(
+        "{}:{}:{}",
+        tip_hash,
+        template.fees,
+        template.transactions.len()
+    );
+    template
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transaction::TXInput;
+    use std::time::Duration;
+
+    /// name_id pads `name` into an id, so ids sort like their names
+    fn name_id(name: &str) -> [u8; 32] {
+        let mut id = [0; 32];
+        id[..name.len()].copy_from_slice(name.as_bytes());
+        id
+    }
+
+    fn entry(id: &str, parent: &str, fee: i32) -> MempoolEntry {
+        MempoolEntry {
+            tx: Transaction {
+                id: TxId(name_id(id)),
+                vin: vec![TXInput {
+                    txid: TxId(name_id(parent)),
+                    vout: 0,
+                    signature: Vec::new(),
+                    pub_key: Vec::new(),
+                }],
+                vout: Vec::new(),
+                lock_time: 0,
+                expiry_height: 0,
+            },
+            fee,
+            size: 1000,
+        }
+    }
+
+    #[test]
+    fn test_build_template() {
+        let entries = vec![
+            entry("low", "chain", 1),
+            entry("child", "low", 9),
+            entry("b", "chain", 5),
+            entry("a", "chain", 5),
+            entry("dust", "chain", 0),
+        ];
+        let policy = TemplatePolicy {
+            max_transactions: 10,
+            min_fee_rate: 1,
+            ..TemplatePolicy::default()
+        };
+
+        let ids = |template: &BlockTemplate| -> Vec<TxId> {
+            template.transactions.iter().map(|tx| tx.id).collect()
+        };
+        let tip = BlockHash(name_id("tip"));
+        let template = build_template(&tip, 4, &entries, &policy);
+        let expected: Vec<TxId> = ["a", "b", "low", "child"]
+            .iter()
+            .map(|name| TxId(name_id(name)))
+            .collect();
+        assert_eq!(ids(&template), expected);
+        assert_eq!(template.height, 5);
+        assert_eq!(template.fees, 20);
+        assert!(!template.supersedes(&template.longpoll_id));
+        assert!(template.supersedes(&format!("{}:19:4", tip)));
+        assert!(template.supersedes(&format!("{}:20:3", tip)));
+        assert!(template.supersedes("old:20:4"));
+        assert!(!template.supersedes(&format!("{}:25:1", tip)));
+
+        let mut reversed = entries.clone();
+        reversed.reverse();
+        assert_eq!(
+            ids(&build_template(&tip, 4, &reversed, &policy)),
+            ids(&template)
+        );
+
+        let policy = TemplatePolicy {
+            max_transactions: 2,
+            min_fee_rate: 0,
+            ..TemplatePolicy::default()
+        };
+        assert_eq!(
+            build_template(&tip, 4, &entries, &policy)
+                .transactions
+                .len(),
+            2
+        );
+
+        // entries take 1000 bytes each, so only the best two fit next to the coinbase
+        let policy = TemplatePolicy {
+            max_block_size: BLOCK_OVERHEAD + 2500,
+            ..TemplatePolicy::default()
+        };
+        let expected: Vec<TxId> = ["a", "b"].iter().map(|name| TxId(name_id(name))).collect();
+        assert_eq!(ids(&build_template(&tip, 4, &entries, &policy)), expected);
+    }
+
+    #[test]
+    fn test_template_refresh() {
+        let policy = TemplatePolicy::default();
+        let tip = BlockHash(name_id("tip"));
+        let entries = vec![entry("a", "chain", 5), entry("dust", "chain", 0)];
+        let manager = TemplateManager::default();
+        let template = build_template(&tip, 4, &entries[..1], &policy);
+        assert!(!manager.refresh(&template));
+
+        let cancel = manager.start_block();
+        manager.mine_template(&template);
+        assert!(!manager.refresh(&build_template(&tip, 4, &entries, &policy)));
+        assert!(!cancel.load(Ordering::Relaxed));
+
+        let mut entries = entries;
+        entries.push(entry("b", "chain", 1));
+        assert!(manager.refresh(&build_template(&tip, 4, &entries, &policy)));
+        assert!(cancel.load(Ordering::Relaxed));
+        assert!(!manager.start_block().load(Ordering::Relaxed));
+        let other = BlockHash(name_id("other"));
+        assert!(manager.refresh(&build_template(&other, 5, &[], &policy)));
+
+        manager.idle();
+        assert!(!manager.is_mining());
+
+        manager.record_search(&PowSearch {
+            found: true,
+            hashes: 300,
+            elapsed: Duration::from_secs(2),
+        });
+        manager.record_block(&template);
+        manager.record_block(&template);
+        let stats = manager.stats();
+        assert_eq!((stats.hashes, stats.hashrate), (300, 150.0));
+        assert_eq!((stats.blocks_mined, stats.fees_earned), (2, 10));
+    }
+}
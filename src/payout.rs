@@ -0,0 +1,196 @@
+use crate::{
+    error::{CodedError, ErrorCode, Result},
+    hash::TxId,
+    script::{PUB_KEY_LEN, SIGNATURE_LEN},
+    transaction::{TXInput, TXOutput, Transaction},
+};
+use bincode::serialized_size;
+use bitcoincash_addr::Address;
+use failure::format_err;
+use std::collections::HashMap;
+
+/// DEFAULT_MAX_TX_SIZE is the size in bytes a payout transaction is kept under
+pub const DEFAULT_MAX_TX_SIZE: usize = 100_000;
+
+/// Payment is a row of a payout file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payment {
+    pub line: usize,
+    pub address: String,
+    pub amount: i32,
+}
+
+fn invalid_row(line: usize, message: String) -> failure::Error {
+    CodedError::new(
+        ErrorCode::InvalidArgument,
+        format!("line {}: {}", line, message),
+    )
+    .with("line", line)
+    .into()
+}
+
+/// parse_payouts reads the `address,amount` rows of a payout CSV file. Blank lines,
+/// `#` comments and a header row are skipped; an invalid address, an amount that is
+/// not positive or an address paid twice fails the whole file.
+pub fn parse_payouts(text: &str) -> Result<Vec<Payment>> {
+    let mut payments: Vec<Payment> = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for (idx, row) in text.lines().enumerate() {
+        let line = idx + 1;
+        let row = row.trim();
+        if row.is_empty() || row.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = row.split(',').map(|field| field.trim()).collect();
+        if fields.len() != 2 {
+            return Err(invalid_row(
+                line,
+                format!("expected address,amount, got {}", row),
+            ));
+        }
+        if payments.is_empty() && fields[0].eq_ignore_ascii_case("address") {
+            continue;
+        }
+
+        let (address, amount) = (fields[0], fields[1]);
+        if Address::decode(address).is_err() {
+            return Err(invalid_row(
+                line,
+                format!("{} is not a valid address", address),
+            ));
+        }
+        let amount = match amount.parse::<i32>() {
+            Ok(amount) if amount > 0 => amount,
+            _ => {
+                return Err(invalid_row(
+                    line,
+                    format!("{} is not a positive amount", amount),
+                ))
+            }
+        };
+        if let Some(first) = seen.insert(address.to_string(), line) {
+            return Err(invalid_row(
+                line,
+                format!("{} is paid on line {} already", address, first),
+            ));
+        }
+        payments.push(Payment {
+            line,
+            address: address.to_string(),
+            amount,
+        });
+    }
+    if payments.is_empty() {
+        return Err(format_err!("The payout file has no payments"));
+    }
+    Ok(payments)
+}
+
+/// spend_size is the size a transaction spending one output with the output `change`
+/// takes before any payment is added
+fn spend_size(change: &TXOutput) -> Result<usize> {
+    let tx = Transaction {
+        id: TxId::default(),
+        vin: vec![TXInput {
+            txid: TxId::default(),
+            vout: 0,
+            signature: vec![0; SIGNATURE_LEN],
+            pub_key: vec![0; PUB_KEY_LEN],
+        }],
+        vout: vec![change.clone()],
+        lock_time: 0,
+        expiry_height: 0,
+    };
+    Ok(serialized_size(&tx)? as usize)
+}
+
+/// split_batches groups the payments into the outputs of transactions that stay under
+/// `max_size` bytes when they spend a single output and pay change back
+pub fn split_batches(payments: &[Payment], max_size: usize) -> Result<Vec<Vec<TXOutput>>> {
+    let outputs = payments
+        .iter()
+        .map(|payment| TXOutput::new(payment.amount, payment.address.clone()))
+        .collect::<Result<Vec<TXOutput>>>()?;
+    let base = match outputs.first() {
+        Some(out) => spend_size(out)?,
+        None => return Ok(Vec::new()),
+    };
+    let mut batches: Vec<Vec<TXOutput>> = Vec::new();
+    let mut batch: Vec<TXOutput> = Vec::new();
+    let mut size = base;
+    for out in outputs {
+        let out_size = serialized_size(&out)? as usize;
+        if base + out_size > max_size {
+            return Err(format_err!(
+                "A payout transaction of {} bytes can not hold a single payment",
+                max_size
+            ));
+        }
+        if !batch.is_empty() && size + out_size > max_size {
+            batches.push(batch);
+            batch = Vec::new();
+            size = base;
+        }
+        size += out_size;
+        batch.push(out);
+    }
+    batches.push(batch);
+    Ok(batches)
+}
+
+/// check_sizes fails when a payout transaction, typically one spending many outputs of
+/// the wallet, is above `max_size` bytes
+pub fn check_sizes(txs: &[Transaction], max_size: usize) -> Result<()> {
+    for (idx, tx) in txs.iter().enumerate() {
+        let size = serialized_size(tx)? as usize;
+        if size > max_size {
+            return Err(format_err!(
+                "Payout transaction {} is {} bytes, above the limit of {}; consolidate the outputs of the wallet first",
+                idx + 1,
+                size,
+                max_size
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wallets::Wallet;
+
+    #[test]
+    fn test_payouts() {
+        let a = Wallet::derive(&[1; 32], &[44, 0], 0).get_address();
+        let b = Wallet::derive(&[1; 32], &[44, 0], 1).get_address();
+        let text = format!("address,amount\n{},5\n\n# bonus\n {} , 7 \n", a, b);
+        let payments = parse_payouts(&text).unwrap();
+        assert_eq!(payments.len(), 2);
+        assert_eq!(payments[1].address, b);
+        assert_eq!(payments[1].amount, 7);
+        assert_eq!(payments[1].line, 5);
+
+        assert!(parse_payouts("").is_err());
+        assert!(parse_payouts(&format!("{},0", a)).is_err());
+        assert!(parse_payouts(&format!("{},5,1", a)).is_err());
+        assert!(parse_payouts("nobody,5").is_err());
+        let twice = parse_payouts(&format!("{},5\n{},6", a, a)).unwrap_err();
+        assert!(twice.to_string().contains("line 2"));
+
+        let out = TXOutput::new(5, a.clone()).unwrap();
+        let out_size = serialized_size(&out).unwrap() as usize;
+        let max_size = spend_size(&out).unwrap() + 2 * out_size;
+        let many: Vec<Payment> = (0..5)
+            .map(|line| Payment {
+                line,
+                address: a.clone(),
+                amount: 5,
+            })
+            .collect();
+        let batches = split_batches(&many, max_size).unwrap();
+        let sizes: Vec<usize> = batches.iter().map(|batch| batch.len()).collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+        assert!(split_batches(&many, max_size - out_size - 1).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+use super::*;
+use crate::{
+    base64,
+    hash::TxId,
+    script::{PUB_KEY_LEN, SIGNATURE_LEN},
+    signer::Signer,
+    transaction::{SighashType, TXInput, TXOutput, Transaction},
+    wallets::fingerprint,
+};
+use bincode::{deserialize, serialize};
+use crypto::ed25519;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// PsbtInput is what the signers of one input need and have made so far
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PsbtInput {
+    /// prev_output is the output the input spends, whose lock tells who signs
+    pub prev_output: TXOutput,
+    /// signatures holds the signatures made so far by hex encoded public key
+    pub signatures: BTreeMap<String, Vec<u8>>,
+}
+
+/// PartiallySignedTransaction carries an unsigned transaction between the signers of
+/// its inputs, who may be offline, until every input holds the signatures it needs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartiallySignedTransaction {
+    pub tx: Transaction,
+    pub inputs: Vec<PsbtInput>,
+}
+
+impl PartiallySignedTransaction {
+    /// new starts a transaction paying `vout`, with inputs to be added
+    pub fn new(vout: Vec<TXOutput>) -> Result<Self> {
+        let mut tx = Transaction {
+            id: TxId::default(),
+            vin: Vec::new(),
+            vout,
+            lock_time: 0,
+            expiry_height: 0,
+        };
+        tx.id = tx.hash()?;
+        Ok(PartiallySignedTransaction {
+            tx,
+            inputs: Vec::new(),
+        })
+    }
+
+    /// add_input spends output `vout` of `txid`, which is `prev_output`, revealing
+    /// `pub_key`. Signatures commit to every input, so those made so far are dropped.
+    pub fn add_input(
+        &mut self,
+        txid: &TxId,
+        vout: i32,
+        pub_key: Vec<u8>,
+        prev_output: TXOutput,
+    ) -> Result<()> {
+        if !prev_output
+            .lock
+            .matches_keys(&prev_output.pub_key_hash, &pub_key)
+        {
+            return Err(format_err!(
+                "The keys do not unlock output {}:{}",
+                txid,
+                vout
+            ));
+        }
+        self.tx.vin.push(TXInput {
+            txid: *txid,
+            vout,
+            signature: Vec::new(),
+            pub_key,
+        });
+        self.tx.id = self.tx.hash()?;
+        for input in &mut self.inputs {
+            input.signatures.clear();
+        }
+        self.inputs.push(PsbtInput {
+            prev_output,
+            signatures: BTreeMap::new(),
+        });
+        Ok(())
+    }
+
+    /// sign signs input `in_id` for `pub_key`, one of the keys it reveals. It returns
+    /// false when that key has already signed or the input needs no more signatures.
+    pub fn sign(&mut self, in_id: usize, pub_key: &[u8], signer: &dyn Signer) -> Result<bool> {
+        let input = match self.inputs.get(in_id) {
+            Some(input) => input,
+            None => return Err(format_err!("The transaction has no input {}", in_id)),
+        };
+        if input.signatures.contains_key(&hex::encode(pub_key))
+            || input.signatures.len() >= input.prev_output.lock.signatures()
+        {
+            return Ok(false);
+        }
+        let digest = self
+            .tx
+            .sighash(in_id, &input.prev_output, SighashType::All)?;
+        let signature = signer.sign(pub_key, &digest)?;
+        self.add_signature(in_id, pub_key, signature)?;
+        Ok(true)
+    }
+
+    /// add_signature records the `signature` of `pub_key` for input `in_id` after
+    /// checking it
+    pub fn add_signature(
+        &mut self,
+        in_id: usize,
+        pub_key: &[u8],
+        signature: Vec<u8>,
+    ) -> Result<()> {
+        let input = match self.inputs.get(in_id) {
+            Some(input) => input,
+            None => return Err(format_err!("The transaction has no input {}", in_id)),
+        };
+        if pub_key.len() != PUB_KEY_LEN || signature.len() != SIGNATURE_LEN {
+            return Err(format_err!(
+                "Malformed key or signature of {} and {} bytes for input {}",
+                pub_key.len(),
+                signature.len(),
+                in_id
+            ));
+        }
+        if !self.tx.vin[in_id]
+            .pub_key
+            .chunks(PUB_KEY_LEN)
+            .any(|key| key == pub_key)
+        {
+            return Err(format_err!(
+                "Key {} does not sign input {}",
+                fingerprint(pub_key),
+                in_id
+            ));
+        }
+        let digest = self
+            .tx
+            .sighash(in_id, &input.prev_output, SighashType::All)?;
+        if !ed25519::verify(digest.as_bytes(), pub_key, &signature) {
+            return Err(format_err!(
+                "Invalid signature of key {} for input {}",
+                fingerprint(pub_key),
+                in_id
+            ));
+        }
+        self.inputs[in_id]
+            .signatures
+            .insert(hex::encode(pub_key), signature);
+        Ok(())
+    }
+
+    /// combine adds the signatures of `other`, a copy of the same transaction signed by
+    /// other keys
+    pub fn combine(&mut self, other: &PartiallySignedTransaction) -> Result<()> {
+        if other.tx.id != self.tx.id || other.inputs.len() != self.inputs.len() {
+            return Err(format_err!(
+                "Cannot combine transaction {} with {}",
+                self.tx.id,
+                other.tx.id
+            ));
+        }
+        for (in_id, input) in other.inputs.iter().enumerate() {
+            for (key, signature) in &input.signatures {
+                self.add_signature(in_id, &hex::decode(key)?, signature.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// missing_signatures is how many signatures the inputs still need
+    pub fn missing_signatures(&self) -> usize {
+        self.inputs
+            .iter()
+            .map(|input| {
+                input
+                    .prev_output
+                    .lock
+                    .signatures()
+                    .saturating_sub(input.signatures.len())
+            })
+            .sum()
+    }
+
+    /// fee is what the inputs hold beyond the outputs, failing when either sum overflows
+    pub fn fee(&self) -> Result<i32> {
+        let inputs = self
+            .inputs
+            .iter()
+            .try_fold(0i32, |sum, i| sum.checked_add(i.prev_output.value));
+        let outputs = self
+            .tx
+            .vout
+            .iter()
+            .try_fold(0i32, |sum, out| sum.checked_add(out.value));
+        match (inputs, outputs) {
+            (Some(inputs), Some(outputs)) => inputs
+                .checked_sub(outputs)
+                .ok_or_else(|| format_err!("The fee of transaction {} overflows", self.tx.id)),
+            _ => Err(format_err!(
+                "The values of transaction {} overflow",
+                self.tx.id
+            )),
+        }
+    }
+
+    /// finalize extracts the broadcastable transaction, each input carrying the
+    /// signatures it needs in the order of its keys
+    pub fn finalize(&self) -> Result<Transaction> {
+        if self.inputs.is_empty() {
+            return Err(format_err!("The transaction has no input"));
+        }
+        let mut tx = self.tx.clone();
+        for (in_id, input) in self.inputs.iter().enumerate() {
+            let needed = input.prev_output.lock.signatures();
+            let signatures: Vec<&Vec<u8>> = tx.vin[in_id]
+                .pub_key
+                .chunks(PUB_KEY_LEN)
+                .filter_map(|key| input.signatures.get(&hex::encode(key)))
+                .take(needed)
+                .collect();
+            if signatures.len() < needed {
+                return Err(format_err!(
+                    "Input {} holds {} of {} signatures",
+                    in_id,
+                    signatures.len(),
+                    needed
+                ));
+            }
+            tx.vin[in_id].signature = signatures.into_iter().flatten().copied().collect();
+        }
+        Ok(tx)
+    }
+
+    pub fn to_base64(&self) -> Result<String> {
+        Ok(base64::encode(&serialize(self)?))
+    }
+
+    pub fn from_base64(text: &str) -> Result<Self> {
+        let psbt: PartiallySignedTransaction = deserialize(&base64::decode(text)?)?;
+        if psbt.inputs.len() != psbt.tx.vin.len() {
+            return Err(format_err!(
+                "Transaction {} has {} inputs but {} input records",
+                psbt.tx.id,
+                psbt.tx.vin.len(),
+                psbt.inputs.len()
+            ));
+        }
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        script::{redeem_hash, LockScript},
+        signer::KeySigner,
+        wallets::{hash_pub_key, Wallet},
+    };
+
+    #[test]
+    fn test_psbt() {
+        let keys: Vec<Wallet> = (0..2)
+            .map(|i| Wallet::derive(&[i; 32], &[44, 0], 0))
+            .collect();
+        let multi_key = [keys[0].public_key.clone(), keys[1].public_key.clone()].concat();
+        let mut single_hash = keys[0].public_key.clone();
+        hash_pub_key(&mut single_hash);
+        let mut prev = Transaction {
+            id: TxId::default(),
+            vin: Vec::new(),
+            vout: vec![
+                TXOutput {
+                    value: 5,
+                    pub_key_hash: single_hash,
+                    lock: LockScript::PayToPubkeyHash,
+                },
+                TXOutput {
+                    value: 7,
+                    pub_key_hash: redeem_hash(&multi_key),
+                    lock: LockScript::MultiSig { m: 2, n: 2 },
+                },
+            ],
+            lock_time: 0,
+            expiry_height: 0,
+        };
+        prev.id = prev.hash().unwrap();
+
+        let mut psbt = PartiallySignedTransaction::new(vec![prev.vout[0].clone()]).unwrap();
+        psbt.add_input(
+            &prev.id,
+            0,
+            keys[0].public_key.clone(),
+            prev.vout[0].clone(),
+        )
+        .unwrap();
+        assert!(psbt
+            .add_input(
+                &prev.id,
+                1,
+                keys[0].public_key.clone(),
+                prev.vout[1].clone()
+            )
+            .is_err());
+        fn signer(w: &Wallet) -> KeySigner<'_> {
+            KeySigner::new(&w.secret_key)
+        }
+        assert!(psbt
+            .sign(0, &keys[0].public_key, &signer(&keys[0]))
+            .unwrap());
+        psbt.add_input(&prev.id, 1, multi_key.clone(), prev.vout[1].clone())
+            .unwrap();
+        // the new input changed what the first signature commits to
+        assert_eq!(psbt.missing_signatures(), 3);
+        assert_eq!(psbt.fee().unwrap(), 7);
+        // a crafted copy cannot make the node verify malformed keys or signatures
+        assert!(psbt
+            .clone()
+            .add_signature(0, &[1; 3], vec![0; SIGNATURE_LEN])
+            .is_err());
+        assert!(psbt
+            .clone()
+            .add_signature(0, &keys[0].public_key, vec![0; 3])
+            .is_err());
+        let mut overflow = psbt.clone();
+        overflow.inputs[1].prev_output.value = i32::MAX;
+        assert!(overflow.fee().is_err());
+
+        let mut first = psbt.clone();
+        assert!(first
+            .sign(0, &keys[0].public_key, &signer(&keys[0]))
+            .unwrap());
+        assert!(!first
+            .sign(0, &keys[0].public_key, &signer(&keys[0]))
+            .unwrap());
+        assert!(first
+            .sign(1, &keys[0].public_key, &signer(&keys[0]))
+            .unwrap());
+        assert!(first
+            .sign(1, &keys[1].public_key, &signer(&keys[0]))
+            .is_err());
+        let mut second =
+            PartiallySignedTransaction::from_base64(&psbt.to_base64().unwrap()).unwrap();
+        assert!(second
+            .sign(1, &keys[1].public_key, &signer(&keys[1]))
+            .unwrap());
+        assert!(first.finalize().is_err());
+
+        first.combine(&second).unwrap();
+        assert_eq!(first.missing_signatures(), 0);
+        let tx = first.finalize().unwrap();
+        assert_eq!(tx.id, psbt.tx.id);
+        assert!(tx.verify_input(0, &prev).unwrap());
+        assert!(tx.verify_input(1, &prev).unwrap());
+    }
+}
@@ -0,0 +1,135 @@
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+/// MAX_TRACKED_IPS is the number of addresses with a token bucket above which the
+/// buckets that refilled completely are dropped
+const MAX_TRACKED_IPS: usize = 10_000;
+
+/// TokenBucket lets through `rate` messages a second on average and bursts of up to
+/// `burst`. Both are passed on every message, so reloaded settings apply at once.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(burst: u32, now: Instant) -> Self {
+        TokenBucket {
+            tokens: burst as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, rate: u32, burst: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(burst as f64);
+        self.last = now;
+    }
+
+    /// take spends a token, telling whether one was left
+    pub fn take(&mut self, rate: u32, burst: u32, now: Instant) -> bool {
+        self.refill(rate, burst, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    fn is_full(&self, rate: u32, burst: u32, now: Instant) -> bool {
+        let mut bucket = self.clone();
+        bucket.refill(rate, burst, now);
+        bucket.tokens >= burst as f64
+    }
+}
+
+/// RateLimiter keeps a token bucket for every address messages come from
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    /// allow tells whether a message from `ip` is within its rate limit
+    pub fn allow(&mut self, ip: IpAddr, rate: u32, burst: u32, now: Instant) -> bool {
+        if self.buckets.len() >= MAX_TRACKED_IPS && !self.buckets.contains_key(&ip) {
+            self.buckets
+                .retain(|_, bucket| !bucket.is_full(rate, burst, now));
+        }
+        self.buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(burst, now))
+            .take(rate, burst, now)
+    }
+}
+
+/// Connections counts the connections being handled, in total and by address
+#[derive(Debug, Default)]
+pub struct Connections {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+impl Connections {
+    /// open counts a new connection from `ip`, unless there are `max` connections
+    /// already or `max_per_ip` from `ip`
+    pub fn open(&mut self, ip: IpAddr, max: usize, max_per_ip: usize) -> bool {
+        let from_ip = self.by_ip.entry(ip).or_insert(0);
+        if self.total >= max || *from_ip >= max_per_ip {
+            if *from_ip == 0 {
+                self.by_ip.remove(&ip);
+            }
+            return false;
+        }
+        *from_ip += 1;
+        self.total += 1;
+        true
+    }
+
+    /// close forgets a connection from `ip` once it is handled
+    pub fn close(&mut self, ip: IpAddr) {
+        if let Some(from_ip) = self.by_ip.get_mut(&ip) {
+            *from_ip -= 1;
+            self.total -= 1;
+            if *from_ip == 0 {
+                self.by_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_limits() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+        let mut limiter = RateLimiter::default();
+        for _ in 0..3 {
+            assert!(limiter.allow(ip, 2, 3, start));
+        }
+        assert!(!limiter.allow(ip, 2, 3, start));
+        assert!(limiter.allow(other, 2, 3, start));
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.allow(ip, 2, 3, later));
+        assert!(!limiter.allow(ip, 2, 3, later));
+
+        let mut connections = Connections::default();
+        assert!(connections.open(ip, 3, 2));
+        assert!(connections.open(ip, 3, 2));
+        assert!(!connections.open(ip, 3, 2));
+        assert!(connections.open(other, 3, 2));
+        assert!(!connections.open(other, 3, 2));
+        connections.close(ip);
+        assert!(connections.open(other, 3, 2));
+        connections.close(ip);
+        connections.close(other);
+        connections.close(other);
+        assert_eq!(connections.total, 0);
+        assert!(connections.by_ip.is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+use super::*;
+use crate::{
+    block::{Block, BlockRejection},
+    blockchain::Blockchain,
+    config::ChainParams,
+    hash::{BlockHash, TxId},
+};
+use bincode::{deserialize, serialize_into};
+use failure::format_err;
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{BufWriter, Seek, SeekFrom, Write},
+};
+
+/// ReplayFailure is a block of an exported or stored chain the rules reject
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayFailure {
+    pub hash: BlockHash,
+    pub height: i32,
+    /// rule is the failed BlockRejection rule, or "error" for other failures
+    pub rule: String,
+    pub reason: String,
+}
+
+impl ReplayFailure {
+    fn new(hash: BlockHash, height: i32, e: failure::Error) -> Self {
+        let rule = match e.downcast_ref::<BlockRejection>() {
+            Some(rejection) => rejection.rule.clone(),
+            None => String::from("error"),
+        };
+        ReplayFailure {
+            hash,
+            height,
+            rule,
+            reason: e.to_string(),
+        }
+    }
+}
+
+/// export_chain writes the blocks of the best chain, genesis first, to `path` and
+/// returns how many were written
+pub fn export_chain(blockchain: &Blockchain, path: &str) -> Result<usize> {
+    if blockchain.is_pruned()? {
+        return Err(format_err!(
+            "Cannot export a pruned chain, its old blocks are deleted"
+        ));
+    }
+    // written one block at a time in the format of a serialized Vec, whose length
+    // comes first and is filled in once the blocks are counted
+    let mut file = BufWriter::new(File::create(path)?);
+    serialize_into(&mut file, &0u64)?;
+    let mut count: u64 = 0;
+    for block in blockchain.walk().oldest_first().blocks() {
+        serialize_into(&mut file, &block)?;
+        count += 1;
+    }
+    file.seek(SeekFrom::Start(0))?;
+    serialize_into(&mut file, &count)?;
+    file.flush()?;
+    Ok(count as usize)
+}
+
+pub fn load_export(path: &str) -> Result<Vec<Block>> {
+    Ok(deserialize(&fs::read(path)?)?)
+}
+
+/// replay validates `blocks`, genesis first, on an empty chain following `params` and
+/// reports every block that fails. Failed blocks are still connected, so the blocks
+/// after them are checked against the chain as it was exported.
+pub fn replay(blocks: &[Block], params: ChainParams) -> Result<Vec<ReplayFailure>> {
+    let mut chain = Blockchain::new_temporary(params)?;
+    let mut failures = Vec::new();
+    for block in blocks {
+        if let Err(e) = chain.add_block(block.clone()) {
+            failures.push(ReplayFailure::new(block.get_hash(), block.get_height(), e));
+            chain.store_block(block)?;
+        }
+    }
+    Ok(failures)
+}
+
+/// verify_chain checks the best chain from genesis up as a new node would: every block
+/// must extend the one before it, pass the consensus rules, signatures included even
+/// below checkpoints, and spend only outputs left unspent by the blocks before it. It
+/// returns how many blocks passed and the first block that failed.
+pub fn verify_chain(blockchain: &Blockchain) -> Result<(usize, Option<ReplayFailure>)> {
+    if blockchain.is_pruned()? {
+        return Err(format_err!(
+            "Cannot verify a pruned chain, its old blocks are deleted"
+        ));
+    }
+    let mut unspent = HashSet::new();
+    let mut prev = BlockHash::default();
+    let mut verified = 0;
+    for entry in blockchain.walk().oldest_first().with_bodies() {
+        let header = &entry.header;
+        let result = if header.prev_block_hash != prev {
+            Err(header.reject("prev-hash", None, prev, header.prev_block_hash))
+        } else {
+            match &entry.body {
+                Some(block) => verify_block(blockchain, block, &mut unspent),
+                None => Err(format_err!(
+                    "The body of block {} is missing or damaged",
+                    header.hash
+                )),
+            }
+        };
+        if let Err(e) = result {
+            let failure = ReplayFailure::new(entry.header.hash, entry.height, e);
+            return Ok((verified, Some(failure)));
+        }
+        prev = entry.header.hash;
+        verified += 1;
+    }
+    Ok((verified, None))
+}
+
+/// verify_block validates `block` against the chain and moves the outputs it spends
+/// and creates in and out of `unspent`
+fn verify_block(
+    blockchain: &Blockchain,
+    block: &Block,
+    unspent: &mut HashSet<(TxId, i32)>,
+) -> Result<()> {
+    let mut state = blockchain.parent_state(block)?;
+    state.check_signatures = true;
+    block.validate_full(&state)?;
+    for tx in block.get_transactions() {
+        for vin in tx.vin.iter().filter(|_| !tx.is_coinbase()) {
+            if !unspent.remove(&(vin.txid, vin.vout)) {
+                return Err(block.header().reject(
+                    "spent-output",
+                    Some(&tx.id),
+                    "unspent output",
+                    format!("{}:{} already spent or not created yet", vin.txid, vin.vout),
+                ));
+            }
+        }
+        for (vout, out) in tx.vout.iter().enumerate() {
+            if !out.is_unspendable() {
+                unspent.insert((tx.id, vout as i32));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{transaction::Transaction, u256::U256};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_replay() {
+        let params = ChainParams {
+            initial_target_hexs: 1,
+            genesis_hash: None,
+            ..ChainParams::default()
+        };
+        let mut chain = Blockchain::new_temporary(params.clone()).unwrap();
+        let address = crate::wallets::hash_to_address(vec![7; 20]);
+        let coinbase = |height| {
+            Transaction::new_coinbase(address.clone(), format!("replay {}", height), height)
+                .unwrap()
+        };
+        chain
+            .add_block(
+                Block::new(
+                    vec![coinbase(0)],
+                    BlockHash::default(),
+                    0,
+                    U256::from_hexs(1),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        chain.mine_block(vec![coinbase(1)]).unwrap();
+        chain.mine_block(vec![coinbase(2)]).unwrap();
+        let blocks: Vec<Block> = chain.walk().oldest_first().blocks().collect();
+
+        assert!(replay(&blocks, params.clone()).unwrap().is_empty());
+        assert_eq!(verify_chain(&chain).unwrap(), (3, None));
+
+        // a stored block the rules reject is found, and the chain cut before it
+        let tip = chain.get_block(&chain.get_tip_hash()).unwrap().header();
+        let mut greedy = coinbase(3);
+        greedy.vout[0].value += 1;
+        greedy.id = greedy.hash().unwrap();
+        let target = chain.target_after(&tip).unwrap();
+        let bad = Block::new(vec![greedy], tip.hash, 3, target).unwrap();
+        chain.store_block(&bad).unwrap();
+        let (verified, failure) = verify_chain(&chain).unwrap();
+        assert_eq!(verified, 3);
+        let failure = failure.unwrap();
+        assert_eq!(
+            (failure.hash, failure.rule.as_str()),
+            (bad.get_hash(), "coinbase-value")
+        );
+        assert_eq!(chain.truncate(&bad.get_hash()).unwrap(), 1);
+        assert_eq!(chain.get_tip_hash(), tip.hash);
+        assert!(!chain.has_block(&bad.get_hash()).unwrap());
+        assert_eq!(verify_chain(&chain).unwrap(), (3, None));
+        // the cut block is neither downloaded again nor built on
+        assert_eq!(chain.get_header_tip().unwrap().hash, tip.hash);
+        assert!(chain.missing_blocks().unwrap().is_empty());
+        assert!(chain.add_header(bad.header()).is_err());
+        assert!(chain.add_block(bad.clone()).is_err());
+        let child = Block::new(
+            vec![coinbase(4)],
+            bad.get_hash(),
+            4,
+            chain.target_after(&bad.header()).unwrap(),
+        )
+        .unwrap();
+        assert!(chain.add_header(child.header()).is_err());
+        let rejections = chain.get_rejections().unwrap();
+        assert_eq!(
+            (rejections[0].block_hash, rejections[0].rule.as_str()),
+            (child.get_hash(), "invalid")
+        );
+        assert!(chain.truncate(&blocks[0].get_hash()).is_err());
+
+        let ruleset = ChainParams {
+            checkpoints: BTreeMap::from([(1, BlockHash::default())]),
+            ..params
+        };
+        let failures = replay(&blocks, ruleset).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].height, 1);
+        assert_eq!(failures[0].rule, "checkpoint");
+    }
+}
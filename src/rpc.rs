@@ -0,0 +1,198 @@
+use crate::{
+    banlist::BanEntry,
+    block::{Block, BlockRejection},
+    blockchain::{IndexInfo, WalletChanges},
+    error::CodedError,
+    events::TimedEvent,
+    filter::BlockFilter,
+    hash::{BlockHash, TxId},
+    miner::{BlockTemplate, MinerStats},
+    services::Services,
+    transaction::Transaction,
+    u256::U256,
+};
+use serde::{Deserialize, Serialize};
+
+/// FEE_RATE_BUCKETS are the lower bounds, in coins per 1000 bytes, of the fee histogram buckets
+pub const FEE_RATE_BUCKETS: [u64; 10] = [0, 1, 2, 5, 10, 20, 50, 100, 200, 500];
+
+/// RpcRequest is a query a running node answers on the same connection
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RpcRequest {
+    MempoolFeeHistogram,
+    BlockTemplate,
+    /// LongPollBlockTemplate answers once the template `longpoll_id` names is superseded
+    /// or after `timeout` seconds
+    LongPollBlockTemplate {
+        longpoll_id: String,
+        timeout: u64,
+    },
+    /// MiningInfo asks for the difficulty, the network hashrate estimated over the last
+    /// `blocks` blocks and the counters of the local miner
+    MiningInfo {
+        blocks: usize,
+    },
+    RejectedBlocks,
+    ReloadConfig,
+    PeerEvents,
+    /// DoubleSpends asks for the recent double spends of pending transactions, answered
+    /// as PeerEvents
+    DoubleSpends,
+    /// SetBan bans `range` for `duration` seconds, or forever, or lifts its ban
+    SetBan {
+        range: String,
+        remove: bool,
+        duration: Option<u64>,
+    },
+    ListBanned,
+    /// IndexInfo asks which historical queries the node can answer
+    IndexInfo,
+    /// PeerInfo asks for the services of the node and of its handshaken peers
+    PeerInfo,
+    /// BlockFilters asks for the compact filters of up to `count` best chain blocks
+    /// from `start_height` on
+    BlockFilters {
+        start_height: i32,
+        count: usize,
+    },
+    /// SubmitBlock hands the node a block mined elsewhere, which it validates and relays
+    /// like one of its own
+    SubmitBlock {
+        block: Block,
+    },
+    /// SendTransaction hands the node a transaction of a local wallet, which it
+    /// validates and relays like one of a peer
+    SendTransaction {
+        transaction: Transaction,
+    },
+    /// WalletChanges asks what changed for the outputs of `pub_key_hashes` after block
+    /// `since`, so a light wallet catches up in one request
+    WalletChanges {
+        pub_key_hashes: Vec<Vec<u8>>,
+        since: Option<BlockHash>,
+    },
+}
+
+impl RpcRequest {
+    /// is_admin tells whether the request changes the node, which the audit log records
+    /// and which is only served over the loopback interface or with the rpc_token
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            RpcRequest::ReloadConfig
+                | RpcRequest::SetBan { .. }
+                | RpcRequest::SubmitBlock { .. }
+                | RpcRequest::SendTransaction { .. }
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RpcResponse {
+    FeeHistogram(Vec<FeeBucket>),
+    BlockTemplate(BlockTemplate),
+    BlockAccepted(BlockHash),
+    TransactionAccepted(TxId),
+    MiningInfo(MiningInfo),
+    RejectedBlocks(Vec<BlockRejection>),
+    ConfigReloaded(Vec<String>),
+    PeerEvents(Vec<TimedEvent>),
+    Banned(Vec<BanEntry>),
+    IndexInfo(IndexInfo),
+    PeerInfo {
+        services: Services,
+        peers: Vec<PeerInfo>,
+    },
+    BlockFilters(Vec<FilterEntry>),
+    WalletChanges(WalletChanges),
+    Error(CodedError),
+}
+
+/// MiningInfo is the mining state of a node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiningInfo {
+    pub height: i32,
+    /// target is the most the next block hash may be
+    pub target: U256,
+    /// network_hashrate is the hashes a second the recent blocks took to find
+    pub network_hashrate: f64,
+    pub mining: bool,
+    pub miner: MinerStats,
+}
+
+/// PeerInfo is a handshaken peer with the services it advertised
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub address: String,
+    pub height: Option<i32>,
+    pub services: Services,
+}
+
+/// FilterEntry is the compact filter of the best chain block at `height`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FilterEntry {
+    pub height: i32,
+    pub hash: BlockHash,
+    pub filter: BlockFilter,
+}
+
+/// FeeBucket sums the pending transactions paying at least `min_fee_rate`
+/// and less than the next bucket's rate
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeBucket {
+    pub min_fee_rate: u64,
+    pub count: usize,
+    pub size: usize,
+}
+
+/// fee_rate is the fee of a transaction in coins per 1000 bytes
+pub fn fee_rate(fee: i32, size: usize) -> u64 {
+    if size == 0 {
+        return 0;
+    }
+    fee.max(0) as u64 * 1000 / size as u64
+}
+
+/// fee_histogram groups `(fee, size)` pairs of pending transactions by fee rate, highest rate first
+pub fn fee_histogram(entries: &[(i32, usize)]) -> Vec<FeeBucket> {
+    let mut buckets: Vec<FeeBucket> = FEE_RATE_BUCKETS
+        .iter()
+        .map(|rate| FeeBucket {
+            min_fee_rate: *rate,
+            count: 0,
+            size: 0,
+        })
+        .collect();
+
+    for (fee, size) in entries {
+        let rate = fee_rate(*fee, *size);
+        let bucket = buckets
+            .iter_mut()
+            .rev()
+            .find(|b| b.min_fee_rate <= rate)
+            .unwrap();
+        bucket.count += 1;
+        bucket.size += size;
+    }
+    buckets.reverse();
+    buckets
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fee_histogram() {
+        let histogram = fee_histogram(&[(0, 250), (1, 500), (1, 1000), (3, 250), (600, 1000)]);
+        assert_eq!(histogram.len(), FEE_RATE_BUCKETS.len());
+        assert_eq!(histogram[0].min_fee_rate, 500);
+        assert_eq!((histogram[0].count, histogram[0].size), (1, 1000));
+
+        let by_rate = |rate| histogram.iter().find(|b| b.min_fee_rate == rate).unwrap();
+        assert_eq!((by_rate(0).count, by_rate(0).size), (1, 250));
+        assert_eq!((by_rate(1).count, by_rate(1).size), (1, 1000));
+        assert_eq!((by_rate(2).count, by_rate(2).size), (1, 500));
+        assert_eq!((by_rate(10).count, by_rate(10).size), (1, 250));
+    }
+}
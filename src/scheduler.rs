@@ -0,0 +1,132 @@
+use super::*;
+use crate::config::data_path;
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+const SCHEDULES_DB: &str = "schedules";
+
+/// ScheduledPayment is a recurring payment executed by the node daemon
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduledPayment {
+    pub id: u64,
+    pub from: String,
+    pub to: String,
+    pub amount: i32,
+    pub interval: u64,
+    pub next_run: u64,
+}
+
+pub struct Scheduler {}
+
+impl Scheduler {
+    pub fn new() -> Result<Self> {
+        Ok(Scheduler {})
+    }
+
+    pub fn add(
+        &self,
+        from: &str,
+        to: &str,
+        amount: i32,
+        interval: u64,
+    ) -> Result<ScheduledPayment> {
+        let db = sled::open(data_path(SCHEDULES_DB))?;
+        let payment = ScheduledPayment {
+            id: db.generate_id()?,
+            from: from.to_string(),
+            to: to.to_string(),
+            amount,
+            interval,
+            next_run: now_secs()? + interval,
+        };
+        db.insert(payment.id.to_be_bytes(), serialize(&payment)?)?;
+        db.flush()?;
+        info!("scheduled payment {}: {} -> {}", payment.id, from, to);
+        Ok(payment)
+    }
+
+    pub fn list(&self) -> Result<Vec<ScheduledPayment>> {
+        let db = sled::open(data_path(SCHEDULES_DB))?;
+        let mut payments = Vec::new();
+        for kv in db.iter() {
+            let (_, value) = kv?;
+            payments.push(deserialize(&value)?);
+        }
+        Ok(payments)
+    }
+
+    pub fn cancel(&self, id: u64) -> Result<bool> {
+        let db = sled::open(data_path(SCHEDULES_DB))?;
+        let removed = db.remove(id.to_be_bytes())?.is_some();
+        db.flush()?;
+        Ok(removed)
+    }
+
+    /// take_due returns the payments whose time has come and moves them to their next run
+    pub fn take_due(&self) -> Result<Vec<ScheduledPayment>> {
+        let now = now_secs()?;
+        let db = sled::open(data_path(SCHEDULES_DB))?;
+        let mut due = Vec::new();
+        for kv in db.iter() {
+            let (key, value) = kv?;
+            let mut payment: ScheduledPayment = deserialize(&value)?;
+            if payment.next_run > now {
+                continue;
+            }
+            due.push(payment.clone());
+            while payment.next_run <= now {
+                payment.next_run += payment.interval;
+            }
+            db.insert(key, serialize(&payment)?)?;
+        }
+        db.flush()?;
+        Ok(due)
+    }
+}
+
+/// parse_interval turns strings like `90`, `30s`, `15m`, `2h` or `1d` into seconds
+pub fn parse_interval(interval: &str) -> Result<u64> {
+    let interval = interval.trim();
+    let (value, unit) = match interval.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => interval.split_at(idx),
+        None => (interval, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format_err!("Unknown interval unit: {}", unit)),
+    };
+    let value: u64 = value.parse()?;
+    if value == 0 {
+        return Err(format_err!("Interval must be greater than zero"));
+    }
+    Ok(value * multiplier)
+}
+
+pub fn now_secs() -> Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("45").unwrap(), 45);
+        assert_eq!(parse_interval("30s").unwrap(), 30);
+        assert_eq!(parse_interval("15m").unwrap(), 900);
+        assert_eq!(parse_interval("2h").unwrap(), 7200);
+        assert_eq!(parse_interval("1d").unwrap(), 86400);
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("5w").is_err());
+        assert!(parse_interval("m").is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::wallets::hash_pub_key;
+use crypto::{digest::Digest, ed25519, sha2::Sha256};
+use serde::{Deserialize, Serialize};
+
+/// PUB_KEY_LEN is the size of an ed25519 public key
+pub const PUB_KEY_LEN: usize = 32;
+/// SIGNATURE_LEN is the size of an ed25519 signature, without a sighash type byte
+pub const SIGNATURE_LEN: usize = 64;
+/// MAX_VAULT_DELAY is the longest a vault may hold back its unvaults, in blocks, which
+/// keeps the heights they mature at far from overflowing
+pub const MAX_VAULT_DELAY: i32 = 1_000_000;
+/// MAX_VAULT_FEE is the most fee a hot key spend of vaulted funds may leave to miners,
+/// so the hot key cannot drain the vault into fees
+pub const MAX_VAULT_FEE: i32 = 10;
+
+/// LockScript is the condition for spending an output, which the output's pub_key_hash
+/// completes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum LockScript {
+    /// the key hashing to pub_key_hash signs
+    #[default]
+    PayToPubkeyHash,
+    /// the spending input reveals `n` keys whose redeem_hash is pub_key_hash, and `m` of
+    /// them sign
+    MultiSig { m: u8, n: u8 },
+    /// like PayToPubkeyHash, but only in a block at `height` or above
+    TimeLock { height: i32 },
+    /// the spending input reveals a hot key followed by a recovery key, whose redeem_hash
+    /// is pub_key_hash, and one of them signs. The recovery key spends it anywhere, the
+    /// hot key only into Unvault outputs of the same keys maturing `delay` blocks later.
+    Vault { delay: i32 },
+    /// funds leaving a vault: the hot key spends it in a block at `height` or above, the
+    /// recovery key at any time to claw it back
+    Unvault { height: i32 },
+}
+
+/// VaultKey is the key of a vault that signed a spend of one of its outputs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VaultKey {
+    Hot,
+    Recovery,
+}
+
+impl LockScript {
+    /// signatures is how many signatures an input spending the output carries
+    pub fn signatures(&self) -> usize {
+        match self {
+            LockScript::MultiSig { m, .. } => *m as usize,
+            _ => 1,
+        }
+    }
+
+    /// matches_keys checks that `pub_key`, the keys a spending input reveals, are the
+    /// ones the output committed to with `pub_key_hash`
+    pub fn matches_keys(&self, pub_key_hash: &[u8], pub_key: &[u8]) -> bool {
+        match self {
+            LockScript::MultiSig { m, n } => {
+                *m >= 1
+                    && m <= n
+                    && pub_key.len() == *n as usize * PUB_KEY_LEN
+                    && redeem_hash(pub_key) == pub_key_hash
+            }
+            LockScript::Vault { .. } | LockScript::Unvault { .. } => {
+                pub_key.len() == 2 * PUB_KEY_LEN && redeem_hash(pub_key) == pub_key_hash
+            }
+            _ => {
+                let mut hash = pub_key.to_vec();
+                hash_pub_key(&mut hash);
+                pub_key.len() == PUB_KEY_LEN && hash == pub_key_hash
+            }
+        }
+    }
+
+    /// is_spendable_at tells whether the output may be spent in a block at `height`
+    pub fn is_spendable_at(&self, height: i32) -> bool {
+        match self {
+            LockScript::TimeLock { height: unlock } => height >= *unlock,
+            _ => true,
+        }
+    }
+
+    /// is_valid tells whether a new output may carry the lock: a vault must delay its
+    /// unvaults by one to MAX_VAULT_DELAY blocks
+    pub fn is_valid(&self) -> bool {
+        match self {
+            LockScript::Vault { delay } => (1..=MAX_VAULT_DELAY).contains(delay),
+            _ => true,
+        }
+    }
+
+    /// is_vault tells whether the output belongs to a vault, vaulted or unvaulting
+    pub fn is_vault(&self) -> bool {
+        matches!(self, LockScript::Vault { .. } | LockScript::Unvault { .. })
+    }
+
+    /// vault_signer tells which key of a vault made `signature` of `digest`, `pub_key`
+    /// being the hot key followed by the recovery key
+    pub fn vault_signer(pub_key: &[u8], signature: &[u8], digest: &[u8]) -> Option<VaultKey> {
+        if pub_key.len() != 2 * PUB_KEY_LEN || signature.len() != SIGNATURE_LEN {
+            return None;
+        }
+        let (hot, recovery) = pub_key.split_at(PUB_KEY_LEN);
+        if ed25519::verify(digest, hot, signature) {
+            Some(VaultKey::Hot)
+        } else if ed25519::verify(digest, recovery, signature) {
+            Some(VaultKey::Recovery)
+        } else {
+            None
+        }
+    }
+
+    /// verify checks `signatures` of `digest`, each of which must be made by a different
+    /// key of `pub_key`
+    pub fn verify(&self, pub_key: &[u8], signatures: &[u8], digest: &[u8]) -> bool {
+        if signatures.len() != self.signatures() * SIGNATURE_LEN {
+            return false;
+        }
+        let keys: Vec<&[u8]> = pub_key.chunks(PUB_KEY_LEN).collect();
+        let mut used = vec![false; keys.len()];
+        signatures.chunks(SIGNATURE_LEN).all(|signature| {
+            let signer =
+                (0..keys.len()).find(|i| !used[*i] && ed25519::verify(digest, keys[*i], signature));
+            match signer {
+                Some(i) => {
+                    used[i] = true;
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+}
+
+/// redeem_hash is the pub_key_hash of a multisig output over the concatenated `pub_keys`
+pub fn redeem_hash(pub_keys: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(pub_keys);
+    let mut hash = vec![0; 32];
+    hasher.result(&mut hash);
+    hash_pub_key(&mut hash);
+    hash
+}
+
+/// spender_hash is the pub_key_hash of the output an input revealing `pub_key` spends:
+/// the key hash of a single key, the redeem hash of several
+pub fn spender_hash(pub_key: &[u8]) -> Vec<u8> {
+    if pub_key.len() == PUB_KEY_LEN {
+        let mut hash = pub_key.to_vec();
+        hash_pub_key(&mut hash);
+        hash
+    } else {
+        redeem_hash(pub_key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wallets::Wallet;
+
+    #[test]
+    fn test_multisig() {
+        let keys: Vec<Wallet> = (0..3)
+            .map(|i| Wallet::derive(&[i; 32], &[44, 0], 0))
+            .collect();
+        let pub_key: Vec<u8> = keys.iter().flat_map(|w| w.public_key.clone()).collect();
+        let lock = LockScript::MultiSig { m: 2, n: 3 };
+        let hash = redeem_hash(&pub_key);
+        assert!(lock.matches_keys(&hash, &pub_key));
+        assert!(!lock.matches_keys(&hash, &pub_key[..2 * PUB_KEY_LEN]));
+        assert!(!LockScript::PayToPubkeyHash.matches_keys(&hash, &pub_key));
+
+        let digest = b"digest";
+        let sign = |w: &Wallet| ed25519::signature(digest, &w.secret_key).to_vec();
+        let signatures = [sign(&keys[2]), sign(&keys[0])].concat();
+        assert!(lock.verify(&pub_key, &signatures, digest));
+        // one key cannot sign twice
+        let twice = [sign(&keys[1]), sign(&keys[1])].concat();
+        assert!(!lock.verify(&pub_key, &twice, digest));
+        assert!(!lock.verify(&pub_key, &signatures[..SIGNATURE_LEN], digest));
+
+        assert!(!LockScript::TimeLock { height: 5 }.is_spendable_at(4));
+        assert!(LockScript::TimeLock { height: 5 }.is_spendable_at(5));
+    }
+}
@@ -1,27 +1,194 @@
 use super::*;
-use crate::{block::Block, transaction::Transaction, utxoset::UTXOSet};
+use crate::{
+    api, audit,
+    banlist::{BanEntry, BanList, IpRange},
+    block::{coinbase_branch, Block, BlockHeader},
+    blockchain::WalletChanges,
+    config::{data_path, Config, NodeSettings},
+    decode::{
+        check_address, check_count, decode_untrusted, malformed, MAX_ADDRESS_LEN, MAX_MESSAGE_SIZE,
+    },
+    discovery,
+    error::{CodedError, ErrorCode},
+    events::{Direction, EventBus, NodeEvent, TimedEvent},
+    hash::{BlockHash, TxId},
+    mempool::Mempool,
+    metrics::Metrics,
+    miner::{build_template, BlockTemplate, MempoolEntry, TemplateManager, TemplatePolicy},
+    ratelimit::{Connections, RateLimiter},
+    rpc::{fee_histogram, FilterEntry, MiningInfo, PeerInfo, RpcRequest, RpcResponse},
+    scheduler::{now_secs, Scheduler},
+    services::Services,
+    transaction::Transaction,
+    utxoset::{CoinSelection, UTXOSet},
+    validation::{first_failure, trace_transaction},
+    wallets::{hash_pub_key, hash_to_address, Conflict, Wallets},
+};
 use bincode::{deserialize, serialize};
 use core::time::Duration;
+use crypto::util::fixed_time_eq;
 use failure::format_err;
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
+use sled::Db;
 use std::{
     collections::{HashMap, HashSet},
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    panic,
+    sync::{mpsc::Receiver, Arc, Mutex, RwLock},
     thread,
+    time::{Instant, SystemTime},
 };
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Message {
-    Address(Vec<String>),
+    Address(AddrMsg),
     Version(VersionMsg),
+    Verack(VerackMsg),
     Transaction(TransactionMsg),
     GetData(GetDataMsg),
     GetBlocks(GetBlocksMsg),
+    GetHeaders(GetHeadersMsg),
+    Headers(HeadersMsg),
     Invite(InviteMsg),
     Block(BlockMsg),
+    Rpc(RpcRequest),
+    AuthRpc(AuthRpcMsg),
+}
+
+impl Message {
+    /// data_sender returns the node a data message claims to come from; data messages
+    /// are only accepted from nodes that completed the version/verack handshake, over a
+    /// connection from the IP they completed it from. Wallets hand transactions over
+    /// with RpcRequest::SendTransaction instead.
+    fn data_sender(&self) -> Option<&str> {
+        match self {
+            Message::Address(msg) => Some(&msg.address_from),
+            Message::GetData(msg) => Some(&msg.address_from),
+            Message::GetBlocks(msg) => Some(&msg.address_from),
+            Message::GetHeaders(msg) => Some(&msg.address_from),
+            Message::Headers(msg) => Some(&msg.address_from),
+            Message::Invite(msg) => Some(&msg.address_from),
+            Message::Block(msg) => Some(&msg.address_from),
+            Message::Transaction(msg) => Some(&msg.address_from),
+            Message::Version(_) | Message::Verack(_) | Message::Rpc(_) | Message::AuthRpc(_) => {
+                None
+            }
+        }
+    }
+
+    /// check verifies what a paranoid node holds every message to: node addresses and
+    /// lists are bounded, and blocks, headers and transactions hash to what they claim
+    fn check(&self) -> Result<()> {
+        if let Some(sender) = self.data_sender() {
+            check_address(sender)?;
+        }
+        match self {
+            Message::Address(msg) => {
+                check_count("node addresses", msg.nodes.len(), MAX_ADDR_NODES)?;
+                for node in &msg.nodes {
+                    check_address(node)?;
+                }
+            }
+            Message::Version(msg) => {
+                check_address(&msg.address_from)?;
+                check_address(&msg.address_to)?;
+            }
+            Message::Verack(msg) => check_address(&msg.address_from)?,
+            Message::Transaction(msg) => {
+                check_address(&msg.address_from)?;
+                check_transaction(&msg.transaction)?;
+            }
+            Message::GetHeaders(msg) => {
+                check_count("locator hashes", msg.locator.len(), MAX_LOCATOR)?
+            }
+            Message::Headers(msg) => {
+                check_count("headers", msg.headers.len(), MAX_HEADERS)?;
+                for header in &msg.headers {
+                    header.check_hash()?;
+                }
+            }
+            Message::Invite(msg) => {
+                check_count("inventory items", msg.items.len(), MAX_KNOWN_INVENTORY)?
+            }
+            Message::Block(msg) => msg.block.check_commitments()?,
+            Message::Rpc(request) => check_rpc(request)?,
+            Message::AuthRpc(msg) => {
+                check_count("rpc token bytes", msg.token.len(), MAX_ADDRESS_LEN)?;
+                check_rpc(&msg.request)?
+            }
+            Message::GetData(_) | Message::GetBlocks(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// check_rpc bounds the lists of an RPC request and checks the blocks and transactions
+/// it hands over
+fn check_rpc(request: &RpcRequest) -> Result<()> {
+    match request {
+        RpcRequest::SubmitBlock { block } => block.check_commitments()?,
+        RpcRequest::SendTransaction { transaction } => check_transaction(transaction)?,
+        RpcRequest::BlockFilters { count, .. } => check_count("filters", *count, MAX_FILTERS)?,
+        RpcRequest::WalletChanges { pub_key_hashes, .. } => {
+            check_count("addresses", pub_key_hashes.len(), MAX_SYNC_ADDRESSES)?
+        }
+        RpcRequest::SetBan { range, .. } => {
+            check_count("ban range bytes", range.len(), MAX_ADDRESS_LEN)?
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// authorize_rpc refuses an admin request from `peer` unless it is on the loopback
+/// interface or `token` is the configured `rpc_token`
+fn authorize_rpc(
+    request: &RpcRequest,
+    peer: Option<IpAddr>,
+    token: Option<&str>,
+    rpc_token: Option<&str>,
+) -> Result<()> {
+    if !request.is_admin() || peer.is_some_and(|ip| ip.to_canonical().is_loopback()) {
+        return Ok(());
+    }
+    if let (Some(token), Some(rpc_token)) = (token, rpc_token)
+        && !rpc_token.is_empty()
+        && fixed_time_eq(token.as_bytes(), rpc_token.as_bytes())
+    {
+        return Ok(());
+    }
+    Err(CodedError::new(
+        ErrorCode::Unauthorized,
+        "Admin requests need the loopback interface or the rpc_token",
+    )
+    .into())
+}
+
+/// check_transaction refuses a transaction whose id is not its hash, or that spends or
+/// pays nothing
+fn check_transaction(tx: &Transaction) -> Result<()> {
+    let id = tx.clone().hash()?;
+    if id != tx.id {
+        return Err(malformed(format!("transaction {} hashes to {}", tx.id, id)));
+    }
+    if tx.vin.is_empty() || tx.vout.is_empty() {
+        return Err(malformed(format!(
+            "transaction {} has {} inputs and {} outputs",
+            tx.id,
+            tx.vin.len(),
+            tx.vout.len()
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AddrMsg {
+    address_from: String,
+    nodes: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -35,18 +202,36 @@ struct GetBlocksMsg {
     address_from: String,
 }
 
+/// GetHeadersMsg asks for the headers following the first `locator` hash the peer knows
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetHeadersMsg {
+    address_from: String,
+    locator: Vec<BlockHash>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct HeadersMsg {
+    address_from: String,
+    headers: Vec<BlockHeader>,
+}
+
+/// Inventory names a block or transaction that a peer announces or asks for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Inventory {
+    Block(BlockHash),
+    Tx(TxId),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GetDataMsg {
     address_from: String,
-    kind: String,
-    id: String,
+    item: Inventory,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct InviteMsg {
     address_from: String,
-    kind: String,
-    items: Vec<String>,
+    items: Vec<Inventory>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -55,46 +240,251 @@ struct TransactionMsg {
     transaction: Transaction,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct VersionMsg {
     address_from: String,
+    /// address_to is the address the sender reached the receiver at, which tells a node
+    /// behind NAT how others see it
+    address_to: String,
     version: i32,
     best_height: i32,
+    services: Services,
+    /// network names the chain of the node, whose magic the message header carries
+    network: String,
+    /// nonce is random for every node, so one that dialed itself recognizes its version
+    nonce: u64,
+}
+
+/// AuthRpcMsg is an RPC request presenting the rpc_token, so another host may make
+/// admin requests
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AuthRpcMsg {
+    token: String,
+    request: RpcRequest,
+}
+
+/// VerackMsg accepts a version, telling the initiating node the services of the peer
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct VerackMsg {
+    address_from: String,
+    version: i32,
+    services: Services,
 }
 
 pub struct Server {
     node_address: String,
     mining_address: String,
-    inner: Arc<Mutex<ServerInner>>,
+    inner: Arc<ServerInner>,
 }
 
+/// ServerInner is the state the threads of a node share. Its parts are locked on their
+/// own, so validating a block does not hold up relay or RPCs that need other parts.
+/// Locks held together are always taken in the order of the fields.
 struct ServerInner {
+    /// utxo is the chain with its UTXO set; readers share it, changing the chain
+    /// takes it alone
+    utxo: RwLock<UTXOSet>,
+    mempool: RwLock<Mempool>,
+    pending_since: Mutex<HashMap<TxId, i32>>,
+    node: RwLock<NodeState>,
+    peers: Mutex<PeerSet>,
+    sync: Mutex<SyncState>,
+    bans: Mutex<BanList>,
+    connections: Mutex<Connections>,
+    rate_limiter: Mutex<RateLimiter>,
+    events: Mutex<EventBus>,
+    metrics: Mutex<Metrics>,
+    /// miner wakes the miner thread and cancels its block when the tip moves
+    miner: TemplateManager,
+    /// listen_address is where the node listens unless the bind setting says otherwise
+    listen_address: String,
+    /// magic and network are those of the chain, read on every message
+    magic: [u8; MAGIC_LEN],
+    network: String,
+    /// nonce is sent in the version messages of the node to detect connections to itself
+    nonce: u64,
+}
+
+/// NodeState holds the reloadable settings of the node and what derives from them
+struct NodeState {
+    settings: NodeSettings,
+    replace_by_fee: bool,
+    template_policy: TemplatePolicy,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    /// seen_addresses counts the addresses peers reached the node at
+    seen_addresses: HashMap<String, usize>,
+}
+
+/// PeerSet holds the known nodes and what the node knows about each of them
+struct PeerSet {
     known_nodes: HashSet<String>,
-    utxo: UTXOSet,
-    blocks_in_transit: Vec<String>,
-    mempool: HashMap<String, Transaction>,
+    db: Db,
+    misbehavior: HashMap<IpAddr, u32>,
+    /// handshaken are the peers ready to exchange data, by who opened the connection
+    handshaken: HashMap<String, Direction>,
+    /// peer_ips holds the IP each handshaken peer completed the handshake from, which
+    /// its data messages must come from too
+    peer_ips: HashMap<String, IpAddr>,
+    /// known_inventory holds, by peer, what it announced or was announced, so nothing
+    /// is announced to a peer twice
+    known_inventory: HashMap<String, HashSet<Inventory>>,
+    heights: HashMap<String, i32>,
+    services: HashMap<String, Services>,
+}
+
+/// SyncState tracks the blocks being downloaded
+struct SyncState {
+    blocks_in_transit: Vec<BlockHash>,
+    downloading: HashMap<BlockHash, Instant>,
+    orphans: HashMap<BlockHash, Block>,
+}
+
+/// PeerRecord is the persisted state of a peer in the peers database
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PeerRecord {
+    last_seen: u128,
+    failures: u32,
+}
+
+/// ConnectionSlot counts an inbound connection against the connection limits until
+/// it is dropped
+struct ConnectionSlot {
+    inner: Arc<ServerInner>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = self.inner.connections.lock() {
+            connections.close(self.ip);
+        }
+    }
 }
 
-const KNOWN_NODE1: &str = "localhost: 3000";
+pub const KNOWN_NODE1: &str = "localhost:3000";
+const PEERS_DB: &str = "peers";
+const PEER_RETRY_INTERVAL: u64 = 30_000;
+const MAX_PEER_FAILURES: u32 = 10;
+const SCHEDULER_INTERVAL: u64 = 10_000;
+/// DEFAULT_READ_TIMEOUT bounds how long a whole message may take to arrive
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// TIMEOUT_PENALTY is added to a peer's misbehavior score for every connection that times out
+const TIMEOUT_PENALTY: u32 = 20;
+/// HANDSHAKE_PENALTY is added for every data message sent before the handshake
+const HANDSHAKE_PENALTY: u32 = 10;
+/// WRONG_NETWORK_PENALTY is added for every message sent for another network
+const WRONG_NETWORK_PENALTY: u32 = 50;
+/// RATE_LIMIT_PENALTY is added for every message dropped for exceeding the rate limit
+const RATE_LIMIT_PENALTY: u32 = 2;
+/// MALFORMED_PENALTY is added for every message that can not be decoded or, on a
+/// paranoid node, breaks an invariant
+const MALFORMED_PENALTY: u32 = 50;
+/// MAX_LOCATOR is the most hashes a block locator holds
+const MAX_LOCATOR: usize = 101;
+/// MAX_ADDR_NODES is the most node addresses an addr message carries
+const MAX_ADDR_NODES: usize = 1000;
+/// MAX_MISBEHAVIOR is the score at which a peer is banned
+const MAX_MISBEHAVIOR: u32 = 100;
+/// MISBEHAVIOR_BAN_DURATION is how long, in seconds, a misbehaving peer stays banned
+const MISBEHAVIOR_BAN_DURATION: u64 = 24 * 60 * 60;
+/// MAX_HEADERS is the most headers sent in one headers message
+const MAX_HEADERS: usize = 2000;
+/// MAX_BLOCKS_IN_FLIGHT bounds the block downloads running at once during sync
+const MAX_BLOCKS_IN_FLIGHT: usize = 64;
+/// PRUNED_PEER_DEPTH is how far below its tip a pruned peer is asked for blocks
+const PRUNED_PEER_DEPTH: i32 = 100;
+/// MAX_KNOWN_INVENTORY is how many items the node remembers a peer knows; past it the
+/// peer may be announced an item again
+const MAX_KNOWN_INVENTORY: usize = 50_000;
+/// MAX_FILTERS is the most compact filters sent in one answer
+const MAX_FILTERS: usize = 2000;
+/// MAX_SEEN_ADDRESSES is how many addresses peers reached the node at are counted
+const MAX_SEEN_ADDRESSES: usize = 100;
+/// MAX_SYNC_ADDRESSES is the most addresses a light wallet may sync in one request
+const MAX_SYNC_ADDRESSES: usize = 1000;
+/// DOWNLOAD_TIMEOUT is how long a requested block may take before it is asked for again
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+/// MAX_LONGPOLL_TIMEOUT bounds how long a long-poll request is held
+pub const MAX_LONGPOLL_TIMEOUT: Duration = Duration::from_secs(300);
+/// MAGIC_LEN is the length of the network magic opening every message
+const MAGIC_LEN: usize = 4;
 const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
+const VERSION: i32 = 6;
+/// MIN_PEER_VERSION is the oldest protocol version a peer may speak
+const MIN_PEER_VERSION: i32 = 6;
 
 impl Server {
     pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Self> {
+        let node_address = format!("localhost:{}", port);
+        let peers = sled::open(data_path(PEERS_DB))?;
         let mut node_set = HashSet::new();
         node_set.insert(String::from(KNOWN_NODE1));
+        for kv in peers.iter() {
+            let (key, _) = kv?;
+            node_set.insert(String::from_utf8(key.to_vec())?);
+        }
+        node_set.remove(&node_address);
+        info!("Loaded {} known nodes", node_set.len());
+
+        let params = utxo.blockchain.get_params();
+        let (magic, network) = (params.magic(), params.network.clone());
+        let template_policy = TemplatePolicy {
+            max_block_size: params.max_block_size,
+            ..TemplatePolicy::default()
+        };
         Ok(Self {
-            node_address: String::from(format!("localhost:{}", port)),
+            node_address: node_address.clone(),
             mining_address: miner_address.to_string(),
-            inner: Arc::new(Mutex::new(ServerInner {
-                known_nodes: node_set,
-                utxo,
-                blocks_in_transit: Vec::new(),
-                mempool: HashMap::new(),
-            })),
+            inner: Arc::new(ServerInner {
+                utxo: RwLock::new(utxo),
+                mempool: RwLock::new(Mempool::new()),
+                pending_since: Mutex::new(HashMap::new()),
+                node: RwLock::new(NodeState {
+                    settings: NodeSettings::default(),
+                    replace_by_fee: false,
+                    template_policy,
+                    read_timeout: DEFAULT_READ_TIMEOUT,
+                    write_timeout: DEFAULT_WRITE_TIMEOUT,
+                    seen_addresses: HashMap::new(),
+                }),
+                peers: Mutex::new(PeerSet {
+                    known_nodes: node_set,
+                    db: peers,
+                    misbehavior: HashMap::new(),
+                    handshaken: HashMap::new(),
+                    peer_ips: HashMap::new(),
+                    known_inventory: HashMap::new(),
+                    heights: HashMap::new(),
+                    services: HashMap::new(),
+                }),
+                sync: Mutex::new(SyncState {
+                    blocks_in_transit: Vec::new(),
+                    downloading: HashMap::new(),
+                    orphans: HashMap::new(),
+                }),
+                bans: Mutex::new(BanList::open()?),
+                connections: Mutex::new(Connections::default()),
+                rate_limiter: Mutex::new(RateLimiter::default()),
+                events: Mutex::new(EventBus::new()),
+                metrics: Mutex::new(Metrics::new()),
+                miner: TemplateManager::default(),
+                listen_address: node_address,
+                magic,
+                network,
+                nonce: thread_rng().next_u64(),
+            }),
         })
     }
 
+    /// set_external_address makes the node known to peers as `addr` instead of
+    /// localhost; it must be called before the node starts
+    pub fn set_external_address(&mut self, addr: String) {
+        self.inner.peers.lock().unwrap().known_nodes.remove(&addr);
+        self.node_address = addr;
+    }
+
     pub fn start(&self) -> Result<()> {
         let server1 = Self {
             node_address: self.node_address.clone(),
@@ -108,32 +498,192 @@ impl Server {
 
         thread::spawn(move || {
             thread::sleep(Duration::from_millis(1000));
-            if server1.get_best_height()? == -1 {
-                server1.request_blocks()
-            } else {
-                server1.send_version(KNOWN_NODE1)
+            // an empty chain reports height -1, so peers answer the handshake with their blocks
+            for node in server1.get_known_nodes() {
+                if let Err(e) = server1.send_version(&node) {
+                    info!("handshake with {} failed: {}", node, e);
+                }
+            }
+        });
+
+        let server2 = Self {
+            node_address: self.node_address.clone(),
+            mining_address: self.mining_address.clone(),
+            inner: Arc::clone(&self.inner),
+        };
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(PEER_RETRY_INTERVAL));
+            if let Err(e) = server2.retry_dropped_peers() {
+                error!("failed to retry dropped peers: {}", e);
+            }
+            if let Err(e) = server2.request_damaged_blocks() {
+                error!("failed to request damaged blocks: {}", e);
+            }
+            if let Err(e) = server2.discover_if_low() {
+                error!("failed to discover peers: {}", e);
+            }
+        });
+
+        let server3 = Self {
+            node_address: self.node_address.clone(),
+            mining_address: self.mining_address.clone(),
+            inner: Arc::clone(&self.inner),
+        };
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(SCHEDULER_INTERVAL));
+            if server3.is_explorer() {
+                continue;
+            }
+            if let Err(e) = server3.run_scheduled_payments() {
+                error!("failed to run scheduled payments: {}", e);
+            }
+            if let Err(e) = server3.bump_stuck_fees() {
+                error!("failed to bump fees: {}", e);
+            }
+        });
+
+        if !self.mining_address.is_empty() {
+            let miner = Self {
+                node_address: self.node_address.clone(),
+                mining_address: self.mining_address.clone(),
+                inner: Arc::clone(&self.inner),
+            };
+            thread::spawn(move || miner.run_miner());
+        }
+
+        let events = self.subscribe();
+        thread::spawn(move || {
+            for timed in events {
+                info!("{}", timed.event);
             }
         });
 
-        let listener = TcpListener::bind(&self.node_address)?;
+        let bind = self.inner.node.read().unwrap().settings.bind.clone();
+        let listener = TcpListener::bind(bind.as_ref().unwrap_or(&self.inner.listen_address))?;
         info!("Server listen...");
 
         for stream in listener.incoming() {
             let stream = stream?;
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(_) => continue,
+            };
+            if self.is_banned(peer.ip()) {
+                debug!("refusing connection from banned peer {}", peer);
+                continue;
+            }
+            let slot = match self.admit(peer.ip()) {
+                Ok(Some(slot)) => slot,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("failed to admit connection from {}: {}", peer, e);
+                    continue;
+                }
+            };
             let server1 = Self {
                 node_address: self.node_address.clone(),
                 mining_address: self.mining_address.clone(),
                 inner: Arc::clone(&self.inner),
             };
-            thread::spawn(move || server1.handle_connection(stream));
+            thread::spawn(move || {
+                let _slot = slot;
+                server1.handle_connection(stream)
+            });
         }
 
         Ok(())
     }
 
-    pub fn send_transaction(tx: &Transaction, utxo_set: UTXOSet) -> Result<()> {
-        let server = Server::new("7000", "", utxo_set)?;
-        server.send_tx(KNOWN_NODE1, tx)
+    /// start_api serves the REST API for block explorers on `addr` in the background
+    pub fn start_api(&self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        info!("REST API listening on {}", addr);
+        let inner = Arc::clone(&self.inner);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("failed to accept API connection: {}", e);
+                        continue;
+                    }
+                };
+                let inner = Arc::clone(&inner);
+                thread::spawn(move || {
+                    stream.set_read_timeout(Some(DEFAULT_READ_TIMEOUT))?;
+                    stream.set_write_timeout(Some(DEFAULT_WRITE_TIMEOUT))?;
+                    api::serve(
+                        stream,
+                        |path| {
+                            let utxo = inner.utxo.read().unwrap();
+                            let mempool = inner.mempool.read().unwrap();
+                            api::route(path, &utxo, &mempool, &inner.metrics.lock().unwrap())
+                        },
+                        || inner.events.lock().unwrap().subscribe(),
+                    )
+                });
+            }
+        });
+        Ok(())
+    }
+
+    /// subscribe returns a receiver for every event the node publishes from now on,
+    /// for applications embedding the node
+    pub fn subscribe(&self) -> Receiver<TimedEvent> {
+        self.inner.events.lock().unwrap().subscribe()
+    }
+
+    pub fn send_transaction(tx: &Transaction) -> Result<()> {
+        Server::send_transactions(std::slice::from_ref(tx))
+    }
+
+    /// send_transactions hands `txs` to the node at KNOWN_NODE1, which relays them
+    pub fn send_transactions(txs: &[Transaction]) -> Result<()> {
+        for tx in txs {
+            audit::record("cli", "send", &format!("txid {}", tx.id))?;
+            let request = RpcRequest::SendTransaction {
+                transaction: tx.clone(),
+            };
+            match Server::rpc(KNOWN_NODE1, request)? {
+                RpcResponse::TransactionAccepted(_) => {}
+                response => return Err(format_err!("Unexpected response: {:?}", response)),
+            }
+        }
+        Ok(())
+    }
+
+    /// rpc sends `request` to the node at `addr` and waits for its answer
+    pub fn rpc(addr: &str, request: RpcRequest) -> Result<RpcResponse> {
+        Server::rpc_with_timeout(addr, request, DEFAULT_READ_TIMEOUT)
+    }
+
+    /// rpc_with_timeout is rpc waiting up to `timeout` for the answer, for requests such
+    /// as long polls that the node holds on purpose
+    pub fn rpc_with_timeout(
+        addr: &str,
+        request: RpcRequest,
+        timeout: Duration,
+    ) -> Result<RpcResponse> {
+        let config = Config::load()?;
+        let magic = config.chain.magic();
+        let data = match config.node.rpc_token {
+            Some(token) if request.is_admin() => serialize(&(
+                magic,
+                cmd_to_bytes("authrpc"),
+                AuthRpcMsg { token, request },
+            ))?,
+            _ => serialize(&(magic, cmd_to_bytes("rpc"), request))?,
+        };
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_write_timeout(Some(DEFAULT_WRITE_TIMEOUT))?;
+        stream.write_all(&data)?;
+        stream.shutdown(Shutdown::Write)?;
+
+        let buffer = read_with_deadline(&mut stream, timeout)?;
+        match deserialize(&buffer)? {
+            RpcResponse::Error(e) => Err(e.into()),
+            response => Ok(response),
+        }
     }
 
     pub fn send_tx(&self, addr: &str, tx: &Transaction) -> Result<()> {
@@ -142,82 +692,450 @@ impl Server {
             address_from: self.node_address.clone(),
             transaction: tx.clone(),
         };
-        let data = serialize(&(cmd_to_bytes("tx"), data))?;
+        let data = serialize(&(self.magic(), cmd_to_bytes("tx"), data))?;
         self.send_data(addr, &data)
     }
 
-    fn remove_node(&self, addr: &str) -> Result<()> {
-        let mut inner = self.inner.lock().unwrap();
-        if inner.known_nodes.contains(addr) {
-            inner.known_nodes.remove(addr);
+    /// apply_settings switches the running node to `settings` and reports what changed
+    pub fn apply_settings(&self, settings: NodeSettings) -> Result<Vec<String>> {
+        if let Some(level) = &settings.log_level {
+            log::set_max_level(level.parse()?);
+        }
+        let changes = {
+            let mut node = self.inner.node.write().unwrap();
+            let changes = node.settings.changes(&settings);
+            node.settings = settings.clone();
+            changes
+        };
+        self.set_replace_by_fee(settings.replace_by_fee);
+        let policy = TemplatePolicy {
+            max_transactions: settings.max_block_txs,
+            min_fee_rate: settings.min_fee_rate,
+            ..self.inner.node.read().unwrap().template_policy
+        };
+        self.set_template_policy(policy);
+        self.set_timeouts(
+            Duration::from_secs(settings.read_timeout),
+            Duration::from_secs(settings.write_timeout),
+        );
+        self.discover_peers()?;
+        Ok(changes)
+    }
+
+    /// reload_config applies the node settings of the configuration file; consensus
+    /// settings only take effect after a restart
+    fn reload_config(&self) -> Result<Vec<String>> {
+        let config = Config::load()?;
+        let mut changes = self.apply_settings(config.node)?;
+        if config.chain != *self.inner.utxo.read().unwrap().blockchain.get_params() {
+            changes.push(String::from(
+                "chain: consensus settings changed, they take effect after a restart",
+            ));
+        }
+        info!("reloaded configuration: {:?}", changes);
+        Ok(changes)
+    }
+
+    /// set_timeouts changes the deadlines for reading and writing a message on a connection
+    pub fn set_timeouts(&self, read_timeout: Duration, write_timeout: Duration) {
+        let mut node = self.inner.node.write().unwrap();
+        node.read_timeout = read_timeout;
+        node.write_timeout = write_timeout;
+    }
+
+    /// discover_peers registers the static seed nodes and those the DNS seeds resolve
+    /// to, returning the ones that were not known yet
+    fn discover_peers(&self) -> Result<Vec<String>> {
+        let (seeds, dns_seeds) = {
+            let node = self.inner.node.read().unwrap();
+            (node.settings.seeds.clone(), node.settings.dns_seeds.clone())
+        };
+        let port = self
+            .inner
+            .listen_address
+            .rsplit(':')
+            .next()
+            .unwrap_or_default();
+        let mut found = Vec::new();
+        for node in discovery::discover(&seeds, &dns_seeds, port) {
+            if node != self.node_address && !self.node_is_known(&node) {
+                self.add_nodes(&node)?;
+                found.push(node);
+            }
+        }
+        Ok(found)
+    }
+
+    /// discover_if_low queries the seeds again and dials the nodes they return when the
+    /// node has fewer than min_peers handshaken peers
+    fn discover_if_low(&self) -> Result<()> {
+        let min_peers = self.inner.node.read().unwrap().settings.min_peers;
+        let peers = self.inner.peers.lock().unwrap().handshaken.len();
+        if peers >= min_peers {
+            return Ok(());
+        }
+        for node in self.discover_peers()? {
+            info!("dialing discovered node {}", node);
+            if let Err(e) = self.send_version(&node) {
+                info!("handshake with {} failed: {}", node, e);
+            }
         }
         Ok(())
     }
 
-    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer)?;
-        info!("Accept request: length {}", count);
+    /// run_scheduled_payments builds, signs and broadcasts every scheduled payment that is due
+    fn run_scheduled_payments(&self) -> Result<()> {
+        let due = Scheduler::new()?.take_due()?;
+        if due.is_empty() {
+            return Ok(());
+        }
+        let wallets = Wallets::new()?;
+
+        for payment in due {
+            let wallet = match wallets.get_wallet(&payment.from) {
+                Some(wallet) => wallet,
+                None => {
+                    error!(
+                        "scheduled payment {}: unknown wallet {}",
+                        payment.id, payment.from
+                    );
+                    continue;
+                }
+            };
+
+            let tx = {
+                let utxo = self.inner.utxo.read().unwrap();
+                let mut pub_key_hash = wallet.public_key.clone();
+                hash_pub_key(&mut pub_key_hash);
+                let (balance, _) = utxo.find_spendable_outputs(
+                    &pub_key_hash,
+                    payment.amount,
+                    CoinSelection::default(),
+                )?;
+                if balance < payment.amount {
+                    info!(
+                        "skipping scheduled payment {}: balance {} is below {}",
+                        payment.id, balance, payment.amount
+                    );
+                    continue;
+                }
+                Transaction::new_UTXO(
+                    wallet,
+                    &[(payment.to.clone(), payment.amount)],
+                    CoinSelection::default(),
+                    &utxo,
+                )?
+            };
+
+            info!("executing scheduled payment {} txid: {}", payment.id, tx.id);
+            audit::record_or_log(
+                "node",
+                "send",
+                &format!("scheduled payment {} txid {}", payment.id, tx.id),
+            );
+            if !self.insert_mempool(tx.clone())? {
+                continue;
+            }
+            self.relay_transaction(&tx.id)?;
+        }
+        Ok(())
+    }
+
+    /// bump_stuck_fees replaces every wallet transaction that has waited fee_bump_after
+    /// blocks in the mempool with one paying fee_bump_step more, up to fee_bump_max.
+    /// Replacements need replace-by-fee, so nothing is bumped without it.
+    fn bump_stuck_fees(&self) -> Result<()> {
+        let settings = Config::load()?.wallet;
+        let after = match settings.fee_bump_after {
+            Some(after) => after,
+            None => return Ok(()),
+        };
+        let wallets = Wallets::new()?;
+
+        if !self.inner.node.read().unwrap().replace_by_fee {
+            debug!("fee bumping is waiting for replace-by-fee to be enabled");
+            return Ok(());
+        }
+        let mut replacements = Vec::new();
+        {
+            let utxo = self.inner.utxo.read().unwrap();
+            let mempool = self.inner.mempool.read().unwrap();
+            let mut pending_since = self.inner.pending_since.lock().unwrap();
+            let height = utxo.blockchain.get_best_height()?;
+            let pending: Vec<Transaction> = mempool.transactions().cloned().collect();
+            pending_since.retain(|txid, _| pending.iter().any(|tx| tx.id == *txid));
+
+            for tx in pending {
+                let since = *pending_since.entry(tx.id).or_insert(height);
+                if height - since < after || tx.is_coinbase() {
+                    continue;
+                }
+                // only transactions spending confirmed outputs of a single wallet key
+                let pub_key = tx.vin[0].pub_key.clone();
+                if tx
+                    .vin
+                    .iter()
+                    .any(|vin| vin.pub_key != pub_key || mempool.get(&vin.txid).is_some())
+                {
+                    continue;
+                }
+                let mut pub_key_hash = pub_key;
+                hash_pub_key(&mut pub_key_hash);
+                let wallet = match wallets.get_wallet(&hash_to_address(pub_key_hash.clone())) {
+                    Some(wallet) => wallet,
+                    None => continue,
+                };
+
+                let fee = utxo.blockchain.get_fee(&tx)?;
+                let extra = settings.fee_bump_step.min(settings.fee_bump_max - fee);
+                if extra <= 0 {
+                    continue;
+                }
+                let mut replacement = match tx.bump_fee(&pub_key_hash, extra)? {
+                    Some(replacement) => replacement,
+                    None => {
+                        info!("cannot bump the fee of tx {}: no change to pay it", tx.id);
+                        continue;
+                    }
+                };
+                utxo.blockchain
+                    .sign_transaction(&mut replacement, wallet.signer()?.as_ref())?;
+                replacements.push((tx.id, replacement, fee + extra));
+            }
+        }
+
+        for (replaced, tx, fee) in replacements {
+            if !self.insert_mempool(tx.clone())? {
+                continue;
+            }
+            audit::record_or_log(
+                "node",
+                "fee-bump",
+                &format!("txid {} replaced by {} paying {}", replaced, tx.id, fee),
+            );
+            self.inner.events.lock().unwrap().publish(
+                now_millis(),
+                NodeEvent::FeeBumped {
+                    replaced,
+                    txid: tx.id,
+                    fee,
+                },
+            );
+            self.relay_transaction(&tx.id)?;
+        }
+        Ok(())
+    }
+
+    /// remove_node forgets `addr` for now after an outgoing connection to it failed
+    fn remove_node(&self, addr: &str, reason: &str) -> Result<()> {
+        let mut peers = self.inner.peers.lock().unwrap();
+        let known = peers.known_nodes.remove(addr);
+        peers.services.remove(addr);
+        peers.known_inventory.remove(addr);
+        peers.peer_ips.remove(addr);
+        if peers.handshaken.remove(addr).is_some() || known {
+            self.inner.events.lock().unwrap().publish(
+                now_millis(),
+                NodeEvent::PeerDisconnected {
+                    address: addr.to_string(),
+                    direction: Direction::Outbound,
+                    reason: reason.to_string(),
+                },
+            );
+        }
+
+        let mut record: PeerRecord = match peers.db.get(addr)? {
+            Some(data) => deserialize(&data)?,
+            None => return Ok(()),
+        };
+        record.failures += 1;
+        if record.failures >= MAX_PEER_FAILURES {
+            info!(
+                "forgetting peer {} after {} failures",
+                addr, record.failures
+            );
+            peers.db.remove(addr)?;
+        } else {
+            peers.db.insert(addr, serialize(&record)?)?;
+        }
+        peers.db.flush()?;
+        Ok(())
+    }
+
+    /// retry_dropped_peers tries to reconnect to persisted peers that are no longer known
+    fn retry_dropped_peers(&self) -> Result<()> {
+        let known_nodes = self.get_known_nodes();
+        let peers = self.inner.peers.lock().unwrap().db.clone();
+        for kv in peers.iter() {
+            let (key, _) = kv?;
+            let addr = String::from_utf8(key.to_vec())?;
+            if known_nodes.contains(&addr) || addr == self.node_address {
+                continue;
+            }
+
+            info!("retrying dropped peer {}", addr);
+            if TcpStream::connect(&addr).is_ok() {
+                self.add_nodes(&addr)?;
+                self.send_version(&addr)?;
+            } else {
+                self.remove_node(&addr, "reconnect failed")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// admit takes a connection slot for a message from `ip` unless it exceeds the
+    /// connection limits or its rate limit, which is penalized
+    fn admit(&self, ip: IpAddr) -> Result<Option<ConnectionSlot>> {
+        let (rate, burst, max, max_per_ip) = {
+            let settings = &self.inner.node.read().unwrap().settings;
+            (
+                settings.message_rate,
+                settings.message_burst,
+                settings.max_connections,
+                settings.max_connections_per_ip,
+            )
+        };
+        let allowed =
+            self.inner
+                .rate_limiter
+                .lock()
+                .unwrap()
+                .allow(ip, rate, burst, Instant::now());
+        if !allowed {
+            debug!("dropping message from {} over the rate limit", ip);
+            self.penalize(ip, RATE_LIMIT_PENALTY)?;
+            return Ok(None);
+        }
+        if !self
+            .inner
+            .connections
+            .lock()
+            .unwrap()
+            .open(ip, max, max_per_ip)
+        {
+            debug!("refusing connection from {}: too many connections", ip);
+            return Ok(None);
+        }
+        Ok(Some(ConnectionSlot {
+            inner: Arc::clone(&self.inner),
+            ip,
+        }))
+    }
 
-        let cmd = bytes_to_cmd(&buffer)?;
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        let (read_timeout, write_timeout) = self.get_timeouts();
+        stream.set_write_timeout(Some(write_timeout))?;
+        let buffer = match read_with_deadline(&mut stream, read_timeout) {
+            Ok(buffer) => buffer,
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                let peer = stream.peer_addr()?;
+                info!("dropping connection from {}: {}", peer, e);
+                self.penalize(peer.ip(), TIMEOUT_PENALTY)?;
+                return Ok(());
+            }
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                let peer = stream.peer_addr()?;
+                info!("dropping connection from {}: {}", peer, e);
+                self.penalize(peer.ip(), MALFORMED_PENALTY)?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        info!("Accept request: length {}", buffer.len());
+
+        let cmd = match self.decode_message(&buffer) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                let peer = stream.peer_addr()?;
+                info!("dropping connection from {}: {}", peer, e);
+                let penalty = match CodedError::from(&e).code {
+                    ErrorCode::WrongNetwork => WRONG_NETWORK_PENALTY,
+                    _ => MALFORMED_PENALTY,
+                };
+                self.penalize(peer.ip(), penalty)?;
+                return Ok(());
+            }
+        };
+        if let Some(sender) = cmd.data_sender()
+            && !self.is_handshaken_from(sender, stream.peer_addr()?.ip())
+        {
+            let peer = stream.peer_addr()?;
+            info!(
+                "rejecting message from {} ({}) before handshake",
+                sender, peer
+            );
+            self.penalize(peer.ip(), HANDSHAKE_PENALTY)?;
+            return Ok(());
+        }
 
         match cmd {
             Message::Address(data) => self.handle_address(data)?,
             Message::Block(data) => self.handle_block(data)?,
             Message::Invite(data) => self.handle_invite(data)?,
             Message::GetBlocks(data) => self.handle_get_blocks(data)?,
+            Message::GetHeaders(data) => self.handle_get_headers(data)?,
+            Message::Headers(data) => self.handle_headers(data)?,
             Message::GetData(data) => self.handle_get_data(data)?,
             Message::Transaction(data) => self.handle_transaction(data)?,
-            Message::Version(data) => self.handle_version(data)?,
+            Message::Version(data) => self.handle_version(data, &mut stream)?,
+            Message::Verack(data) => info!("unexpected verack from {}", data.address_from),
+            Message::Rpc(request) => self.handle_rpc(request, None, &mut stream)?,
+            Message::AuthRpc(msg) => self.handle_rpc(msg.request, Some(&msg.token), &mut stream)?,
         };
         Ok(())
     }
 
-    fn request_blocks(&self) -> Result<()> {
-        for node in self.get_known_nodes() {
-            self.send_get_blocks(&node)?
-        }
-        Ok(())
-    }
-
     fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
         if addr == self.node_address {
             return Ok(());
         }
         let mut stream = match TcpStream::connect(addr) {
             Ok(stream) => stream,
-            Err(_) => {
-                self.remove_node(addr)?;
+            Err(e) => {
+                self.remove_node(addr, &e.to_string())?;
                 return Ok(());
             }
         };
 
-        stream.write(data)?;
+        stream.set_write_timeout(Some(self.get_timeouts().1))?;
+        if let Err(e) = stream.write_all(data) {
+            info!("failed to send data to {}: {}", addr, e);
+            self.remove_node(addr, &e.to_string())?;
+            return Ok(());
+        }
 
         info!("data send successfully to {}", addr);
         Ok(())
     }
 
-    fn send_get_blocks(&self, addr: &str) -> Result<()> {
-        info!("send get blocks message to {}", addr);
-        let data = GetBlocksMsg {
+    fn send_get_headers(&self, addr: &str) -> Result<()> {
+        info!("send get headers message to {}", addr);
+        let data = GetHeadersMsg {
             address_from: self.node_address.clone(),
+            locator: self.inner.utxo.read().unwrap().blockchain.get_locator()?,
         };
-        let data = serialize(&(cmd_to_bytes("getblocks"), data))?;
+        let data = serialize(&(self.magic(), cmd_to_bytes("getheaders"), data))?;
         self.send_data(addr, &data)
     }
 
-    fn send_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
-        info!(
-            "send get data message to {} kind: {} id: {}",
-            addr, kind, id
-        );
+    fn send_headers(&self, addr: &str, headers: Vec<BlockHeader>) -> Result<()> {
+        info!("send {} headers to {}", headers.len(), addr);
+        let data = HeadersMsg {
+            address_from: self.node_address.clone(),
+            headers,
+        };
+        let data = serialize(&(self.magic(), cmd_to_bytes("headers"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn send_get_data(&self, addr: &str, item: Inventory) -> Result<()> {
+        info!("send get data message to {} item: {:?}", addr, item);
         let data = GetDataMsg {
             address_from: self.node_address.clone(),
-            kind: kind.to_string(),
-            id: id.to_string(),
+            item,
         };
-        let data = serialize(&(cmd_to_bytes("getdata"), data))?;
+        let data = serialize(&(self.magic(), cmd_to_bytes("getdata"), data))?;
         self.send_data(addr, &data)
     }
 
@@ -227,200 +1145,1351 @@ impl Server {
             address_from: self.node_address.clone(),
             block: block.clone(),
         };
-        let data = serialize(&(cmd_to_bytes("block"), data))?;
+        let data = serialize(&(self.magic(), cmd_to_bytes("block"), data))?;
         self.send_data(addr, &data)
     }
 
-    fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
-        info!(
-            "send inv message to {} kind: {} data: {:?}",
-            addr, kind, items
-        );
+    fn send_inv(&self, addr: &str, items: Vec<Inventory>) -> Result<()> {
+        info!("send inv message to {} data: {:?}", addr, items);
         let data = InviteMsg {
             address_from: self.node_address.clone(),
-            kind: kind.to_string(),
             items,
         };
-        let data = serialize(&(cmd_to_bytes("inv"), data))?;
+        let data = serialize(&(self.magic(), cmd_to_bytes("inv"), data))?;
         self.send_data(addr, &data)
     }
 
+    /// send_version starts the handshake with `addr` and waits for its verack on the same connection
     fn send_version(&self, addr: &str) -> Result<()> {
+        if addr == self.node_address {
+            return Ok(());
+        }
+        if !self.is_handshaken(addr) && self.peer_count(Direction::Outbound) >= self.max_peers().1 {
+            debug!("not connecting to {}: outbound peer limit reached", addr);
+            return Ok(());
+        }
         info!("send version info to: {}", addr);
         let data = VersionMsg {
             address_from: self.node_address.clone(),
+            address_to: addr.to_string(),
             best_height: self.get_best_height()?,
             version: VERSION,
+            services: self.local_services()?,
+            network: self.network(),
+            nonce: self.inner.nonce,
         };
-        let data = serialize(&(cmd_to_bytes("version"), data))?;
-        self.send_data(addr, &data)
+        let data = serialize(&(self.magic(), cmd_to_bytes("version"), data))?;
+
+        let mut stream = match TcpStream::connect(addr) {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.remove_node(addr, &e.to_string())?;
+                return Ok(());
+            }
+        };
+        let (read_timeout, write_timeout) = self.get_timeouts();
+        stream.set_write_timeout(Some(write_timeout))?;
+        stream.write_all(&data)?;
+        stream.shutdown(Shutdown::Write)?;
+
+        let answer = read_with_deadline(&mut stream, read_timeout)?;
+        match self.decode_message(&answer) {
+            Ok(Message::Verack(msg)) if msg.version < MIN_PEER_VERSION => {
+                self.remove_node(addr, "outdated protocol version")?;
+                Err(format_err!(
+                    "Peer {} speaks protocol version {}, older than {}",
+                    addr,
+                    msg.version,
+                    MIN_PEER_VERSION
+                ))
+            }
+            Ok(Message::Verack(msg))
+                if !resolves_to(&msg.address_from, stream.peer_addr()?.ip()) =>
+            {
+                self.remove_node(addr, "claimed another address")?;
+                Err(format_err!(
+                    "Peer {} claimed the address {} of another host",
+                    addr,
+                    msg.address_from
+                ))
+            }
+            Ok(Message::Verack(msg)) => {
+                info!(
+                    "handshake with {} completed, version {}, services: {}",
+                    msg.address_from, msg.version, msg.services
+                );
+                self.set_peer_services(&msg.address_from, msg.services);
+                self.handshake_completed(
+                    &msg.address_from,
+                    Direction::Outbound,
+                    stream.peer_addr()?.ip(),
+                );
+                Ok(())
+            }
+            // peers refusing the version, and the node itself under another address,
+            // close the connection without a verack
+            _ => {
+                self.remove_node(addr, "no verack")?;
+                Err(format_err!(
+                    "Peer {} did not answer version with verack",
+                    addr
+                ))
+            }
+        }
     }
 
     fn send_addr(&self, addr: &str) -> Result<()> {
         info!("send addr to {}", addr);
-        let nodes = self.get_known_nodes();
-        let data = serialize(&(cmd_to_bytes("addr"), nodes))?;
+        let mut nodes: Vec<String> = self.get_known_nodes().into_iter().collect();
+        if let Some(external) = self.external_address() {
+            nodes.push(external);
+        }
+        let data = AddrMsg {
+            address_from: self.node_address.clone(),
+            nodes,
+        };
+        let data = serialize(&(self.magic(), cmd_to_bytes("addr"), data))?;
         self.send_data(addr, &data)
     }
 
-    fn get_known_nodes(&self) -> HashSet<String> {
-        self.inner.lock().unwrap().known_nodes.clone()
+    /// external_address is the address the node is reachable at from other machines:
+    /// the configured one, or else the one most peers reached it at
+    fn external_address(&self) -> Option<String> {
+        let node = self.inner.node.read().unwrap();
+        if node.settings.external_address.is_some() {
+            return Some(self.node_address.clone());
+        }
+        node.seen_addresses
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(addr, _)| addr.clone())
     }
 
-    fn handle_address(&self, msg: Vec<String>) -> Result<()> {
-        info!("recieved address message: {:#?}", msg);
-        for node in msg {
-            self.add_nodes(&node);
+    /// observe_address counts that a peer reached the node at `addr`, unless that is
+    /// a local address
+    fn observe_address(&self, addr: &str) {
+        if addr == self.node_address || discovery::is_local(addr) {
+            return;
         }
-        Ok(())
+        let mut node = self.inner.node.write().unwrap();
+        if node.seen_addresses.len() >= MAX_SEEN_ADDRESSES
+            && !node.seen_addresses.contains_key(addr)
+        {
+            node.seen_addresses.clear();
+        }
+        *node.seen_addresses.entry(addr.to_string()).or_insert(0) += 1;
     }
 
-    fn handle_block(&self, msg: BlockMsg) -> Result<()> {
+    /// local_services are the services this node advertises in its handshakes
+    fn local_services(&self) -> Result<Services> {
+        let utxo = self.inner.utxo.read().unwrap();
+        let settings = &self.inner.node.read().unwrap().settings;
+        let mut services = Services::NONE;
+        if utxo.blockchain.is_archive() {
+            services = services | Services::ARCHIVE | Services::FILTERS;
+        } else if settings.prune.is_some() || utxo.blockchain.is_pruned()? {
+            services = services | Services::PRUNED;
+        }
+        if !settings.blocks_only {
+            services = services | Services::MEMPOOL;
+        }
+        if utxo.blockchain.has_txindex() {
+            services = services | Services::TXINDEX;
+        }
+        if !self.mining_address.is_empty() {
+            services = services | Services::MINING;
+        }
+        Ok(services)
+    }
+
+    fn set_peer_services(&self, addr: &str, services: Services) {
+        self.inner
+            .peers
+            .lock()
+            .unwrap()
+            .services
+            .insert(addr.to_string(), services);
+    }
+
+    /// tx_relay_peers are the known nodes that take unconfirmed transactions, which
+    /// includes those whose services are not known yet
+    fn tx_relay_peers(&self) -> Vec<String> {
+        let peers = self.inner.peers.lock().unwrap();
+        peers
+            .known_nodes
+            .iter()
+            .filter(|node| {
+                peers
+                    .services
+                    .get(*node)
+                    .is_none_or(|services| services.contains(Services::MEMPOOL))
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn get_timeouts(&self) -> (Duration, Duration) {
+        let node = self.inner.node.read().unwrap();
+        (node.read_timeout, node.write_timeout)
+    }
+
+    /// handshake_completed marks `addr`, connected from `ip`, as handshaken, announcing
+    /// it when it is new
+    fn handshake_completed(&self, addr: &str, direction: Direction, ip: IpAddr) {
+        let mut peers = self.inner.peers.lock().unwrap();
+        peers.peer_ips.insert(addr.to_string(), ip);
+        if !peers.handshaken.contains_key(addr) {
+            peers.handshaken.insert(addr.to_string(), direction);
+            self.inner.events.lock().unwrap().publish(
+                now_millis(),
+                NodeEvent::PeerConnected {
+                    address: addr.to_string(),
+                    direction,
+                },
+            );
+        }
+    }
+
+    /// penalize adds `penalty` to the misbehavior score of a peer, banning it for
+    /// MISBEHAVIOR_BAN_DURATION once the score reaches MAX_MISBEHAVIOR
+    fn penalize(&self, ip: IpAddr, penalty: u32) -> Result<()> {
+        let score = {
+            let mut peers = self.inner.peers.lock().unwrap();
+            let score = peers.misbehavior.entry(ip).or_insert(0);
+            *score += penalty;
+            *score
+        };
+        if score >= MAX_MISBEHAVIOR {
+            // the ban list takes over, so the peer is let back in once the ban expires
+            self.inner.peers.lock().unwrap().misbehavior.remove(&ip);
+            self.ban(
+                &IpRange::from(ip),
+                Some(MISBEHAVIOR_BAN_DURATION),
+                &format!("misbehavior score {}", score),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// ban adds `range` to the ban list for `duration` seconds, or until it is removed
+    fn ban(&self, range: &IpRange, duration: Option<u64>, reason: &str) -> Result<()> {
+        self.inner
+            .bans
+            .lock()
+            .unwrap()
+            .add(range, duration, reason, now_secs()?)?;
+        audit::record_or_log("node", "ban", &format!("{}: {}", range, reason));
+        self.inner.events.lock().unwrap().publish(
+            now_millis(),
+            NodeEvent::PeerBanned {
+                address: range.to_string(),
+                reason: reason.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        let bans = self.inner.bans.lock().unwrap();
+        match now_secs().and_then(|now| bans.is_banned(ip, now)) {
+            Ok(banned) => banned,
+            Err(e) => {
+                error!("failed to read the ban list: {}", e);
+                false
+            }
+        }
+    }
+
+    /// set_ban adds or removes a ban and returns the bans in force
+    fn set_ban(&self, range: &str, remove: bool, duration: Option<u64>) -> Result<Vec<BanEntry>> {
+        let range: IpRange = range.parse()?;
+        if remove {
+            if !self.inner.bans.lock().unwrap().remove(&range)? {
+                return Err(format_err!("{} is not banned", range));
+            }
+            audit::record_or_log("node", "unban", &range.to_string());
+        } else {
+            self.ban(&range, duration, "setban")?;
+        }
+        self.list_banned()
+    }
+
+    fn list_banned(&self) -> Result<Vec<BanEntry>> {
+        self.inner.bans.lock().unwrap().list(now_secs()?)
+    }
+
+    fn get_known_nodes(&self) -> HashSet<String> {
+        self.inner.peers.lock().unwrap().known_nodes.clone()
+    }
+
+    fn handle_address(&self, msg: AddrMsg) -> Result<()> {
+        info!("recieved address message: {:#?}", msg);
+        for node in msg.nodes {
+            self.add_nodes(&node)?;
+        }
+        Ok(())
+    }
+
+    fn handle_block(&self, msg: BlockMsg) -> Result<()> {
         info!(
             "recieved block message: {}, {}",
             msg.address_from,
             msg.block.get_hash()
         );
-        self.add_block(msg.block)?;
+        let block = msg.block;
+        let hash = block.get_hash();
+        self.set_peer_height(&msg.address_from, block.get_height());
+        self.inner.sync.lock().unwrap().downloading.remove(&hash);
+        let parent_missing = !block.get_prev_hash().is_zero()
+            && !self
+                .inner
+                .utxo
+                .read()
+                .unwrap()
+                .blockchain
+                .has_block(&block.get_prev_hash())?;
+        if parent_missing && self.header_is_known(&hash)? {
+            // blocks are downloaded in parallel, so a block may arrive before its parent
+            self.inner.sync.lock().unwrap().orphans.insert(hash, block);
+            return Ok(());
+        }
+        self.add_block(block)?;
+        self.connect_orphans(&hash)?;
 
         let mut in_transit = self.get_in_transit();
         if in_transit.len() > 0 {
-            let block_hash = &in_transit[0];
-            self.send_get_data(&msg.address_from, "block", block_hash)?;
+            self.send_get_data(&msg.address_from, Inventory::Block(in_transit[0]))?;
             in_transit.remove(0);
             self.replace_in_transit(in_transit);
+        } else if self.is_synced()? {
+            self.prune_blocks()?;
         } else {
-            self.utxo_reindex()?;
+            self.request_missing_blocks()?;
+        }
+        Ok(())
+    }
+
+    /// connect_orphans adds the downloaded blocks that were waiting for block `hash`
+    fn connect_orphans(&self, hash: &BlockHash) -> Result<()> {
+        let mut parents = vec![*hash];
+        while let Some(parent) = parents.pop() {
+            let children: Vec<Block> = {
+                let mut sync = self.inner.sync.lock().unwrap();
+                let hashes: Vec<BlockHash> = sync
+                    .orphans
+                    .iter()
+                    .filter(|(_, block)| block.get_prev_hash() == parent)
+                    .map(|(hash, _)| *hash)
+                    .collect();
+                hashes
+                    .iter()
+                    .filter_map(|hash| sync.orphans.remove(hash))
+                    .collect()
+            };
+            for child in children {
+                parents.push(child.get_hash());
+                self.add_block(child)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_get_headers(&self, msg: GetHeadersMsg) -> Result<()> {
+        info!("recieved get headers message from {}", msg.address_from);
+        let headers = self
+            .inner
+            .utxo
+            .read()
+            .unwrap()
+            .blockchain
+            .headers_after(&msg.locator, MAX_HEADERS)?;
+        self.send_headers(&msg.address_from, headers)
+    }
+
+    /// handle_headers extends the header chain, then downloads the blocks it is missing
+    fn handle_headers(&self, msg: HeadersMsg) -> Result<()> {
+        info!(
+            "recieved {} headers from {}",
+            msg.headers.len(),
+            msg.address_from
+        );
+        let count = msg.headers.len();
+        if let Some(last) = msg.headers.last() {
+            self.set_peer_height(&msg.address_from, last.height);
+        }
+        for header in msg.headers {
+            let hash = header.hash;
+            let added = self
+                .inner
+                .utxo
+                .write()
+                .unwrap()
+                .blockchain
+                .add_header(header);
+            if let Err(e) = added {
+                info!("rejecting header {} from {}: {}", hash, msg.address_from, e);
+                return Ok(());
+            }
+        }
+
+        if count == MAX_HEADERS {
+            self.send_get_headers(&msg.address_from)?;
+        }
+        self.request_missing_blocks()
+    }
+
+    /// request_missing_blocks asks for the blocks of the header chain that are not
+    /// downloaded yet, spreading them over the peers known to have them. Pruned peers
+    /// are only asked for blocks close to their tip.
+    fn request_missing_blocks(&self) -> Result<()> {
+        let missing = self
+            .inner
+            .utxo
+            .read()
+            .unwrap()
+            .blockchain
+            .missing_blocks()?;
+        let mut peers: Vec<(String, i32, bool)> = {
+            let peers = self.inner.peers.lock().unwrap();
+            peers
+                .heights
+                .iter()
+                .filter(|(peer, _)| peers.handshaken.contains_key(*peer))
+                .map(|(peer, height)| {
+                    let pruned = peers
+                        .services
+                        .get(peer)
+                        .is_some_and(|services| services.contains(Services::PRUNED));
+                    (peer.clone(), *height, pruned)
+                })
+                .collect()
+        };
+        peers.sort();
+        let mut requests = Vec::new();
+        {
+            let mut sync = self.inner.sync.lock().unwrap();
+            let now = Instant::now();
+            sync.downloading
+                .retain(|_, since| now.duration_since(*since) < DOWNLOAD_TIMEOUT);
+
+            for header in missing {
+                if sync.downloading.len() >= MAX_BLOCKS_IN_FLIGHT {
+                    break;
+                }
+                if sync.downloading.contains_key(&header.hash)
+                    || sync.orphans.contains_key(&header.hash)
+                {
+                    continue;
+                }
+                let holders: Vec<&String> = peers
+                    .iter()
+                    .filter(|(_, height, pruned)| {
+                        *height >= header.height
+                            && (!pruned || *height - header.height < PRUNED_PEER_DEPTH)
+                    })
+                    .map(|(peer, _, _)| peer)
+                    .collect();
+                if holders.is_empty() {
+                    break;
+                }
+                let peer = holders[requests.len() % holders.len()].clone();
+                sync.downloading.insert(header.hash, now);
+                requests.push((peer, header.hash));
+            }
+        }
+
+        for (peer, hash) in requests {
+            self.send_get_data(&peer, Inventory::Block(hash))?;
+        }
+        Ok(())
+    }
+
+    /// request_damaged_blocks asks peers keeping old blocks again for the blocks whose
+    /// stored copies went missing or failed their checksum
+    fn request_damaged_blocks(&self) -> Result<()> {
+        let hashes = self
+            .inner
+            .utxo
+            .read()
+            .unwrap()
+            .blockchain
+            .get_damaged_blocks()?;
+        let peers: Vec<String> = {
+            let peers = self.inner.peers.lock().unwrap();
+            peers
+                .handshaken
+                .keys()
+                .filter(|peer| {
+                    peers
+                        .services
+                        .get(*peer)
+                        .is_none_or(|services| !services.contains(Services::PRUNED))
+                })
+                .cloned()
+                .collect()
+        };
+        if peers.is_empty() {
+            return Ok(());
+        }
+        for (i, hash) in hashes.into_iter().enumerate() {
+            let peer = &peers[i % peers.len()];
+            info!("asking {} again for damaged block {}", peer, hash);
+            self.send_get_data(peer, Inventory::Block(hash))?;
         }
         Ok(())
     }
 
+    fn set_peer_height(&self, addr: &str, height: i32) {
+        let mut peers = self.inner.peers.lock().unwrap();
+        let known = peers.heights.entry(addr.to_string()).or_insert(height);
+        *known = (*known).max(height);
+    }
+
+    fn header_is_known(&self, hash: &BlockHash) -> Result<bool> {
+        Ok(self
+            .inner
+            .utxo
+            .read()
+            .unwrap()
+            .blockchain
+            .get_header(hash)?
+            .is_some())
+    }
+
+    /// is_synced tells whether every block of the header chain is downloaded
+    fn is_synced(&self) -> Result<bool> {
+        let blockchain = &self.inner.utxo.read().unwrap().blockchain;
+        let tip = blockchain.get_block(&blockchain.get_tip_hash())?.header();
+        Ok(blockchain.get_chainwork(&tip)?
+            >= blockchain.get_chainwork(&blockchain.get_header_tip()?)?)
+    }
+
+    /// handle_get_blocks serves nodes that sync by announcing every block hash
     fn handle_get_blocks(&self, msg: GetBlocksMsg) -> Result<()> {
         info!("recieved get blocks message: {:#?}", msg);
-        let block_hashes = self.get_block_hashes();
-        self.send_inv(&msg.address_from, "block", block_hashes)?;
+        // oldest first, so every block arrives after its parent
+        let mut block_hashes = self.get_block_hashes();
+        block_hashes.reverse();
+        let items = block_hashes.into_iter().map(Inventory::Block).collect();
+        self.send_inv(&msg.address_from, items)?;
         Ok(())
     }
-    //TODO: refactor this function to make it shorter and more readable
     fn handle_transaction(&self, msg: TransactionMsg) -> Result<()> {
         info!("recieved transaction message: {:#?}", msg);
-        self.insert_mempool(msg.transaction.clone());
+        self.mark_known(&msg.address_from, Inventory::Tx(msg.transaction.id));
+        self.accept_transaction(msg.transaction)?;
+        Ok(())
+    }
 
-        let known_nodes = self.get_known_nodes();
+    /// accept_transaction adds `tx` to the mempool and relays it; it returns false when
+    /// the mempool refused it
+    fn accept_transaction(&self, tx: Transaction) -> Result<bool> {
+        let txid = tx.id;
+        if !self.insert_mempool(tx)? {
+            return Ok(false);
+        }
+
+        self.relay_transaction(&txid)?;
+        if !self.mining_address.is_empty() {
+            self.inner.miner.notify();
+            self.refresh_template()?;
+        }
+        Ok(true)
+    }
+
+    /// refresh_template rebuilds the template of the block being mined with the new
+    /// transaction and, when it pays more, stops the search so the miner starts over
+    fn refresh_template(&self) -> Result<()> {
+        if !self.inner.miner.is_mining() {
+            return Ok(());
+        }
+        let template = self.block_template()?;
+        if self.inner.miner.refresh(&template) {
+            info!(
+                "refreshing block {}: the mempool pays {} in fees",
+                template.height, template.fees
+            );
+        }
+        Ok(())
+    }
+
+    /// run_miner mines pending transactions on its own thread whenever new ones arrive,
+    /// so handling messages never waits for a proof of work
+    fn run_miner(&self) {
+        loop {
+            self.inner.miner.wait();
+            if let Err(e) = self.mine_pending() {
+                error!("failed to mine: {}", e);
+            }
+        }
+    }
+
+    /// mine_pending mines blocks of the mempool until it holds nothing worth mining.
+    /// When a competing block moves the tip or better paying transactions arrive, the
+    /// block being mined is dropped and the next is assembled from a fresh template.
+    /// Connecting a block drops only the transactions it mined, so those the templates
+    /// left out stay pending.
+    fn mine_pending(&self) -> Result<()> {
+        let result = self.mine_templates();
+        self.inner.miner.idle();
+        result
+    }
+
+    /// mine_templates mines one template after the other until no pending transaction
+    /// fits one
+    fn mine_templates(&self) -> Result<()> {
+        loop {
+            let cancel = self.inner.miner.start_block();
+            let template = self.block_template()?;
+            if template.transactions.is_empty() {
+                return Ok(());
+            }
+            self.inner.miner.mine_template(&template);
+
+            let cbtx = Transaction::new_coinbase(
+                self.mining_address.clone(),
+                String::new(),
+                template.height,
+            )?;
+            let mut txs = vec![cbtx];
+            txs.extend(template.transactions.iter().cloned());
+
+            let mut new_block = self.inner.utxo.read().unwrap().blockchain.new_block(txs)?;
+            let threads = self.inner.node.read().unwrap().settings.mining_threads;
+            let search = new_block.mine(threads, cancel)?;
+            self.inner.metrics.lock().unwrap().observe_mining(&search);
+            self.inner.miner.record_search(&search);
+            if !search.found {
+                info!(
+                    "stopped mining block {}: its template is stale",
+                    template.height
+                );
+                continue;
+            }
+            info!(
+                "mined block {} at {:.0} hashes/s",
+                template.height,
+                search.hashrate()
+            );
+            self.add_block(new_block.clone())?;
+            self.inner.miner.record_block(&template);
+            self.prune_blocks()?;
+            self.announce_block(&new_block.get_hash())?;
+        }
+    }
+
+    /// announce_block tells the known nodes about a block found by this node's miners
+    fn announce_block(&self, hash: &BlockHash) -> Result<()> {
+        for node in self.get_known_nodes() {
+            if node != self.node_address {
+                self.send_inv(&node, vec![Inventory::Block(*hash)])?;
+            }
+        }
+        Ok(())
+    }
 
-        if self.node_address == KNOWN_NODE1 {
-            for node in known_nodes {
-                if node != self.node_address && node != msg.address_from {
-                    self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
+    /// submit_block adds a block mined by a standalone miner from a block template, and
+    /// relays it once it passed validation
+    fn submit_block(&self, block: Block) -> Result<BlockHash> {
+        let hash = block.get_hash();
+        if self
+            .inner
+            .utxo
+            .read()
+            .unwrap()
+            .blockchain
+            .has_block(&hash)?
+        {
+            return Err(CodedError::new(
+                ErrorCode::BlockRejected,
+                format!("Block {} is known already", hash),
+            )
+            .with("block_hash", hash)
+            .into());
+        }
+        info!("submitted block {} at height {}", hash, block.get_height());
+        self.add_block(block)?;
+        self.prune_blocks()?;
+        self.announce_block(&hash)?;
+        Ok(hash)
+    }
+
+    fn handle_rpc(
+        &self,
+        request: RpcRequest,
+        token: Option<&str>,
+        stream: &mut TcpStream,
+    ) -> Result<()> {
+        info!("recieved rpc request: {:?}", request);
+        if request.is_admin() {
+            let peer = match stream.peer_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => String::from("unknown"),
+            };
+            audit::record_or_log(&peer, "rpc", &format!("{:?}", request));
+            let rpc_token = self.inner.node.read().unwrap().settings.rpc_token.clone();
+            let peer_ip = stream.peer_addr().ok().map(|addr| addr.ip());
+            if let Err(e) = authorize_rpc(&request, peer_ip, token, rpc_token.as_deref()) {
+                info!("refusing admin rpc request from {}", peer);
+                let response = RpcResponse::Error(CodedError::from(&e));
+                stream.write_all(&serialize(&response)?)?;
+                return Ok(());
+            }
+        }
+        let response = match request {
+            RpcRequest::MempoolFeeHistogram => match self.mempool_fee_entries() {
+                Ok(entries) => RpcResponse::FeeHistogram(fee_histogram(&entries)),
+                Err(e) => RpcResponse::Error(CodedError::from(&e)),
+            },
+            RpcRequest::BlockTemplate => match self.rpc_block_template() {
+                Ok(template) => RpcResponse::BlockTemplate(template),
+                Err(e) => RpcResponse::Error(CodedError::from(&e)),
+            },
+            RpcRequest::LongPollBlockTemplate {
+                longpoll_id,
+                timeout,
+            } => {
+                let timeout = Duration::from_secs(timeout).min(MAX_LONGPOLL_TIMEOUT);
+                match self.wait_block_template(&longpoll_id, timeout) {
+                    Ok(template) => RpcResponse::BlockTemplate(template),
+                    Err(e) => RpcResponse::Error(CodedError::from(&e)),
                 }
             }
-        } else {
-            let mut mempool = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
-            if mempool.len() >= 1 && !self.mining_address.is_empty() {
-                loop {
-                    let mut txs = Vec::new();
-
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
+            RpcRequest::MiningInfo { blocks } => match self.mining_info(blocks) {
+                Ok(info) => RpcResponse::MiningInfo(info),
+                Err(e) => RpcResponse::Error(CodedError::from(&e)),
+            },
+            RpcRequest::ReloadConfig => match self.reload_config() {
+                Ok(changes) => RpcResponse::ConfigReloaded(changes),
+                Err(e) => RpcResponse::Error(CodedError::from(&e)),
+            },
+            RpcRequest::SetBan {
+                range,
+                remove,
+                duration,
+            } => match self.set_ban(&range, remove, duration) {
+                Ok(bans) => RpcResponse::Banned(bans),
+                Err(e) => RpcResponse::Error(CodedError::from(&e)),
+            },
+            RpcRequest::ListBanned => match self.list_banned() {
+                Ok(bans) => RpcResponse::Banned(bans),
+                Err(e) => RpcResponse::Error(CodedError::from(&e)),
+            },
+            RpcRequest::PeerEvents => {
+                RpcResponse::PeerEvents(self.inner.events.lock().unwrap().history())
+            }
+            RpcRequest::DoubleSpends => RpcResponse::PeerEvents(self.double_spends()),
+            RpcRequest::RejectedBlocks => {
+                match self.inner.utxo.read().unwrap().blockchain.get_rejections() {
+                    Ok(rejections) => RpcResponse::RejectedBlocks(rejections),
+                    Err(e) => RpcResponse::Error(CodedError::from(&e)),
+                }
+            }
+            RpcRequest::PeerInfo => match self.peer_info() {
+                Ok((services, peers)) => RpcResponse::PeerInfo { services, peers },
+                Err(e) => RpcResponse::Error(CodedError::from(&e)),
+            },
+            RpcRequest::BlockFilters {
+                start_height,
+                count,
+            } => match self.block_filters(start_height, count) {
+                Ok(filters) => RpcResponse::BlockFilters(filters),
+                Err(e) => RpcResponse::Error(CodedError::from(&e)),
+            },
+            RpcRequest::SubmitBlock { block } => match self.submit_block(block) {
+                Ok(hash) => RpcResponse::BlockAccepted(hash),
+                Err(e) => RpcResponse::Error(CodedError::from(&e)),
+            },
+            RpcRequest::SendTransaction { transaction } => {
+                let txid = transaction.id;
+                match self.accept_transaction(transaction) {
+                    Ok(true) => RpcResponse::TransactionAccepted(txid),
+                    Ok(false) => RpcResponse::Error(
+                        CodedError::new(
+                            ErrorCode::TransactionRejected,
+                            format!("Transaction {} is not accepted into the mempool", txid),
+                        )
+                        .with("txid", txid),
+                    ),
+                    Err(e) => RpcResponse::Error(CodedError::from(&e)),
+                }
+            }
+            RpcRequest::WalletChanges {
+                pub_key_hashes,
+                since,
+            } => match self.wallet_changes(&pub_key_hashes, since.as_ref()) {
+                Ok(changes) => RpcResponse::WalletChanges(changes),
+                Err(e) => RpcResponse::Error(CodedError::from(&e)),
+            },
+            RpcRequest::IndexInfo => {
+                match self.inner.utxo.read().unwrap().blockchain.index_info() {
+                    Ok(info) => RpcResponse::IndexInfo(info),
+                    Err(e) => RpcResponse::Error(CodedError::from(&e)),
+                }
+            }
+        };
+        stream.write_all(&serialize(&response)?)?;
+        Ok(())
+    }
 
-                    if txs.is_empty() {
-                        return Ok(());
-                    }
+    /// mempool_fee_entries returns the fee and serialized size of each pending transaction.
+    /// Transactions spending other pending transactions have no known fee yet and are skipped.
+    fn mempool_fee_entries(&self) -> Result<Vec<(i32, usize)>> {
+        let utxo = self.inner.utxo.read().unwrap();
+        let mempool = self.inner.mempool.read().unwrap();
+        let mut entries = Vec::new();
+        for tx in mempool.transactions() {
+            match utxo.blockchain.get_fee(tx) {
+                Ok(fee) => entries.push((fee, serialize(tx)?.len())),
+                Err(e) => debug!("no fee for mempool tx {}: {}", tx.id, e),
+            }
+        }
+        Ok(entries)
+    }
 
-                    let cbtx =
-                        Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
-                    txs.push(cbtx);
+    /// mempool_entries returns the pending transactions that are valid on the current tip
+    /// with their fees; transactions spending other pending transactions wait for their parents
+    fn mempool_entries(&self) -> Result<Vec<MempoolEntry>> {
+        let utxo = self.inner.utxo.read().unwrap();
+        let mempool = self.inner.mempool.read().unwrap();
+        let mut entries = Vec::new();
+        for tx in mempool.transactions() {
+            let fee = match utxo.blockchain.get_fee(tx) {
+                Ok(fee) => fee,
+                Err(e) => {
+                    debug!("not mining tx {} yet: {}", tx.id, e);
+                    continue;
+                }
+            };
+            if !utxo.blockchain.verify_transaction(tx)? {
+                debug!("not mining invalid tx {}", tx.id);
+                continue;
+            }
+            entries.push(MempoolEntry {
+                tx: tx.clone(),
+                fee,
+                size: serialize(tx)?.len(),
+            });
+        }
+        Ok(entries)
+    }
 
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
+    /// peer_info returns the services of the node and of its handshaken peers
+    fn peer_info(&self) -> Result<(Services, Vec<PeerInfo>)> {
+        let services = self.local_services()?;
+        let peers = self.inner.peers.lock().unwrap();
+        let mut peers: Vec<PeerInfo> = peers
+            .handshaken
+            .keys()
+            .map(|address| PeerInfo {
+                address: address.clone(),
+                height: peers.heights.get(address).copied(),
+                services: peers.services.get(address).copied().unwrap_or_default(),
+            })
+            .collect();
+        peers.sort_by(|a, b| a.address.cmp(&b.address));
+        Ok((services, peers))
+    }
 
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
+    /// block_filters returns the compact filters of up to `count` best chain blocks from
+    /// `start_height` on, for light clients
+    fn block_filters(&self, start_height: i32, count: usize) -> Result<Vec<FilterEntry>> {
+        let utxo = self.inner.utxo.read().unwrap();
+        let blockchain = &utxo.blockchain;
+        let mut filters = Vec::new();
+        for height in (start_height.max(0)..).take(count.min(MAX_FILTERS)) {
+            let hash = match blockchain.get_block_hash(height)? {
+                Some(hash) => hash,
+                None => break,
+            };
+            let filter = match blockchain.get_block_filter(&hash)? {
+                Some(filter) => filter,
+                None => return Err(format_err!("Block {} has no filter indexed", hash)),
+            };
+            filters.push(FilterEntry {
+                height,
+                hash,
+                filter,
+            });
+        }
+        Ok(filters)
+    }
 
-                    for node in self.get_known_nodes() {
-                        if node != self.node_address {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
-                    }
+    /// wallet_changes answers a light wallet catching up with the best chain
+    fn wallet_changes(
+        &self,
+        pub_key_hashes: &[Vec<u8>],
+        since: Option<&BlockHash>,
+    ) -> Result<WalletChanges> {
+        if pub_key_hashes.len() > MAX_SYNC_ADDRESSES {
+            return Err(CodedError::new(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "At most {} addresses are synced at once",
+                    MAX_SYNC_ADDRESSES
+                ),
+            )
+            .with("addresses", pub_key_hashes.len())
+            .into());
+        }
+        self.inner
+            .utxo
+            .read()
+            .unwrap()
+            .blockchain
+            .wallet_changes(pub_key_hashes, since)
+    }
 
-                    if mempool.len() == 0 {
-                        break;
+    /// block_template selects the transactions this node would mine next
+    /// rpc_block_template is block_template for miners asking over RPC, who would
+    /// work on a stale tip while the node is behind its peers
+    fn rpc_block_template(&self) -> Result<BlockTemplate> {
+        let best_height = self.get_best_height()?;
+        let peer_height = self
+            .inner
+            .peers
+            .lock()
+            .unwrap()
+            .heights
+            .values()
+            .copied()
+            .max();
+        if let Some(peer_height) = peer_height
+            && peer_height > best_height
+        {
+            return Err(
+                CodedError::new(ErrorCode::NodeSyncing, "The node is still syncing")
+                    .with("height", best_height)
+                    .with("peer_height", peer_height)
+                    .into(),
+            );
+        }
+        self.work_template()
+    }
+
+    /// work_template is the block template with what a standalone miner needs on top to
+    /// assemble the block: the target and the merkle branch of its coinbase
+    fn work_template(&self) -> Result<BlockTemplate> {
+        let mut template = self.block_template()?;
+        let utxo = self.inner.utxo.read().unwrap();
+        let prev = match utxo.blockchain.get_header(&template.prev_block_hash)? {
+            Some(prev) => prev,
+            None => return Err(format_err!("The tip header is not found")),
+        };
+        template.target = utxo.blockchain.target_after(&prev)?;
+        template.coinbase_branch = coinbase_branch(&template.transactions)?;
+        Ok(template)
+    }
+
+    /// mining_info reports the difficulty of the next block, the network hashrate over
+    /// the last `blocks` blocks and what the local miner did
+    fn mining_info(&self, blocks: usize) -> Result<MiningInfo> {
+        let utxo = self.inner.utxo.read().unwrap();
+        let tip = utxo
+            .blockchain
+            .get_block(&utxo.blockchain.get_tip_hash())?
+            .header();
+        Ok(MiningInfo {
+            height: tip.height,
+            target: utxo.blockchain.target_after(&tip)?,
+            network_hashrate: utxo.blockchain.network_hashrate(blocks)?,
+            mining: self.inner.miner.is_mining(),
+            miner: self.inner.miner.stats(),
+        })
+    }
+
+    /// wait_block_template holds a long-poll request until a template superseding the
+    /// one `longpoll_id` names is ready or `timeout` passes, then returns the current one.
+    /// New blocks, reorgs and transactions wake it up to check.
+    fn wait_block_template(&self, longpoll_id: &str, timeout: Duration) -> Result<BlockTemplate> {
+        let events = self.subscribe();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let template = self.rpc_block_template()?;
+            if template.supersedes(longpoll_id) {
+                return Ok(template);
+            }
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match events.recv_timeout(remaining) {
+                    Ok(timed) => {
+                        if matches!(
+                            timed.event,
+                            NodeEvent::NewBlock { .. }
+                                | NodeEvent::Reorg { .. }
+                                | NodeEvent::NewTransaction { .. }
+                        ) {
+                            break;
+                        }
                     }
+                    Err(_) => return Ok(template),
                 }
-
-                self.clear_mempool();
             }
         }
+    }
 
-        Ok(())
+    fn block_template(&self) -> Result<BlockTemplate> {
+        let entries = self.mempool_entries()?;
+        let utxo = self.inner.utxo.read().unwrap();
+        Ok(build_template(
+            &utxo.blockchain.get_tip_hash(),
+            utxo.blockchain.get_best_height()?,
+            &entries,
+            &self.inner.node.read().unwrap().template_policy,
+        ))
+    }
+
+    /// set_template_policy changes which pending transactions the miner includes
+    pub fn set_template_policy(&self, policy: TemplatePolicy) {
+        self.inner.node.write().unwrap().template_policy = policy;
     }
 
     fn handle_invite(&self, msg: InviteMsg) -> Result<()> {
         info!("recieved invite message: {:#?}", msg);
-        if msg.kind == "block" {
-            let block_hash = &msg.items[0];
-            self.send_get_data(&msg.address_from, "block", block_hash)?;
-
-            let mut new_in_transit = Vec::new();
-            for b in &msg.items {
-                if b != block_hash {
-                    new_in_transit.push(b.clone());
+        match msg.items.first() {
+            Some(Inventory::Block(block_hash)) => {
+                self.send_get_data(&msg.address_from, Inventory::Block(*block_hash))?;
+
+                let mut new_in_transit = Vec::new();
+                for item in &msg.items {
+                    if let Inventory::Block(hash) = item
+                        && hash != block_hash
+                    {
+                        new_in_transit.push(*hash);
+                    }
                 }
+                self.replace_in_transit(new_in_transit);
             }
-            self.replace_in_transit(new_in_transit);
-        } else if msg.kind == "tx" {
-            let tx_id = &msg.items[0];
-            match self.get_mempool_tx(tx_id) {
-                Some(tx) => {
-                    if tx.id.is_empty() {
-                        self.send_get_data(&msg.address_from, "tx", tx_id)?;
+            Some(Inventory::Tx(_)) => {
+                for item in &msg.items {
+                    self.mark_known(&msg.address_from, *item);
+                    if let Inventory::Tx(txid) = item
+                        && self.get_mempool_tx(txid).is_none()
+                    {
+                        self.send_get_data(&msg.address_from, *item)?;
                     }
                 }
-                None => self.send_get_data(&msg.address_from, "tx", tx_id)?,
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// mark_known records that `peer` knows `item`, telling whether it was news
+    fn mark_known(&self, peer: &str, item: Inventory) -> bool {
+        let mut peers = self.inner.peers.lock().unwrap();
+        let known = peers.known_inventory.entry(peer.to_string()).or_default();
+        if known.len() >= MAX_KNOWN_INVENTORY {
+            known.clear();
+        }
+        known.insert(item)
+    }
+
+    /// relay_transaction announces transaction `txid` to every peer taking transactions
+    /// that does not know it yet
+    fn relay_transaction(&self, txid: &TxId) -> Result<()> {
+        let item = Inventory::Tx(*txid);
+        for node in self.tx_relay_peers() {
+            if self.mark_known(&node, item) {
+                self.send_inv(&node, vec![item])?;
             }
         }
         Ok(())
     }
 
     fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+        let double_spends = {
+            let mut utxo = self.inner.utxo.write().unwrap();
+            if utxo.blockchain.has_block(&block.get_hash())? {
+                return Ok(());
+            }
+            let started = Instant::now();
+            let tip = utxo.blockchain.get_tip_hash();
+            utxo.blockchain.add_block(block.clone())?;
+            self.inner
+                .metrics
+                .lock()
+                .unwrap()
+                .observe_validation(started.elapsed().as_secs_f64());
+            if utxo.blockchain.get_tip_hash() != tip {
+                self.inner.miner.cancel();
+            }
+            self.connect_blocks(&mut utxo)?
+        };
+        self.report_block_double_spends(double_spends)
+    }
+
+    /// connect_blocks brings the UTXO set and the mempool up to date with the blocks
+    /// just stored, while the caller still holds the chain for writing. Readers thus see
+    /// a new tip together with its balances and mempool, never the tip alone. Only the
+    /// blocks that joined the best chain are observed; the transactions of those a
+    /// reorganization disconnected return to the mempool.
+    fn connect_blocks(&self, utxo: &mut UTXOSet) -> Result<Vec<(Transaction, TxId, BlockHash)>> {
+        let (disconnected, connected) = self.sync_utxo(utxo)?;
+        let mut double_spends = Vec::new();
+        for block in &connected {
+            for (original, conflicting) in self.observe_block(utxo, block)? {
+                double_spends.push((original, conflicting, block.get_hash()));
+            }
+        }
+        self.restore_transactions(utxo, &disconnected)?;
+        Ok(double_spends)
+    }
+
+    /// restore_transactions returns the transactions of `disconnected`, newest block
+    /// first, to the mempool when they are still valid on the new best chain
+    fn restore_transactions(&self, utxo: &UTXOSet, disconnected: &[Block]) -> Result<()> {
+        let mut mempool = self.inner.mempool.write().unwrap();
+        for block in disconnected.iter().rev() {
+            for tx in block.get_transactions() {
+                if tx.is_coinbase() || mempool.get(&tx.id).is_some() {
+                    continue;
+                }
+                if !mempool.conflicts(tx).is_empty() {
+                    debug!("not restoring tx {}: it double spends a pending tx", tx.id);
+                    continue;
+                }
+                if let Some(failure) = first_failure(trace_transaction(tx, utxo, &mempool)?) {
+                    debug!("not restoring tx {}: {}", tx.id, failure);
+                    continue;
+                }
+                mempool.insert(tx.clone())?;
+                info!(
+                    "returned tx {} of disconnected block {} to the mempool",
+                    tx.id,
+                    block.get_hash()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// observe_block adds a connected block to the chain usage metrics, drops its
+    /// transactions from the mempool and evicts the pending transactions it double
+    /// spends, returning them with the transaction spending their outputs instead
+    fn observe_block(&self, utxo: &UTXOSet, block: &Block) -> Result<Vec<(Transaction, TxId)>> {
+        let mut double_spends = Vec::new();
+        let mut mempool = self.inner.mempool.write().unwrap();
+        let mut txs = Vec::new();
+        for tx in block.get_transactions() {
+            if tx.is_coinbase() {
+                continue;
+            }
+            match utxo.blockchain.get_fee(tx) {
+                Ok(fee) => txs.push((fee, serialize(tx)?.len())),
+                Err(e) => debug!("no fee for tx {} in metrics: {}", tx.id, e),
+            }
+            mempool.remove(&tx.id);
+            for txid in mempool.conflicts(tx) {
+                let evicted = mempool.remove_with_descendants(&txid);
+                if let Some(original) = evicted.into_iter().next() {
+                    double_spends.push((original, tx.id));
+                }
+            }
+        }
+        // offers that can no longer make it into the next block lapse
+        let next_height = utxo.blockchain.get_best_height()? + 1;
+        let expired: Vec<TxId> = mempool
+            .transactions()
+            .filter(|tx| tx.is_expired_at(next_height))
+            .map(|tx| tx.id)
+            .collect();
+        for txid in expired {
+            for tx in mempool.remove_with_descendants(&txid) {
+                info!("tx {} expired before block {}", tx.id, next_height);
+            }
+        }
+        let size = block.size()?;
+        self.inner
+            .metrics
+            .lock()
+            .unwrap()
+            .observe_block(block, size, &txs);
+        self.inner.events.lock().unwrap().publish(
+            now_millis(),
+            NodeEvent::NewBlock {
+                hash: block.get_hash(),
+                height: block.get_height(),
+            },
+        );
+        Ok(double_spends)
+    }
+
+    /// report_block_double_spends reports the pending transactions connected blocks
+    /// evicted, once the chain is no longer held
+    fn report_block_double_spends(
+        &self,
+        double_spends: Vec<(Transaction, TxId, BlockHash)>,
+    ) -> Result<()> {
+        for (original, conflicting, block) in double_spends {
+            self.report_double_spends(&[original], &conflicting, Some(block))?;
+        }
+        Ok(())
+    }
+
+    /// report_double_spends warns of `conflicting` double spending the pending
+    /// `originals`, publishes a DoubleSpend event for each and records those of the
+    /// wallet in it, so merchants relying on few confirmations can react
+    fn report_double_spends(
+        &self,
+        originals: &[Transaction],
+        conflicting: &TxId,
+        block: Option<BlockHash>,
+    ) -> Result<()> {
+        let mut wallets = if self.is_explorer() {
+            None
+        } else {
+            match Wallets::new() {
+                Ok(wallets) => Some(wallets),
+                Err(e) => {
+                    error!("failed to record double spends in the wallet: {}", e);
+                    None
+                }
+            }
+        };
+        let seen = now_secs()?;
+        for original in originals {
+            let wallet = match &mut wallets {
+                Some(wallets) if wallets.is_mine(original)? => {
+                    wallets.record_conflict(Conflict {
+                        txid: original.id,
+                        conflicting: *conflicting,
+                        block,
+                        seen,
+                    });
+                    true
+                }
+                _ => false,
+            };
+            let event = NodeEvent::DoubleSpend {
+                txid: original.id,
+                conflicting: *conflicting,
+                block,
+                wallet,
+            };
+            warn!("{}", event);
+            self.inner
+                .events
+                .lock()
+                .unwrap()
+                .publish(now_millis(), event);
+        }
+        if let Some(wallets) = wallets {
+            wallets.save_all()?;
+        }
+        Ok(())
+    }
+
+    /// double_spends returns the recent DoubleSpend events
+    fn double_spends(&self) -> Vec<TimedEvent> {
+        self.inner
+            .events
+            .lock()
+            .unwrap()
+            .history()
+            .into_iter()
+            .filter(|timed| matches!(timed.event, NodeEvent::DoubleSpend { .. }))
+            .collect()
     }
 
     fn handle_get_data(&self, msg: GetDataMsg) -> Result<()> {
         info!("recieved get data message: {:#?}", msg);
-        match msg.kind.as_str() {
-            "block" => {
-                let block = self.get_block(&msg.id)?;
+        match msg.item {
+            Inventory::Block(hash) => {
+                let block = self.get_block(&hash)?;
                 self.send_block(&msg.address_from, &block)?;
             }
-            "tx" => {
-                let tx = self.get_mempool_tx(&msg.id).unwrap();
+            Inventory::Tx(txid) => {
+                // the transaction may have been mined or evicted since it was announced
+                let Some(tx) = self.get_mempool_tx(&txid) else {
+                    info!(
+                        "{} asked for {}, which is not in the mempool",
+                        msg.address_from, txid
+                    );
+                    return Ok(());
+                };
                 self.send_tx(&msg.address_from, &tx)?;
             }
-            _ => {}
         }
         Ok(())
     }
 
-    fn get_block(&self, block_hash: &str) -> Result<Block> {
+    fn get_block(&self, block_hash: &BlockHash) -> Result<Block> {
         self.inner
-            .lock()
-            .unwrap()
             .utxo
+            .read()
+            .unwrap()
             .blockchain
             .get_block(block_hash)
     }
 
-    fn handle_version(&self, msg: VersionMsg) -> Result<()> {
+    fn handle_version(&self, msg: VersionMsg, stream: &mut TcpStream) -> Result<()> {
         info!("recieved version message: {:#?}", msg);
+        let network = self.network();
+        if msg.network != network {
+            info!(
+                "refusing {} of network {}, not {}",
+                msg.address_from, msg.network, network
+            );
+            self.penalize(stream.peer_addr()?.ip(), WRONG_NETWORK_PENALTY)?;
+            return Ok(());
+        }
+        if msg.nonce == self.inner.nonce {
+            info!("dropping connection to self as {}", msg.address_from);
+            return Ok(());
+        }
+        if msg.version < MIN_PEER_VERSION {
+            info!(
+                "refusing {}: protocol version {} is older than {}",
+                msg.address_from, msg.version, MIN_PEER_VERSION
+            );
+            return Ok(());
+        }
+        if !self.is_handshaken(&msg.address_from)
+            && self.peer_count(Direction::Inbound) >= self.max_peers().0
+        {
+            info!("refusing {}: inbound peer limit reached", msg.address_from);
+            return Ok(());
+        }
+
+        let ip = stream.peer_addr()?.ip();
+        if !resolves_to(&msg.address_from, ip) {
+            info!(
+                "refusing {}: it connected from {}, another host",
+                msg.address_from, ip
+            );
+            return Ok(());
+        }
+
+        self.observe_address(&msg.address_to);
+
+        // the peer is ready once it has read the verack; its messages may arrive on
+        // other connections as soon as it has, so it is marked before and dropped again
+        // if the verack does not get through
+        self.set_peer_services(&msg.address_from, msg.services);
+        self.handshake_completed(&msg.address_from, Direction::Inbound, ip);
+        let verack = VerackMsg {
+            address_from: self.node_address.clone(),
+            version: VERSION,
+            services: self.local_services()?,
+        };
+        let delivered = serialize(&(self.magic(), cmd_to_bytes("verack"), verack))
+            .map_err(failure::Error::from)
+            .and_then(|data| {
+                stream.write_all(&data)?;
+                stream.shutdown(Shutdown::Write)?;
+                read_with_deadline(stream, self.get_timeouts().0)?;
+                Ok(())
+            });
+        if let Err(e) = delivered {
+            self.remove_node(&msg.address_from, &e.to_string())?;
+            return Err(e);
+        }
+
+        self.set_peer_height(&msg.address_from, msg.best_height);
         let my_best_height = self.get_best_height()?;
         if my_best_height < msg.best_height {
-            self.send_get_blocks(&msg.address_from)?;
+            self.send_get_headers(&msg.address_from)?;
         } else if my_best_height > msg.best_height {
             self.send_version(&msg.address_from)?;
         }
@@ -428,125 +2497,373 @@ impl Server {
         self.send_addr(&msg.address_from)?;
 
         if !self.node_is_known(&msg.address_from) {
-            self.add_nodes(&msg.address_from);
+            self.add_nodes(&msg.address_from)?;
         }
         Ok(())
     }
 
+    /// magic is the network magic of the chain, opening every message the node sends
+    fn magic(&self) -> [u8; MAGIC_LEN] {
+        self.inner.magic
+    }
+
+    /// decode_message decodes a message received from a peer. A paranoid node also
+    /// checks every invariant of the message and turns a panic while doing so into an
+    /// error, so a hostile message costs the peer its score rather than the node a thread.
+    fn decode_message(&self, bytes: &[u8]) -> Result<Message> {
+        if !self.inner.node.read().unwrap().settings.paranoid {
+            return bytes_to_cmd(self.magic(), bytes, false);
+        }
+        let magic = self.magic();
+        match panic::catch_unwind(|| {
+            let cmd = bytes_to_cmd(magic, bytes, true)?;
+            cmd.check()?;
+            Ok(cmd)
+        }) {
+            Ok(result) => result,
+            Err(_) => Err(malformed(String::from("decoding panicked"))),
+        }
+    }
+
+    fn network(&self) -> String {
+        self.inner.network.clone()
+    }
+
+    /// is_explorer tells whether the wallet of the node is disabled
+    fn is_explorer(&self) -> bool {
+        self.inner.node.read().unwrap().settings.explorer
+    }
+
     fn get_best_height(&self) -> Result<i32> {
-        self.inner.lock().unwrap().utxo.blockchain.get_best_height()
+        self.inner.utxo.read().unwrap().blockchain.get_best_height()
     }
 
-    fn get_block_hashes(&self) -> Vec<String> {
+    fn get_block_hashes(&self) -> Vec<BlockHash> {
         self.inner
-            .lock()
-            .unwrap()
             .utxo
+            .read()
+            .unwrap()
             .blockchain
             .get_block_hashes()
     }
 
-    fn node_is_known(&self, addr: &str) -> bool {
-        self.inner.lock().unwrap().known_nodes.get(addr).is_some()
+    fn is_handshaken(&self, addr: &str) -> bool {
+        self.inner
+            .peers
+            .lock()
+            .unwrap()
+            .handshaken
+            .contains_key(addr)
     }
 
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
+    /// is_handshaken_from tells whether `addr` is handshaken and a connection from `ip`
+    /// may speak for it
+    fn is_handshaken_from(&self, addr: &str, ip: IpAddr) -> bool {
+        let peers = self.inner.peers.lock().unwrap();
+        peers.handshaken.contains_key(addr) && peers.peer_ips.get(addr) == Some(&ip)
+    }
+
+    /// peer_count counts the handshaken peers of `direction`
+    fn peer_count(&self, direction: Direction) -> usize {
         self.inner
+            .peers
             .lock()
             .unwrap()
-            .utxo
-            .blockchain
-            .verify_transaction(tx)
+            .handshaken
+            .values()
+            .filter(|d| **d == direction)
+            .count()
     }
 
-    fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+    /// max_peers returns the inbound and outbound peer limits
+    fn max_peers(&self) -> (usize, usize) {
+        let settings = &self.inner.node.read().unwrap().settings;
+        (settings.max_inbound, settings.max_outbound)
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+    fn node_is_known(&self, addr: &str) -> bool {
+        self.inner.peers.lock().unwrap().known_nodes.contains(addr)
     }
 
-    fn add_nodes(&self, addr: &str) {
-        self.inner
-            .lock()
-            .unwrap()
-            .known_nodes
-            .insert(String::from(addr));
+    /// sync_utxo applies the blocks accepted since the last call to the UTXO set and
+    /// returns the blocks it disconnected and connected
+    fn sync_utxo(&self, utxo: &mut UTXOSet) -> Result<(Vec<Block>, Vec<Block>)> {
+        let (disconnected, connected) = utxo.sync()?;
+        if !disconnected.is_empty() {
+            let (disconnected, connected) = (disconnected.len(), connected.len());
+            info!(
+                "reorganized the UTXO set: {} blocks rolled back, {} applied",
+                disconnected, connected
+            );
+            let tip = utxo.blockchain.get_tip_hash();
+            audit::record_or_log(
+                "node",
+                "reorg",
+                &format!(
+                    "to {}: {} blocks disconnected, {} connected",
+                    tip, disconnected, connected
+                ),
+            );
+            self.inner.events.lock().unwrap().publish(
+                now_millis(),
+                NodeEvent::Reorg {
+                    tip,
+                    disconnected,
+                    connected,
+                },
+            );
+        }
+        Ok((disconnected, connected))
+    }
+
+    /// prune_blocks deletes old block bodies when the node settings enable pruning and
+    /// the node is no archive
+    fn prune_blocks(&self) -> Result<()> {
+        let mut utxo = self.inner.utxo.write().unwrap();
+        let prune = self.inner.node.read().unwrap().settings.prune;
+        if let Some(keep) = prune
+            && !utxo.blockchain.is_archive()
+        {
+            let pruned = utxo.prune(keep)?;
+            if pruned > 0 {
+                info!("pruned {} blocks, keeping the last {}", pruned, keep);
+            }
+        }
+        Ok(())
     }
 
-    fn replace_in_transit(&self, hashs: Vec<String>) {
-        let bit = &mut self.inner.lock().unwrap().blocks_in_transit;
+    fn add_nodes(&self, addr: &str) -> Result<()> {
+        if addr == self.node_address {
+            return Ok(());
+        }
+        let mut peers = self.inner.peers.lock().unwrap();
+        peers.known_nodes.insert(String::from(addr));
+
+        let record = PeerRecord {
+            last_seen: now_millis(),
+            failures: 0,
+        };
+        peers.db.insert(addr, serialize(&record)?)?;
+        peers.db.flush()?;
+        Ok(())
+    }
+
+    fn replace_in_transit(&self, hashs: Vec<BlockHash>) {
+        let bit = &mut self.inner.sync.lock().unwrap().blocks_in_transit;
         bit.clone_from(&hashs);
     }
 
-    fn get_in_transit(&self) -> Vec<String> {
-        self.inner.lock().unwrap().blocks_in_transit.clone()
+    fn get_in_transit(&self) -> Vec<BlockHash> {
+        self.inner.sync.lock().unwrap().blocks_in_transit.clone()
     }
 
-    fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.lock().unwrap().mempool.get(addr) {
+    fn get_mempool_tx(&self, txid: &TxId) -> Option<Transaction> {
+        match self.inner.mempool.read().unwrap().get(txid) {
             Some(tx) => Some(tx.clone()),
             None => None,
         }
     }
 
-    fn get_mempool(&self) -> HashMap<String, Transaction> {
-        self.inner.lock().unwrap().mempool.clone()
-    }
+    /// insert_mempool accepts `tx` unless it double spends a pending transaction.
+    /// With replace-by-fee enabled a conflicting transaction paying a higher fee
+    /// evicts the ones it conflicts with and their descendants.
+    fn insert_mempool(&self, tx: Transaction) -> Result<bool> {
+        let double_spent: Vec<Transaction> = {
+            let utxo = self.inner.utxo.read().unwrap();
+            let mempool = self.inner.mempool.read().unwrap();
+            let checks = trace_transaction(&tx, &utxo, &mempool)?;
+            if let Some(failure) = first_failure(checks) {
+                info!("rejecting tx {}: {}", tx.id, failure);
+                return Ok(false);
+            }
+            mempool
+                .conflicts(&tx)
+                .iter()
+                .filter_map(|txid| mempool.get(txid).cloned())
+                .collect()
+        };
+        if !double_spent.is_empty() {
+            self.report_double_spends(&double_spent, &tx.id, None)?;
+        }
 
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+        let utxo = self.inner.utxo.read().unwrap();
+        let mut mempool = self.inner.mempool.write().unwrap();
+        let conflicts = mempool.conflicts(&tx);
+        if !conflicts.is_empty() {
+            if !self.inner.node.read().unwrap().replace_by_fee {
+                info!("rejecting tx {}: double spends {:?}", tx.id, conflicts);
+                return Ok(false);
+            }
+            let fee = utxo.blockchain.get_fee(&tx)?;
+            let mut replaced_fee = 0;
+            for txid in &conflicts {
+                if let Some(conflict) = mempool.get(txid) {
+                    replaced_fee += utxo.blockchain.get_fee(conflict)?;
+                }
+            }
+            if fee <= replaced_fee {
+                info!(
+                    "rejecting replacement {}: fee {} does not exceed {}",
+                    tx.id, fee, replaced_fee
+                );
+                return Ok(false);
+            }
+            for txid in &conflicts {
+                for evicted in mempool.remove_with_descendants(txid) {
+                    info!("tx {} replaced by {}", evicted.id, tx.id);
+                }
+            }
+        }
+        let txid = tx.id;
+        mempool.insert(tx)?;
+        self.inner
+            .events
+            .lock()
+            .unwrap()
+            .publish(now_millis(), NodeEvent::NewTransaction { txid });
+        debug!("mempool holds {} transactions", mempool.len());
+        Ok(true)
     }
 
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear()
+    /// set_replace_by_fee lets higher-fee transactions replace conflicting pending ones
+    pub fn set_replace_by_fee(&self, enabled: bool) {
+        self.inner.node.write().unwrap().replace_by_fee = enabled;
     }
 }
 
-fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
-    let cmd_bytes = &bytes[0..CMD_LEN];
+/// bytes_to_cmd decodes a message, refusing those opening with the magic of another
+/// network than `magic`. `strict` refuses trailing bytes after the payload.
+fn bytes_to_cmd(magic: [u8; MAGIC_LEN], bytes: &[u8], strict: bool) -> Result<Message> {
+    if bytes.len() < MAGIC_LEN + CMD_LEN {
+        return Err(malformed(String::from("Message is shorter than a command")));
+    }
+    if bytes[..MAGIC_LEN] != magic {
+        return Err(
+            CodedError::new(ErrorCode::WrongNetwork, "Message is for another network")
+                .with("expected", hex::encode(magic))
+                .with("actual", hex::encode(&bytes[..MAGIC_LEN]))
+                .into(),
+        );
+    }
+    let bytes = &bytes[MAGIC_LEN..];
+    // commands are padded with zeros up to CMD_LEN
+    let cmd_bytes: Vec<u8> = bytes[0..CMD_LEN]
+        .iter()
+        .copied()
+        .take_while(|b| *b != 0)
+        .collect();
     let data = &bytes[CMD_LEN..];
-    for b in cmd_bytes {
-        if 0 as u8 == *b {
-            return Err(format_err!("Command is empty"));
-        }
+    if cmd_bytes.is_empty() {
+        return Err(malformed(String::from("Command is empty")));
     }
-    info!("cmd: {}", String::from_utf8(cmd_bytes.to_vec())?);
+    info!("cmd: {}", String::from_utf8_lossy(&cmd_bytes));
 
-    return match cmd_bytes {
+    return match cmd_bytes.as_slice() {
         b"addr" => {
-            let data: Vec<String> = deserialize(data)?;
+            let data: AddrMsg = decode_untrusted(data, strict)?;
             Ok(Message::Address(data))
         }
         b"block" => {
-            let data: BlockMsg = deserialize(data)?;
+            let data: BlockMsg = decode_untrusted(data, strict)?;
             Ok(Message::Block(data))
         }
         b"inv" => {
-            let data: InviteMsg = deserialize(data)?;
+            let data: InviteMsg = decode_untrusted(data, strict)?;
             Ok(Message::Invite(data))
         }
         b"getblocks" => {
-            let data: GetBlocksMsg = deserialize(data)?;
+            let data: GetBlocksMsg = decode_untrusted(data, strict)?;
             Ok(Message::GetBlocks(data))
         }
+        b"getheaders" => {
+            let data: GetHeadersMsg = decode_untrusted(data, strict)?;
+            Ok(Message::GetHeaders(data))
+        }
+        b"headers" => {
+            let data: HeadersMsg = decode_untrusted(data, strict)?;
+            Ok(Message::Headers(data))
+        }
         b"getdata" => {
-            let data: GetDataMsg = deserialize(data)?;
+            let data: GetDataMsg = decode_untrusted(data, strict)?;
             Ok(Message::GetData(data))
         }
         b"tx" => {
-            let data: TransactionMsg = deserialize(data)?;
+            let data: TransactionMsg = decode_untrusted(data, strict)?;
             Ok(Message::Transaction(data))
         }
         b"version" => {
-            let data: VersionMsg = deserialize(data)?;
+            let data: VersionMsg = decode_untrusted(data, strict)?;
             Ok(Message::Version(data))
         }
-        _ => Err(format_err!("Unknown command in the server")),
+        b"verack" => {
+            let data: VerackMsg = decode_untrusted(data, strict)?;
+            Ok(Message::Verack(data))
+        }
+        b"rpc" => {
+            let data: RpcRequest = decode_untrusted(data, strict)?;
+            Ok(Message::Rpc(data))
+        }
+        b"authrpc" => {
+            let data: AuthRpcMsg = decode_untrusted(data, strict)?;
+            Ok(Message::AuthRpc(data))
+        }
+        _ => Err(malformed(String::from("Unknown command in the server"))),
     };
 }
 
+/// resolves_to tells whether node address `address` names the host at `ip`, so a peer
+/// cannot take over the address of another node
+fn resolves_to(address: &str, ip: IpAddr) -> bool {
+    match address.to_socket_addrs() {
+        Ok(mut addrs) => addrs.any(|addr| addr.ip().to_canonical() == ip.to_canonical()),
+        Err(_) => false,
+    }
+}
+
+/// read_with_deadline reads until the peer closes the connection, failing with
+/// `TimedOut` once `timeout` has passed, however slowly the bytes trickle in, and with
+/// `InvalidData` once more than MAX_MESSAGE_SIZE bytes came
+fn read_with_deadline(stream: &mut TcpStream, timeout: Duration) -> io::Result<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        if buffer.len() > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("message exceeds {} bytes", MAX_MESSAGE_SIZE),
+            ));
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "read deadline exceeded",
+            ));
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        match stream.read(&mut chunk) {
+            Ok(0) => return Ok(buffer),
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "read deadline exceeded",
+                ))
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
 fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
     let mut data = [0; CMD_LEN];
     for (i, d) in cmd.as_bytes().iter().enumerate() {
@@ -558,27 +2875,155 @@ fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::blockchain::*;
-    use crate::wallets::*;
+    use crate::{
+        blockchain::*,
+        config::{ChainParams, MAIN_NETWORK, REGTEST_NETWORK},
+        u256::U256,
+    };
 
     #[test]
     fn test_cmd() {
-        let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
-        let bc = Blockchain::create_blockchain(wa1).unwrap();
+        let bc = Blockchain::create_blockchain().unwrap();
         let utxo_set = UTXOSet { blockchain: bc };
         let server = Server::new("7878", "localhost:3001", utxo_set).unwrap();
 
-        let vmsg = Versionmsg {
-            addr_from: server.node_address.clone(),
+        let vmsg = VersionMsg {
+            address_from: server.node_address.clone(),
+            address_to: String::from("localhost:3000"),
             best_height: server.get_best_height().unwrap(),
             version: VERSION,
+            services: Services::ARCHIVE | Services::MEMPOOL,
+            network: server.network(),
+            nonce: 7,
         };
-        let data = serialize(&(cmd_to_bytes("version"), vmsg.clone())).unwrap();
-        if let Message::Version(v) = bytes_to_cmd(&data).unwrap() {
+        let data = serialize(&(server.magic(), cmd_to_bytes("version"), vmsg.clone())).unwrap();
+        if let Message::Version(v) = bytes_to_cmd(server.magic(), &data, false).unwrap() {
             assert_eq!(v, vmsg);
         } else {
             panic!("wrong!");
         }
+
+        // a node of another network cannot even be decoded
+        let magic = ChainParams::for_network(REGTEST_NETWORK).magic();
+        let e = bytes_to_cmd(magic, &data, false).unwrap_err();
+        assert_eq!(CodedError::from(&e).code, ErrorCode::WrongNetwork);
+
+        // a transaction that left the mempool since it was announced is not served
+        server
+            .handle_get_data(GetDataMsg {
+                address_from: String::from("localhost:3000"),
+                item: Inventory::Tx(TxId::default()),
+            })
+            .unwrap();
+    }
+    #[test]
+    fn test_data_messages_need_handshake() {
+        let version = Message::Version(VersionMsg {
+            address_from: String::from("localhost:3001"),
+            address_to: String::from("localhost:3000"),
+            version: VERSION,
+            best_height: 0,
+            services: Services::NONE,
+            network: String::from(MAIN_NETWORK),
+            nonce: 7,
+        });
+        assert_eq!(version.data_sender(), None);
+
+        let magic = ChainParams::default().magic();
+        let data = serialize(&(
+            magic,
+            cmd_to_bytes("getblocks"),
+            GetBlocksMsg {
+                address_from: String::from("localhost:3001"),
+            },
+        ))
+        .unwrap();
+        assert_eq!(
+            bytes_to_cmd(magic, &data, false).unwrap().data_sender(),
+            Some("localhost:3001")
+        );
+
+        // the claimed address must name the host the connection comes from
+        let local = IpAddr::from([127, 0, 0, 1]);
+        assert!(resolves_to("localhost:3001", local));
+        assert!(resolves_to("127.0.0.1:3001", local));
+        assert!(!resolves_to("10.1.2.3:3001", local));
+        assert!(!resolves_to("not an address", local));
+    }
+
+    #[test]
+    fn test_admin_rpc() {
+        let ban = RpcRequest::SetBan {
+            range: String::from("10.0.0.0/8"),
+            remove: false,
+            duration: None,
+        };
+        let remote = Some(IpAddr::from([10, 1, 2, 3]));
+        let e = authorize_rpc(&ban, remote, None, None).unwrap_err();
+        assert_eq!(CodedError::from(&e).code, ErrorCode::Unauthorized);
+        assert!(authorize_rpc(&ban, remote, Some("guess"), Some("secret")).is_err());
+        assert!(authorize_rpc(&ban, remote, Some(""), Some("")).is_err());
+        assert!(authorize_rpc(&ban, remote, Some("secret"), Some("secret")).is_ok());
+
+        // the loopback interface needs no token, and read-only requests are open to all
+        let local = "::ffff:127.0.0.1".parse().ok();
+        assert!(authorize_rpc(&ban, local, None, None).is_ok());
+        let info = RpcRequest::MiningInfo { blocks: 10 };
+        assert!(authorize_rpc(&info, remote, None, None).is_ok());
+    }
+
+    #[test]
+    fn test_paranoid_checks() {
+        let magic = ChainParams::default().magic();
+        let coinbase = Transaction::new_coinbase(
+            String::from("3f6bc2718a911d5d6e117f7ae2b3380eaf4fa329f"),
+            String::from("paranoid"),
+            1,
+        )
+        .unwrap();
+        let block = Block::new(vec![coinbase], BlockHash::default(), 0, U256::MAX).unwrap();
+        let headers = |header: BlockHeader| {
+            let msg = HeadersMsg {
+                address_from: String::from("localhost:3001"),
+                headers: vec![header],
+            };
+            serialize(&(magic, cmd_to_bytes("headers"), msg)).unwrap()
+        };
+        let data = headers(block.header());
+        assert!(bytes_to_cmd(magic, &data, true).unwrap().check().is_ok());
+
+        let mut forged = block.header();
+        forged.nonce += 1;
+        let cmd = bytes_to_cmd(magic, &headers(forged), true).unwrap();
+        assert!(cmd.check().is_err());
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(bytes_to_cmd(magic, &trailing, false).is_ok());
+        assert!(bytes_to_cmd(magic, &trailing, true).is_err());
+        assert!(bytes_to_cmd(magic, &data[..data.len() - 1], false).is_err());
+
+        let addr = AddrMsg {
+            address_from: String::from("localhost:3001"),
+            nodes: vec![String::from("no port")],
+        };
+        let data = serialize(&(magic, cmd_to_bytes("addr"), addr)).unwrap();
+        assert!(bytes_to_cmd(magic, &data, true).unwrap().check().is_err());
+    }
+
+    #[test]
+    fn test_read_deadline() {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        client.write_all(b"slow").unwrap();
+        let err = read_with_deadline(&mut stream, Duration::from_millis(200)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        client.write_all(b"done").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let data = read_with_deadline(&mut stream, Duration::from_millis(200)).unwrap();
+        assert_eq!(data, b"done");
     }
 }
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, ops::BitOr};
+
+/// Services are the capabilities a node advertises in its handshake, as bit flags
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Services(pub u64);
+
+impl Services {
+    pub const NONE: Services = Services(0);
+    /// ARCHIVE serves every block body and answers historical queries
+    pub const ARCHIVE: Services = Services(1);
+    /// FILTERS serves the compact filters of blocks to light clients
+    pub const FILTERS: Services = Services(1 << 1);
+    /// PRUNED serves only the most recent block bodies
+    pub const PRUNED: Services = Services(1 << 2);
+    /// MEMPOOL accepts and relays unconfirmed transactions
+    pub const MEMPOOL: Services = Services(1 << 3);
+    /// TXINDEX looks any confirmed transaction up by its id
+    pub const TXINDEX: Services = Services(1 << 4);
+    /// MINING mines blocks, so block templates and new transactions are worth sending
+    pub const MINING: Services = Services(1 << 5);
+
+    const NAMES: [(Services, &'static str); 6] = [
+        (Services::ARCHIVE, "archive"),
+        (Services::FILTERS, "filters"),
+        (Services::PRUNED, "pruned"),
+        (Services::MEMPOOL, "mempool"),
+        (Services::TXINDEX, "txindex"),
+        (Services::MINING, "mining"),
+    ];
+
+    /// contains tells whether every service of `other` is offered
+    pub fn contains(self, other: Services) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Services {
+    type Output = Services;
+
+    fn bitor(self, other: Services) -> Services {
+        Services(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Services {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = Services::NAMES
+            .iter()
+            .filter(|(service, _)| self.contains(*service))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_services() {
+        let services = Services::ARCHIVE | Services::FILTERS | Services::MEMPOOL | Services::MINING;
+        assert!(services.contains(Services::FILTERS));
+        assert!(services.contains(Services::ARCHIVE | Services::MEMPOOL));
+        assert!(!services.contains(Services::PRUNED | Services::MEMPOOL));
+        assert!(services.contains(Services::NONE));
+        assert_eq!(services.to_string(), "archive,filters,mempool,mining");
+        assert_eq!(Services::NONE.to_string(), "none");
+    }
+}
@@ -0,0 +1,168 @@
+use super::*;
+use core::time::Duration;
+use crypto::ed25519;
+use failure::format_err;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+/// Signer produces the ed25519 signature of a sighash digest for the key `public_key`
+pub trait Signer {
+    fn sign(&self, public_key: &[u8], digest: &str) -> Result<Vec<u8>>;
+}
+
+/// KeySigner signs with a secret key held in the wallet file
+pub struct KeySigner<'a> {
+    secret_key: &'a [u8],
+}
+
+impl<'a> KeySigner<'a> {
+    pub fn new(secret_key: &'a [u8]) -> Self {
+        KeySigner { secret_key }
+    }
+}
+
+impl Signer for KeySigner<'_> {
+    fn sign(&self, _public_key: &[u8], digest: &str) -> Result<Vec<u8>> {
+        Ok(ed25519::signature(digest.as_bytes(), self.secret_key).to_vec())
+    }
+}
+
+/// RemoteSigner forwards digests to a signing service, such as an HSM gateway, that
+/// keeps the secret keys. It posts a JSON SignRequest to `/sign` over HTTP and expects
+/// a SignResponse back; mutual TLS is left to a proxy in front of the service.
+pub struct RemoteSigner {
+    address: String,
+    timeout: Duration,
+}
+
+/// SignRequest asks the remote signer to sign `digest` with the key of `public_key`, in hex
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SignRequest {
+    pub public_key: String,
+    pub digest: String,
+}
+
+/// SignResponse holds the hex encoded 64 byte signature
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SignResponse {
+    pub signature: String,
+}
+
+impl RemoteSigner {
+    pub fn new(address: &str, timeout: Duration) -> Self {
+        RemoteSigner {
+            address: address.to_string(),
+            timeout,
+        }
+    }
+}
+
+impl Signer for RemoteSigner {
+    fn sign(&self, public_key: &[u8], digest: &str) -> Result<Vec<u8>> {
+        info!("asking remote signer {} to sign {}", self.address, digest);
+        let body = serde_json::to_string(&SignRequest {
+            public_key: hex::encode(public_key),
+            digest: digest.to_string(),
+        })?;
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        write!(
+            stream,
+            "POST /sign HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.address,
+            body.len(),
+            body
+        )?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let (head, body) = match response.split_once("\r\n\r\n") {
+            Some(parts) => parts,
+            None => return Err(format_err!("Malformed response from remote signer")),
+        };
+        let status = head.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(format_err!("Remote signer answered {}: {}", status, body));
+        }
+
+        let response: SignResponse = serde_json::from_str(body)?;
+        let signature = hex::decode(&response.signature)?;
+        // a misconfigured signer must not get an unspendable transaction broadcast
+        if signature.len() != 64 || !ed25519::verify(digest.as_bytes(), public_key, &signature) {
+            return Err(format_err!(
+                "Remote signer returned an invalid signature for {}",
+                digest
+            ));
+        }
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wallets::Wallet;
+    use std::{io::BufRead, io::BufReader, net::TcpListener, thread};
+
+    /// serve_once answers one sign request like a signing service holding `wallet`
+    fn serve_once(listener: TcpListener, wallet: Wallet) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        let request: SignRequest = serde_json::from_slice(&body).unwrap();
+
+        let signature = KeySigner::new(&wallet.secret_key)
+            .sign(&wallet.public_key, &request.digest)
+            .unwrap();
+        let body = serde_json::to_string(&SignResponse {
+            signature: hex::encode(signature),
+        })
+        .unwrap();
+        write!(
+            reader.get_mut(),
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_remote_signer() {
+        let wallet = Wallet::derive(&[5; 32], &[44, 0], 0);
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let service_wallet = wallet.clone();
+        let service = thread::spawn(move || serve_once(listener, service_wallet));
+
+        let signer = RemoteSigner::new(&address, Duration::from_secs(5));
+        let signature = signer.sign(&wallet.public_key, "digest").unwrap();
+        assert!(ed25519::verify(b"digest", &wallet.public_key, &signature));
+        service.join().unwrap();
+
+        let other = Wallet::derive(&[6; 32], &[44, 0], 0);
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let service = thread::spawn(move || serve_once(listener, other));
+        let signer = RemoteSigner::new(&address, Duration::from_secs(5));
+        assert!(signer.sign(&wallet.public_key, "digest").is_err());
+        service.join().unwrap();
+    }
+}
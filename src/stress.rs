@@ -0,0 +1,237 @@
+use super::*;
+use crate::{
+    hash::TxId,
+    mempool::Mempool,
+    miner::{build_template, MempoolEntry, TemplatePolicy},
+    transaction::{TXInput, TXOutput, Transaction},
+    utxoset::{outpoint_key, UTXOSet},
+    validation::{first_failure, trace_transaction},
+    wallets::{hash_pub_key, Wallets},
+};
+use bincode::serialize;
+use core::time::Duration;
+use failure::format_err;
+use log::{debug, info};
+use std::{
+    collections::{HashMap, HashSet},
+    thread,
+    time::Instant,
+};
+
+/// StressReport sums up a stress run
+#[derive(Debug, Clone, Default)]
+pub struct StressReport {
+    pub submitted: usize,
+    pub rejected: usize,
+    /// starved counts the transactions skipped because no wallet had a free output
+    pub starved: usize,
+    pub mined: usize,
+    pub blocks: usize,
+    /// mempool_sizes holds the mempool size at the end of every second
+    pub mempool_sizes: Vec<usize>,
+    /// validation_tps is how many transactions the validation rules check per second
+    pub validation_tps: f64,
+    pub mean_latency: Duration,
+    pub max_latency: Duration,
+}
+
+/// StressTest pays one coin at a time between the local wallets at a fixed rate and mines
+/// the mempool on the local chain, so it is meant for a development chain
+pub struct StressTest<'a> {
+    utxo: UTXOSet,
+    wallets: &'a Wallets,
+    addresses: Vec<String>,
+    mempool: Mempool,
+    /// reserved holds the outpoints spent by pending transactions
+    reserved: HashSet<Vec<u8>>,
+    submitted_at: HashMap<TxId, Instant>,
+    next_sender: usize,
+}
+
+impl<'a> StressTest<'a> {
+    pub fn new(utxo: UTXOSet, wallets: &'a Wallets) -> Result<Self> {
+        let mut addresses: Vec<String> = wallets
+            .get_all_addresses()
+            .into_iter()
+            .filter(|address| !wallets.get_wallet(address).unwrap().is_remote())
+            .collect();
+        if addresses.len() < 2 {
+            return Err(format_err!(
+                "A stress test needs at least two wallets holding their secret key"
+            ));
+        }
+        addresses.sort();
+        Ok(StressTest {
+            utxo,
+            wallets,
+            addresses,
+            mempool: Mempool::new(),
+            reserved: HashSet::new(),
+            submitted_at: HashMap::new(),
+            next_sender: 0,
+        })
+    }
+
+    /// run submits `tps` transactions a second for `duration`, mining a block every
+    /// `block_interval` with the first wallet as miner
+    pub fn run(
+        &mut self,
+        tps: u32,
+        duration: Duration,
+        block_interval: Duration,
+    ) -> Result<StressReport> {
+        let mut report = StressReport::default();
+        let tx_interval = Duration::from_secs(1) / tps.max(1);
+        let started = Instant::now();
+        let mut next_tx = started;
+        let mut next_block = started + block_interval;
+        let mut next_sample = started + Duration::from_secs(1);
+        let mut validation_time = Duration::ZERO;
+        let mut latencies = Vec::new();
+
+        while started.elapsed() < duration {
+            let now = Instant::now();
+            if now >= next_tx {
+                next_tx += tx_interval;
+                match self.build_transaction()? {
+                    Some(tx) => {
+                        report.submitted += 1;
+                        let validating = Instant::now();
+                        let accepted = self.submit(tx)?;
+                        validation_time += validating.elapsed();
+                        if !accepted {
+                            report.rejected += 1;
+                        }
+                    }
+                    None => report.starved += 1,
+                }
+            }
+            if now >= next_block {
+                next_block += block_interval;
+                latencies.extend(self.mine()?);
+                report.blocks += 1;
+            }
+            if now >= next_sample {
+                next_sample += Duration::from_secs(1);
+                info!(
+                    "stress: {}s, {} submitted, mempool {}",
+                    report.mempool_sizes.len() + 1,
+                    report.submitted,
+                    self.mempool.len()
+                );
+                report.mempool_sizes.push(self.mempool.len());
+            }
+            let wake = next_tx.min(next_block).min(next_sample);
+            thread::sleep(wake.saturating_duration_since(Instant::now()));
+        }
+
+        report.mined = latencies.len();
+        report.validation_tps = if validation_time.is_zero() {
+            0.0
+        } else {
+            report.submitted as f64 / validation_time.as_secs_f64()
+        };
+        if !latencies.is_empty() {
+            report.mean_latency = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+            report.max_latency = latencies.iter().max().copied().unwrap_or_default();
+        }
+        Ok(report)
+    }
+
+    /// build_transaction pays one coin from the next wallet with a confirmed output that
+    /// no pending transaction spends, returning the rest as change
+    fn build_transaction(&mut self) -> Result<Option<Transaction>> {
+        for _ in 0..self.addresses.len() {
+            let from = &self.addresses[self.next_sender];
+            self.next_sender = (self.next_sender + 1) % self.addresses.len();
+            let to = &self.addresses[self.next_sender];
+            let wallet = self.wallets.get_wallet(from).unwrap();
+            let mut pub_key_hash = wallet.public_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+
+            let free =
+                self.utxo
+                    .find_outputs_for(&pub_key_hash)?
+                    .into_iter()
+                    .find(|(txid, vout, out)| {
+                        out.value >= 2 && !self.reserved.contains(&outpoint_key(txid, *vout))
+                    });
+            let (txid, vout, out) = match free {
+                Some(output) => output,
+                None => continue,
+            };
+            let mut tx = Transaction {
+                id: TxId::default(),
+                vin: vec![TXInput {
+                    txid,
+                    vout,
+                    signature: Vec::new(),
+                    pub_key: wallet.public_key.clone(),
+                }],
+                vout: vec![
+                    TXOutput::new(1, to.clone())?,
+                    TXOutput::new(out.value - 1, from.clone())?,
+                ],
+                lock_time: 0,
+                expiry_height: 0,
+            };
+            tx.id = tx.hash()?;
+            self.utxo
+                .blockchain
+                .sign_transaction(&mut tx, wallet.signer()?.as_ref())?;
+            return Ok(Some(tx));
+        }
+        Ok(None)
+    }
+
+    /// submit validates `tx` like a node receiving it and adds it to the mempool
+    fn submit(&mut self, tx: Transaction) -> Result<bool> {
+        let checks = trace_transaction(&tx, &self.utxo, &self.mempool)?;
+        if let Some(failure) = first_failure(checks) {
+            debug!("stress tx {} rejected: {}", tx.id, failure);
+            return Ok(false);
+        }
+        for vin in &tx.vin {
+            self.reserved.insert(outpoint_key(&vin.txid, vin.vout));
+        }
+        self.submitted_at.insert(tx.id, Instant::now());
+        self.mempool.insert(tx)?;
+        Ok(true)
+    }
+
+    /// mine mines the mempool into a block and returns how long its transactions waited
+    fn mine(&mut self) -> Result<Vec<Duration>> {
+        let mut entries = Vec::new();
+        for tx in self.mempool.transactions() {
+            entries.push(MempoolEntry {
+                tx: tx.clone(),
+                fee: self.utxo.blockchain.get_fee(tx)?,
+                size: serialize(tx)?.len(),
+            });
+        }
+        let template = build_template(
+            &self.utxo.blockchain.get_tip_hash(),
+            self.utxo.blockchain.get_best_height()?,
+            &entries,
+            &TemplatePolicy::default(),
+        );
+        let coinbase =
+            Transaction::new_coinbase(self.addresses[0].clone(), String::new(), template.height)?;
+        let mut txs = vec![coinbase];
+        txs.extend(template.transactions.iter().cloned());
+        let block = self.utxo.blockchain.mine_block(txs)?;
+        self.utxo.update(&block)?;
+
+        let mut latencies = Vec::new();
+        for tx in &template.transactions {
+            self.mempool.remove(&tx.id);
+            for vin in &tx.vin {
+                self.reserved.remove(&outpoint_key(&vin.txid, vin.vout));
+            }
+            if let Some(submitted) = self.submitted_at.remove(&tx.id) {
+                latencies.push(submitted.elapsed());
+            }
+        }
+        Ok(latencies)
+    }
+}
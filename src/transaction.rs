@@ -1,5 +1,13 @@
 use super::*;
-use crate::{utxoset::UTXOSet, wallets::*};
+use crate::{
+    block::{verify_merkle_branch, MerkleBranch},
+    descriptor::Descriptor,
+    hash::TxId,
+    script::{redeem_hash, LockScript, VaultKey, MAX_VAULT_FEE, PUB_KEY_LEN, SIGNATURE_LEN},
+    signer::Signer,
+    utxoset::{CoinSelection, UTXOSet},
+    wallets::*,
+};
 use bincode::serialize;
 use bitcoincash_addr::Address;
 use crypto::{digest::Digest, ed25519, sha2::Sha256};
@@ -7,14 +15,26 @@ use failure::format_err;
 use log::{debug, error, info};
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    thread,
+};
 
 const SUBSIDY: i32 = 10;
+/// HALVING_INTERVAL is the number of blocks after which the block subsidy halves
+pub const HALVING_INTERVAL: i32 = 210_000;
+/// EXTRA_NONCE_LEN is the size of the extra nonce ending the data of a coinbase input,
+/// which miners roll once the header nonce space is exhausted
+pub const EXTRA_NONCE_LEN: usize = 8;
+/// LOCKTIME_THRESHOLD is the lowest lock_time read as a unix time rather than a height
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+/// BURN_PUB_KEY_HASH locks outputs that no key can ever spend
+pub const BURN_PUB_KEY_HASH: [u8; 20] = [0; 20];
 
 /// TXInput represents a transaction input
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
-    pub txid: String,
+    pub txid: TxId,
     pub vout: i32,
     pub signature: Vec<u8>,
     pub pub_key: Vec<u8>,
@@ -25,34 +45,248 @@ pub struct TXInput {
 pub struct TXOutput {
     pub value: i32,
     pub pub_key_hash: Vec<u8>,
+    pub lock: LockScript,
 }
 
-// TXOutputs collects TXOutput
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+/// SighashType selects the parts of a transaction a signature commits to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SighashType {
+    /// every input and output
+    All,
+    /// every input but no output, so anyone may choose where the coins go
+    None,
+    /// every input and only the output with the same index as the signed input
+    Single,
+    /// only the signed input and every output, so others may add inputs
+    AllAnyoneCanPay,
+}
+
+impl SighashType {
+    /// from_byte reads the type byte following a 64 byte signature; plain
+    /// signatures without one are `All`
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(SighashType::All),
+            2 => Some(SighashType::None),
+            3 => Some(SighashType::Single),
+            0x81 => Some(SighashType::AllAnyoneCanPay),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            SighashType::All => 1,
+            SighashType::None => 2,
+            SighashType::Single => 3,
+            SighashType::AllAnyoneCanPay => 0x81,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
-    pub id: String,
+    pub id: TxId,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    /// lock_time keeps the transaction out of blocks below this height or, from
+    /// LOCKTIME_THRESHOLD on, timestamped before this unix time in seconds. 0 is none.
+    pub lock_time: u64,
+    /// expiry_height keeps the transaction out of blocks from this height on, so an
+    /// offer lapses and leaves the mempool by itself. 0 is none.
+    pub expiry_height: i32,
 }
 
 impl Transaction {
-    pub fn new_UTXO(wallet: &Wallet, to: &str, amount: i32, utxo: &UTXOSet) -> Result<Self> {
+    /// new_UTXO creates one transaction paying every `(address, amount)` recipient, plus change
+    pub fn new_UTXO(
+        wallet: &Wallet,
+        recipients: &[(String, i32)],
+        selection: CoinSelection,
+        utxo: &UTXOSet,
+    ) -> Result<Self> {
+        Transaction::new_locked(
+            wallet,
+            recipients,
+            LockScript::PayToPubkeyHash,
+            0,
+            0,
+            selection,
+            utxo,
+        )
+    }
+
+    /// new_locked is new_UTXO with the recipient outputs under `lock` and the
+    /// transaction under `lock_time` and `expiry_height`
+    pub fn new_locked(
+        wallet: &Wallet,
+        recipients: &[(String, i32)],
+        lock: LockScript,
+        lock_time: u64,
+        expiry_height: i32,
+        selection: CoinSelection,
+        utxo: &UTXOSet,
+    ) -> Result<Self> {
         info!(
-            "new UTXO Transaction from: {} to: {}",
+            "new UTXO Transaction from: {} to: {:?}",
             wallet.get_address(),
-            to
+            recipients
         );
-        let mut vin = Vec::new();
+        let vout = recipient_outputs(recipients, lock)?;
+        Transaction::new_spend(wallet, vout, lock_time, expiry_height, selection, utxo)
+    }
 
-        let mut pub_key_hash = wallet.public_key.clone();
+    /// new_unsigned creates the transaction new_UTXO would for the key `pub_key`, but
+    /// leaves the signing to wherever its secret key is kept
+    pub fn new_unsigned(
+        pub_key: &[u8],
+        recipients: &[(String, i32)],
+        selection: CoinSelection,
+        utxo: &UTXOSet,
+    ) -> Result<Self> {
+        let mut pub_key_hash = pub_key.to_vec();
         hash_pub_key(&mut pub_key_hash);
+        let from = hash_to_address(pub_key_hash);
+        info!(
+            "new unsigned Transaction from: {} to: {:?}",
+            from, recipients
+        );
+        let vout = recipient_outputs(recipients, LockScript::PayToPubkeyHash)?;
+        let change = TXOutput::new(0, from)?;
+        Transaction::fund(vout, pub_key, change, selection, utxo, &HashSet::new())
+    }
+
+    /// new_multisig creates the unsigned transaction paying `recipients` from the
+    /// outputs of the `required`-of-`keys` multisig, with change back to it. Each cosigner
+    /// then adds a signature with add_multisig_signature.
+    pub fn new_multisig(
+        keys: &[Vec<u8>],
+        required: usize,
+        recipients: &[(String, i32)],
+        selection: CoinSelection,
+        utxo: &UTXOSet,
+    ) -> Result<Self> {
+        let pub_key = keys.concat();
+        info!(
+            "new multisig Transaction from: {} to: {:?}",
+            hash_to_address(redeem_hash(&pub_key)),
+            recipients
+        );
+        let vout = recipient_outputs(recipients, LockScript::PayToPubkeyHash)?;
+        let change = TXOutput {
+            value: 0,
+            pub_key_hash: redeem_hash(&pub_key),
+            lock: LockScript::MultiSig {
+                m: required as u8,
+                n: keys.len() as u8,
+            },
+        };
+        Transaction::fund(vout, &pub_key, change, selection, utxo, &HashSet::new())
+    }
+
+    /// new_burn creates a transaction that destroys `amount` coins of the wallet
+    pub fn new_burn(wallet: &Wallet, amount: i32, utxo: &UTXOSet) -> Result<Self> {
+        info!(
+            "new burn Transaction from: {} amount: {}",
+            wallet.get_address(),
+            amount
+        );
+        Transaction::new_spend(
+            wallet,
+            vec![TXOutput::new_burn(amount)],
+            0,
+            0,
+            CoinSelection::default(),
+            utxo,
+        )
+    }
+
+    /// new_payout pays each of `batches` from the wallet in a transaction of its own.
+    /// The transactions spend different outputs of the wallet, so they do not depend on
+    /// each other and can be sent at once.
+    pub fn new_payout(
+        wallet: &Wallet,
+        batches: Vec<Vec<TXOutput>>,
+        selection: CoinSelection,
+        utxo: &UTXOSet,
+    ) -> Result<Vec<Self>> {
+        let mut spent = HashSet::new();
+        let mut txs = Vec::new();
+        for vout in batches {
+            let change = TXOutput::new(0, wallet.get_address())?;
+            let mut tx =
+                Transaction::fund(vout, &wallet.public_key, change, selection, utxo, &spent)?;
+            utxo.blockchain
+                .sign_transaction(&mut tx, wallet.signer()?.as_ref())?;
+            spent.extend(tx.vin.iter().map(|vin| (vin.txid, vin.vout)));
+            txs.push(tx);
+        }
+        Ok(txs)
+    }
+
+    /// bump_fee returns an unsigned replacement paying `extra` more fee out of the change
+    /// output back to `pub_key_hash`, None when there is no change output worth more
+    pub fn bump_fee(&self, pub_key_hash: &[u8], extra: i32) -> Result<Option<Self>> {
+        let change = self.vout.iter().rposition(|out| {
+            out.lock == LockScript::PayToPubkeyHash
+                && out.pub_key_hash == pub_key_hash
+                && out.value > extra
+        });
+        let change = match change {
+            Some(change) => change,
+            None => return Ok(None),
+        };
+        let mut tx = self.clone();
+        tx.vout[change].value -= extra;
+        for vin in &mut tx.vin {
+            vin.signature.clear();
+        }
+        tx.id = tx.hash()?;
+        Ok(Some(tx))
+    }
+
+    /// new_spend funds `vout` from the wallet's unspent outputs, adds change and signs
+    fn new_spend(
+        wallet: &Wallet,
+        vout: Vec<TXOutput>,
+        lock_time: u64,
+        expiry_height: i32,
+        selection: CoinSelection,
+        utxo: &UTXOSet,
+    ) -> Result<Self> {
+        let change = TXOutput::new(0, wallet.get_address())?;
+        let mut tx = Transaction::fund(
+            vout,
+            &wallet.public_key,
+            change,
+            selection,
+            utxo,
+            &HashSet::new(),
+        )?;
+        tx.lock_time = lock_time;
+        tx.expiry_height = expiry_height;
+        tx.id = tx.hash()?;
+        utxo.blockchain
+            .sign_transaction(&mut tx, wallet.signer()?.as_ref())?;
+        Ok(tx)
+    }
+
+    /// fund adds unsigned inputs revealing `pub_key` that spend outputs locked like
+    /// `change` worth at least `vout`, other than the `spent` ones, and pays what is left
+    /// over to `change`
+    fn fund(
+        mut vout: Vec<TXOutput>,
+        pub_key: &[u8],
+        mut change: TXOutput,
+        selection: CoinSelection,
+        utxo: &UTXOSet,
+        spent: &HashSet<(TxId, i32)>,
+    ) -> Result<Self> {
+        let amount = total_value(&vout)?;
+        let mut vin = Vec::new();
 
-        let acc_v = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
+        let acc_v =
+            utxo.find_spendable_outputs_except(&change.pub_key_hash, amount, selection, spent)?;
 
         if acc_v.0 < amount {
             error!("Not Enough balance");
@@ -65,33 +299,35 @@ impl Transaction {
         for tx in acc_v.1 {
             for out in tx.1 {
                 let input = TXInput {
-                    txid: tx.0.clone(),
+                    txid: tx.0,
                     vout: out,
                     signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
+                    pub_key: pub_key.to_vec(),
                 };
                 vin.push(input);
             }
         }
 
-        let mut vout = vec![TXOutput::new(amount, to.to_string())?];
         if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, wallet.get_address())?)
+            change.value = acc_v.0 - amount;
+            vout.push(change);
         }
 
         let mut tx = Transaction {
-            id: String::new(),
+            id: TxId::default(),
             vin,
             vout,
+            lock_time: 0,
+            expiry_height: 0,
         };
         tx.id = tx.hash()?;
-        utxo.blockchain
-            .sign_transaction(&mut tx, &wallet.secret_key)?;
         Ok(tx)
     }
 
-    /// NewCoinbaseTX creates a new coinbase transaction
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Self> {
+    /// NewCoinbaseTX creates a new coinbase transaction for the block at `height`. Its
+    /// input data ends with 32 bytes, random without `data`, the last EXTRA_NONCE_LEN of
+    /// which are the extra nonce.
+    pub fn new_coinbase(to: String, mut data: String, height: i32) -> Result<Self> {
         info!("new coinbase Transaction to: {to}");
         let mut key: [u8; 32] = [0; 32];
         if data.is_empty() {
@@ -102,80 +338,194 @@ impl Transaction {
         pub_key.append(&mut Vec::from(key));
 
         let mut tx = Transaction {
-            id: String::new(),
+            id: TxId::default(),
             vin: vec![TXInput {
-                txid: String::new(),
+                txid: TxId::default(),
                 vout: -1,
                 signature: Vec::new(),
                 pub_key,
             }],
-            vout: vec![TXOutput::new(SUBSIDY, to)?],
+            vout: vec![TXOutput::new(block_subsidy(height), to)?],
+            lock_time: 0,
+            expiry_height: 0,
         };
 
         tx.id = tx.hash()?;
         Ok(tx)
     }
 
+    /// verify_inclusion lets light clients check a merkle branch against a block's merkle root
+    pub fn verify_inclusion(&self, branch: &MerkleBranch, merkle_root: &[u8]) -> Result<bool> {
+        let leaf = self.clone().hash()?;
+        Ok(verify_merkle_branch(leaf.as_ref(), branch, merkle_root))
+    }
+
     pub fn is_coinbase(&self) -> bool {
-        self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
+        self.vin.len() == 1 && self.vin[0].txid.is_zero() && self.vin[0].vout == -1
+    }
+
+    /// is_final_at tells whether lock_time lets the transaction into a block at `height`
+    /// with a `timestamp` in milliseconds
+    pub fn is_final_at(&self, height: i32, timestamp: u128) -> bool {
+        match self.lock_time {
+            0 => true,
+            lock_time if lock_time < LOCKTIME_THRESHOLD => height as i64 >= lock_time as i64,
+            lock_time => timestamp / 1000 >= lock_time as u128,
+        }
+    }
+
+    /// is_expired_at tells whether expiry_height keeps the transaction out of a block at
+    /// `height`
+    pub fn is_expired_at(&self, height: i32) -> bool {
+        self.expiry_height != 0 && height >= self.expiry_height
+    }
+
+    /// roll_extra_nonce increments the extra nonce of a coinbase, which changes its id,
+    /// and returns the new extra nonce
+    pub fn roll_extra_nonce(&mut self) -> Result<u64> {
+        if !self.is_coinbase() || self.vin[0].pub_key.len() < EXTRA_NONCE_LEN {
+            return Err(format_err!("Transaction {} has no extra nonce", self.id));
+        }
+        let data = &mut self.vin[0].pub_key;
+        let start = data.len() - EXTRA_NONCE_LEN;
+        let mut bytes = [0; EXTRA_NONCE_LEN];
+        bytes.copy_from_slice(&data[start..]);
+        let extra_nonce = u64::from_le_bytes(bytes).wrapping_add(1);
+        data[start..].copy_from_slice(&extra_nonce.to_le_bytes());
+        self.id = self.hash()?;
+        Ok(extra_nonce)
     }
 
-    pub fn sign(
+    /// sign_with signs every input with `signer`, which may keep the key elsewhere
+    pub fn sign_with(
         &mut self,
-        private_key: &[u8],
-        prev_TXs: HashMap<String, Transaction>,
+        signer: &dyn Signer,
+        prev_TXs: HashMap<TxId, Transaction>,
     ) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
         }
 
         for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
+            if prev_TXs.get(&vin.txid).unwrap().id.is_zero() {
                 return Err(format_err!("ERROR: Previous transaction is not correct"));
             }
         }
-        let mut tx_copy = self.trim_copy();
 
         for in_id in 0..self.vin.len() {
-            let prev_tx = prev_TXs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key.clear();
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            self.vin[in_id].signature = signature.to_vec();
+            let prev_tx = prev_TXs.get(&self.vin[in_id].txid).unwrap();
+            let prev_out = prev_tx.vout[self.vin[in_id].vout as usize].clone();
+            self.sign_input(in_id, &prev_out, signer, SighashType::All)?;
+        }
+        Ok(())
+    }
+
+    /// sign_input signs input `in_id`, which spends `prev_output`, committing to the
+    /// parts of the transaction `sighash_type` selects
+    pub fn sign_input(
+        &mut self,
+        in_id: usize,
+        prev_output: &TXOutput,
+        signer: &dyn Signer,
+        sighash_type: SighashType,
+    ) -> Result<()> {
+        let digest = self.sighash(in_id, prev_output, sighash_type)?;
+        let mut signature = signer.sign(&self.vin[in_id].pub_key, &digest)?;
+        if sighash_type != SighashType::All {
+            signature.push(sighash_type.to_byte());
         }
+        self.vin[in_id].signature = signature;
         Ok(())
     }
 
-    pub fn verify(&self, prev_TXs: HashMap<String, Transaction>) -> Result<bool> {
+    /// add_multisig_signature signs multisig input `in_id`, which spends `prev_output`,
+    /// for `pub_key`, one of the keys the input reveals. It returns false when that key
+    /// has already signed or the input holds every signature it needs.
+    pub fn add_multisig_signature(
+        &mut self,
+        in_id: usize,
+        prev_output: &TXOutput,
+        signer: &dyn Signer,
+        pub_key: &[u8],
+    ) -> Result<bool> {
+        let digest = self.sighash(in_id, prev_output, SighashType::All)?;
+        let vin = &self.vin[in_id];
+        if !matches!(prev_output.lock, LockScript::MultiSig { .. })
+            || !vin.pub_key.chunks(PUB_KEY_LEN).any(|key| key == pub_key)
+        {
+            return Err(format_err!(
+                "Input {} is not a multisig input of key {}",
+                in_id,
+                fingerprint(pub_key)
+            ));
+        }
+        let signed = vin.signature.chunks(SIGNATURE_LEN).any(|signature| {
+            signature.len() == SIGNATURE_LEN
+                && ed25519::verify(digest.as_bytes(), pub_key, signature)
+        });
+        if signed || vin.signature.len() >= prev_output.lock.signatures() * SIGNATURE_LEN {
+            return Ok(false);
+        }
+        let signature = signer.sign(pub_key, &digest)?;
+        self.vin[in_id].signature.extend(signature);
+        Ok(true)
+    }
+
+    /// sighash returns the digest an input signature signs: the ed25519 signature of
+    /// input `in_id` is made over the bytes of this hex string. The digest covers the
+    /// transaction without signatures or public keys, with the signed input holding the
+    /// key hash of `prev_output`, the output it spends.
+    pub fn sighash(
+        &self,
+        in_id: usize,
+        prev_output: &TXOutput,
+        sighash_type: SighashType,
+    ) -> Result<String> {
+        if in_id >= self.vin.len() {
+            return Err(format_err!(
+                "Transaction {} has no input {}",
+                self.id,
+                in_id
+            ));
+        }
+        let mut tx_copy = self.trim_copy();
+        tx_copy.vin[in_id].pub_key = prev_output.pub_key_hash.clone();
+        match sighash_type {
+            SighashType::All => return Ok(tx_copy.hash()?.to_string()),
+            SighashType::None => tx_copy.vout.clear(),
+            SighashType::Single => match tx_copy.vout.get(in_id) {
+                Some(out) => tx_copy.vout = vec![out.clone()],
+                None => {
+                    return Err(format_err!(
+                        "Input {} has no output to sign with Single",
+                        in_id
+                    ))
+                }
+            },
+            SighashType::AllAnyoneCanPay => tx_copy.vin = vec![tx_copy.vin[in_id].clone()],
+        }
+        tx_copy.id = TxId::default();
+        let mut data = serialize(&tx_copy)?;
+        data.push(sighash_type.to_byte());
+        let mut hasher = Sha256::new();
+        hasher.input(&data);
+        Ok(hasher.result_str())
+    }
+
+    pub fn verify(&self, prev_TXs: HashMap<TxId, Transaction>) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
         }
 
         for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
+            if prev_TXs.get(&vin.txid).unwrap().id.is_zero() {
                 return Err(format_err!("ERROR: Previous transaction is not correct"));
             }
         }
 
-        let mut tx_copy = self.trim_copy();
-
         for in_id in 0..self.vin.len() {
-            let prev_tx = prev_TXs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key.clear();
-            if !ed25519::verify(
-                tx_copy.id.as_bytes(),
-                &self.vin[in_id].pub_key,
-                &self.vin[in_id].pub_key,
-            ) {
+            let prev_tx = prev_TXs.get(&self.vin[in_id].txid).unwrap();
+            if !self.verify_input(in_id, prev_tx)? {
                 return Ok(false);
             }
         }
@@ -183,13 +533,180 @@ impl Transaction {
         Ok(true)
     }
 
-    pub fn hash(&mut self) -> Result<String> {
+    /// verify_batch checks the input signatures of `txs` spread over the available cores,
+    /// `prev_TXs` holding every transaction they spend, and returns the ids of those
+    /// with an invalid signature, in order
+    pub fn verify_batch(
+        txs: &[Transaction],
+        prev_TXs: &HashMap<TxId, Transaction>,
+    ) -> Result<Vec<TxId>> {
+        let inputs: Vec<(usize, usize)> = txs
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| !tx.is_coinbase())
+            .flat_map(|(idx, tx)| (0..tx.vin.len()).map(move |in_id| (idx, in_id)))
+            .collect();
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = inputs.len().div_ceil(workers).max(1);
+
+        let results: Vec<Result<Vec<usize>>> = thread::scope(|scope| {
+            let handles: Vec<_> = inputs
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        let mut invalid = Vec::new();
+                        for (idx, in_id) in chunk {
+                            let tx = &txs[*idx];
+                            let prev_tx = match prev_TXs.get(&tx.vin[*in_id].txid) {
+                                Some(prev_tx) => prev_tx,
+                                None => {
+                                    return Err(format_err!(
+                                        "Previous transaction {} of {} is not found",
+                                        tx.vin[*in_id].txid,
+                                        tx.id
+                                    ))
+                                }
+                            };
+                            if !tx.verify_input(*in_id, prev_tx)? {
+                                invalid.push(*idx);
+                            }
+                        }
+                        Ok(invalid)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(format_err!("Signature verification panicked")))
+                })
+                .collect()
+        });
+
+        let mut invalid = BTreeSet::new();
+        for result in results {
+            invalid.extend(result?);
+        }
+        Ok(invalid.into_iter().map(|idx| txs[idx].id).collect())
+    }
+
+    /// verify_input checks the signature of input `in_id`, which spends an output of `prev_tx`
+    pub fn verify_input(&self, in_id: usize, prev_tx: &Transaction) -> Result<bool> {
+        let vin = &self.vin[in_id];
+        let prev_out = match prev_tx.vout.get(vin.vout as usize) {
+            Some(out) => out,
+            None => return Ok(false),
+        };
+        let lock = prev_out.lock;
+        if !lock.matches_keys(&prev_out.pub_key_hash, &vin.pub_key) {
+            return Ok(false);
+        }
+        Ok(match self.signed_digest(in_id, prev_out) {
+            Some((digest, signatures)) => lock.verify(&vin.pub_key, signatures, digest.as_bytes()),
+            None => false,
+        })
+    }
+
+    /// signed_digest returns the digest the signatures of input `in_id`, which spends
+    /// `prev_out`, sign under their sighash type, along with the signatures
+    fn signed_digest(&self, in_id: usize, prev_out: &TXOutput) -> Option<(String, &[u8])> {
+        let signature = &self.vin[in_id].signature;
+        let signatures_len = prev_out.lock.signatures() * SIGNATURE_LEN;
+        let sighash_type = match signature.len() {
+            len if len == signatures_len => SighashType::All,
+            len if len == signatures_len + 1 => SighashType::from_byte(signature[signatures_len])?,
+            _ => return None,
+        };
+        let digest = self.sighash(in_id, prev_out, sighash_type).ok()?;
+        Some((digest, &signature[..signatures_len]))
+    }
+
+    /// vault_key tells which key of the vault signed input `in_id`, which spends the
+    /// vault output `prev_out`
+    pub fn vault_key(&self, in_id: usize, prev_out: &TXOutput) -> Option<VaultKey> {
+        let (digest, signature) = self.signed_digest(in_id, prev_out)?;
+        LockScript::vault_signer(&self.vin[in_id].pub_key, signature, digest.as_bytes())
+    }
+
+    /// vault_violation returns the vault rule input `in_id` breaks in a block at `height`,
+    /// if any, `in_value` being the value of all the inputs. The hot key may only move a
+    /// vault output into Unvault outputs of the same keys maturing `delay` blocks later,
+    /// or back into the vault, leaving at most MAX_VAULT_FEE in fees, and spend an
+    /// Unvault output once it matured; the recovery key spends both at any time.
+    pub fn vault_violation(
+        &self,
+        in_id: usize,
+        prev_out: &TXOutput,
+        in_value: i32,
+        height: i32,
+    ) -> Option<String> {
+        if self.vault_key(in_id, prev_out) != Some(VaultKey::Hot) {
+            return None;
+        }
+        match prev_out.lock {
+            LockScript::Vault { delay } => {
+                let unlock = match height.checked_add(delay) {
+                    Some(unlock) => unlock,
+                    None => return Some(format!("the vault delay {} overflows", delay)),
+                };
+                let kept = self.vout.iter().all(|out| {
+                    out.pub_key_hash == prev_out.pub_key_hash
+                        && match out.lock {
+                            LockScript::Unvault { height } => height >= unlock,
+                            lock => lock == prev_out.lock,
+                        }
+                });
+                if self.vout.is_empty() || !kept {
+                    return Some(format!(
+                        "the hot key only unvaults into outputs of the vault spendable from height {}",
+                        unlock
+                    ));
+                }
+                let out_value = self
+                    .vout
+                    .iter()
+                    .try_fold(0i32, |sum, out| sum.checked_add(out.value));
+                match out_value {
+                    Some(out_value) if in_value.saturating_sub(out_value) <= MAX_VAULT_FEE => None,
+                    _ => Some(format!(
+                        "the hot key keeps the {} spent in the vault, less at most {} in fees",
+                        in_value, MAX_VAULT_FEE
+                    )),
+                }
+            }
+            LockScript::Unvault { height: unlock } if height < unlock => Some(format!(
+                "the hot key spends the unvaulted output from height {}",
+                unlock
+            )),
+            _ => None,
+        }
+    }
+
+    /// sign_vault_input signs input `in_id`, which spends the vault output `prev_output`,
+    /// with `pub_key`, the hot or the recovery key of the vault
+    pub fn sign_vault_input(
+        &mut self,
+        in_id: usize,
+        prev_output: &TXOutput,
+        signer: &dyn Signer,
+        pub_key: &[u8],
+    ) -> Result<()> {
+        let digest = self.sighash(in_id, prev_output, SighashType::All)?;
+        self.vin[in_id].signature = signer.sign(pub_key, &digest)?;
+        Ok(())
+    }
+
+    /// hash computes the transaction id, which does not cover the input signatures
+    /// since they are made after the id is set
+    pub fn hash(&mut self) -> Result<TxId> {
         let mut copy = self.clone();
-        copy.id = String::new();
-        let data = serialize(&copy)?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        Ok(hasher.result_str())
+        copy.id = TxId::default();
+        for vin in &mut copy.vin {
+            vin.signature.clear();
+        }
+        Ok(TxId::digest(&serialize(&copy)?))
     }
 
     fn trim_copy(&self) -> Self {
@@ -198,7 +715,7 @@ impl Transaction {
 
         for v in &self.vin {
             vin.push(TXInput {
-                txid: v.txid.clone(),
+                txid: v.txid,
                 vout: v.vout,
                 signature: Vec::new(),
                 pub_key: Vec::new(),
@@ -209,15 +726,53 @@ impl Transaction {
             vout.push(TXOutput {
                 value: v.value,
                 pub_key_hash: v.pub_key_hash.clone(),
+                lock: v.lock,
             })
         }
 
         Self {
-            id: self.id.clone(),
+            id: self.id,
             vin,
             vout,
+            lock_time: self.lock_time,
+            expiry_height: self.expiry_height,
+        }
+    }
+}
+
+/// block_subsidy returns the coins a coinbase may create at `height`
+pub fn block_subsidy(height: i32) -> i32 {
+    let halvings = height / HALVING_INTERVAL;
+    if halvings >= 31 {
+        return 0;
+    }
+    SUBSIDY >> halvings
+}
+
+/// total_value adds up the values of `vout`, failing when the sum overflows
+pub fn total_value(vout: &[TXOutput]) -> Result<i32> {
+    vout.iter()
+        .try_fold(0i32, |sum, out| sum.checked_add(out.value))
+        .ok_or_else(|| format_err!("The outputs add up to more than {}", i32::MAX))
+}
+
+/// recipient_outputs pays every `(address, amount)` recipient under `lock`. A recipient
+/// may also be a `pk` or `multi` descriptor, whose own lock is used.
+fn recipient_outputs(recipients: &[(String, i32)], lock: LockScript) -> Result<Vec<TXOutput>> {
+    if recipients.is_empty() {
+        return Err(format_err!("A transaction needs at least one recipient"));
+    }
+    let mut vout = Vec::new();
+    for (to, amount) in recipients {
+        if *amount <= 0 {
+            return Err(format_err!("Amount for {} must be positive", to));
+        }
+        match to.parse::<Descriptor>() {
+            Ok(descriptor) => vout.push(descriptor.output(*amount)?),
+            Err(_) => vout.push(TXOutput::new_locked(*amount, to.clone(), lock)?),
         }
     }
+    Ok(vout)
 }
 
 impl TXInput {
@@ -234,6 +789,28 @@ impl TXOutput {
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
         self.pub_key_hash == pub_key_hash
     }
+    /// IsUnspendable checks if the output is provably burned
+    pub fn is_unspendable(&self) -> bool {
+        self.pub_key_hash.iter().all(|b| *b == 0)
+    }
+
+    /// address is the address the output pays to, None when it is provably unspendable
+    pub fn address(&self) -> Option<String> {
+        if self.is_unspendable() {
+            None
+        } else {
+            Some(hash_to_address(self.pub_key_hash.clone()))
+        }
+    }
+
+    pub fn new_burn(value: i32) -> Self {
+        TXOutput {
+            value,
+            pub_key_hash: BURN_PUB_KEY_HASH.to_vec(),
+            lock: LockScript::PayToPubkeyHash,
+        }
+    }
+
     /// Lock signs the output
     fn lock(&mut self, address: &str) -> Result<()> {
         let pub_key_hash = Address::decode(address).unwrap().body;
@@ -243,9 +820,15 @@ impl TXOutput {
     }
 
     pub fn new(value: i32, address: String) -> Result<Self> {
+        TXOutput::new_locked(value, address, LockScript::PayToPubkeyHash)
+    }
+
+    /// new_locked pays `address` under `lock`
+    pub fn new_locked(value: i32, address: String, lock: LockScript) -> Result<Self> {
         let mut txo = TXOutput {
             value,
             pub_key_hash: Vec::new(),
+            lock,
         };
         txo.lock(&address)?;
         Ok(txo)
@@ -255,20 +838,186 @@ impl TXOutput {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::signer::KeySigner;
 
     #[test]
     fn test_signature() {
         let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
+        let wa1 = ws.create_wallet().unwrap();
         let w = ws.get_wallet(&wa1).unwrap().clone();
         ws.save_all().unwrap();
         drop(ws);
 
         let data = String::from("test");
-        let tx = Transaction::new_coinbase(wa1, data).unwrap();
+        let tx = Transaction::new_coinbase(wa1, data, 0).unwrap();
         assert!(tx.is_coinbase());
 
-        let signature = ed25519::signature(tx.id.as_bytes(), &w.secret_key);
-        assert!(ed25519::verify(tx.id.as_bytes(), &w.public_key, &signature));
+        let signature = ed25519::signature(tx.id.as_ref(), &w.secret_key);
+        assert!(ed25519::verify(tx.id.as_ref(), &w.public_key, &signature));
+    }
+
+    #[test]
+    fn test_verify_input() {
+        let w = Wallet::derive(&[3; 32], &[44, 0], 0);
+        let prev = Transaction::new_coinbase(w.get_address(), String::from("prev"), 1).unwrap();
+        let mut tx = Transaction {
+            id: TxId::default(),
+            vin: vec![TXInput {
+                txid: prev.id,
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: w.public_key.clone(),
+            }],
+            vout: vec![TXOutput::new(4, w.get_address()).unwrap()],
+            lock_time: 0,
+            expiry_height: 0,
+        };
+        tx.id = tx.hash().unwrap();
+        let prev_TXs: HashMap<TxId, Transaction> = [(prev.id, prev.clone())].into();
+        tx.sign_with(&KeySigner::new(&w.secret_key), prev_TXs.clone())
+            .unwrap();
+        assert!(tx.verify_input(0, &prev).unwrap());
+        assert!(tx.verify(prev_TXs.clone()).unwrap());
+
+        let batch = [prev.clone(), tx.clone()];
+        assert!(Transaction::verify_batch(&batch, &prev_TXs)
+            .unwrap()
+            .is_empty());
+
+        tx.vout[0].value = 5;
+        assert!(!tx.verify_input(0, &prev).unwrap());
+        let batch = [prev.clone(), tx.clone()];
+        assert_eq!(
+            Transaction::verify_batch(&batch, &prev_TXs).unwrap(),
+            vec![tx.id]
+        );
+        assert!(Transaction::verify_batch(&batch, &HashMap::new()).is_err());
+        tx.vin[0].signature.truncate(10);
+        assert!(!tx.verify(prev_TXs).unwrap());
+    }
+
+    #[test]
+    fn test_bump_fee() {
+        let w = Wallet::derive(&[5; 32], &[44, 0], 0);
+        let prev = Transaction::new_coinbase(w.get_address(), String::from("prev"), 1).unwrap();
+        let mut pub_key_hash = w.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let mut tx = Transaction {
+            id: TxId::default(),
+            vin: vec![TXInput {
+                txid: prev.id,
+                vout: 0,
+                signature: vec![1; 64],
+                pub_key: w.public_key.clone(),
+            }],
+            vout: vec![
+                TXOutput::new(3, hash_to_address(vec![9; 20])).unwrap(),
+                TXOutput::new(6, w.get_address()).unwrap(),
+            ],
+            lock_time: 7,
+            expiry_height: 0,
+        };
+        tx.id = tx.hash().unwrap();
+
+        let bumped = tx.bump_fee(&pub_key_hash, 2).unwrap().unwrap();
+        assert_ne!(bumped.id, tx.id);
+        assert_eq!(bumped.vout[0].value, 3);
+        assert_eq!(bumped.vout[1].value, 4);
+        assert_eq!(bumped.lock_time, 7);
+        assert!(bumped.vin[0].signature.is_empty());
+        assert!(tx.bump_fee(&pub_key_hash, 6).unwrap().is_none());
+        assert!(tx.bump_fee(&[8; 20], 1).unwrap().is_none());
+
+        // spends refuse recipients adding up past what an amount can hold
+        assert_eq!(total_value(&tx.vout).unwrap(), 9);
+        tx.vout[1].value = i32::MAX;
+        assert!(total_value(&tx.vout).is_err());
+    }
+
+    #[test]
+    fn test_multisig_signatures() {
+        let keys: Vec<Wallet> = (0..3)
+            .map(|i| Wallet::derive(&[10 + i; 32], &[44, 0], 0))
+            .collect();
+        let descriptor = Descriptor::Multi {
+            required: 2,
+            keys: keys.iter().map(|w| w.public_key.clone()).collect(),
+        };
+        let mut prev =
+            Transaction::new_coinbase(keys[0].get_address(), String::from("prev"), 1).unwrap();
+        prev.vout = vec![descriptor.output(10).unwrap()];
+        prev.id = prev.hash().unwrap();
+
+        let mut tx = Transaction {
+            id: TxId::default(),
+            vin: vec![TXInput {
+                txid: prev.id,
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: keys.iter().flat_map(|w| w.public_key.clone()).collect(),
+            }],
+            vout: vec![TXOutput::new(10, keys[0].get_address()).unwrap()],
+            lock_time: 0,
+            expiry_height: 0,
+        };
+        tx.id = tx.hash().unwrap();
+        let sign = |tx: &mut Transaction, w: &Wallet| {
+            tx.add_multisig_signature(
+                0,
+                &prev.vout[0],
+                &KeySigner::new(&w.secret_key),
+                &w.public_key,
+            )
+            .unwrap()
+        };
+        assert!(sign(&mut tx, &keys[2]));
+        assert!(!sign(&mut tx, &keys[2]));
+        assert!(!tx.verify_input(0, &prev).unwrap());
+        assert!(sign(&mut tx, &keys[0]));
+        assert!(tx.verify_input(0, &prev).unwrap());
+        assert!(!sign(&mut tx, &keys[1]));
+    }
+
+    #[test]
+    fn test_sighash() {
+        let w = Wallet::derive(&[4; 32], &[44, 0], 0);
+        let prev = Transaction::new_coinbase(w.get_address(), String::from("prev"), 1).unwrap();
+        let mut tx = Transaction {
+            id: TxId::default(),
+            vin: vec![TXInput {
+                txid: prev.id,
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: w.public_key.clone(),
+            }],
+            vout: vec![TXOutput::new(4, w.get_address()).unwrap()],
+            lock_time: 0,
+            expiry_height: 0,
+        };
+        tx.id = tx.hash().unwrap();
+        let digest = tx.sighash(0, &prev.vout[0], SighashType::All).unwrap();
+        assert_ne!(
+            digest,
+            tx.sighash(0, &prev.vout[0], SighashType::None).unwrap()
+        );
+        assert!(tx.sighash(1, &prev.vout[0], SighashType::All).is_err());
+
+        // an external signer only needs the digest
+        tx.vin[0].signature = ed25519::signature(digest.as_bytes(), &w.secret_key).to_vec();
+        assert!(tx.verify_input(0, &prev).unwrap());
+        assert_eq!(tx.clone().hash().unwrap(), tx.id);
+
+        tx.sign_input(
+            0,
+            &prev.vout[0],
+            &KeySigner::new(&w.secret_key),
+            SighashType::None,
+        )
+        .unwrap();
+        assert_eq!(tx.vin[0].signature.len(), 65);
+        tx.vout[0].value = 3;
+        assert!(tx.verify_input(0, &prev).unwrap());
+        *tx.vin[0].signature.last_mut().unwrap() = SighashType::Single.to_byte();
+        assert!(!tx.verify_input(0, &prev).unwrap());
     }
 }
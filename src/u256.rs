@@ -0,0 +1,230 @@
+use crate::hash::{BlockHash, HASH_LEN};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// U256 is an unsigned 256 bit integer held as big-endian bytes, so it orders, shows and
+/// serializes like the block hashes it is compared with
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct U256(pub [u8; HASH_LEN]);
+
+impl U256 {
+    pub const MAX: U256 = U256([0xff; HASH_LEN]);
+    pub const ONE: U256 = U256::from_u64(1);
+
+    pub const fn from_u64(value: u64) -> Self {
+        let mut bytes = [0; HASH_LEN];
+        let value = value.to_be_bytes();
+        let mut i = 0;
+        while i < 8 {
+            bytes[HASH_LEN - 8 + i] = value[i];
+            i += 1;
+        }
+        U256(bytes)
+    }
+
+    /// from_hexs returns the largest number whose 64 hex digits start with `hexs` zeros
+    pub fn from_hexs(hexs: usize) -> Self {
+        let mut bytes = [0xff; HASH_LEN];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let zeros = hexs.saturating_sub(2 * i).min(2) as u32;
+            *byte = 0xffu8.checked_shr(4 * zeros).unwrap_or(0);
+        }
+        U256(bytes)
+    }
+
+    /// mul_div returns `self * num / den` rounded down, saturating at MAX
+    pub fn mul_div(&self, num: u64, den: u64) -> Self {
+        let mut limbs = [0u64; 5];
+        let mut carry = 0u128;
+        for (i, limb) in self.limbs().into_iter().enumerate().rev() {
+            let product = limb as u128 * num as u128 + carry;
+            limbs[i + 1] = product as u64;
+            carry = product >> 64;
+        }
+        limbs[0] = carry as u64;
+
+        let mut rem = 0u128;
+        for limb in limbs.iter_mut() {
+            let value = (rem << 64) | *limb as u128;
+            *limb = (value / den as u128) as u64;
+            rem = value % den as u128;
+        }
+        if limbs[0] != 0 {
+            return U256::MAX;
+        }
+        U256::from_limbs(&limbs[1..])
+    }
+
+    /// saturating_add returns `self + other`, saturating at MAX
+    pub fn saturating_add(&self, other: &U256) -> Self {
+        let (a, b) = (self.limbs(), other.limbs());
+        let mut sum = [0u64; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (limb, overflow) = a[i].overflowing_add(b[i]);
+            let (limb, carried) = limb.overflowing_add(carry as u64);
+            sum[i] = limb;
+            carry = overflow || carried;
+        }
+        if carry {
+            return U256::MAX;
+        }
+        U256::from_limbs(&sum)
+    }
+
+    /// work is how many hashes it takes on average to find one not above the number
+    /// taken as a target, 2^256 / (target + 1), computed as !target / (target + 1) + 1
+    /// so nothing exceeds 256 bits
+    pub fn work(&self) -> Self {
+        if *self == U256::MAX {
+            return U256::ONE;
+        }
+        let mut inverse = *self;
+        for byte in inverse.0.iter_mut() {
+            *byte = !*byte;
+        }
+        inverse
+            .div(&self.saturating_add(&U256::ONE))
+            .saturating_add(&U256::ONE)
+    }
+
+    /// div returns `self / divisor` rounded down, by binary long division
+    fn div(&self, divisor: &U256) -> Self {
+        let (dividend, divisor) = (self.limbs(), divisor.limbs());
+        let mut quotient = [0u64; 4];
+        let mut rem = [0u64; 4];
+        for bit in 0..256 {
+            let (limb, shift) = (bit / 64, 63 - bit % 64);
+            // the remainder shifted left may need a 257th bit, and then exceeds divisor
+            let overflow = rem[0] >> 63 == 1;
+            for i in 0..4 {
+                rem[i] = (rem[i] << 1) | rem.get(i + 1).map_or(0, |next| next >> 63);
+            }
+            rem[3] |= (dividend[limb] >> shift) & 1;
+            if overflow || rem >= divisor {
+                let mut borrow = false;
+                for i in (0..4).rev() {
+                    let (limb, under) = rem[i].overflowing_sub(divisor[i]);
+                    let (limb, borrowed) = limb.overflowing_sub(borrow as u64);
+                    rem[i] = limb;
+                    borrow = under || borrowed;
+                }
+                quotient[limb] |= 1 << shift;
+            }
+        }
+        U256::from_limbs(&quotient)
+    }
+
+    /// limbs splits the number into 64 bit limbs, the most significant first
+    fn limbs(&self) -> [u64; 4] {
+        let mut limbs = [0; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(self.0.chunks(8)) {
+            *limb = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        limbs
+    }
+
+    fn from_limbs(limbs: &[u64]) -> Self {
+        let mut bytes = [0; HASH_LEN];
+        for (chunk, limb) in bytes.chunks_mut(8).zip(limbs) {
+            chunk.copy_from_slice(&limb.to_be_bytes());
+        }
+        U256(bytes)
+    }
+
+    /// expected_hashes is how many hashes it takes on average to find one not above
+    /// the number taken as a target
+    pub fn expected_hashes(&self) -> f64 {
+        let target = self
+            .0
+            .iter()
+            .fold(0.0, |acc, byte| acc * 256.0 + *byte as f64);
+        2f64.powi(256) / (target + 1.0)
+    }
+}
+
+impl From<BlockHash> for U256 {
+    fn from(hash: BlockHash) -> Self {
+        U256(hash.0)
+    }
+}
+
+impl fmt::Display for U256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for U256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl Serialize for U256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for U256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            match hex::decode(&s) {
+                Ok(bytes) if bytes.len() == HASH_LEN => Ok(U256(bytes.try_into().unwrap())),
+                _ => Err(de::Error::custom(format!(
+                    "{} is not a number of {} hex digits",
+                    s,
+                    HASH_LEN * 2
+                ))),
+            }
+        } else {
+            <[u8; HASH_LEN]>::deserialize(deserializer).map(U256)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_u256() {
+        assert_eq!(U256::from_hexs(0), U256::MAX);
+        assert_eq!(U256::from_hexs(64), U256::default());
+        let target = U256::from_hexs(3);
+        assert_eq!(&target.to_string()[..4], "000f");
+        assert!(U256::from(BlockHash::digest(b"block")) <= U256::MAX);
+
+        // 0x000fff..ff * 3 / 2 = 0x0017ff..fe, rounded down
+        let scaled = target.mul_div(3, 2);
+        assert_eq!(&scaled.to_string()[..4], "0017");
+        assert_eq!(scaled.0[HASH_LEN - 1], 0xfe);
+        assert_eq!(target.mul_div(1, 1), target);
+        assert_eq!(target.mul_div(1 << 16, 1), U256::MAX);
+
+        assert_eq!(U256::MAX.work(), U256::ONE);
+        assert_eq!(U256::from_hexs(2).work(), U256::from_u64(256));
+        assert_eq!(U256::from_u64(1).work().to_string()[..2], *"80");
+        assert_eq!(U256::from_hexs(6).work(), U256::from_u64(1 << 24));
+        let mut carried = [0; HASH_LEN];
+        carried[HASH_LEN - 9] = 1;
+        carried[HASH_LEN - 1] = 7;
+        assert_eq!(
+            U256::from_hexs(48).saturating_add(&U256::from_u64(8)),
+            U256(carried)
+        );
+        assert_eq!(U256::MAX.saturating_add(&U256::ONE), U256::MAX);
+        assert_eq!(U256::MAX.expected_hashes(), 1.0);
+        assert_eq!(U256::from_hexs(2).expected_hashes(), 256.0);
+
+        let json = serde_json::to_string(&target).unwrap();
+        assert_eq!(serde_json::from_str::<U256>(&json).unwrap(), target);
+        assert_eq!(bincode::serialize(&target).unwrap(), target.0);
+    }
+}
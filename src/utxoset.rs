@@ -1,122 +1,762 @@
 use super::*;
-use crate::{block::Block, blockchain::Blockchain, transaction::*};
+use crate::{
+    block::{Block, BlockHeader},
+    blockchain::Blockchain,
+    config::data_path,
+    hash::{BlockHash, TxId, HASH_LEN},
+    transaction::*,
+    wallets::hash_to_address,
+};
 use bincode::{deserialize, serialize};
+use failure::format_err;
 use log::info;
-use sled::open;
-use std::{collections::HashMap, fs::remove_dir_all};
+use serde::{Deserialize, Serialize};
+use sled::{open, Db, Tree};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, remove_dir_all},
+    str::FromStr,
+    thread,
+    time::Duration,
+};
+
+/// UTXOS_DB is the UTXO database in the data directory
+const UTXOS_DB: &str = "utxos";
+/// UTXO_TIP is the key of the block the UTXO set is up to date with, in the "meta" tree
+const UTXO_TIP: &str = "TIP";
+/// BNB_MAX_TRIES bounds the branch-and-bound search before it falls back to largest-first
+const BNB_MAX_TRIES: usize = 100_000;
+/// OPEN_RETRIES is how often opening the UTXO database is retried, OPEN_RETRY_INTERVAL
+/// apart, while another thread has it open
+const OPEN_RETRIES: u32 = 100;
+const OPEN_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// open_utxos opens the UTXO database. sled locks it for a single handle, so when the
+/// node handles messages at once the open waits for the other thread to close it.
+fn open_utxos() -> Result<Db> {
+    let mut tries = 0;
+    loop {
+        match open(data_path(UTXOS_DB)) {
+            Err(sled::Error::Io(e))
+                if e.to_string().starts_with("could not acquire lock") && tries < OPEN_RETRIES =>
+            {
+                tries += 1;
+                thread::sleep(OPEN_RETRY_INTERVAL);
+            }
+            result => return Ok(result?),
+        }
+    }
+}
 
 pub struct UTXOSet {
     pub blockchain: Blockchain,
 }
 
+/// UTXOSnapshot is an exported copy of the UTXO set at a chain tip, keyed by outpoint
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UTXOSnapshot {
+    pub tip: BlockHash,
+    pub height: i32,
+    pub outputs: BTreeMap<String, TXOutput>,
+}
+
+/// UTXODiff lists the outputs created and spent between two snapshots and
+/// the resulting balance change of every address
+pub struct UTXODiff {
+    pub created: Vec<(String, TXOutput)>,
+    pub spent: Vec<(String, TXOutput)>,
+    pub balance_changes: BTreeMap<String, i64>,
+}
+
+impl UTXOSnapshot {
+    pub fn save(&self, path: &str) -> Result<()> {
+        fs::write(path, serialize(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        Ok(deserialize(&fs::read(path)?)?)
+    }
+
+    /// diff reports what changed from `self` to `newer`
+    pub fn diff(&self, newer: &UTXOSnapshot) -> UTXODiff {
+        let mut diff = UTXODiff {
+            created: Vec::new(),
+            spent: Vec::new(),
+            balance_changes: BTreeMap::new(),
+        };
+        for (outpoint, out) in &newer.outputs {
+            if !self.outputs.contains_key(outpoint) {
+                *diff
+                    .balance_changes
+                    .entry(hash_to_address(out.pub_key_hash.clone()))
+                    .or_insert(0) += out.value as i64;
+                diff.created.push((outpoint.clone(), out.clone()));
+            }
+        }
+        for (outpoint, out) in &self.outputs {
+            if !newer.outputs.contains_key(outpoint) {
+                *diff
+                    .balance_changes
+                    .entry(hash_to_address(out.pub_key_hash.clone()))
+                    .or_insert(0) -= out.value as i64;
+                diff.spent.push((outpoint.clone(), out.clone()));
+            }
+        }
+        diff.balance_changes.retain(|_, change| *change != 0);
+        diff
+    }
+}
+
+/// CoinSelection is the strategy used to pick the outputs that fund a transaction
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum CoinSelection {
+    /// spend the biggest outputs first, using as few inputs as possible
+    LargestFirst,
+    /// spend the smallest outputs first, consolidating dust
+    SmallestFirst,
+    /// look for a set of outputs matching the amount exactly, so no change is needed,
+    /// and fall back to largest-first
+    #[default]
+    BranchAndBound,
+}
+
+impl FromStr for CoinSelection {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "largest-first" => Ok(CoinSelection::LargestFirst),
+            "smallest-first" => Ok(CoinSelection::SmallestFirst),
+            "bnb" | "branch-and-bound" => Ok(CoinSelection::BranchAndBound),
+            _ => Err(format_err!("Unknown coin selection strategy: {}", s)),
+        }
+    }
+}
+
+/// select_coins returns the indices of the `values` that fund `amount`,
+/// or None when all of them together are not enough
+pub fn select_coins(values: &[i32], amount: i32, selection: CoinSelection) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    match selection {
+        CoinSelection::SmallestFirst => order.sort_by_key(|i| values[*i]),
+        CoinSelection::LargestFirst => order.sort_by_key(|i| std::cmp::Reverse(values[*i])),
+        CoinSelection::BranchAndBound => {
+            order.sort_by_key(|i| std::cmp::Reverse(values[*i]));
+            let mut tries = 0;
+            let mut selected = Vec::new();
+            if exact_match(values, &order, 0, amount, &mut selected, &mut tries) {
+                return Some(selected);
+            }
+        }
+    }
+
+    let mut accumulated = 0;
+    let mut selected = Vec::new();
+    for i in order {
+        if accumulated >= amount {
+            break;
+        }
+        accumulated += values[i];
+        selected.push(i);
+    }
+    if accumulated >= amount {
+        Some(selected)
+    } else {
+        None
+    }
+}
+
+/// exact_match searches, largest values first, for a subset of `order[from..]` summing to `remaining`
+fn exact_match(
+    values: &[i32],
+    order: &[usize],
+    from: usize,
+    remaining: i32,
+    selected: &mut Vec<usize>,
+    tries: &mut usize,
+) -> bool {
+    if remaining == 0 {
+        return true;
+    }
+    *tries += 1;
+    if *tries > BNB_MAX_TRIES {
+        return false;
+    }
+    let available: i32 = order[from..].iter().map(|i| values[*i]).sum();
+    if available < remaining {
+        return false;
+    }
+    for pos in from..order.len() {
+        let value = values[order[pos]];
+        if value > remaining || value <= 0 {
+            continue;
+        }
+        selected.push(order[pos]);
+        if exact_match(values, order, pos + 1, remaining - value, selected, tries) {
+            return true;
+        }
+        selected.pop();
+        if *tries > BNB_MAX_TRIES {
+            return false;
+        }
+    }
+    false
+}
+
+/// UnspentOutput is an output of the UTXO set with the confirmations of the transaction
+/// creating it, 0 when that transaction is not found on the best chain
+#[derive(Debug, Clone)]
+pub struct UnspentOutput {
+    pub output: TXOutput,
+    pub confirmations: i32,
+}
+
+/// outpoint_key is the UTXO database key of output `vout` of transaction `txid`: the
+/// id bytes followed by the big endian output index
+pub fn outpoint_key(txid: &TxId, vout: i32) -> Vec<u8> {
+    let mut key = txid.0.to_vec();
+    key.extend_from_slice(&vout.to_be_bytes());
+    key
+}
+
+fn parse_outpoint_key(key: &[u8]) -> Result<(TxId, i32)> {
+    if key.len() != HASH_LEN + 4 {
+        return Err(format_err!("Invalid outpoint key: {}", hex::encode(key)));
+    }
+    let mut vout = [0; 4];
+    vout.copy_from_slice(&key[HASH_LEN..]);
+    Ok((
+        TxId::from_slice(&key[..HASH_LEN])?,
+        i32::from_be_bytes(vout),
+    ))
+}
+
+/// address_key is the address index key of an outpoint locked to `pub_key_hash`
+fn address_key(pub_key_hash: &[u8], outpoint: &[u8]) -> Vec<u8> {
+    let mut key = pub_key_hash.to_vec();
+    key.extend_from_slice(outpoint);
+    key
+}
+
+/// open_address_index opens the tree indexing unspent outputs by pub key hash,
+/// building it from the UTXO set when the set predates the index
+fn open_address_index(db: &Db) -> Result<Tree> {
+    let index = db.open_tree("addresses")?;
+    if index.is_empty() && !db.is_empty() {
+        info!("building address index");
+        for kv in db.iter() {
+            let (key, value) = kv?;
+            let out: TXOutput = deserialize(&value)?;
+            index.insert(address_key(&out.pub_key_hash, &key), &[])?;
+        }
+    }
+    Ok(index)
+}
+
 impl UTXOSet {
     pub fn reindex(&self) -> Result<()> {
-        if remove_dir_all("data/utxos").is_err() {
+        if self.blockchain.is_pruned()? {
+            return Err(format_err!(
+                "Cannot reindex a pruned chain, its old blocks are deleted"
+            ));
+        }
+        if remove_dir_all(data_path(UTXOS_DB)).is_err() {
             info!("not exists any utxos to delete")
         }
-        let db = open("data/utxos")?;
+        let db = open_utxos()?;
+        let index = db.open_tree("addresses")?;
 
         let utxos = self.blockchain.find_UTXO();
 
         for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), serialize(&outs)?)?;
+            for (vout, out) in outs {
+                let key = outpoint_key(&txid, vout);
+                db.insert(&key, serialize(&out)?)?;
+                index.insert(address_key(&out.pub_key_hash, &key), &[])?;
+            }
+        }
+
+        let burned = db.open_tree("burned")?;
+        for block in self.blockchain.walk().blocks() {
+            for tx in block.get_transactions() {
+                for (vout, out) in tx.vout.iter().enumerate() {
+                    if out.is_unspendable() {
+                        burned.insert(outpoint_key(&tx.id, vout as i32), serialize(out)?)?;
+                    }
+                }
+            }
+        }
+
+        db.open_tree("meta")?
+            .insert(UTXO_TIP, self.blockchain.get_tip_hash().as_ref())?;
+        db.flush()?;
+        Ok(())
+    }
+
+    /// get_tip returns the block the UTXO set was last brought up to date with
+    pub fn get_tip(&self) -> Result<Option<BlockHash>> {
+        let db = open_utxos()?;
+        match db.open_tree("meta")?.get(UTXO_TIP)? {
+            Some(hash) => Ok(Some(BlockHash::from_slice(&hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// sync brings the UTXO set to the chain tip, rolling back the blocks of a branch
+    /// the chain left and applying the new blocks one by one; it returns the blocks
+    /// rolled back, newest first, and those applied, oldest first. A set without a
+    /// recorded tip is reindexed.
+    pub fn sync(&mut self) -> Result<(Vec<Block>, Vec<Block>)> {
+        let utxo_tip = match self.get_tip()? {
+            Some(hash) => hash,
+            None => {
+                self.reindex()?;
+                return Ok((Vec::new(), Vec::new()));
+            }
+        };
+        let mut undo = self.header(&utxo_tip)?;
+        let mut header = self.header(&self.blockchain.get_tip_hash())?;
+        let mut connect = Vec::new();
+        let mut disconnected = Vec::new();
+        while header.hash != undo.hash {
+            if header.height >= undo.height {
+                let prev = header.prev_block_hash;
+                connect.push(header.hash);
+                header = self.header(&prev)?;
+            } else {
+                let block = self.blockchain.get_block(&undo.hash)?;
+                self.rollback(&block)?;
+                disconnected.push(block);
+                undo = self.header(&undo.prev_block_hash)?;
+            }
+        }
+        let mut connected = Vec::with_capacity(connect.len());
+        for hash in connect.iter().rev() {
+            let block = self.blockchain.get_block(hash)?;
+            self.update(&block)?;
+            connected.push(block);
+        }
+        Ok((disconnected, connected))
+    }
+
+    fn header(&self, hash: &BlockHash) -> Result<BlockHeader> {
+        match self.blockchain.get_header(hash)? {
+            Some(header) => Ok(header),
+            None => Err(format_err!("Header {} is not found", hash)),
         }
+    }
+
+    /// rollback undoes `block`, the block the UTXO set is at: its outputs are removed
+    /// and the outputs it spent are restored from its undo record
+    pub fn rollback(&self, block: &Block) -> Result<()> {
+        let db = open_utxos()?;
+        let burned = db.open_tree("burned")?;
+        let index = open_address_index(&db)?;
+        let undo = db.open_tree("undo")?;
 
+        let spent: Vec<(Vec<u8>, TXOutput)> = match undo.get(block.get_hash())? {
+            Some(record) => deserialize(&record)?,
+            None => self.find_spent_outputs(block)?,
+        };
+        for tx in block.get_transactions() {
+            for (vout, out) in tx.vout.iter().enumerate() {
+                let key = outpoint_key(&tx.id, vout as i32);
+                if out.is_unspendable() {
+                    burned.remove(&key)?;
+                } else {
+                    db.remove(&key)?;
+                    index.remove(address_key(&out.pub_key_hash, &key))?;
+                }
+            }
+        }
+        for (key, out) in spent {
+            db.insert(&key, serialize(&out)?)?;
+            index.insert(address_key(&out.pub_key_hash, &key), &[])?;
+        }
+
+        undo.remove(block.get_hash())?;
+        db.open_tree("meta")?
+            .insert(UTXO_TIP, block.get_prev_hash().as_ref())?;
         db.flush()?;
         Ok(())
     }
 
+    /// find_spent_outputs rebuilds the undo record of a block connected before undo
+    /// records were kept, by looking the spent outputs up on the chain
+    fn find_spent_outputs(&self, block: &Block) -> Result<Vec<(Vec<u8>, TXOutput)>> {
+        let created: HashSet<TxId> = block.get_transactions().iter().map(|tx| tx.id).collect();
+        let mut spent = Vec::new();
+        for tx in block.get_transactions() {
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in &tx.vin {
+                if created.contains(&vin.txid) {
+                    continue;
+                }
+                let prev_tx = self
+                    .blockchain
+                    .find_transaction_from(&block.get_hash(), &vin.txid)?;
+                match prev_tx.vout.get(vin.vout as usize) {
+                    Some(out) => spent.push((outpoint_key(&vin.txid, vin.vout), out.clone())),
+                    None => {
+                        return Err(format_err!(
+                            "Block {} spends missing output {}:{}",
+                            block.get_hash(),
+                            vin.txid,
+                            vin.vout
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(spent)
+    }
+
+    /// update applies `block` to the UTXO set and writes its undo record, the outputs it
+    /// spent that were in the set before it, so rollback can restore them. A block
+    /// spending an output the set does not hold is refused before anything changes.
     pub fn update(&self, block: &Block) -> Result<()> {
-        let db = open("data/utxos")?;
+        let db = open_utxos()?;
+        let burned = db.open_tree("burned")?;
+        let index = open_address_index(&db)?;
 
+        let (mut unspent, mut taken) = (HashSet::new(), HashSet::new());
+        for tx in block.get_transactions() {
+            for vin in tx.vin.iter().filter(|_| !tx.is_coinbase()) {
+                let key = outpoint_key(&vin.txid, vin.vout);
+                let in_set = unspent.remove(&key) || (db.contains_key(&key)? && taken.insert(key));
+                if !in_set {
+                    return Err(format_err!(
+                        "Block {} spends {}:{}, which is not in the UTXO set",
+                        block.get_hash(),
+                        vin.txid,
+                        vin.vout
+                    ));
+                }
+            }
+            for (vout, out) in tx.vout.iter().enumerate() {
+                if !out.is_unspendable() {
+                    unspent.insert(outpoint_key(&tx.id, vout as i32));
+                }
+            }
+        }
+
+        let mut created = HashSet::new();
+        let mut spent = Vec::new();
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
-                    };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
+                    let key = outpoint_key(&vin.txid, vin.vout);
+                    if let Some(value) = db.remove(&key)? {
+                        let out: TXOutput = deserialize(&value)?;
+                        index.remove(address_key(&out.pub_key_hash, &key))?;
+                        if !created.contains(&vin.txid) {
+                            spent.push((key, out));
                         }
                     }
-
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), serialize(&update_outputs)?)?;
-                    }
                 }
             }
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
-            };
+            created.insert(tx.id);
 
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+            for (vout, out) in tx.vout.iter().enumerate() {
+                let key = outpoint_key(&tx.id, vout as i32);
+                if out.is_unspendable() {
+                    burned.insert(&key, serialize(out)?)?;
+                } else {
+                    db.insert(&key, serialize(out)?)?;
+                    index.insert(address_key(&out.pub_key_hash, &key), &[])?;
+                }
             }
-
-            db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
         }
 
+        db.open_tree("undo")?
+            .insert(block.get_hash(), serialize(&spent)?)?;
+        db.open_tree("meta")?
+            .insert(UTXO_TIP, block.get_hash().as_ref())?;
         db.flush()?;
         Ok(())
     }
 
+    /// prune deletes the bodies of the blocks more than `keep` blocks below the tip,
+    /// keeping their transactions that still have unspent outputs; it returns how many
+    /// blocks were pruned
+    pub fn prune(&mut self, keep: i32) -> Result<usize> {
+        let prune_height = self.blockchain.get_best_height()? - keep.max(1);
+        let db = open_utxos()?;
+        let undo = db.open_tree("undo")?;
+        let mut pruned = 0;
+        let start = match self.blockchain.get_block_hash(prune_height)? {
+            Some(hash) => hash,
+            None => return Ok(0),
+        };
+        // the blocks below the first without a body were pruned before
+        let blocks = self.blockchain.walk_from(start).with_bodies();
+        for block in blocks.map_while(|entry| entry.body) {
+            let mut spendable = Vec::new();
+            for tx in block.get_transactions() {
+                for vout in 0..tx.vout.len() {
+                    if db.contains_key(outpoint_key(&tx.id, vout as i32))? {
+                        spendable.push(tx.clone());
+                        break;
+                    }
+                }
+            }
+            self.blockchain.prune_block(&block, &spendable)?;
+            // a block without a body cannot be rolled back
+            undo.remove(block.get_hash())?;
+            pruned += 1;
+        }
+        Ok(pruned)
+    }
+
+    /// get_output returns the unspent output at `txid:vout`, if there is one
+    pub fn get_output(&self, txid: &TxId, vout: i32) -> Result<Option<TXOutput>> {
+        let db = open_utxos()?;
+        match db.get(outpoint_key(txid, vout))? {
+            Some(value) => Ok(Some(deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// resolve_inputs finds the output each input of `tx` spends: among the unspent
+    /// outputs for transactions not yet in a block, and through the transaction index
+    /// or the chain otherwise. Outputs that cannot be found, such as those of pruned
+    /// blocks, are None; a coinbase has no inputs to resolve.
+    pub fn resolve_inputs(&self, tx: &Transaction) -> Result<Vec<Option<TXOutput>>> {
+        if tx.is_coinbase() {
+            return Ok(Vec::new());
+        }
+        let mut prev_txs: HashMap<TxId, Option<Transaction>> = HashMap::new();
+        let mut outputs = Vec::new();
+        for vin in &tx.vin {
+            if let Some(out) = self.get_output(&vin.txid, vin.vout)? {
+                outputs.push(Some(out));
+                continue;
+            }
+            let prev_tx = prev_txs
+                .entry(vin.txid)
+                .or_insert_with(|| self.blockchain.find_transaction(&vin.txid).ok());
+            outputs.push(
+                prev_tx
+                    .as_ref()
+                    .and_then(|prev_tx| prev_tx.vout.get(vin.vout as usize).cloned()),
+            );
+        }
+        Ok(outputs)
+    }
+
     pub fn count_transactions(&self) -> Result<i32> {
-        let mut counter = 0;
-        let db = open("data/utxos")?;
+        let mut txids = HashSet::new();
+        let db = open_utxos()?;
+        for kv in db.iter() {
+            let (key, _) = kv?;
+            txids.insert(parse_outpoint_key(&key)?.0);
+        }
+        Ok(txids.len() as i32)
+    }
+
+    /// snapshot copies the current UTXO set together with the chain tip it belongs to
+    pub fn snapshot(&self) -> Result<UTXOSnapshot> {
+        let db = open_utxos()?;
+        let mut outputs = BTreeMap::new();
         for kv in db.iter() {
-            kv?;
-            counter += 1;
+            let (key, value) = kv?;
+            let (txid, vout) = parse_outpoint_key(&key)?;
+            outputs.insert(format!("{}:{}", txid, vout), deserialize(&value)?);
+        }
+        Ok(UTXOSnapshot {
+            tip: self.blockchain.get_tip_hash(),
+            height: self.blockchain.get_best_height()?,
+            outputs,
+        })
+    }
+
+    /// total_burned sums the value of every provably unspendable output on the chain
+    pub fn total_burned(&self) -> Result<i64> {
+        let db = open_utxos()?;
+        let mut total: i64 = 0;
+        for kv in db.open_tree("burned")?.iter() {
+            let (_, value) = kv?;
+            let out: TXOutput = deserialize(&value)?;
+            total += out.value as i64;
         }
-        Ok(counter)
+        Ok(total)
     }
 
+    /// find_spendable_outputs picks outputs of `pub_hash_key` worth at least `amount`.
+    /// When the balance is too low every output is returned with the full balance.
+    /// Outputs still timelocked for the next block are left out.
     pub fn find_spendable_outputs(
         &self,
         pub_hash_key: &[u8],
         amount: i32,
-    ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
+        selection: CoinSelection,
+    ) -> Result<(i32, HashMap<TxId, Vec<i32>>)> {
+        self.find_spendable_outputs_except(pub_hash_key, amount, selection, &HashSet::new())
+    }
+
+    /// find_spendable_outputs_except is find_spendable_outputs leaving out the `spent`
+    /// outputs, which transactions not sent yet spend
+    pub fn find_spendable_outputs_except(
+        &self,
+        pub_hash_key: &[u8],
+        amount: i32,
+        selection: CoinSelection,
+        spent: &HashSet<(TxId, i32)>,
+    ) -> Result<(i32, HashMap<TxId, Vec<i32>>)> {
+        let next_height = self.blockchain.get_best_height()? + 1;
+        let outputs: Vec<(TxId, i32, TXOutput)> = self
+            .find_outputs_for(pub_hash_key)?
+            .into_iter()
+            .filter(|(txid, vout, out)| {
+                out.lock.is_spendable_at(next_height) && !spent.contains(&(*txid, *vout))
+            })
+            .collect();
+        let values: Vec<i32> = outputs.iter().map(|(_, _, out)| out.value).collect();
+        let selected = match select_coins(&values, amount, selection) {
+            Some(selected) => selected,
+            None => (0..outputs.len()).collect(),
+        };
+
+        let mut unspent_outputs: HashMap<TxId, Vec<i32>> = HashMap::new();
         let mut accumulated: i32 = 0;
-        let db = open("data/utxos")?;
-        for kv in db.iter() {
-            let (key, value) = kv?;
-            let txid = String::from_utf8(key.to_vec())?;
-            let outs: TXOutputs = deserialize(&value)?;
-
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].is_locked_with_key(pub_hash_key) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
+        for i in selected {
+            let (txid, vout, out) = &outputs[i];
+            accumulated += out.value;
+            match unspent_outputs.get_mut(txid) {
+                Some(v) => v.push(*vout),
+                None => {
+                    unspent_outputs.insert(*txid, vec![*vout]);
                 }
             }
         }
         Ok((accumulated, unspent_outputs))
     }
 
-    pub fn find_UTXO(&self, pub_hash_key: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
-        let db = open("data/utxos")?;
+    /// get_balances sums the unspent outputs of every key hash in a single pass over the set
+    pub fn get_balances(&self, pub_key_hashes: &[Vec<u8>]) -> Result<HashMap<Vec<u8>, i32>> {
+        let mut balances: HashMap<Vec<u8>, i32> = pub_key_hashes
+            .iter()
+            .map(|hash| (hash.clone(), 0))
+            .collect();
+        let db = open_utxos()?;
         for kv in db.iter() {
             let (_, value) = kv?;
-            let outs: TXOutputs = deserialize(&value)?;
+            let out: TXOutput = deserialize(&value)?;
+            if let Some(balance) = balances.get_mut(&out.pub_key_hash) {
+                *balance += out.value;
+            }
+        }
+        Ok(balances)
+    }
 
-            for out in outs.outputs {
-                if out.is_locked_with_key(pub_hash_key) {
-                    utxos.outputs.push(out.clone());
+    /// find_UTXO returns the unspent outputs of `pub_hash_key` with their confirmations
+    pub fn find_UTXO(&self, pub_hash_key: &[u8]) -> Result<Vec<UnspentOutput>> {
+        let best_height = self.blockchain.get_best_height()?;
+        let mut heights: HashMap<TxId, Option<i32>> = HashMap::new();
+        let mut utxos = Vec::new();
+        for (txid, _, output) in self.find_outputs_for(pub_hash_key)? {
+            let height = match heights.get(&txid) {
+                Some(height) => *height,
+                None => {
+                    let height = self.blockchain.find_transaction_height(&txid)?;
+                    heights.insert(txid, height);
+                    height
                 }
-            }
+            };
+            utxos.push(UnspentOutput {
+                output,
+                confirmations: height.map_or(0, |height| best_height - height + 1),
+            });
         }
         Ok(utxos)
     }
+
+    /// find_outputs_for looks up the unspent outputs locked to `pub_hash_key` in the address index
+    pub fn find_outputs_for(&self, pub_hash_key: &[u8]) -> Result<Vec<(TxId, i32, TXOutput)>> {
+        let db = open_utxos()?;
+        let index = open_address_index(&db)?;
+        let mut outputs = Vec::new();
+        for kv in index.scan_prefix(pub_hash_key) {
+            let (key, _) = kv?;
+            let outpoint = &key[pub_hash_key.len()..];
+            let out: TXOutput = match db.get(outpoint)? {
+                Some(value) => deserialize(&value)?,
+                None => continue,
+            };
+            // a longer key hash can share the prefix
+            if out.is_locked_with_key(pub_hash_key) {
+                let (txid, vout) = parse_outpoint_key(outpoint)?;
+                outputs.push((txid, vout, out));
+            }
+        }
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::LockScript;
+
+    #[test]
+    fn test_select_coins() {
+        let values = [5, 1, 8, 3, 2];
+        let picked = |selection| {
+            let mut picked: Vec<i32> = select_coins(&values, 9, selection)
+                .unwrap()
+                .iter()
+                .map(|i| values[*i])
+                .collect();
+            picked.sort();
+            picked
+        };
+        assert_eq!(picked(CoinSelection::LargestFirst), vec![5, 8]);
+        assert_eq!(picked(CoinSelection::SmallestFirst), vec![1, 2, 3, 5]);
+        assert_eq!(picked(CoinSelection::BranchAndBound), vec![1, 8]);
+        assert_eq!(
+            select_coins(&[4, 6], 7, CoinSelection::BranchAndBound),
+            Some(vec![1, 0])
+        );
+        assert_eq!(select_coins(&values, 20, CoinSelection::LargestFirst), None);
+        assert_eq!(
+            "bnb".parse::<CoinSelection>().unwrap(),
+            CoinSelection::BranchAndBound
+        );
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let out = |key: u8, value| TXOutput {
+            value,
+            pub_key_hash: vec![key; 20],
+            lock: LockScript::PayToPubkeyHash,
+        };
+        let snapshot = |outputs: Vec<(&str, TXOutput)>| UTXOSnapshot {
+            tip: BlockHash::default(),
+            height: 0,
+            outputs: outputs
+                .into_iter()
+                .map(|(outpoint, out)| (outpoint.to_string(), out))
+                .collect(),
+        };
+        let a = snapshot(vec![("tx1:0", out(1, 10)), ("tx1:1", out(2, 5))]);
+        let b = snapshot(vec![
+            ("tx1:1", out(2, 5)),
+            ("tx2:0", out(3, 7)),
+            ("tx2:1", out(1, 3)),
+        ]);
+
+        let diff = a.diff(&b);
+        assert_eq!(diff.created.len(), 2);
+        assert_eq!(diff.spent.len(), 1);
+        assert_eq!(diff.spent[0].0, "tx1:0");
+        assert_eq!(diff.balance_changes[&hash_to_address(vec![1; 20])], -7);
+        assert_eq!(diff.balance_changes[&hash_to_address(vec![3; 20])], 7);
+        assert!(!diff
+            .balance_changes
+            .contains_key(&hash_to_address(vec![2; 20])));
+    }
 }
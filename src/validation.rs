@@ -0,0 +1,273 @@
+use super::*;
+use crate::{
+    mempool::Mempool,
+    script::{LockScript, MAX_VAULT_DELAY},
+    transaction::Transaction,
+    utxoset::UTXOSet,
+};
+use std::{collections::HashSet, fmt, time::SystemTime};
+
+/// DUST_LIMIT is the smallest output value the mempool relays
+pub const DUST_LIMIT: i32 = 1;
+
+/// RuleCheck is the outcome of one validation rule applied to a transaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleCheck {
+    pub rule: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl RuleCheck {
+    fn new(rule: &str, passed: bool, detail: String) -> Self {
+        RuleCheck {
+            rule: rule.to_string(),
+            passed,
+            detail,
+        }
+    }
+}
+
+impl fmt::Display for RuleCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = if self.passed { "ok" } else { "FAIL" };
+        write!(f, "[{}] {}: {}", status, self.rule, self.detail)
+    }
+}
+
+/// check_structure runs the rules that only look at the transaction itself
+pub fn check_structure(tx: &Transaction) -> Result<Vec<RuleCheck>> {
+    let mut checks = Vec::new();
+    let id = tx.clone().hash()?;
+    checks.push(RuleCheck::new(
+        "txid",
+        id == tx.id,
+        format!("computed {}", id),
+    ));
+    checks.push(RuleCheck::new(
+        "coinbase",
+        !tx.is_coinbase(),
+        String::from("coinbase transactions are only valid inside a block"),
+    ));
+    checks.push(RuleCheck::new(
+        "inputs",
+        !tx.vin.is_empty(),
+        format!("{} inputs", tx.vin.len()),
+    ));
+    checks.push(RuleCheck::new(
+        "outputs",
+        !tx.vout.is_empty(),
+        format!("{} outputs", tx.vout.len()),
+    ));
+    for (idx, out) in tx.vout.iter().enumerate() {
+        checks.push(RuleCheck::new(
+            &format!("output {} dust", idx),
+            out.value >= DUST_LIMIT,
+            format!("pays {}, dust limit is {}", out.value, DUST_LIMIT),
+        ));
+        checks.push(RuleCheck::new(
+            &format!("output {} lock", idx),
+            out.lock.is_valid(),
+            format!(
+                "{:?}, a vault delays by 1 to {} blocks",
+                out.lock, MAX_VAULT_DELAY
+            ),
+        ));
+    }
+
+    let mut seen = HashSet::new();
+    for (idx, vin) in tx.vin.iter().enumerate() {
+        checks.push(RuleCheck::new(
+            &format!("input {} duplicate", idx),
+            seen.insert((vin.txid, vin.vout)),
+            format!("spends {}:{}", vin.txid, vin.vout),
+        ));
+    }
+    Ok(checks)
+}
+
+/// trace_transaction runs `tx` through every mempool and consensus rule and reports
+/// each outcome instead of stopping at the first failure. Inputs may spend the
+/// outputs of transactions in `mempool`.
+pub fn trace_transaction(
+    tx: &Transaction,
+    utxo: &UTXOSet,
+    mempool: &Mempool,
+) -> Result<Vec<RuleCheck>> {
+    let mut checks = check_structure(tx)?;
+    if tx.is_coinbase() {
+        return Ok(checks);
+    }
+
+    let next_height = utxo.blockchain.get_best_height()? + 1;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis();
+    checks.push(RuleCheck::new(
+        "locktime",
+        tx.is_final_at(next_height, now),
+        format!("lock time {}, next block is {}", tx.lock_time, next_height),
+    ));
+    checks.push(RuleCheck::new(
+        "expiry",
+        !tx.is_expired_at(next_height),
+        format!(
+            "expiry height {}, next block is {}",
+            tx.expiry_height, next_height
+        ),
+    ));
+
+    let mut in_value = 0;
+    let mut inputs_known = true;
+    let mut vault_inputs = Vec::new();
+    for (idx, vin) in tx.vin.iter().enumerate() {
+        let (prev_tx, unspent) = match mempool.get(&vin.txid) {
+            Some(prev_tx) => (Some(prev_tx.clone()), true),
+            None => (
+                utxo.blockchain.find_transaction(&vin.txid).ok(),
+                utxo.get_output(&vin.txid, vin.vout)?.is_some(),
+            ),
+        };
+        let spent = prev_tx.as_ref().and_then(|prev_tx| {
+            prev_tx
+                .vout
+                .get(vin.vout as usize)
+                .map(|prev_out| (prev_tx, prev_out))
+        });
+        let (prev_tx, prev_out) = match spent {
+            Some(spent) => spent,
+            None => {
+                checks.push(RuleCheck::new(
+                    &format!("input {} exists", idx),
+                    false,
+                    format!("missing input {}:{}", vin.txid, vin.vout),
+                ));
+                inputs_known = false;
+                continue;
+            }
+        };
+        checks.push(RuleCheck::new(
+            &format!("input {} exists", idx),
+            true,
+            format!("{}:{} holds {}", vin.txid, vin.vout, prev_out.value),
+        ));
+        in_value += prev_out.value;
+
+        checks.push(RuleCheck::new(
+            &format!("input {} unspent", idx),
+            unspent,
+            format!(
+                "{}:{} is not in the UTXO set or mempool",
+                vin.txid, vin.vout
+            ),
+        ));
+        checks.push(RuleCheck::new(
+            &format!("input {} key", idx),
+            prev_out
+                .lock
+                .matches_keys(&prev_out.pub_key_hash, &vin.pub_key),
+            format!(
+                "{:?} keys must hash to the spent output's key hash",
+                prev_out.lock
+            ),
+        ));
+        if let LockScript::TimeLock { height } = prev_out.lock {
+            checks.push(RuleCheck::new(
+                &format!("input {} timelock", idx),
+                prev_out.lock.is_spendable_at(next_height),
+                format!(
+                    "spendable from height {}, next block is {}",
+                    height, next_height
+                ),
+            ));
+        }
+        if prev_out.lock.is_vault() {
+            vault_inputs.push((idx, prev_out.clone()));
+        }
+        checks.push(RuleCheck::new(
+            &format!("input {} signature", idx),
+            tx.verify_input(idx, prev_tx)?,
+            format!("{} byte signature", vin.signature.len()),
+        ));
+    }
+    // the vault rules weigh the outputs against the value of every input
+    for (idx, prev_out) in vault_inputs {
+        let violation = tx.vault_violation(idx, &prev_out, in_value, next_height);
+        checks.push(RuleCheck::new(
+            &format!("input {} vault", idx),
+            violation.is_none(),
+            violation.unwrap_or_else(|| format!("{:?} spent by an allowed key", prev_out.lock)),
+        ));
+    }
+
+    let out_value: i32 = tx.vout.iter().map(|out| out.value).sum();
+    if inputs_known {
+        checks.push(RuleCheck::new(
+            "value",
+            out_value <= in_value,
+            format!(
+                "inputs {}, outputs {}, fee {}",
+                in_value,
+                out_value,
+                in_value - out_value
+            ),
+        ));
+    }
+    Ok(checks)
+}
+
+/// first_failure returns the first rule `tx` breaks, if any
+pub fn first_failure(checks: Vec<RuleCheck>) -> Option<RuleCheck> {
+    checks.into_iter().find(|check| !check.passed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hash::TxId;
+    use crate::script::LockScript;
+    use crate::transaction::{TXInput, TXOutput};
+
+    #[test]
+    fn test_check_structure() {
+        let mut tx = Transaction {
+            id: TxId::default(),
+            vin: vec![
+                TXInput {
+                    txid: TxId::digest(b"prev"),
+                    vout: 0,
+                    signature: Vec::new(),
+                    pub_key: Vec::new(),
+                };
+                2
+            ],
+            vout: vec![
+                TXOutput {
+                    value: 5,
+                    pub_key_hash: vec![1; 20],
+                    lock: LockScript::PayToPubkeyHash,
+                },
+                TXOutput {
+                    value: 0,
+                    pub_key_hash: vec![1; 20],
+                    lock: LockScript::PayToPubkeyHash,
+                },
+            ],
+            lock_time: 0,
+            expiry_height: 0,
+        };
+        tx.id = tx.hash().unwrap();
+
+        let failed: Vec<String> = check_structure(&tx)
+            .unwrap()
+            .into_iter()
+            .filter(|check| !check.passed)
+            .map(|check| check.rule)
+            .collect();
+        assert_eq!(failed, vec!["output 1 dust", "input 1 duplicate"]);
+
+        tx.id = TxId::digest(b"forged");
+        let failure = first_failure(check_structure(&tx).unwrap()).unwrap();
+        assert_eq!(failure.rule, "txid");
+    }
+}
@@ -0,0 +1,293 @@
+use crate::{
+    descriptor::Descriptor,
+    error::Result,
+    hash::TxId,
+    script::{redeem_hash, LockScript, VaultKey, PUB_KEY_LEN},
+    signer::Signer,
+    transaction::{TXInput, TXOutput, Transaction},
+    utxoset::UTXOSet,
+};
+use failure::format_err;
+
+/// DEFAULT_VAULT_DELAY is the number of blocks the recovery key has to claw back an unvault
+pub const DEFAULT_VAULT_DELAY: i32 = 144;
+
+/// Vault is a vault descriptor: the hot key spends its funds through an unvault the
+/// recovery key can claw back during `delay` blocks
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vault {
+    pub delay: i32,
+    /// keys holds the hot key followed by the recovery key
+    pub keys: Vec<u8>,
+}
+
+/// VaultOutput is an unspent output of a vault
+pub type VaultOutput = (TxId, i32, TXOutput);
+
+impl Vault {
+    /// key returns the public key of `key`
+    pub fn key(&self, key: VaultKey) -> &[u8] {
+        let (hot, recovery) = self.keys.split_at(PUB_KEY_LEN);
+        match key {
+            VaultKey::Hot => hot,
+            VaultKey::Recovery => recovery,
+        }
+    }
+
+    /// descriptor returns the vault() descriptor the vault is imported with
+    pub fn descriptor(&self) -> Descriptor {
+        Descriptor::Vault {
+            delay: self.delay,
+            keys: self.keys.clone(),
+        }
+    }
+
+    /// outputs returns the unspent outputs of the vault, vaulted and unvaulting
+    pub fn outputs(&self, utxo: &UTXOSet) -> Result<Vec<VaultOutput>> {
+        Ok(utxo
+            .find_outputs_for(&redeem_hash(&self.keys))?
+            .into_iter()
+            .filter(|(_, _, out)| out.lock.is_vault())
+            .collect())
+    }
+
+    /// new_unvault starts moving `amount` out of the vault with the hot key: it becomes
+    /// spendable by the hot key `delay` blocks after the block at `height` holding the
+    /// unvault, and the rest of the outputs it spends goes back into the vault. The
+    /// unvault expires after that block, as consensus rejects it in any later one.
+    pub fn new_unvault(
+        &self,
+        amount: i32,
+        height: i32,
+        utxo: &UTXOSet,
+        signer: &dyn Signer,
+    ) -> Result<Transaction> {
+        if amount <= 0 {
+            return Err(format_err!("Amount to unvault must be positive"));
+        }
+        let mut vaulted: Vec<VaultOutput> = self
+            .outputs(utxo)?
+            .into_iter()
+            .filter(|(_, _, out)| matches!(out.lock, LockScript::Vault { .. }))
+            .collect();
+        vaulted.sort_by_key(|(_, _, out)| -out.value);
+
+        let mut inputs = Vec::new();
+        let mut total = 0;
+        for output in vaulted {
+            if total >= amount {
+                break;
+            }
+            total += output.2.value;
+            inputs.push(output);
+        }
+        if total < amount {
+            return Err(format_err!("Not enough in the vault: {} is vaulted", total));
+        }
+
+        let unlock = match height.checked_add(self.delay) {
+            Some(unlock) => unlock,
+            None => return Err(format_err!("The vault delay {} overflows", self.delay)),
+        };
+        let pub_key_hash = redeem_hash(&self.keys);
+        let mut vout = vec![TXOutput {
+            value: amount,
+            pub_key_hash: pub_key_hash.clone(),
+            lock: LockScript::Unvault { height: unlock },
+        }];
+        if total > amount {
+            vout.push(TXOutput {
+                value: total - amount,
+                pub_key_hash,
+                lock: LockScript::Vault { delay: self.delay },
+            });
+        }
+        self.new_spend(&inputs, vout, height + 1, VaultKey::Hot, signer)
+    }
+
+    /// new_withdrawal pays the unvaulted outputs spendable in a block at `height` to
+    /// `address` with the hot key
+    pub fn new_withdrawal(
+        &self,
+        address: &str,
+        height: i32,
+        utxo: &UTXOSet,
+        signer: &dyn Signer,
+    ) -> Result<Transaction> {
+        let matured: Vec<VaultOutput> = self
+            .outputs(utxo)?
+            .into_iter()
+            .filter(|(_, _, out)| {
+                matches!(out.lock, LockScript::Unvault { height: unlock } if unlock <= height)
+            })
+            .collect();
+        if matured.is_empty() {
+            return Err(format_err!("No unvaulted output is spendable yet"));
+        }
+        self.sweep(&matured, address, VaultKey::Hot, signer)
+    }
+
+    /// new_clawback moves every output of the vault, vaulted or unvaulting, to `address`
+    /// with the recovery key
+    pub fn new_clawback(
+        &self,
+        address: &str,
+        utxo: &UTXOSet,
+        signer: &dyn Signer,
+    ) -> Result<Transaction> {
+        let outputs = self.outputs(utxo)?;
+        if outputs.is_empty() {
+            return Err(format_err!("The vault holds nothing to claw back"));
+        }
+        self.sweep(&outputs, address, VaultKey::Recovery, signer)
+    }
+
+    fn sweep(
+        &self,
+        inputs: &[VaultOutput],
+        address: &str,
+        key: VaultKey,
+        signer: &dyn Signer,
+    ) -> Result<Transaction> {
+        let value = inputs.iter().map(|(_, _, out)| out.value).sum();
+        let vout = vec![TXOutput::new(value, address.to_string())?];
+        self.new_spend(inputs, vout, 0, key, signer)
+    }
+
+    /// new_spend spends `inputs` into `vout`, signed by `key`, expiring at
+    /// `expiry_height` unless it is 0
+    fn new_spend(
+        &self,
+        inputs: &[VaultOutput],
+        vout: Vec<TXOutput>,
+        expiry_height: i32,
+        key: VaultKey,
+        signer: &dyn Signer,
+    ) -> Result<Transaction> {
+        let mut tx = Transaction {
+            id: TxId::default(),
+            vin: inputs
+                .iter()
+                .map(|(txid, vout, _)| TXInput {
+                    txid: *txid,
+                    vout: *vout,
+                    signature: Vec::new(),
+                    pub_key: self.keys.clone(),
+                })
+                .collect(),
+            vout,
+            lock_time: 0,
+            expiry_height,
+        };
+        tx.id = tx.hash()?;
+        for (in_id, (_, _, prev_out)) in inputs.iter().enumerate() {
+            tx.sign_vault_input(in_id, prev_out, signer, self.key(key))?;
+        }
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{script::MAX_VAULT_FEE, signer::KeySigner, wallets::Wallet};
+
+    #[test]
+    fn test_vault_rules() {
+        let hot = Wallet::derive(&[5; 32], &[44, 0], 0);
+        let recovery = Wallet::derive(&[5; 32], &[44, 0], 1);
+        let vault = Vault {
+            delay: 10,
+            keys: [hot.public_key.clone(), recovery.public_key.clone()].concat(),
+        };
+        let hash = redeem_hash(&vault.keys);
+        let deposit = Transaction {
+            id: TxId::digest(b"deposit"),
+            vin: Vec::new(),
+            vout: vec![TXOutput {
+                value: 50,
+                pub_key_hash: hash.clone(),
+                lock: LockScript::Vault { delay: 10 },
+            }],
+            lock_time: 0,
+            expiry_height: 0,
+        };
+        let inputs = vec![(deposit.id, 0, deposit.vout[0].clone())];
+        let hot_signer = KeySigner::new(&hot.secret_key);
+        let recovery_signer = KeySigner::new(&recovery.secret_key);
+        let unvault = |height| TXOutput {
+            value: 50,
+            pub_key_hash: hash.clone(),
+            lock: LockScript::Unvault { height },
+        };
+
+        // the hot key only starts an unvault long enough for the recovery key to react,
+        // which lapses with the block it was made for
+        let tx = vault
+            .new_spend(&inputs, vec![unvault(110)], 101, VaultKey::Hot, &hot_signer)
+            .unwrap();
+        assert!(!tx.is_expired_at(100) && tx.is_expired_at(101));
+        assert!(tx.verify_input(0, &deposit).unwrap());
+        assert_eq!(tx.vault_key(0, &deposit.vout[0]), Some(VaultKey::Hot));
+        assert_eq!(tx.vault_violation(0, &deposit.vout[0], 50, 100), None);
+        assert!(tx.vault_violation(0, &deposit.vout[0], 50, 101).is_some());
+        // nor may it leave the vault to the miners, by spending into nothing or into
+        // outputs far below the input
+        let tx = vault
+            .new_spend(&inputs, Vec::new(), 0, VaultKey::Hot, &hot_signer)
+            .unwrap();
+        assert!(tx.vault_violation(0, &deposit.vout[0], 50, 100).is_some());
+        let low = TXOutput {
+            value: 50 - MAX_VAULT_FEE - 1,
+            ..unvault(110)
+        };
+        let tx = vault
+            .new_spend(&inputs, vec![low], 0, VaultKey::Hot, &hot_signer)
+            .unwrap();
+        assert!(tx.vault_violation(0, &deposit.vout[0], 50, 100).is_some());
+        let tx = vault
+            .new_spend(
+                &inputs,
+                vec![TXOutput {
+                    value: 50 - MAX_VAULT_FEE,
+                    ..unvault(110)
+                }],
+                0,
+                VaultKey::Hot,
+                &hot_signer,
+            )
+            .unwrap();
+        assert_eq!(tx.vault_violation(0, &deposit.vout[0], 50, 100), None);
+        let away = vec![TXOutput::new(50, hot.get_address()).unwrap()];
+        let tx = vault
+            .new_spend(&inputs, away.clone(), 0, VaultKey::Hot, &hot_signer)
+            .unwrap();
+        assert!(tx.vault_violation(0, &deposit.vout[0], 50, 100).is_some());
+        let tx = vault
+            .new_spend(&inputs, away, 0, VaultKey::Recovery, &recovery_signer)
+            .unwrap();
+        assert!(tx.verify_input(0, &deposit).unwrap());
+        assert_eq!(tx.vault_violation(0, &deposit.vout[0], 50, 100), None);
+
+        // the unvaulted output is the hot key's once it matured, the recovery key's always
+        let unvaulted = Transaction {
+            vout: vec![unvault(110)],
+            ..deposit.clone()
+        };
+        let inputs = vec![(unvaulted.id, 0, unvaulted.vout[0].clone())];
+        let tx = vault
+            .sweep(&inputs, &hot.get_address(), VaultKey::Hot, &hot_signer)
+            .unwrap();
+        assert!(tx.vault_violation(0, &unvaulted.vout[0], 50, 109).is_some());
+        assert_eq!(tx.vault_violation(0, &unvaulted.vout[0], 50, 110), None);
+        let tx = vault
+            .sweep(
+                &inputs,
+                &recovery.get_address(),
+                VaultKey::Recovery,
+                &recovery_signer,
+            )
+            .unwrap();
+        assert_eq!(tx.vault_violation(0, &unvaulted.vout[0], 50, 100), None);
+    }
+}
@@ -1,11 +1,39 @@
 use super::*;
+use crate::{
+    approvals::{PendingSend, SpendLimits, DAY},
+    blockchain::HistoryEntry,
+    config::{data_path, Config},
+    descriptor::Descriptor,
+    hash::{BlockHash, TxId},
+    scheduler::now_secs,
+    script::spender_hash,
+    signer::{KeySigner, RemoteSigner, Signer},
+    transaction::Transaction,
+    vault::Vault,
+};
 use bincode::{deserialize, serialize};
+use bip39::Mnemonic;
 use bitcoincash_addr::{Address, HashType, Scheme};
-use crypto::{digest::Digest, ed25519, ripemd160::Ripemd160, sha2::Sha256};
+use core::time::Duration;
+use crypto::{
+    digest::Digest,
+    ed25519,
+    hmac::Hmac,
+    mac::Mac,
+    ripemd160::Ripemd160,
+    sha2::{Sha256, Sha512},
+};
+use failure::format_err;
 use log::info;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// HD_PATH is the hardened BIP44-style prefix `m/44'/0'/0'/0'` of derived wallets
+const HD_PATH: [u32; 4] = [44, 0, 0, 0];
+const HARDENED_OFFSET: u32 = 0x8000_0000;
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+const WALLETS_DB: &str = "wallets";
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
@@ -17,7 +45,30 @@ impl Wallet {
     fn new() -> Self {
         let mut key: [u8; 32] = [0; 32];
         thread_rng().fill_bytes(&mut key);
-        let (secret_key, public_key) = ed25519::keypair(&key);
+        Wallet::from_key(&key)
+    }
+
+    /// from_hd_seed derives the wallet at `m/44'/0'/0'/0'/index'` from a BIP39 seed
+    fn from_hd_seed(seed: &[u8], index: u32) -> Self {
+        Wallet::derive(seed, &HD_PATH, index)
+    }
+
+    /// derive derives the hardened child `index` below the hardened `path` of a seed
+    pub fn derive(seed: &[u8], path: &[u32], index: u32) -> Self {
+        let (mut key, mut chain_code) = split_hmac(b"ed25519 seed", seed);
+        for i in path.iter().chain([index].iter()) {
+            let mut data = vec![0];
+            data.extend_from_slice(&key);
+            data.extend_from_slice(&(i | HARDENED_OFFSET).to_be_bytes());
+            let (child_key, child_chain_code) = split_hmac(&chain_code, &data);
+            key = child_key;
+            chain_code = child_chain_code;
+        }
+        Wallet::from_key(&key)
+    }
+
+    fn from_key(key: &[u8; 32]) -> Self {
+        let (secret_key, public_key) = ed25519::keypair(key);
         let secret_key = secret_key.to_vec();
         let public_key = public_key.to_vec();
         Wallet {
@@ -29,14 +80,105 @@ impl Wallet {
     pub fn get_address(&self) -> String {
         let mut pub_hash: Vec<u8> = self.public_key.clone();
         hash_pub_key(&mut pub_hash);
-        let address = Address {
-            body: pub_hash,
-            scheme: Scheme::Base58,
-            hash_type: HashType::Script,
-            ..Default::default()
-        };
-        // 0 O 1 I
-        address.encode().unwrap()
+        hash_to_address(pub_hash)
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+
+    /// is_remote tells whether the secret key is kept by the remote signer
+    pub fn is_remote(&self) -> bool {
+        self.secret_key.is_empty()
+    }
+
+    /// signer signs with the wallet's secret key, or through the remote signer of the
+    /// config file when the key is kept there
+    pub fn signer(&self) -> Result<Box<dyn Signer + '_>> {
+        if !self.is_remote() {
+            return Ok(Box::new(KeySigner::new(&self.secret_key)));
+        }
+        let settings = Config::load()?.wallet;
+        match settings.remote_signer {
+            Some(address) => Ok(Box::new(RemoteSigner::new(
+                &address,
+                Duration::from_secs(settings.signer_timeout),
+            ))),
+            None => Err(format_err!(
+                "Key {} is kept by a remote signer, but none is configured",
+                self.fingerprint()
+            )),
+        }
+    }
+}
+
+/// fingerprint identifies a public key by the first 4 bytes of its hash, in hex
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let mut pub_hash = public_key.to_vec();
+    hash_pub_key(&mut pub_hash);
+    hex::encode(&pub_hash[..4])
+}
+
+/// check_key returns what is wrong with a wallet stored under `address`, if anything:
+/// its secret key must produce its public key, which must hash to the address
+pub fn check_key(address: &str, wallet: &Wallet) -> Option<String> {
+    if wallet.is_remote() {
+        if wallet.get_address() != address {
+            return Some(format!("key belongs to address {}", wallet.get_address()));
+        }
+        return None;
+    }
+    if wallet.secret_key.len() != 64 {
+        return Some(format!(
+            "secret key is {} bytes instead of 64",
+            wallet.secret_key.len()
+        ));
+    }
+    let mut seed = [0; 32];
+    seed.copy_from_slice(&wallet.secret_key[..32]);
+    let (secret_key, public_key) = ed25519::keypair(&seed);
+    if secret_key.to_vec() != wallet.secret_key || public_key.to_vec() != wallet.public_key {
+        return Some(String::from(
+            "secret key does not produce the stored public key",
+        ));
+    }
+    if wallet.get_address() != address {
+        return Some(format!("key belongs to address {}", wallet.get_address()));
+    }
+    None
+}
+
+/// hash_to_address encodes a public key hash as a wallet address
+pub fn hash_to_address(pub_key_hash: Vec<u8>) -> String {
+    let address = Address {
+        body: pub_key_hash,
+        scheme: Scheme::Base58,
+        hash_type: HashType::Script,
+        ..Default::default()
+    };
+    // 0 O 1 I
+    address.encode().unwrap()
+}
+
+/// split_hmac computes HMAC-SHA512 and splits it into a key and a chain code (SLIP-0010)
+fn split_hmac(key: &[u8], data: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut hmac = Hmac::new(Sha512::new(), key);
+    hmac.input(data);
+    let mut result = [0; 64];
+    hmac.raw_result(&mut result);
+
+    let mut child_key = [0; 32];
+    let mut chain_code = [0; 32];
+    child_key.copy_from_slice(&result[..32]);
+    chain_code.copy_from_slice(&result[32..]);
+    (child_key, chain_code)
+}
+
+/// hd_descriptor describes the wallets derived from `seed` by create_hd_wallet
+fn hd_descriptor(seed: &[u8]) -> Descriptor {
+    Descriptor::Hd {
+        seed: seed.to_vec(),
+        path: HD_PATH.to_vec(),
     }
 }
 
@@ -50,33 +192,379 @@ pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     hasher2.result(pub_key);
 }
 
+/// KeyPolicy retires receive addresses after a number of payments or an age in seconds
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct KeyPolicy {
+    pub max_uses: Option<u32>,
+    pub max_age: Option<u64>,
+}
+
+impl KeyPolicy {
+    /// retired tells whether an address created at `created` is retired at time `at`
+    /// after it has received `uses` payments
+    pub fn retired(&self, created: u64, uses: u32, at: u64) -> bool {
+        self.max_uses.is_some_and(|max| uses >= max)
+            || self.max_age.is_some_and(|age| at >= created + age)
+    }
+
+    /// late_payments returns the payments in `history` that arrived after the address retired
+    pub fn late_payments<'a>(
+        &self,
+        created: u64,
+        history: &'a [HistoryEntry],
+    ) -> Vec<&'a HistoryEntry> {
+        let mut late = Vec::new();
+        for (uses, entry) in history
+            .iter()
+            .filter(|entry| entry.received > 0)
+            .enumerate()
+        {
+            if self.retired(created, uses as u32, (entry.timestamp / 1000) as u64) {
+                late.push(entry);
+            }
+        }
+        late
+    }
+}
+
+/// Conflict records a wallet transaction double spent by another one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Conflict {
+    /// txid is the wallet transaction, the one seen first
+    pub txid: TxId,
+    /// conflicting spends an output `txid` spends too
+    pub conflicting: TxId,
+    /// block holds the conflicting transaction; None while it was only offered to the mempool
+    pub block: Option<BlockHash>,
+    /// seen is the unix time, in seconds, the conflict was observed
+    pub seen: u64,
+}
+
 pub struct Wallets {
+    /// path is the wallet database, a copy of which can sign on an offline machine
+    path: String,
     wallets: HashMap<String, Wallet>,
+    hd_seed: Option<Vec<u8>>,
+    hd_next_index: u32,
+    descriptors: HashMap<String, u32>,
+    created: HashMap<String, u64>,
+    policy: KeyPolicy,
+    /// labels names addresses by address, the wallet's own and those of its address book
+    labels: HashMap<String, String>,
+    limits: HashMap<String, SpendLimits>,
+    /// spends holds the time and amount of the recent sends of every limited address
+    spends: HashMap<String, Vec<(u64, i32)>>,
+    approvals: BTreeMap<u64, PendingSend>,
+    /// conflicts are the double spends of wallet transactions, by txid and conflicting txid
+    conflicts: BTreeMap<String, Conflict>,
 }
 
 impl Wallets {
+    /// new loads the wallet database of the network, which an explorer node has not
     pub fn new() -> Result<Self> {
+        if Config::load()?.node.explorer {
+            return Err(format_err!("The wallet is disabled on an explorer node"));
+        }
+        Wallets::open(&data_path(WALLETS_DB))
+    }
+
+    /// open loads the wallet database at `path`
+    pub fn open(path: &str) -> Result<Self> {
         let mut wlt = Wallets {
+            path: path.to_string(),
             wallets: HashMap::<String, Wallet>::new(),
+            hd_seed: None,
+            hd_next_index: 0,
+            descriptors: HashMap::new(),
+            created: HashMap::new(),
+            policy: KeyPolicy::default(),
+            labels: HashMap::new(),
+            limits: HashMap::new(),
+            spends: HashMap::new(),
+            approvals: BTreeMap::new(),
+            conflicts: BTreeMap::new(),
         };
 
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(path)?;
         for item in db.into_iter() {
             let i = item?;
             let address = String::from_utf8(i.0.to_vec())?;
             let wallet = deserialize(&i.1)?;
             wlt.wallets.insert(address, wallet);
         }
+
+        let hd = db.open_tree("hd")?;
+        if let Some(seed) = hd.get("seed")? {
+            wlt.hd_seed = Some(seed.to_vec());
+        }
+        if let Some(index) = hd.get("next_index")? {
+            wlt.hd_next_index = deserialize(&index)?;
+        }
+        for item in db.open_tree("descriptors")?.iter() {
+            let (descriptor, range) = item?;
+            wlt.descriptors.insert(
+                String::from_utf8(descriptor.to_vec())?,
+                deserialize(&range)?,
+            );
+        }
+        for item in db.open_tree("created")?.iter() {
+            let (address, created) = item?;
+            wlt.created
+                .insert(String::from_utf8(address.to_vec())?, deserialize(&created)?);
+        }
+        // keys from before creation times were recorded start their age now
+        let now = now_secs()?;
+        for address in wlt.wallets.keys() {
+            wlt.created.entry(address.clone()).or_insert(now);
+        }
+        if let Some(policy) = db.open_tree("policy")?.get("policy")? {
+            wlt.policy = deserialize(&policy)?;
+        }
+        for item in db.open_tree("labels")?.iter() {
+            let (address, label) = item?;
+            wlt.labels.insert(
+                String::from_utf8(address.to_vec())?,
+                String::from_utf8(label.to_vec())?,
+            );
+        }
+        for item in db.open_tree("limits")?.iter() {
+            let (address, limits) = item?;
+            wlt.limits
+                .insert(String::from_utf8(address.to_vec())?, deserialize(&limits)?);
+        }
+        for item in db.open_tree("spends")?.iter() {
+            let (address, spends) = item?;
+            wlt.spends
+                .insert(String::from_utf8(address.to_vec())?, deserialize(&spends)?);
+        }
+        for item in db.open_tree("approvals")?.iter() {
+            let pending: PendingSend = deserialize(&item?.1)?;
+            wlt.approvals.insert(pending.id, pending);
+        }
+        for item in db.open_tree("conflicts")?.iter() {
+            let (key, conflict) = item?;
+            wlt.conflicts
+                .insert(String::from_utf8(key.to_vec())?, deserialize(&conflict)?);
+        }
         drop(db);
         Ok(wlt)
     }
 
-    pub fn create_wallet(&mut self) -> String {
+    /// generate_mnemonic creates a fresh 12 word BIP39 phrase
+    pub fn generate_mnemonic() -> Result<String> {
+        let mut entropy: [u8; 16] = [0; 16];
+        thread_rng().fill_bytes(&mut entropy);
+        Ok(Mnemonic::from_entropy(&entropy)?.to_string())
+    }
+
+    pub fn has_hd_seed(&self) -> bool {
+        self.hd_seed.is_some()
+    }
+
+    /// set_mnemonic stores the seed of `phrase` as the source of derived wallets
+    pub fn set_mnemonic(&mut self, phrase: &str) -> Result<()> {
+        let seed = Mnemonic::parse(phrase)?.to_seed("").to_vec();
+        match &self.hd_seed {
+            Some(existing) if *existing != seed => Err(format_err!(
+                "A different mnemonic is already stored in this wallet file"
+            )),
+            _ => {
+                self.hd_seed = Some(seed);
+                Ok(())
+            }
+        }
+    }
+
+    /// create_hd_wallet derives the next wallet from the stored mnemonic seed
+    pub fn create_hd_wallet(&mut self) -> Result<String> {
+        let seed = match &self.hd_seed {
+            Some(seed) => seed.clone(),
+            None => return Err(format_err!("No mnemonic is stored in this wallet file")),
+        };
+        // indexes restored or imported before are already in the wallet and skipped
+        loop {
+            let wallet = Wallet::from_hd_seed(&seed, self.hd_next_index);
+            let address = wallet.get_address();
+            self.hd_next_index += 1;
+            if self.insert_wallet(wallet)? {
+                info!(
+                    "create hd wallet: {address} index: {}",
+                    self.hd_next_index - 1
+                );
+                self.descriptors
+                    .insert(hd_descriptor(&seed).to_string(), self.hd_next_index);
+                return Ok(address);
+            }
+        }
+    }
+
+    /// restore_from_mnemonic re-derives the first `count` wallets of `phrase` and returns
+    /// the addresses that were not in the wallet yet
+    pub fn restore_from_mnemonic(&mut self, phrase: &str, count: u32) -> Result<Vec<String>> {
+        self.set_mnemonic(phrase)?;
+        let seed = Mnemonic::parse(phrase)?.to_seed("").to_vec();
+
+        let mut addresses = Vec::new();
+        for index in 0..count {
+            let wallet = Wallet::from_hd_seed(&seed, index);
+            let address = wallet.get_address();
+            if self.insert_wallet(wallet)? {
+                addresses.push(address);
+            }
+        }
+        self.hd_next_index = self.hd_next_index.max(count);
+        self.descriptors
+            .insert(hd_descriptor(&seed).to_string(), self.hd_next_index);
+        Ok(addresses)
+    }
+
+    pub fn create_wallet(&mut self) -> Result<String> {
         let wallet = Wallet::new();
         let address = wallet.get_address();
-        self.wallets.insert(address.clone(), wallet);
+        self.descriptors
+            .insert(Descriptor::Key(wallet.public_key.clone()).to_string(), 1);
+        self.insert_wallet(wallet)?;
         info!("create wallet: {address}");
-        address
+        Ok(address)
+    }
+
+    /// import_remote_key adds a key whose secret is kept by the remote signer
+    pub fn import_remote_key(&mut self, public_key: Vec<u8>) -> Result<String> {
+        if public_key.len() != 32 {
+            return Err(format_err!(
+                "Public key is {} bytes instead of 32",
+                public_key.len()
+            ));
+        }
+        let wallet = Wallet {
+            secret_key: Vec::new(),
+            public_key,
+        };
+        let address = wallet.get_address();
+        if !self.insert_wallet(wallet.clone())? {
+            return Err(format_err!(
+                "Key {} is already in the wallet",
+                wallet.fingerprint()
+            ));
+        }
+        info!("import remote key {}: {address}", wallet.fingerprint());
+        Ok(address)
+    }
+
+    /// import_descriptor stores `descriptor` with the wallet and returns the addresses it describes.
+    /// The private keys of hd descriptors become spendable wallets, single keys are watch-only.
+    pub fn import_descriptor(&mut self, descriptor: &str, range: u32) -> Result<Vec<String>> {
+        let descriptor: Descriptor = descriptor.parse()?;
+        let addresses = match &descriptor {
+            Descriptor::Hd { .. } => {
+                let mut addresses = Vec::new();
+                let mut added = false;
+                for wallet in descriptor.wallets(range)? {
+                    addresses.push(wallet.get_address());
+                    added |= self.insert_wallet(wallet)?;
+                }
+                if !added && self.descriptors.contains_key(&descriptor.to_string()) {
+                    return Err(format_err!(
+                        "Descriptor {} is already imported with these keys",
+                        descriptor
+                    ));
+                }
+                addresses
+            }
+            Descriptor::Key(public_key) => {
+                let addresses = descriptor.addresses(range)?;
+                if self.wallets.contains_key(&addresses[0])
+                    || self.descriptors.contains_key(&descriptor.to_string())
+                {
+                    return Err(format_err!(
+                        "Key {} is already in the wallet",
+                        fingerprint(public_key)
+                    ));
+                }
+                addresses
+            }
+            Descriptor::Multi { .. } | Descriptor::Vault { .. } => descriptor.addresses(range)?,
+        };
+        let descriptor = descriptor.to_string();
+        info!("import descriptor: {descriptor}");
+        let range = self
+            .descriptors
+            .get(&descriptor)
+            .copied()
+            .unwrap_or(0)
+            .max(range);
+        self.descriptors.insert(descriptor, range);
+        Ok(addresses)
+    }
+
+    /// list_descriptors returns every descriptor of the wallet file with its range.
+    /// Keys created before descriptors were stored are listed as `pk` descriptors.
+    pub fn list_descriptors(&self) -> Result<Vec<(String, u32)>> {
+        let mut descriptors: Vec<(String, u32)> = self
+            .descriptors
+            .iter()
+            .map(|(d, range)| (d.clone(), *range))
+            .collect();
+
+        let mut described = HashSet::new();
+        for (descriptor, range) in &descriptors {
+            if let Ok(addresses) = descriptor.parse::<Descriptor>()?.addresses(*range) {
+                described.extend(addresses);
+            }
+        }
+        for (address, wallet) in &self.wallets {
+            if !described.contains(address) {
+                descriptors.push((Descriptor::Key(wallet.public_key.clone()).to_string(), 1));
+            }
+        }
+        descriptors.sort();
+        Ok(descriptors)
+    }
+
+    /// get_multisig returns the imported multisig descriptor paying to `address`
+    pub fn get_multisig(&self, address: &str) -> Option<Descriptor> {
+        self.descriptors
+            .keys()
+            .filter_map(|descriptor| descriptor.parse::<Descriptor>().ok())
+            .find(|descriptor| {
+                matches!(descriptor, Descriptor::Multi { .. })
+                    && descriptor
+                        .addresses(1)
+                        .is_ok_and(|addresses| addresses[0] == address)
+            })
+    }
+
+    /// get_vault returns the imported vault paying to `address`
+    pub fn get_vault(&self, address: &str) -> Option<Vault> {
+        self.descriptors
+            .keys()
+            .filter_map(|descriptor| descriptor.parse::<Descriptor>().ok())
+            .find_map(|descriptor| match descriptor {
+                Descriptor::Vault { delay, ref keys }
+                    if descriptor
+                        .addresses(1)
+                        .is_ok_and(|addresses| addresses[0] == address) =>
+                {
+                    Some(Vault {
+                        delay,
+                        keys: keys.clone(),
+                    })
+                }
+                _ => None,
+            })
+    }
+
+    /// watched_addresses lists the wallet's own addresses and those of its watch-only descriptors
+    pub fn watched_addresses(&self) -> Result<Vec<String>> {
+        let mut addresses: HashSet<String> = self.wallets.keys().cloned().collect();
+        for (descriptor, range) in &self.descriptors {
+            if let Ok(derived) = descriptor.parse::<Descriptor>()?.addresses(*range) {
+                addresses.extend(derived);
+            }
+        }
+        let mut addresses: Vec<String> = addresses.into_iter().collect();
+        addresses.sort();
+        Ok(addresses)
     }
 
     pub fn get_all_addresses(&self) -> Vec<String> {
@@ -91,14 +579,279 @@ impl Wallets {
         self.wallets.get(address)
     }
 
+    /// insert_wallet adds a key to the wallet file and records when it was created.
+    /// It returns false when the key is already there and fails when another key is
+    /// stored under its address.
+    fn insert_wallet(&mut self, wallet: Wallet) -> Result<bool> {
+        let address = wallet.get_address();
+        if let Some(existing) = self.wallets.get(&address) {
+            if *existing != wallet {
+                return Err(format_err!(
+                    "Address {} is stored with key {} instead of {}",
+                    address,
+                    existing.fingerprint(),
+                    wallet.fingerprint()
+                ));
+            }
+            info!("key {} is already in the wallet", wallet.fingerprint());
+            return Ok(false);
+        }
+        let now = now_secs().unwrap_or_default();
+        self.created.entry(address.clone()).or_insert(now);
+        self.wallets.insert(address, wallet);
+        Ok(true)
+    }
+
+    /// check_keys verifies every stored key and returns the addresses whose key is broken
+    pub fn check_keys(&self) -> Vec<(String, String)> {
+        let mut broken: Vec<(String, String)> = self
+            .wallets
+            .iter()
+            .filter_map(|(address, wallet)| {
+                check_key(address, wallet).map(|problem| (address.clone(), problem))
+            })
+            .collect();
+        broken.sort();
+        broken
+    }
+
+    pub fn get_policy(&self) -> KeyPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: KeyPolicy) {
+        self.policy = policy;
+    }
+
+    /// set_label names `address`, which need not be one of the wallet's. A label names a
+    /// single address and may not be mistaken for one.
+    pub fn set_label(&mut self, address: &str, label: &str) -> Result<()> {
+        if Address::decode(address).is_err() {
+            return Err(format_err!("{} is not a valid address", address));
+        }
+        if label.trim().is_empty() || Address::decode(label).is_ok() {
+            return Err(format_err!("Invalid label: {:?}", label));
+        }
+        if let Some((other, _)) = self
+            .labels
+            .iter()
+            .find(|(other, name)| *name == label && *other != address)
+        {
+            return Err(format_err!("Label {} already names {}", label, other));
+        }
+        self.labels.insert(address.to_string(), label.to_string());
+        Ok(())
+    }
+
+    pub fn get_label(&self, address: &str) -> Option<&str> {
+        self.labels.get(address).map(String::as_str)
+    }
+
+    /// get_labels returns every labelled address with its label, sorted by label
+    pub fn get_labels(&self) -> Vec<(String, String)> {
+        let mut labels: Vec<(String, String)> = self
+            .labels
+            .iter()
+            .map(|(address, label)| (address.clone(), label.clone()))
+            .collect();
+        labels.sort_by(|a, b| a.1.cmp(&b.1));
+        labels
+    }
+
+    /// resolve returns the address labelled `name`, or `name` itself when it is no label
+    pub fn resolve(&self, name: &str) -> String {
+        match self.labels.iter().find(|(_, label)| *label == name) {
+            Some((address, _)) => address.clone(),
+            None => name.to_string(),
+        }
+    }
+
+    /// resolve_recipient is resolve, turning the address of an imported vault into its
+    /// descriptor so that paying it deposits into the vault
+    pub fn resolve_recipient(&self, name: &str) -> String {
+        let address = self.resolve(name);
+        match self.get_vault(&address) {
+            Some(vault) => vault.descriptor().to_string(),
+            None => address,
+        }
+    }
+
+    /// set_spend_limits caps the sends of `address`, one of the wallet's keys, or lifts
+    /// the caps when `limits` is None. Limits already set can only be changed with their
+    /// approval code.
+    pub fn set_spend_limits(
+        &mut self,
+        address: &str,
+        limits: Option<SpendLimits>,
+        current_code: &str,
+    ) -> Result<()> {
+        if self.get_wallet(address).is_none() {
+            return Err(format_err!("{} is not a key of the wallet", address));
+        }
+        if let Some(current) = self.limits.get(address)
+            && !current.accepts_code(current_code)
+        {
+            return Err(format_err!("Wrong approval code for {}", address));
+        }
+        match limits {
+            Some(limits) => self.limits.insert(address.to_string(), limits),
+            None => self.limits.remove(address),
+        };
+        Ok(())
+    }
+
+    /// check_spend returns the limit sending `amount` from `address` at `now` is above,
+    /// None when the send may go out without approval
+    pub fn check_spend(&self, address: &str, amount: i32, now: u64) -> Option<String> {
+        let limits = self.limits.get(address)?;
+        let spent_today = self.spends.get(address).map_or(0, |spends| {
+            spends
+                .iter()
+                .filter(|(at, _)| at + DAY > now)
+                .map(|(_, amount)| amount)
+                .sum()
+        });
+        limits.exceeded(amount, spent_today)
+    }
+
+    /// record_spend counts a send of `amount` from `address` towards its daily limit
+    pub fn record_spend(&mut self, address: &str, amount: i32, now: u64) {
+        if !self.limits.contains_key(address) {
+            return;
+        }
+        let spends = self.spends.entry(address.to_string()).or_default();
+        spends.retain(|(at, _)| at + DAY > now);
+        spends.push((now, amount));
+    }
+
+    /// queue_send holds `pending` back until it is approved and returns its id
+    pub fn queue_send(&mut self, mut pending: PendingSend) -> u64 {
+        pending.id = self.approvals.keys().next_back().map_or(1, |id| id + 1);
+        let id = pending.id;
+        self.approvals.insert(id, pending);
+        id
+    }
+
+    /// get_pending_sends returns the sends waiting for approval, oldest first
+    pub fn get_pending_sends(&self) -> Vec<&PendingSend> {
+        self.approvals.values().collect()
+    }
+
+    /// approve releases pending send `id` when `code` is the approval code of the
+    /// address it spends from
+    pub fn approve(&mut self, id: u64, code: &str) -> Result<PendingSend> {
+        let pending = match self.approvals.get(&id) {
+            Some(pending) => pending,
+            None => return Err(format_err!("No send {} is pending approval", id)),
+        };
+        let accepted = self
+            .limits
+            .get(&pending.from)
+            .is_none_or(|limits| limits.accepts_code(code));
+        if !accepted {
+            return Err(format_err!("Wrong approval code for send {}", id));
+        }
+        Ok(self.approvals.remove(&id).unwrap())
+    }
+
+    /// is_mine tells whether `tx` pays to or spends from an address the wallet watches
+    pub fn is_mine(&self, tx: &Transaction) -> Result<bool> {
+        let watched: HashSet<String> = self.watched_addresses()?.into_iter().collect();
+        let pays = tx
+            .vout
+            .iter()
+            .filter_map(|out| out.address())
+            .any(|address| watched.contains(&address));
+        let spends = !tx.is_coinbase()
+            && tx
+                .vin
+                .iter()
+                .any(|vin| watched.contains(&hash_to_address(spender_hash(&vin.pub_key))));
+        Ok(pays || spends)
+    }
+
+    /// record_conflict keeps `conflict`, or notes the block of one already kept, and
+    /// tells whether anything changed
+    pub fn record_conflict(&mut self, conflict: Conflict) -> bool {
+        let key = format!("{}:{}", conflict.txid, conflict.conflicting);
+        match self.conflicts.get_mut(&key) {
+            Some(known) if known.block.is_none() && conflict.block.is_some() => {
+                known.block = conflict.block;
+                true
+            }
+            Some(_) => false,
+            None => {
+                self.conflicts.insert(key, conflict);
+                true
+            }
+        }
+    }
+
+    /// get_conflicts returns the double spends of wallet transaction `txid`, or of every
+    /// wallet transaction
+    pub fn get_conflicts(&self, txid: Option<&TxId>) -> Vec<&Conflict> {
+        self.conflicts
+            .values()
+            .filter(|conflict| txid.is_none_or(|txid| conflict.txid == *txid))
+            .collect()
+    }
+
+    /// get_created returns when the key of `address` was created, in seconds
+    pub fn get_created(&self, address: &str) -> Option<u64> {
+        self.created.get(address).copied()
+    }
+
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(&self.path)?;
 
         for (address, wallet) in &self.wallets {
             let data = serialize(wallet)?;
             db.insert(address, data)?;
         }
 
+        if let Some(seed) = &self.hd_seed {
+            let hd = db.open_tree("hd")?;
+            hd.insert("seed", seed.as_slice())?;
+            hd.insert("next_index", serialize(&self.hd_next_index)?)?;
+        }
+
+        let created = db.open_tree("created")?;
+        for (address, time) in &self.created {
+            created.insert(address.as_str(), serialize(time)?)?;
+        }
+        db.open_tree("policy")?
+            .insert("policy", serialize(&self.policy)?)?;
+
+        let descriptors = db.open_tree("descriptors")?;
+        for (descriptor, range) in &self.descriptors {
+            descriptors.insert(descriptor.as_str(), serialize(range)?)?;
+        }
+
+        let labels = db.open_tree("labels")?;
+        for (address, label) in &self.labels {
+            labels.insert(address.as_str(), label.as_str())?;
+        }
+
+        // limits can be lifted and sends approved, so these trees are rewritten
+        let limits = db.open_tree("limits")?;
+        limits.clear()?;
+        for (address, address_limits) in &self.limits {
+            limits.insert(address.as_str(), serialize(address_limits)?)?;
+        }
+        let spends = db.open_tree("spends")?;
+        for (address, address_spends) in &self.spends {
+            spends.insert(address.as_str(), serialize(address_spends)?)?;
+        }
+        let approvals = db.open_tree("approvals")?;
+        approvals.clear()?;
+        for (id, pending) in &self.approvals {
+            approvals.insert(id.to_be_bytes(), serialize(pending)?)?;
+        }
+        let conflicts = db.open_tree("conflicts")?;
+        for (key, conflict) in &self.conflicts {
+            conflicts.insert(key.as_str(), serialize(conflict)?)?;
+        }
+
         db.flush()?;
         drop(db);
         Ok(())
@@ -126,7 +879,7 @@ mod test {
     #[test]
     fn test_wallets() {
         let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
+        let wa1 = ws.create_wallet().unwrap();
         let w1 = ws.get_wallet(&wa1).unwrap().clone();
         ws.save_all().unwrap();
 
@@ -135,6 +888,107 @@ mod test {
         assert_eq!(&w1, w2);
     }
 
+    #[test]
+    fn test_labels() {
+        let path = std::env::temp_dir().join(format!("labels-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut ws = Wallets::open(path).unwrap();
+        let alice = Wallet::derive(&[1; 32], &[44, 0], 0).get_address();
+        let bob = Wallet::derive(&[2; 32], &[44, 0], 0).get_address();
+        ws.set_label(&alice, "alice").unwrap();
+        assert!(ws.set_label(&bob, "alice").is_err());
+        assert!(ws.set_label(&bob, &alice).is_err());
+        assert!(ws.set_label("bob", "bob").is_err());
+        ws.set_label(&bob, "bob").unwrap();
+        ws.save_all().unwrap();
+
+        let ws = Wallets::open(path).unwrap();
+        assert_eq!(ws.get_label(&bob), Some("bob"));
+        assert_eq!(ws.resolve("alice"), alice);
+        assert_eq!(ws.resolve(&bob), bob);
+        assert_eq!(ws.get_labels()[0].1, "alice");
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_spend_limits() {
+        let path = std::env::temp_dir().join(format!("limits-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut ws = Wallets::open(path).unwrap();
+        let from = ws.create_wallet().unwrap();
+        let limits = SpendLimits::new(Some(10), Some(15), "cfo");
+        assert!(ws
+            .set_spend_limits(
+                "1BoatSLRHtKNngkdXEeobR76b53LETtpyT",
+                Some(limits.clone()),
+                ""
+            )
+            .is_err());
+        ws.set_spend_limits(&from, Some(limits), "").unwrap();
+        assert!(ws.set_spend_limits(&from, None, "guess").is_err());
+
+        assert_eq!(ws.check_spend(&from, 8, 100), None);
+        ws.record_spend(&from, 8, 100);
+        assert!(ws.check_spend(&from, 8, 200).is_some());
+        assert_eq!(ws.check_spend(&from, 8, 100 + DAY), None);
+        let id = ws.queue_send(PendingSend {
+            id: 0,
+            from: from.clone(),
+            recipients: vec![(from.clone(), 20)],
+            lock: crate::script::LockScript::PayToPubkeyHash,
+            lock_time: 0,
+            expiry_height: 0,
+            selection: crate::utxoset::CoinSelection::default(),
+            mine_now: false,
+            created: 200,
+            reason: String::from("above the limit"),
+        });
+        ws.save_all().unwrap();
+
+        let mut ws = Wallets::open(path).unwrap();
+        assert!(ws.check_spend(&from, 8, 200).is_some());
+        assert_eq!(ws.get_pending_sends().len(), 1);
+        assert!(ws.approve(id, "guess").is_err());
+        assert_eq!(ws.approve(id, "cfo").unwrap().amount(), 20);
+        assert!(ws.approve(id, "cfo").is_err());
+        ws.save_all().unwrap();
+        assert!(Wallets::open(path).unwrap().get_pending_sends().is_empty());
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let path = std::env::temp_dir().join(format!("conflicts-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut ws = Wallets::open(path).unwrap();
+        let mine = Transaction::new_coinbase(ws.create_wallet().unwrap(), String::new(), 1);
+        let other = Transaction::new_coinbase(hash_to_address(vec![7; 20]), String::new(), 1);
+        assert!(ws.is_mine(&mine.unwrap()).unwrap());
+        assert!(!ws.is_mine(&other.unwrap()).unwrap());
+
+        let conflict = Conflict {
+            txid: TxId::digest(b"a"),
+            conflicting: TxId::digest(b"b"),
+            block: None,
+            seen: 1,
+        };
+        assert!(ws.record_conflict(conflict.clone()));
+        assert!(!ws.record_conflict(conflict.clone()));
+        assert!(ws.record_conflict(Conflict {
+            block: Some(BlockHash::default()),
+            ..conflict
+        }));
+        ws.save_all().unwrap();
+
+        let ws = Wallets::open(path).unwrap();
+        assert_eq!(
+            ws.get_conflicts(Some(&TxId::digest(b"a")))[0].block,
+            Some(BlockHash::default())
+        );
+        assert!(ws.get_conflicts(Some(&TxId::digest(b"b"))).is_empty());
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
     #[test]
     #[should_panic]
     fn test_wallets_not_exist() {
@@ -143,6 +997,69 @@ mod test {
         ws2.get_wallet(&w3.get_address()).unwrap();
     }
 
+    #[test]
+    fn test_hd_wallets_are_deterministic() {
+        let phrase = Wallets::generate_mnemonic().unwrap();
+        let seed = Mnemonic::parse(&phrase).unwrap().to_seed("");
+        let w1 = Wallet::from_hd_seed(&seed, 0);
+        assert_eq!(w1, Wallet::from_hd_seed(&seed, 0));
+        assert_ne!(w1, Wallet::from_hd_seed(&seed, 1));
+
+        let signature = ed25519::signature("test".as_bytes(), &w1.secret_key);
+        assert!(ed25519::verify(
+            "test".as_bytes(),
+            &w1.public_key,
+            &signature
+        ));
+    }
+
+    #[test]
+    fn test_key_policy() {
+        let policy = KeyPolicy {
+            max_uses: Some(2),
+            max_age: Some(100),
+        };
+        assert!(!policy.retired(1000, 1, 1050));
+        assert!(policy.retired(1000, 2, 1050));
+        assert!(policy.retired(1000, 0, 1100));
+        assert!(!KeyPolicy::default().retired(0, 1000, u64::MAX));
+
+        let payment = |received, timestamp: u128| HistoryEntry {
+            txid: TxId::default(),
+            height: 0,
+            timestamp: timestamp * 1000,
+            received,
+            sent: 0,
+        };
+        let history = vec![
+            payment(5, 1010),
+            payment(0, 1020),
+            payment(5, 1030),
+            payment(5, 1040),
+        ];
+        let late = policy.late_payments(1000, &history);
+        assert_eq!(late.len(), 1);
+        assert_eq!(late[0].timestamp, 1_040_000);
+    }
+
+    #[test]
+    fn test_check_key() {
+        let w = Wallet::new();
+        assert_eq!(w.fingerprint(), w.fingerprint());
+        assert_eq!(w.fingerprint().len(), 8);
+        assert_eq!(check_key(&w.get_address(), &w), None);
+
+        let other = Wallet::new();
+        assert!(check_key(&other.get_address(), &w).is_some());
+
+        let mut corrupted = w.clone();
+        corrupted.secret_key[0] ^= 1;
+        assert_eq!(
+            check_key(&w.get_address(), &corrupted).unwrap(),
+            "secret key does not produce the stored public key"
+        );
+    }
+
     #[test]
     fn test_signature() {
         let w = Wallet::new();
@@ -0,0 +1,69 @@
+use super::*;
+use crate::base64;
+use crypto::{digest::Digest, sha1::Sha1};
+use std::io::Write;
+
+/// WEBSOCKET_GUID is appended to the client key to prove the server speaks WebSocket
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// accept_key is the Sec-WebSocket-Accept answer to the client's Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(key.trim());
+    sha1.input_str(WEBSOCKET_GUID);
+    let mut digest = [0; 20];
+    sha1.result(&mut digest);
+    base64::encode(&digest)
+}
+
+/// handshake switches an HTTP connection whose upgrade request carried `key` to WebSocket
+pub fn handshake(stream: &mut impl Write, key: &str) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )?;
+    Ok(())
+}
+
+/// send_text writes `text` as one unmasked text frame, as servers send them
+pub fn send_text(stream: &mut impl Write, text: &str) -> Result<()> {
+    let payload = text.as_bytes();
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_websocket() {
+        // the example of RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let mut frame = Vec::new();
+        send_text(&mut frame, "hi").unwrap();
+        assert_eq!(frame, vec![0x81, 2, b'h', b'i']);
+        let mut frame = Vec::new();
+        send_text(&mut frame, &"x".repeat(300)).unwrap();
+        assert_eq!(frame[..4], [0x81, 126, 1, 44]);
+    }
+}